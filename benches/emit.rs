@@ -0,0 +1,301 @@
+// Benchmarks of handle emission, channel throughput, batch serialization and end-to-end batch
+// uploads to a mock endpoint.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, Throughput};
+use nano_gcp_logging::{
+    build_entry, write_entries, BatchPolicy, EntryConfig, GcpLogEntry, GcpLoggingLayer,
+    GcpLoggingLayerBuilder, InstanceMetadata, LogContextMetadata, LogEntry, LogSink, PendingFields,
+    Severity, TokenFuture, TokenProvider,
+};
+use serde_json::{Map, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Dispatch;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Events per iteration of the throughput benchmark
+const THROUGHPUT_EVENTS: usize = 1_000;
+
+/// Entries per request in the end-to-end benchmark
+const UPLOAD_BATCH: usize = 50;
+
+/// Environment variable holding the throughput floor checked after the benchmarks
+const MIN_EVENTS_PER_SEC: &str = "NANO_GCP_LOGGING_MIN_EVENTS_PER_SEC";
+
+/// Sink counting the entries it receives
+#[derive(Clone, Default)]
+struct CountingSink(Arc<AtomicUsize>);
+
+impl LogSink for CountingSink {
+    fn write(&self, _entry: Value) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Token provider handing out a fixed token, so uploads are attempted
+struct StaticToken;
+
+impl TokenProvider for StaticToken {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async { Ok("bench-token".to_string()) })
+    }
+}
+
+fn instance() -> InstanceMetadata {
+    InstanceMetadata {
+        name: "bench".into(),
+        id: "1".into(),
+        zone: "us-central1-a".into(),
+        region: "us-central1".into(),
+        project_id: "bench-project".into(),
+    }
+}
+
+fn builder() -> GcpLoggingLayerBuilder {
+    GcpLoggingLayer::builder("bench-project").instance_metadata(instance())
+}
+
+/// Spin until `counter` reaches `target`
+fn wait_for(counter: &AtomicUsize, target: usize) {
+    while counter.load(Ordering::Relaxed) < target {
+        std::thread::yield_now();
+    }
+}
+
+/// Answer `entries:write` requests with 200, counting the entries received
+///
+/// # Returns
+/// The address to send requests to
+async fn mock_endpoint(received: Arc<AtomicUsize>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let address = listener.local_addr().expect("address");
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, received.clone()));
+        }
+    });
+    format!("http://{}/v2/entries:write", address)
+}
+
+/// Serve keep-alive requests on one connection
+async fn serve(mut stream: TcpStream, received: Arc<AtomicUsize>) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let Some(head_end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+            continue;
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_end]).to_ascii_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        let request_end = head_end + 4 + length;
+        while buffer.len() < request_end {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+        let body = &buffer[head_end + 4..request_end];
+        let entries = body.windows(9).filter(|w| w == b"\"logName\"").count();
+        buffer.drain(..request_end);
+        let response =
+            b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\n\r\n{}";
+        if stream.write_all(response).await.is_err() {
+            return;
+        }
+        received.fetch_add(entries, Ordering::Relaxed);
+    }
+}
+
+/// Latency of `GcpLoggingHandle::emit` on the caller's thread
+fn bench_handle_emit(c: &mut Criterion, runtime: &tokio::runtime::Runtime) {
+    let layer = runtime
+        .block_on(builder().sink(CountingSink::default()).build())
+        .expect("layer");
+    let handle = layer.handle();
+    let _runtime = runtime.enter();
+    c.bench_function("handle_emit", |b| {
+        b.iter(|| {
+            handle
+                .emit(LogEntry::new(Severity::Info, "request handled").field("user_id", 42))
+                .expect("emit")
+        })
+    });
+}
+
+/// Events per second from `on_event` through the channel to built entries
+fn bench_throughput(c: &mut Criterion, runtime: &tokio::runtime::Runtime) {
+    let sink = CountingSink::default();
+    let layer = runtime
+        .block_on(builder().sink(sink.clone()).build())
+        .expect("layer");
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    let _runtime = runtime.enter();
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(THROUGHPUT_EVENTS as u64));
+    group.bench_function("sink", |b| {
+        b.iter(|| {
+            let target = sink.0.load(Ordering::Relaxed) + THROUGHPUT_EVENTS;
+            tracing::dispatcher::with_default(&dispatch, || {
+                for i in 0..THROUGHPUT_EVENTS {
+                    tracing::info!(user_id = i, path = "/checkout", "request handled");
+                }
+            });
+            wait_for(&sink.0, target);
+        })
+    });
+    group.finish();
+}
+
+/// Time to serialize a batch of entries, through a JSON value and streamed
+fn bench_serialize(c: &mut Criterion) {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let config = EntryConfig::default();
+    let entries: Vec<GcpLogEntry> = (0..THROUGHPUT_EVENTS)
+        .map(|i| {
+            let mut fields = Map::new();
+            fields.insert("user_id".into(), i.into());
+            fields.insert("path".into(), "/checkout".into());
+            GcpLogEntry {
+                message: "request handled".into(),
+                severity: Severity::Info,
+                fields,
+                timestamp: chrono::Utc::now(),
+                source_location: None,
+                operation: None,
+                trace: None,
+                labels: Default::default(),
+                runtime_labels: None,
+                internal: false,
+                callsite: None,
+                pending: PendingFields::default(),
+            }
+        })
+        .collect();
+    let mut body = Vec::new();
+    let mut group = c.benchmark_group("serialize");
+    group.throughput(Throughput::Elements(THROUGHPUT_EVENTS as u64));
+    group.bench_function("value", |b| {
+        b.iter(|| {
+            body.clear();
+            for entry in &entries {
+                let entry = build_entry(&metadata, &config, entry);
+                serde_json::to_writer(&mut body, &entry).expect("serialize");
+            }
+        })
+    });
+    group.bench_function("stream", |b| {
+        b.iter(|| {
+            body.clear();
+            write_entries(&metadata, &config, &entries, &mut body).expect("serialize");
+        })
+    });
+    group.finish();
+}
+
+/// Time from emitting a full batch until a mock endpoint has received it
+fn bench_end_to_end(c: &mut Criterion, runtime: &tokio::runtime::Runtime) {
+    let received = Arc::new(AtomicUsize::new(0));
+    let url = runtime.block_on(mock_endpoint(received.clone()));
+    let url = reqwest::Url::parse(&url).expect("url");
+    let policy = BatchPolicy {
+        batch_size: UPLOAD_BATCH,
+        flush_interval: Duration::from_secs(60),
+        gzip_backlog: false,
+        ..BatchPolicy::default()
+    };
+    let layer = runtime
+        .block_on(
+            builder()
+                .token_provider(StaticToken)
+                .batch_policy(policy)
+                .on_request(move |request| {
+                    let (client, built) = request.build_split();
+                    let mut built = built.expect("request");
+                    *built.url_mut() = url.clone();
+                    reqwest::RequestBuilder::from_parts(client, built)
+                })
+                .build(),
+        )
+        .expect("layer");
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    let _runtime = runtime.enter();
+    let mut group = c.benchmark_group("end_to_end");
+    group.throughput(Throughput::Elements(UPLOAD_BATCH as u64));
+    group.bench_function("batch_upload", |b| {
+        b.iter(|| {
+            let target = received.load(Ordering::Relaxed) + UPLOAD_BATCH;
+            tracing::dispatcher::with_default(&dispatch, || {
+                for i in 0..UPLOAD_BATCH {
+                    tracing::info!(user_id = i, path = "/checkout", "request handled");
+                }
+            });
+            wait_for(&received, target);
+        })
+    });
+    group.finish();
+}
+
+/// Fail when throughput falls below `NANO_GCP_LOGGING_MIN_EVENTS_PER_SEC`
+///
+/// Criterion only reports changes against a saved baseline; this gives CI a
+/// hard floor. Skipped unless the variable is set.
+fn regression_guard(runtime: &tokio::runtime::Runtime) {
+    let Some(floor) = std::env::var(MIN_EVENTS_PER_SEC)
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+    else {
+        return;
+    };
+    let sink = CountingSink::default();
+    let layer = runtime
+        .block_on(builder().sink(sink.clone()).build())
+        .expect("layer");
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    let _runtime = runtime.enter();
+    let events = 100 * THROUGHPUT_EVENTS;
+    let started = Instant::now();
+    tracing::dispatcher::with_default(&dispatch, || {
+        for i in 0..events {
+            tracing::info!(user_id = i, path = "/checkout", "request handled");
+        }
+    });
+    wait_for(&sink.0, events);
+    let measured = events as f64 / started.elapsed().as_secs_f64();
+    println!(
+        "regression guard: {:.0} events/s (floor {:.0})",
+        measured, floor
+    );
+    assert!(
+        measured >= floor,
+        "throughput {:.0} events/s is below {}={:.0}",
+        measured,
+        MIN_EVENTS_PER_SEC,
+        floor
+    );
+}
+
+fn main() {
+    std::env::set_var("NANO_GCP_LOGGING_QUIET", "1");
+    let runtime = tokio::runtime::Runtime::new().expect("runtime");
+    let mut criterion = Criterion::default().configure_from_args();
+    bench_handle_emit(&mut criterion, &runtime);
+    bench_throughput(&mut criterion, &runtime);
+    bench_serialize(&mut criterion);
+    bench_end_to_end(&mut criterion, &runtime);
+    criterion.final_summary();
+    regression_guard(&runtime);
+}
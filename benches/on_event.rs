@@ -0,0 +1,70 @@
+// Benchmarks of the per-event cost of `on_event` for filtered, discarded and shipped events,
+// against `tracing_subscriber::fmt` writing to a sink.
+use criterion::{criterion_group, criterion_main, Criterion};
+use nano_gcp_logging::{GcpLoggingLayer, GcpLoggingLayerBuilder, InstanceMetadata, LogSink};
+use serde_json::Value;
+use tracing::Dispatch;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Sink discarding every entry, so only the layer's own work is measured
+struct NullSink;
+
+impl LogSink for NullSink {
+    fn write(&self, _entry: Value) {}
+}
+
+fn builder() -> GcpLoggingLayerBuilder {
+    GcpLoggingLayer::builder("bench-project").instance_metadata(InstanceMetadata {
+        name: "bench".into(),
+        id: "1".into(),
+        zone: "us-central1-a".into(),
+        region: "us-central1".into(),
+        project_id: "bench-project".into(),
+    })
+}
+
+fn bench_on_event(c: &mut Criterion) {
+    std::env::set_var("NANO_GCP_LOGGING_QUIET", "1");
+    let runtime = tokio::runtime::Runtime::new().expect("runtime");
+    let build = |builder: GcpLoggingLayerBuilder| runtime.block_on(builder.build()).expect("layer");
+    let filtered = builder()
+        .sink(NullSink)
+        .shipped_targets("other_target=info")
+        .build();
+    let dispatches = [
+        (
+            "filtered",
+            Dispatch::new(Registry::default().with(runtime.block_on(filtered).expect("layer"))),
+        ),
+        // Without credentials (as in CI) entries are dropped by the layer.
+        (
+            "discarded",
+            Dispatch::new(Registry::default().with(build(builder()))),
+        ),
+        (
+            "shipped",
+            Dispatch::new(Registry::default().with(build(builder().sink(NullSink)))),
+        ),
+    ];
+    for (name, dispatch) in dispatches {
+        let _runtime = runtime.enter();
+        tracing::dispatcher::with_default(&dispatch, || {
+            c.bench_function(name, |b| {
+                b.iter(|| tracing::info!(user_id = 42, path = "/checkout", "request handled"))
+            });
+        });
+    }
+    let fmt = tracing_subscriber::fmt()
+        .with_writer(std::io::sink)
+        .with_ansi(false)
+        .finish();
+    let dispatch = Dispatch::new(fmt);
+    tracing::dispatcher::with_default(&dispatch, || {
+        c.bench_function("fmt", |b| {
+            b.iter(|| tracing::info!(user_id = 42, path = "/checkout", "request handled"))
+        });
+    });
+}
+
+criterion_group!(benches, bench_on_event);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+// An example of flushing queued log entries when Kubernetes stops a pod
+// during a rolling deploy.
+use std::time::Duration;
+
+use nano_gcp_logging::GcpLoggingLayer;
+use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let project_id = std::env::var("PROJECT_ID").unwrap_or_else(|_| "your-gcp-project-id".into());
+
+    let gcp_layer = GcpLoggingLayer::new(project_id).await?;
+    let handle = gcp_layer.handle();
+    let subscriber = Registry::default().with(gcp_layer);
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    // Stand-in for the application's real work.
+    let work = tokio::spawn(async {
+        let mut ticks = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticks.tick().await;
+            info!("Serving requests");
+        }
+    });
+
+    // Kubernetes sends SIGTERM after the preStop hook and kills the pod once
+    // terminationGracePeriodSeconds (30s by default) elapse, so keep the
+    // flush timeout well below it.
+    let report = handle.shutdown_on_signal(Duration::from_secs(10)).await;
+    work.abort();
+    match report {
+        Some(report) => eprintln!(
+            "Log entries at shutdown: {} sent, {} dropped, {} remaining",
+            report.sent, report.dropped, report.remaining
+        ),
+        None => eprintln!("Log entries at shutdown: unknown"),
+    }
+
+    Ok(())
+}
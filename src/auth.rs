@@ -0,0 +1,204 @@
+//! Access token acquisition and refresh for uploads.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use gcp_auth::AuthenticationManager;
+
+/// OAuth scope required to write log entries
+const LOGGING_WRITE_SCOPE: &str = "https://www.googleapis.com/auth/logging.write";
+
+/// OAuth scope required to report errors to Error Reporting
+pub(crate) const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Snapshot of the access token's lifecycle, for diagnosing auth issues
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenStatus {
+    /// When the current token was acquired, None if no token was ever acquired
+    pub acquired_at: Option<DateTime<Utc>>,
+    /// When the current token expires, None if no token was ever acquired or
+    /// it came from a `TokenProvider`
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Number of times the token was replaced after the initial acquisition
+    pub refresh_count: u64,
+}
+
+/// Error returned by a `TokenProvider`
+pub type TokenError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Future returned by `TokenProvider::token`
+pub type TokenFuture<'a> = Pin<Box<dyn Future<Output = Result<String, TokenError>> + Send + 'a>>;
+
+/// Source of OAuth access tokens used instead of Application Default Credentials
+///
+/// Install one with `GcpLoggingLayerBuilder::token_provider` to reuse auth the
+/// application already manages, e.g. impersonated credentials or another auth
+/// library. Implement `token` as `Box::pin(async move { .. })`.
+///
+/// Refresh contract: `token` is called once when the layer starts and then
+/// before every upload, so it must be cheap. Cache the token and refresh it
+/// before it expires (a few minutes ahead is customary); the layer never
+/// caches tokens itself. The token needs the
+/// `https://www.googleapis.com/auth/logging.write` scope. An error at startup
+/// makes the layer proceed without auth; later errors fail that upload, which
+/// is retried like any other transient failure.
+pub trait TokenProvider: Send + Sync + 'static {
+    /// Get a valid access token
+    ///
+    /// # Returns
+    /// The bearer token, or the error that prevented obtaining one
+    fn token(&self) -> TokenFuture<'_>;
+}
+
+/// Where tokens come from
+enum Credentials {
+    /// Default credentials manager; it caches the token and refreshes it when expired
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    Default(Box<AuthenticationManager>),
+    /// Provider supplied by the application
+    Custom(Arc<dyn TokenProvider>),
+}
+
+/// Provides a valid access token, refreshing it as it nears expiry
+pub(crate) struct TokenSource {
+    /// Where tokens come from
+    credentials: Credentials,
+    /// Lifecycle snapshot shared with handles
+    status: Arc<Mutex<TokenStatus>>,
+    /// Last token handed out by a custom provider, to detect refreshes
+    last_token: Mutex<Option<String>>,
+}
+
+impl TokenSource {
+    /// Initialize authentication and acquire the first token
+    ///
+    /// Initialization is allowed to fail (e.g. in tests or local dev): a warning
+    /// is printed and None is returned so the layer can proceed without auth.
+    ///
+    /// # Arguments
+    /// * `provider` - Token provider replacing the default credentials, if any
+    /// * `status` - Lifecycle snapshot updated on every acquisition
+    ///
+    /// # Returns
+    /// The token source, or None when no credentials are available
+    pub(crate) async fn acquire(
+        provider: Option<Arc<dyn TokenProvider>>,
+        status: Arc<Mutex<TokenStatus>>,
+    ) -> Option<Self> {
+        let credentials = match provider {
+            Some(provider) => Credentials::Custom(provider),
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            None => match AuthenticationManager::new().await {
+                Ok(manager) => Credentials::Default(Box::new(manager)),
+                Err(e) => {
+                    warning!(
+                        "failed to initialize AuthenticationManager: {}. Proceeding without auth.",
+                        e
+                    );
+                    return None;
+                }
+            },
+            // Application Default Credentials are read by the async client's stack.
+            #[cfg(not(any(feature = "reqwest", feature = "hyper")))]
+            None => return None,
+        };
+        let source = Self {
+            credentials,
+            status,
+            last_token: Mutex::new(None),
+        };
+        if let Err(e) = source.token().await {
+            warning!(
+                "failed to acquire GCP token: {}. Proceeding without auth.",
+                e
+            );
+            return None;
+        }
+        Some(source)
+    }
+
+    /// Get a valid access token
+    ///
+    /// # Returns
+    /// The bearer token, refreshed if the cached one has expired
+    pub(crate) async fn token(&self) -> Result<String, TokenError> {
+        match &self.credentials {
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            Credentials::Default(manager) => self.default_token(manager).await,
+            Credentials::Custom(provider) => {
+                let token = provider.token().await?;
+                self.record_custom(&token);
+                Ok(token)
+            }
+        }
+    }
+
+    /// Get an upload token from the default credentials manager
+    ///
+    /// # Arguments
+    /// * `manager` - The manager, which caches the token until it expires
+    ///
+    /// # Returns
+    /// The bearer token
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    async fn default_token(&self, manager: &AuthenticationManager) -> Result<String, TokenError> {
+        let token = manager.get_token(&[LOGGING_WRITE_SCOPE]).await?;
+        let expires_at = token.expires_at();
+
+        // The manager hands back its cached token until it expires; a new
+        // expiry means it was refreshed.
+        if let Ok(mut status) = self.status.lock() {
+            if status.expires_at != Some(expires_at) {
+                if status.expires_at.is_some() {
+                    status.refresh_count += 1;
+                }
+                status.acquired_at = Some(Utc::now());
+                status.expires_at = Some(expires_at);
+            }
+        }
+        Ok(token.as_str().to_string())
+    }
+
+    /// Get a valid access token for another API
+    ///
+    /// Tokens from a `TokenProvider` are used as they are, so they need the
+    /// scope already. The lifecycle snapshot only tracks upload tokens.
+    ///
+    /// # Arguments
+    /// * `scope` - The OAuth scope the token needs
+    ///
+    /// # Returns
+    /// The bearer token
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) async fn token_for(&self, scope: &str) -> Result<String, TokenError> {
+        match &self.credentials {
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            Credentials::Default(manager) => {
+                Ok(manager.get_token(&[scope]).await?.as_str().to_string())
+            }
+            Credentials::Custom(provider) => provider.token().await,
+        }
+    }
+
+    /// Record a token from a custom provider in the lifecycle snapshot
+    ///
+    /// Providers do not report expiry, so a different token counts as a
+    /// refresh and `expires_at` stays unset.
+    fn record_custom(&self, token: &str) {
+        let Ok(mut last_token) = self.last_token.lock() else {
+            return;
+        };
+        if last_token.as_deref() == Some(token) {
+            return;
+        }
+        if let Ok(mut status) = self.status.lock() {
+            if last_token.is_some() {
+                status.refresh_count += 1;
+            }
+            status.acquired_at = Some(Utc::now());
+        }
+        *last_token = Some(token.to_string());
+    }
+}
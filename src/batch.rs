@@ -0,0 +1,183 @@
+//! Batching policy and the request body accumulator used by the worker.
+use std::io::Write;
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::encoder::WireEncoder;
+use crate::pool::{MAX_POOLED, MAX_POOLED_CAPACITY};
+use crate::Severity;
+
+/// Controls how queued entries are grouped into `entries:write` requests
+///
+/// In steady state the worker sends small batches for low latency. When the
+/// queue is at least `backlog_threshold` entries deep (e.g. after an outage),
+/// it switches to backlog mode: batches grow up to `backlog_batch_size` entries
+/// and bodies are gzip-compressed, then it returns to small batches once the
+/// queue has drained below the threshold.
+#[derive(Debug, Clone)]
+pub struct BatchPolicy {
+    /// Entries per request while the queue is shallow
+    pub batch_size: usize,
+    /// Longest time a partial batch waits before being sent
+    pub flush_interval: Duration,
+    /// Queue depth at or above which backlog mode is used
+    pub backlog_threshold: usize,
+    /// Entries per request in backlog mode
+    pub backlog_batch_size: usize,
+    /// Upper bound on the uncompressed request body in bytes
+    pub max_batch_bytes: usize,
+    /// Compress request bodies with gzip in backlog mode
+    pub gzip_backlog: bool,
+    /// Lowest severity that sends the pending batch immediately, if any
+    pub flush_on: Option<Severity>,
+    /// Longest time any entry waits after capture before its batch is sent
+    pub max_latency: Option<Duration>,
+    /// Grace period after startup during which entries are only sent once
+    /// the batch fills, so the burst of startup logs shares requests
+    ///
+    /// It replaces `flush_interval` only: `flush_on` and `max_latency` still
+    /// send partial batches early. None sends from the start.
+    pub warmup: Option<Duration>,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        Self {
+            batch_size: 50,
+            flush_interval: Duration::from_secs(1),
+            backlog_threshold: 1_000,
+            backlog_batch_size: 1_000,
+            // The API rejects requests above 10 MB; stay well below it.
+            max_batch_bytes: 5 * 1024 * 1024,
+            gzip_backlog: true,
+            flush_on: None,
+            max_latency: None,
+            warmup: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// Serialized entries waiting to be sent in a single request
+#[derive(Default)]
+pub(crate) struct Batch {
+    /// Serialized `LogEntry` JSON objects
+    entries: Vec<Vec<u8>>,
+    /// Total size of `entries` in bytes
+    bytes: usize,
+    /// Emptied entry buffers, reused for the next serializations
+    free: Vec<Vec<u8>>,
+}
+
+impl Batch {
+    /// Number of entries in the batch
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the batch holds no entries
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether adding an entry of `size` bytes would exceed `max_bytes`
+    ///
+    /// A batch always accepts its first entry so oversized entries still go out.
+    pub(crate) fn would_overflow(&self, size: usize, max_bytes: usize) -> bool {
+        !self.is_empty() && self.bytes + size > max_bytes
+    }
+
+    /// Add a serialized entry
+    pub(crate) fn push(&mut self, entry: Vec<u8>) {
+        self.bytes += entry.len();
+        self.entries.push(entry);
+    }
+
+    /// Drain the batch into a request body
+    ///
+    /// # Arguments
+    /// * `encoder` - Joins the entries, e.g. into an `entries:write` JSON body
+    ///
+    /// # Returns
+    /// The uncompressed body
+    pub(crate) fn take_body(&mut self, encoder: &dyn WireEncoder) -> Vec<u8> {
+        let mut body = Vec::new();
+        encoder.encode_batch(&self.entries, &mut body);
+        self.bytes = 0;
+        for mut entry in self.entries.drain(..) {
+            if self.free.len() < MAX_POOLED && entry.capacity() <= MAX_POOLED_CAPACITY {
+                entry.clear();
+                self.free.push(entry);
+            }
+        }
+        body
+    }
+
+    /// An empty buffer to serialize the next entry into
+    ///
+    /// Reuses the buffers of entries already sent, so a steady stream of
+    /// entries does not allocate one each.
+    pub(crate) fn buffer(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_default()
+    }
+}
+
+/// Compress a request body with gzip
+///
+/// # Returns
+/// The compressed body, or None if compression failed
+pub(crate) fn gzip(body: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+    encoder.write_all(body).ok()?;
+    encoder.finish().ok()
+}
+
+/// Count the entries of a request body built by `Batch::take_body`
+///
+/// Entries are skipped over rather than parsed into values, so counting stays
+/// cheap enough for every upload. JSON bodies open with `{`, which a
+/// protobuf body from the gRPC encoder never starts with.
+pub(crate) fn count_entries(body: &[u8]) -> usize {
+    #[cfg(feature = "grpc")]
+    if body.first().is_some_and(|first| *first != b'{') {
+        return crate::grpc::count_entries(body);
+    }
+    #[derive(serde::Deserialize)]
+    struct Body {
+        entries: Vec<serde::de::IgnoredAny>,
+    }
+    serde_json::from_slice::<Body>(body).map_or(0, |body| body.entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::JsonEncoder;
+
+    #[test]
+    fn test_sent_entry_buffers_are_reused() {
+        let mut batch = Batch::default();
+        let mut entry = batch.buffer();
+        entry.extend_from_slice(br#"{"severity":"INFO"}"#);
+        let address = entry.as_ptr();
+        batch.push(entry);
+        assert_eq!(
+            batch.take_body(&JsonEncoder),
+            br#"{"entries":[{"severity":"INFO"}]}"#
+        );
+
+        let entry = batch.buffer();
+        assert!(entry.is_empty());
+        assert_eq!(entry.as_ptr(), address);
+        assert_eq!(batch.buffer().capacity(), 0);
+    }
+
+    #[test]
+    fn test_oversized_entry_buffers_are_freed() {
+        let mut batch = Batch::default();
+        batch.push(vec![b' '; MAX_POOLED_CAPACITY + 1]);
+        batch.take_body(&JsonEncoder);
+        assert_eq!(batch.buffer().capacity(), 0);
+    }
+}
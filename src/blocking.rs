@@ -0,0 +1,538 @@
+//! Sender running on a plain thread with a blocking HTTP client, for applications without Tokio.
+use std::error::Error;
+use std::future::{self, Future};
+use std::iter;
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::auth::{TokenError, TokenFuture, TokenProvider, TokenSource};
+use crate::batch::{count_entries, gzip};
+use crate::console::Console;
+use crate::diagnostics;
+use crate::entry::StaticMetadata;
+use crate::environment::{self, MetadataServer, RuntimeEnvironment};
+use crate::executor::{block_on, ThreadWaker};
+use crate::handle::{EntrySender, Message, SharedState};
+use crate::metrics::Metrics;
+use crate::pipeline::{Pipeline, Upload};
+use crate::retry::RetryPolicy;
+use crate::shutdown::{Shutdown, ShutdownReport};
+use crate::trace::TraceLookup;
+use crate::transport::{
+    classify, user_agent, Delivery, ForbiddenReport, ENTRIES_WRITE_URL, QUOTA_PROJECT_HEADER,
+};
+use crate::{
+    disabled_by_env, local_sink, resolve_metadata, GcpLoggingLayer, GcpLoggingLayerBuilder,
+    OutputFormat, THREAD_NAME,
+};
+
+/// Path of the default service account's access token on the metadata server
+const TOKEN_PATH: &str = "instance/service-accounts/default/token";
+
+/// How long before expiry a token from the metadata server is replaced
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Start the layer with its sender on a plain thread
+///
+/// Metadata detection and authentication run on the calling thread, blocking
+/// it until they finish; no Tokio runtime is created or needed.
+///
+/// # Arguments
+/// * `builder` - A validated builder
+///
+/// # Returns
+/// The layer, or the error that stopped it from starting
+pub(crate) fn start(builder: GcpLoggingLayerBuilder) -> Result<GcpLoggingLayer, Box<dyn Error>> {
+    let GcpLoggingLayerBuilder {
+        project_id,
+        instance,
+        container,
+        heartbeat_interval: _,
+        config,
+        batch_policy,
+        mut entry_config,
+        enabled,
+        sync_write: _,
+        shipped_targets: _,
+        dedicated_runtime: _,
+        user_agent_suffix,
+        #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            http_client: _,
+        circuit_breaker: _,
+        stdout_fallback,
+        structured_stdout,
+        output_format,
+        sink,
+        retry_policy,
+        #[cfg(feature = "grpc")]
+            grpc: _,
+        host_id,
+        token_provider,
+        quota_project,
+        #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            request_hook: _,
+        startup_metadata,
+        collect_container,
+        on_drop,
+        error_reporting: _,
+        metrics_export: _,
+        diagnostics_file,
+    } = builder;
+
+    if !enabled || disabled_by_env() {
+        return Ok(GcpLoggingLayer {
+            sender: EntrySender::default(),
+            config,
+            state: Arc::new(SharedState::new(&entry_config)),
+            trace: TraceLookup::default(),
+            span_exporter: None,
+        });
+    }
+    if let Some(diagnostics_file) = diagnostics_file {
+        diagnostics::install(diagnostics_file)?;
+    }
+
+    if let Some(discover) = startup_metadata {
+        for (key, value) in block_on(discover()) {
+            entry_config.labels.entry(key).or_insert(value);
+        }
+    }
+
+    // Statuses are classified by the caller rather than raised as errors.
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .build()
+        .into();
+    let metadata_root = environment::metadata_root_url(&environment::metadata_host());
+    let on_gcp = || RuntimeEnvironment::probe_blocking(&agent).on_gcp;
+    let state = Arc::new(SharedState::new(&entry_config));
+    let reformat = output_format != OutputFormat::CloudLogging;
+    let auth = if structured_stdout || reformat {
+        None
+    } else {
+        let provider = token_provider.or_else(|| {
+            on_gcp().then(|| {
+                Arc::new(MetadataToken::new(agent.clone(), &metadata_root))
+                    as Arc<dyn TokenProvider>
+            })
+        });
+        match provider {
+            Some(provider) => block_on(TokenSource::acquire(
+                Some(provider),
+                state.token_status.clone(),
+            )),
+            None => None,
+        }
+    };
+    let sink = local_sink(sink, output_format, structured_stdout);
+
+    let drops_entries = auth.is_none() && !stdout_fallback && sink.is_none();
+    if drops_entries {
+        warning!("no GCP auth token available; log entries will not be sent. Set up authentication to enable sending.");
+    }
+
+    let server = BlockingMetadata {
+        agent: &agent,
+        root: &metadata_root,
+    };
+    let metadata = block_on(resolve_metadata(
+        project_id,
+        instance,
+        container,
+        collect_container,
+        host_id,
+        &mut entry_config,
+        &server,
+    ));
+
+    let (channel, rx) = unbounded_channel::<Message>();
+    let skip_sending = auth.is_none();
+    let flush_on = batch_policy.flush_on;
+    let pipeline = Pipeline::new(
+        Arc::new(StaticMetadata::new(metadata)),
+        entry_config,
+        batch_policy,
+        state.metrics.clone(),
+    )
+    .with_local_delivery(
+        sink,
+        stdout_fallback.then(Console::new),
+        on_drop.clone(),
+        skip_sending,
+    );
+    let worker = BlockingWorker {
+        agent,
+        auth,
+        user_agent: user_agent(user_agent_suffix.as_deref()),
+        quota_project,
+        pipeline,
+        retry_policy,
+        forbidden: ForbiddenReport::default(),
+        metrics: state.metrics.clone(),
+        shutdown: state.shutdown.clone(),
+    };
+    thread::Builder::new()
+        .name(THREAD_NAME.into())
+        .spawn(move || worker.run(rx))?;
+
+    Ok(GcpLoggingLayer {
+        sender: EntrySender {
+            channel: Some(channel),
+            sync_writer: None,
+            drops_entries,
+            metrics: state.metrics.clone(),
+            on_drop,
+            flush_on,
+            runtime_labels: state.labels.clone(),
+        },
+        config,
+        state,
+        trace: TraceLookup::default(),
+        span_exporter: None,
+    })
+}
+
+/// The metadata server, queried with blocking requests
+struct BlockingMetadata<'a> {
+    /// The blocking HTTP client
+    agent: &'a ureq::Agent,
+    /// The root URL of the metadata API
+    root: &'a str,
+}
+
+impl MetadataServer for BlockingMetadata<'_> {
+    async fn on_gcp(&self) -> bool {
+        RuntimeEnvironment::probe_blocking(self.agent).on_gcp
+    }
+
+    async fn get(&self, path: &str) -> Option<String> {
+        get_metadata(self.agent, self.root, path)
+    }
+}
+
+/// Retrieve a value from the metadata server with a blocking request
+///
+/// # Arguments
+/// * `agent` - The blocking HTTP client
+/// * `root` - The root URL of the metadata API
+/// * `path` - The metadata path to retrieve
+///
+/// # Returns
+/// The value, None if the server could not be reached or did not find the path
+fn get_metadata(agent: &ureq::Agent, root: &str, path: &str) -> Option<String> {
+    let mut response = agent
+        .get(format!("{}{}", root, path))
+        .header("Metadata-Flavor", "Google")
+        .call()
+        .ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    response.body_mut().read_to_string().ok()
+}
+
+/// Access tokens of the default service account, read from the metadata server
+struct MetadataToken {
+    /// The blocking HTTP client
+    agent: ureq::Agent,
+    /// URL of the token endpoint
+    url: String,
+    /// The last token and when it must be replaced
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+/// Token endpoint response
+#[derive(Deserialize)]
+struct TokenResponse {
+    /// The bearer token
+    access_token: String,
+    /// Seconds until the token expires
+    expires_in: u64,
+}
+
+impl MetadataToken {
+    /// Create a provider for the metadata server at `root`
+    ///
+    /// # Arguments
+    /// * `agent` - The blocking HTTP client
+    /// * `root` - The root URL of the metadata API
+    fn new(agent: ureq::Agent, root: &str) -> Self {
+        Self {
+            agent,
+            url: format!("{}{}", root, TOKEN_PATH),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The cached token, fetching a new one once it nears expiry
+    fn fetch(&self) -> Result<String, TokenError> {
+        let mut cached = self.cached.lock().map_err(|e| e.to_string())?;
+        if let Some((token, refresh_at)) = cached.as_ref() {
+            if Instant::now() < *refresh_at {
+                return Ok(token.clone());
+            }
+        }
+        let mut response = self
+            .agent
+            .get(&self.url)
+            .header("Metadata-Flavor", "Google")
+            .call()?;
+        if !response.status().is_success() {
+            return Err(format!("metadata server answered HTTP {}", response.status()).into());
+        }
+        let body = response.body_mut().read_to_string()?;
+        let token: TokenResponse = serde_json::from_str(&body)?;
+        let lifetime = Duration::from_secs(token.expires_in).saturating_sub(TOKEN_REFRESH_MARGIN);
+        *cached = Some((token.access_token.clone(), Instant::now() + lifetime));
+        Ok(token.access_token)
+    }
+}
+
+impl TokenProvider for MetadataToken {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move { self.fetch() })
+    }
+}
+
+/// State owned by the blocking sender thread
+struct BlockingWorker {
+    /// HTTP client used for uploads
+    agent: ureq::Agent,
+    /// Token source, None when authentication is unavailable
+    auth: Option<TokenSource>,
+    /// `User-Agent` sent with every request
+    user_agent: String,
+    /// Project billed for API quota instead of the credentials' project
+    quota_project: Option<String>,
+    /// Batches entries and delivers those that are not uploaded
+    pipeline: Pipeline,
+    /// How failed uploads are retried
+    retry_policy: RetryPolicy,
+    /// Explains the first permission error on stderr
+    forbidden: ForbiddenReport,
+    /// Counters updated with the outcome of every upload
+    metrics: Arc<Metrics>,
+    /// Shutdown requests from handles
+    shutdown: Arc<Shutdown>,
+}
+
+/// Why the sender thread woke up without an entry
+enum Interrupt {
+    /// The deadline passed
+    Timeout,
+    /// Every sender was dropped
+    Closed,
+    /// Shutdown was requested, with its deadline
+    Shutdown(Instant),
+}
+
+impl BlockingWorker {
+    /// Drain the channel until every sender is dropped or shutdown is requested
+    fn run(mut self, mut rx: UnboundedReceiver<Message>) {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let shutdown = self.shutdown.clone();
+        let mut requested = pin!(shutdown.requested());
+
+        loop {
+            let deadline = self.pipeline.deadline().map(tokio::time::Instant::into_std);
+            let log_entry = match next(&mut rx, requested.as_mut(), deadline, &mut cx) {
+                Ok(Message::Entry(entry)) => entry.resolved(),
+                Ok(Message::Flush) => {
+                    self.pipeline.request_flush();
+                    if let Some(upload) = self.pipeline.settle(false) {
+                        self.flush(upload);
+                    }
+                    continue;
+                }
+                Err(Interrupt::Closed) => break,
+                Err(Interrupt::Timeout) => {
+                    if let Some(upload) = self.pipeline.expire() {
+                        self.flush(upload);
+                    }
+                    continue;
+                }
+                Err(Interrupt::Shutdown(stop)) => {
+                    let report = self.drain(&mut rx, stop);
+                    shutdown.finish(report);
+                    return;
+                }
+            };
+            if self.pipeline.deliver_locally(&log_entry).is_some() {
+                log_entry.recycle();
+                continue;
+            }
+
+            let backlog = self.pipeline.backlog(rx.len());
+            let mut next_entry = Some(log_entry);
+            while let Some(log_entry) = next_entry.take() {
+                for upload in self.pipeline.add(&log_entry, backlog) {
+                    self.flush(upload);
+                }
+                log_entry.recycle();
+                if backlog {
+                    next_entry = self.pipeline.next_queued(&mut rx);
+                }
+            }
+            if let Some(upload) = self.pipeline.settle(backlog) {
+                self.flush(upload);
+            }
+        }
+
+        if let Some(upload) = self.pipeline.expire() {
+            self.flush(upload);
+        }
+    }
+
+    /// Deliver everything still queued for a shutdown
+    ///
+    /// # Arguments
+    /// * `rx` - The receiving end of the layer's channel, closed here
+    /// * `stop` - When to give up on uploads
+    ///
+    /// # Returns
+    /// What happened to each pending entry
+    fn drain(&mut self, rx: &mut UnboundedReceiver<Message>, stop: Instant) -> ShutdownReport {
+        rx.close();
+        let mut report = ShutdownReport::default();
+        let mut bodies = Vec::new();
+        self.pipeline.drain(
+            iter::from_fn(|| rx.try_recv().ok()).filter_map(Message::into_entry),
+            &mut report,
+            &mut bodies,
+            |_| {},
+        );
+        for (entries, body, compress) in bodies {
+            let left = stop.saturating_duration_since(Instant::now());
+            let delivery = match left.is_zero() {
+                true => Delivery::Retryable,
+                false => self.deliver(&body, compress, Some(left)),
+            };
+            report.record(entries, delivery);
+        }
+        report
+    }
+
+    /// Upload a request body, retrying in place
+    ///
+    /// # Arguments
+    /// * `upload` - The body and whether to gzip it
+    fn flush(&mut self, (body, compress): Upload) {
+        let mut attempts = 0;
+        while self.deliver(&body, compress, None) == Delivery::Retryable {
+            let entries = count_entries(&body);
+            if self.retry_policy.exhausted(attempts) {
+                self.metrics.give_up(entries);
+                return;
+            }
+            thread::sleep(self.retry_policy.backoff(attempts));
+            attempts += 1;
+            Metrics::add(&self.metrics.retried, entries);
+        }
+    }
+
+    /// Upload a request body and record the outcome
+    ///
+    /// # Arguments
+    /// * `body` - The uncompressed JSON body
+    /// * `compress` - Whether to gzip the body
+    /// * `timeout` - Longest time the request may take, None for no limit
+    ///
+    /// # Returns
+    /// The outcome of the upload
+    fn deliver(&mut self, body: &[u8], compress: bool, timeout: Option<Duration>) -> Delivery {
+        let started = Instant::now();
+        let delivery = match self.upload(body, compress, timeout) {
+            Ok(status) => {
+                if !(200..300).contains(&status) {
+                    diagnostic!("Failed to send log entry: HTTP {}", status);
+                }
+                classify(status)
+            }
+            Err(e) => {
+                diagnostic!("Failed to send log entry: {}", e);
+                Delivery::Retryable
+            }
+        };
+        self.metrics
+            .record(delivery, count_entries(body), body.len(), started.elapsed());
+        if delivery == Delivery::Forbidden {
+            // The metadata token does not say whose it is.
+            let project_id = &self.pipeline.metadata.context.instance.project_id;
+            block_on(self.forbidden.report(project_id, future::ready(None)));
+        }
+        delivery
+    }
+
+    /// Send an `entries:write` request
+    ///
+    /// # Returns
+    /// The HTTP status, or the error that prevented sending
+    fn upload(
+        &self,
+        body: &[u8],
+        compress: bool,
+        timeout: Option<Duration>,
+    ) -> Result<u16, Box<dyn Error>> {
+        let mut req = self
+            .agent
+            .post(ENTRIES_WRITE_URL)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", self.user_agent.as_str());
+        if let Some(project) = &self.quota_project {
+            req = req.header(QUOTA_PROJECT_HEADER, project);
+        }
+        if let Some(auth) = &self.auth {
+            let token = block_on(auth.token())
+                .map_err(|e| format!("failed to refresh GCP token: {}", e))?;
+            req = req.header("Authorization", format!("Bearer {}", token));
+        }
+        let req = req.config().timeout_global(timeout).build();
+        let response = match compress.then(|| gzip(body)).flatten() {
+            Some(compressed) => req.header("Content-Encoding", "gzip").send(&compressed)?,
+            None => req.send(body)?,
+        };
+        Ok(response.status().as_u16())
+    }
+}
+
+/// Wait for the next message, the deadline or a shutdown request
+///
+/// # Returns
+/// The message, or why waiting stopped without one
+///
+/// # Arguments
+/// * `rx` - The receiving end of the layer's channel
+/// * `requested` - Resolves with the deadline once shutdown is requested
+/// * `deadline` - When to give up waiting, None to wait indefinitely
+/// * `cx` - Context waking the current thread
+fn next(
+    rx: &mut UnboundedReceiver<Message>,
+    mut requested: Pin<&mut impl Future<Output = tokio::time::Instant>>,
+    deadline: Option<Instant>,
+    cx: &mut Context<'_>,
+) -> Result<Message, Interrupt> {
+    loop {
+        if let Poll::Ready(stop) = requested.as_mut().poll(cx) {
+            return Err(Interrupt::Shutdown(stop.into_std()));
+        }
+        match rx.poll_recv(cx) {
+            Poll::Ready(Some(entry)) => return Ok(entry),
+            Poll::Ready(None) => return Err(Interrupt::Closed),
+            Poll::Pending => {}
+        }
+        match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => thread::park_timeout(left),
+                _ => return Err(Interrupt::Timeout),
+            },
+            None => thread::park(),
+        }
+    }
+}
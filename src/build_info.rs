@@ -0,0 +1,94 @@
+//! Build information attached to entries as labels.
+
+/// Environment variable holding the git commit the binary was built from
+pub const GIT_SHA_ENV_VAR: &str = "GIT_SHA";
+
+/// Environment variable holding the CI build identifier
+pub const BUILD_ID_ENV_VAR: &str = "BUILD_ID";
+
+/// Environment variable holding the build timestamp
+pub const BUILD_TIMESTAMP_ENV_VAR: &str = "BUILD_TIMESTAMP";
+
+/// Identifies the exact build that produced a log line
+///
+/// Usually created with the `build_info!` macro, which captures the calling
+/// crate's version and the `GIT_SHA`, `BUILD_ID` and `BUILD_TIMESTAMP`
+/// variables present at compile time. Passed to
+/// `GcpLoggingLayerBuilder::with_build_info`, each known value becomes a label
+/// (`version`, `git_sha`, `build_id`, `build_timestamp`) on every entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Application version, e.g. `CARGO_PKG_VERSION`
+    pub version: Option<String>,
+    /// Git commit SHA
+    pub git_sha: Option<String>,
+    /// CI build identifier
+    pub build_id: Option<String>,
+    /// Build timestamp
+    pub build_timestamp: Option<String>,
+}
+
+impl BuildInfo {
+    /// Read `GIT_SHA`, `BUILD_ID` and `BUILD_TIMESTAMP` from the runtime environment
+    ///
+    /// # Returns
+    /// A BuildInfo without a version
+    pub fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        Self {
+            version: None,
+            git_sha: var(GIT_SHA_ENV_VAR),
+            build_id: var(BUILD_ID_ENV_VAR),
+            build_timestamp: var(BUILD_TIMESTAMP_ENV_VAR),
+        }
+    }
+
+    /// Fill missing values from another BuildInfo
+    ///
+    /// # Arguments
+    /// * `other` - Values used where this one has none
+    ///
+    /// # Returns
+    /// The merged BuildInfo
+    pub fn or(self, other: BuildInfo) -> Self {
+        Self {
+            version: self.version.or(other.version),
+            git_sha: self.git_sha.or(other.git_sha),
+            build_id: self.build_id.or(other.build_id),
+            build_timestamp: self.build_timestamp.or(other.build_timestamp),
+        }
+    }
+
+    /// The labels this build information contributes
+    ///
+    /// # Returns
+    /// Label key/value pairs for every known value
+    pub fn labels(&self) -> Vec<(&'static str, String)> {
+        [
+            ("version", &self.version),
+            ("git_sha", &self.git_sha),
+            ("build_id", &self.build_id),
+            ("build_timestamp", &self.build_timestamp),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.clone().map(|value| (key, value)))
+        .collect()
+    }
+}
+
+/// Capture the calling crate's build information at compile time
+///
+/// Expands to a `BuildInfo` with the caller's `CARGO_PKG_VERSION` and the
+/// `GIT_SHA`, `BUILD_ID` and `BUILD_TIMESTAMP` environment variables seen by
+/// the compiler (e.g. exported by CI before `cargo build`).
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            git_sha: option_env!("GIT_SHA").map(String::from),
+            build_id: option_env!("BUILD_ID").map(String::from),
+            build_timestamp: option_env!("BUILD_TIMESTAMP").map(String::from),
+        }
+    };
+}
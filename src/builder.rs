@@ -0,0 +1,1424 @@
+//! Builder for configuring a `GcpLoggingLayer` before it starts.
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::subscriber::Interest;
+use tracing::Metadata;
+use tracing_subscriber::filter::{Filtered, LevelFilter, Targets};
+use tracing_subscriber::layer::{Context, Filter};
+
+use crate::auth::TokenProvider;
+use crate::batch::BatchPolicy;
+#[cfg(feature = "blocking")]
+use crate::blocking;
+use crate::build_info::BuildInfo;
+use crate::circuit::CircuitBreakerConfig;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::client;
+use crate::cloud_trace::TraceExport;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::dedicated;
+use crate::diagnostics::DiagnosticsFile;
+use crate::encoder::WireEncoder;
+use crate::entry::{
+    validate_log_name, EntryConfig, GcpLogEntry, MessageFormat, MetadataNamespace, SeverityRoute,
+    TENANT_LABEL,
+};
+use crate::error::ConfigError;
+use crate::error_reporting::ErrorReporting;
+use crate::format::OutputFormat;
+use crate::handle::DropHook;
+use crate::host::HostIdSource;
+use crate::message::MessageFormatter;
+use crate::monitoring::MetricsExport;
+use crate::payload::PayloadSerializer;
+use crate::retry::RetryPolicy;
+use crate::sink::LogSink;
+use crate::span::SpanFieldMode;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::transport::RequestHook;
+use crate::{
+    ContainerMetadata, GcpLoggingLayer, InstanceMetadata, LayerConfig, MonitoredResource, Severity,
+    DEFAULT_IGNORED_TARGETS,
+};
+
+/// Callback computing labels once while the layer starts
+pub(crate) type StartupMetadata = Box<
+    dyn FnOnce() -> Pin<Box<dyn Future<Output = HashMap<String, String>> + Send>> + Send + Sync,
+>;
+
+/// Builder for a `GcpLoggingLayer`
+///
+/// Obtain one with `GcpLoggingLayer::builder` and finish with `build`.
+pub struct GcpLoggingLayerBuilder {
+    /// Google Cloud project ID used when metadata is detected
+    pub(crate) project_id: String,
+    /// Instance metadata overriding detection entirely
+    pub(crate) instance: Option<InstanceMetadata>,
+    /// Container metadata overriding detection
+    pub(crate) container: Option<ContainerMetadata>,
+    /// Interval between internal heartbeat entries
+    pub(crate) heartbeat_interval: Option<Duration>,
+    /// Options applied while capturing events
+    pub(crate) config: LayerConfig,
+    /// How entries are grouped into requests
+    pub(crate) batch_policy: BatchPolicy,
+    /// Options applied when turning entries into JSON
+    pub(crate) entry_config: EntryConfig,
+    /// Whether the layer ships logs at all
+    pub(crate) enabled: bool,
+    /// Lowest severity written synchronously and the per-write timeout
+    pub(crate) sync_write: Option<(Severity, Duration)>,
+    /// Shipped target directives, parsed during `build`
+    pub(crate) shipped_targets: Option<String>,
+    /// Text appended to the `User-Agent` of upload requests
+    pub(crate) user_agent_suffix: Option<String>,
+    /// HTTP client shared by metadata lookups and uploads
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) http_client: Option<client::Client>,
+    /// Thresholds for pausing uploads while the API keeps failing
+    pub(crate) circuit_breaker: CircuitBreakerConfig,
+    /// Print entries to stdout when no credentials are available
+    pub(crate) stdout_fallback: bool,
+    /// Write entries to stdout as Cloud Run structured logging JSON
+    pub(crate) structured_stdout: bool,
+    /// Schema of the entries, Cloud Logging unless another aggregator reads them
+    pub(crate) output_format: OutputFormat,
+    /// Destination replacing uploads, set through `sink`
+    pub(crate) sink: Option<Arc<dyn LogSink>>,
+    /// How failed batches are retried
+    pub(crate) retry_policy: RetryPolicy,
+    /// Upload through the gRPC API instead of REST
+    #[cfg(feature = "grpc")]
+    pub(crate) grpc: bool,
+    /// Source of the instance ID used when metadata has none
+    pub(crate) host_id: Option<HostIdSource>,
+    /// Token provider replacing Application Default Credentials
+    pub(crate) token_provider: Option<Arc<dyn TokenProvider>>,
+    /// Project billed for API quota, sent as `X-Goog-User-Project`
+    pub(crate) quota_project: Option<String>,
+    /// Callback applied to each upload request before sending
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) request_hook: Option<RequestHook>,
+    /// Callback computing extra labels during `build`
+    pub(crate) startup_metadata: Option<StartupMetadata>,
+    /// Whether container metadata is read from cgroups, None for auto
+    pub(crate) collect_container: Option<bool>,
+    /// Callback receiving each discarded entry
+    pub(crate) on_drop: Option<DropHook>,
+    /// Report severe entries to the Error Reporting API as well
+    pub(crate) error_reporting: Option<ErrorReporting>,
+    /// Push delivery metrics to Cloud Monitoring
+    pub(crate) metrics_export: Option<MetricsExport>,
+    /// Write the layer's diagnostics to a file instead of stderr
+    pub(crate) diagnostics_file: Option<DiagnosticsFile>,
+    /// Run the background tasks on a thread of their own
+    pub(crate) dedicated_runtime: bool,
+}
+
+impl GcpLoggingLayerBuilder {
+    /// Create a builder for the given project
+    ///
+    /// # Arguments
+    /// * `project_id` - The Google Cloud project ID
+    pub fn new(project_id: impl Into<String>) -> Self {
+        Self {
+            project_id: project_id.into(),
+            instance: None,
+            container: None,
+            heartbeat_interval: None,
+            config: LayerConfig {
+                parse_json_debug: false,
+                targets: None,
+                log_span_lifecycle: false,
+                summarize_fields: false,
+                span_fields: SpanFieldMode::default(),
+                max_fields: None,
+                default_severity: Severity::Default,
+                split_lines: None,
+                ignored_targets: DEFAULT_IGNORED_TARGETS.map(String::from).to_vec(),
+                global_filter: false,
+                human_units: false,
+                label_resolvers: Vec::new(),
+                parse_json_messages: false,
+                strip_ansi: true,
+                group_stack_traces: false,
+                trace_export: None,
+            },
+            batch_policy: BatchPolicy::default(),
+            entry_config: EntryConfig::default(),
+            enabled: true,
+            sync_write: None,
+            shipped_targets: None,
+            user_agent_suffix: None,
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            http_client: None,
+            circuit_breaker: CircuitBreakerConfig::default(),
+            stdout_fallback: false,
+            structured_stdout: false,
+            output_format: OutputFormat::default(),
+            sink: None,
+            retry_policy: RetryPolicy::default(),
+            #[cfg(feature = "grpc")]
+            grpc: false,
+            host_id: None,
+            token_provider: None,
+            quota_project: None,
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            request_hook: None,
+            startup_metadata: None,
+            collect_container: None,
+            on_drop: None,
+            error_reporting: None,
+            metrics_export: None,
+            diagnostics_file: None,
+            dedicated_runtime: false,
+        }
+    }
+
+    /// Use the given instance metadata instead of querying the metadata server
+    ///
+    /// The container metadata is still taken from `container_metadata` or read
+    /// from cgroups.
+    ///
+    /// # Arguments
+    /// * `instance` - The instance metadata attached to every entry
+    pub fn instance_metadata(mut self, instance: InstanceMetadata) -> Self {
+        self.instance = Some(instance);
+        self
+    }
+
+    /// Choose whether container metadata is read from cgroups
+    ///
+    /// By default it is read only when `/proc/self/cgroup` exists, so macOS
+    /// and other hosts without `/proc` skip it. Pass false on VMs and bare
+    /// metal, where the cgroup path says nothing about a container, to leave
+    /// `container` empty. `container_metadata` takes precedence.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to read cgroups
+    pub fn collect_container_metadata(mut self, enabled: bool) -> Self {
+        self.collect_container = Some(enabled);
+        self
+    }
+
+    /// Use the given container metadata instead of reading it from cgroups
+    ///
+    /// # Arguments
+    /// * `container` - The container metadata attached to every entry
+    pub fn container_metadata(mut self, container: ContainerMetadata) -> Self {
+        self.container = Some(container);
+        self
+    }
+
+    /// Authenticate uploads with the given provider instead of `gcp_auth`
+    ///
+    /// The provider replaces Application Default Credentials entirely; see
+    /// `TokenProvider` for when tokens are requested and how they must be
+    /// refreshed. `GcpLoggingHandle::token_status` counts a new token as a
+    /// refresh but cannot report its expiry.
+    ///
+    /// # Arguments
+    /// * `provider` - Source of access tokens
+    pub fn token_provider(mut self, provider: impl TokenProvider) -> Self {
+        self.token_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Derive a stable instance ID when the metadata server provides none
+    ///
+    /// Off GCE every host would otherwise report the same placeholder ID, so
+    /// multi-host deployments could not be told apart. The identifier is used
+    /// for the `instance_id` resource label and the payload's `instance.id`;
+    /// each source falls back to a hash of the host name. Not applied to an
+    /// explicit `instance_metadata` override.
+    ///
+    /// # Arguments
+    /// * `source` - Where the identifier comes from
+    pub fn host_id_fallback(mut self, source: HostIdSource) -> Self {
+        self.host_id = Some(source);
+        self
+    }
+
+    /// Attach every entry to the given monitored resource
+    ///
+    /// The resource replaces the detected `gce_instance` verbatim and the
+    /// metadata server is not queried, so the payload's `instance` object only
+    /// carries the project ID unless `instance_metadata` is also set. The
+    /// resource type must not be empty.
+    ///
+    /// # Arguments
+    /// * `resource` - A `ResourceType`, or a `MonitoredResource` for other types
+    pub fn resource(mut self, resource: impl Into<MonitoredResource>) -> Self {
+        self.entry_config.resource = Some(resource.into());
+        self
+    }
+
+    /// Periodically emit an internal heartbeat entry
+    ///
+    /// Heartbeats are tagged with the `INTERNAL_LABEL` label and are generated by
+    /// the background task, so they never pass through `on_event`.
+    ///
+    /// # Arguments
+    /// * `interval` - Time between heartbeats
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Parse `Debug`-only field values that look like JSON into structured values
+    ///
+    /// Fields recorded through `record_debug` (e.g. `payload = %json_string`)
+    /// whose rendering starts with `{` or `[` and parses as JSON are emitted as
+    /// nested structure instead of an escaped string. Off by default since it is
+    /// a heuristic; values that fail to parse are kept as strings.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to attempt parsing
+    pub fn parse_json_debug_fields(mut self, enabled: bool) -> Self {
+        self.config.parse_json_debug = enabled;
+        self
+    }
+
+    /// Parse messages that are JSON objects into the structured payload
+    ///
+    /// Loggers bridged into `tracing` often hand over a whole JSON document as
+    /// the message. With this enabled, such a message's keys are merged into
+    /// `jsonPayload` instead of shipping escaped JSON in a string: its
+    /// `message` (or `msg`) string becomes the entry's message and the event's
+    /// own fields win over its other keys. Messages that fail to parse are
+    /// kept as-is. Off by default.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to parse JSON messages
+    pub fn parse_json_messages(mut self, enabled: bool) -> Self {
+        self.config.parse_json_messages = enabled;
+        self
+    }
+
+    /// Remove ANSI escape sequences from messages
+    ///
+    /// Libraries that colorize their output leave `\u{1b}[33m`-style codes in
+    /// their messages, which Cloud Logging shows as noise. They are stripped
+    /// by the background task before the entry is sent; on by default.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to strip escape sequences
+    pub fn strip_ansi(mut self, enabled: bool) -> Self {
+        self.config.strip_ansi = enabled;
+        self
+    }
+
+    /// Keep stack traces in a single entry, under a `stack_trace` field
+    ///
+    /// A multi-line message ending in a stack trace (a Rust backtrace, a
+    /// Python traceback, a Go goroutine dump or indented `at …` frames, as
+    /// bridged loggers forward them) keeps the lines before the trace as its
+    /// message and the trace as `stack_trace`, the field the panic hook uses.
+    /// Such messages are never split by `split_multiline`. Events that
+    /// already record `stack_trace` are left alone. Off by default.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to recognize stack traces
+    pub fn group_stack_traces(mut self, enabled: bool) -> Self {
+        self.config.group_stack_traces = enabled;
+        self
+    }
+
+    /// Keep sizes and counts numeric and add human-readable companions
+    ///
+    /// Fields named with `BYTES_SUFFIX` (`_bytes`) or `COUNT_SUFFIX`
+    /// (`_count`) whose `Debug` or string rendering is a number are shipped
+    /// as that number, and every numeric one gets a `HUMAN_SUFFIX` companion,
+    /// e.g. `body_bytes_human: "1.2 MiB"` (binary units) or
+    /// `row_count_human: "3.4k"` (decimal units). Off by default.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to handle unit suffixes
+    pub fn human_readable_units(mut self, enabled: bool) -> Self {
+        self.config.human_units = enabled;
+        self
+    }
+
+    /// Choose how the fields of enclosing spans are added to event payloads
+    ///
+    /// `SpanFieldMode::Flatten` (the default) merges them into `jsonPayload`,
+    /// with event fields taking precedence. `SpanFieldMode::Nested` keeps them
+    /// apart under `context`, one object per span name from outermost to
+    /// innermost, so they cannot collide with event fields.
+    /// `SpanFieldMode::prefixed()` keeps the payload flat but names each field
+    /// after its span, e.g. `request.id`, so queries can target it precisely.
+    /// `SpanFieldMode::Omit` leaves them out, so payloads only hold the
+    /// event's own fields.
+    ///
+    /// # Arguments
+    /// * `mode` - How span fields are added
+    pub fn span_fields(mut self, mode: SpanFieldMode) -> Self {
+        self.config.span_fields = mode;
+        self
+    }
+
+    /// Collect at most `max` structured fields per event
+    ///
+    /// Guards against call sites recording pathological numbers of fields.
+    /// Fields past the limit are dropped in recording order and the payload
+    /// gets `fields_truncated: true`. Span fields are not counted. Unlimited
+    /// by default.
+    ///
+    /// # Arguments
+    /// * `max` - Most fields kept per event
+    pub fn max_fields(mut self, max: usize) -> Self {
+        self.config.max_fields = Some(max);
+        self
+    }
+
+    /// Drop the largest event fields of entries serializing above `bytes`
+    ///
+    /// Keeps an entry with a few huge fields under Cloud Logging's 256 KiB
+    /// entry limit without losing the rest of it. Event fields (including
+    /// flattened span fields) are removed from the largest serialized value
+    /// down, ties in key order, until the entry fits; the removed keys are
+    /// listed under `fields_dropped`. The message, severity and injected
+    /// metadata are never removed, so an entry may still exceed `bytes`.
+    /// Unlimited by default.
+    ///
+    /// # Arguments
+    /// * `bytes` - Largest serialized size of an entry
+    pub fn max_entry_bytes(mut self, bytes: usize) -> Self {
+        self.entry_config.max_entry_bytes = Some(bytes);
+        self
+    }
+
+    /// Choose the payload key carrying each event's target (module path)
+    ///
+    /// Makes per-module filtering a field comparison, e.g.
+    /// `jsonPayload.logger="myapp::db"`. Defaults to `logger`; pass None to
+    /// omit the field.
+    ///
+    /// # Arguments
+    /// * `key` - Payload key for the target, or None
+    pub fn target_field(mut self, key: Option<&str>) -> Self {
+        self.entry_config.target_field = key.map(str::to_string);
+        self
+    }
+
+    /// Include the target in the message line
+    ///
+    /// On by default; disable it when the `target_field` is enough. Only
+    /// applies to `MessageFormat::Default`.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether the message line names the target
+    pub fn target_in_message(mut self, enabled: bool) -> Self {
+        self.entry_config.target_in_message = enabled;
+        self
+    }
+
+    /// Choose how the message line of captured events is formatted
+    ///
+    /// `MessageFormat::Default` keeps the
+    /// `[{time}] {severity} [{target} {file}:{line}] [{message}]` line;
+    /// `MessageFormat::Plain` sends the bare message, for setups relying on
+    /// structured fields; `MessageFormat::Custom` builds the line from the
+    /// event's `MessageParts`, and `MessageFormat::Formatter` with a
+    /// `MessageFormatter`.
+    ///
+    /// # Arguments
+    /// * `format` - How the line is built
+    pub fn message_format(mut self, format: MessageFormat) -> Self {
+        self.entry_config.message_format = format;
+        self
+    }
+
+    /// Build the message line of captured events with a formatter
+    ///
+    /// Built in are `FullFormatter` (the default line), `CompactFormatter`
+    /// (`{severity} {target}: {message}`) and `JsonOnlyFormatter` (the bare
+    /// message); implement `MessageFormatter` for anything else. Same as
+    /// `message_format(MessageFormat::Formatter(..))`.
+    ///
+    /// # Arguments
+    /// * `formatter` - Builds the line from the event's `MessageParts`
+    pub fn message_formatter(mut self, formatter: impl MessageFormatter) -> Self {
+        self.entry_config.message_format = MessageFormat::Formatter(Arc::new(formatter));
+        self
+    }
+
+    /// Build `jsonPayload` with a serializer instead of the crate's layout
+    ///
+    /// The serializer receives each entry's message line, fields, target,
+    /// location, trace and the detected metadata, and returns the whole
+    /// payload; `metadata_namespace`, `target_field`, `severity_number` and
+    /// `queue_latency` no longer apply. `max_entry_bytes` still drops the
+    /// largest payload values whose key is an event field's.
+    ///
+    /// # Arguments
+    /// * `serializer` - Builds the payload from the entry's `PayloadParts`
+    pub fn payload_serializer(mut self, serializer: impl PayloadSerializer) -> Self {
+        self.entry_config.payload_serializer = Some(Arc::new(serializer));
+        self
+    }
+
+    /// Encode batched entries with a custom encoder
+    ///
+    /// The default `JsonEncoder` produces `entries:write` JSON, which the REST
+    /// transport expects; wrap it to observe or adjust what is uploaded, e.g.
+    /// to capture request bodies in tests. Ignored with `grpc(true)`, which
+    /// encodes entries as protobuf.
+    ///
+    /// # Arguments
+    /// * `encoder` - Encodes each entry and joins batches into request bodies
+    pub fn wire_encoder(mut self, encoder: impl WireEncoder) -> Self {
+        self.entry_config.encoder = Arc::new(encoder);
+        self
+    }
+
+    /// Choose where the crate's injected metadata is placed in the payload
+    ///
+    /// `MetadataNamespace::Flat` (the default) writes `container`, `instance`
+    /// and the other injected keys next to the event's fields;
+    /// `MetadataNamespace::Nested("_meta".into())` groups them under one
+    /// reserved object and `MetadataNamespace::Prefixed("_".into())` prefixes
+    /// their keys. Event fields whose keys are taken are renamed with a `user_`
+    /// prefix in every mode.
+    ///
+    /// # Arguments
+    /// * `namespace` - Where injected metadata goes
+    pub fn metadata_namespace(mut self, namespace: MetadataNamespace) -> Self {
+        self.entry_config.metadata_namespace = namespace;
+        self
+    }
+
+    /// Severity given to events whose metadata carries no level
+    ///
+    /// Every `tracing::Level` maps to a fixed severity (`TRACE` and `DEBUG` to
+    /// `DEBUG`, `INFO` to `INFO`, `WARN` to `WARNING`, `ERROR` to `ERROR`); this
+    /// is a defensive fallback for events forwarded by bridges. Defaults to
+    /// `Severity::Default`.
+    ///
+    /// # Arguments
+    /// * `severity` - Severity used when no level is available
+    pub fn default_severity(mut self, severity: impl Into<Severity>) -> Self {
+        self.config.default_severity = severity.into();
+        self
+    }
+
+    /// Append a compact `key=value` rendering of the fields to each message
+    ///
+    /// The Logs Explorer summary line only shows `message`, so this makes the
+    /// fields visible without expanding the entry, e.g.
+    /// `[...] [request done status=200 path=/health]`. The fields stay
+    /// structured in `jsonPayload` for filtering; only the message changes.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to append the fields
+    pub fn summarize_fields(mut self, enabled: bool) -> Self {
+        self.config.summarize_fields = enabled;
+        self
+    }
+
+    /// Split multi-line messages into one entry per line
+    ///
+    /// Makes each line of a formatted table or multi-line diagnostic readable
+    /// and searchable on its own. The entries share an `operation`, so the Logs
+    /// Explorer groups them, and each carries the event's fields. At most
+    /// `max_entries` are emitted per event; the last one keeps the remaining
+    /// lines. Off by default.
+    ///
+    /// # Arguments
+    /// * `max_entries` - Most entries one event is split into, at least 1
+    pub fn split_multiline(mut self, max_entries: usize) -> Self {
+        self.config.split_lines = Some(max_entries.max(1));
+        self
+    }
+
+    /// Add a numeric `severity_number` field to every payload
+    ///
+    /// The value follows Cloud Logging's numeric `LogSeverity` (see
+    /// `Severity::number`), which makes numeric comparisons and log-based
+    /// metric thresholds straightforward. Off by default since it duplicates
+    /// the string `severity`.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to add the field
+    pub fn severity_number(mut self, enabled: bool) -> Self {
+        self.entry_config.severity_number = enabled;
+        self
+    }
+
+    /// Preserve emission order for entries sharing a log name
+    ///
+    /// Each entry gets its capture `timestamp` and an `insertId` carrying a
+    /// per-process, monotonically increasing sequence, which Cloud Logging uses
+    /// to break ties between identical timestamps. Entries from one process and
+    /// log name are then displayed in the order the layer received them, even
+    /// within the same millisecond and across batches. Entries from different
+    /// processes are still only ordered by timestamp.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to stamp entries with ordering information
+    pub fn preserve_order(mut self, enabled: bool) -> Self {
+        self.entry_config.preserve_order = enabled;
+        self
+    }
+
+    /// Set each entry's `timestamp` to the time the event was captured
+    ///
+    /// Without it, Cloud Logging uses the time the entry was received, so queueing
+    /// and batching delays shift entries later. The backend still records its
+    /// own `receiveTimestamp`, making the two easy to compare.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to send the capture time
+    pub fn event_timestamp(mut self, enabled: bool) -> Self {
+        self.entry_config.event_timestamp = enabled;
+        self
+    }
+
+    /// Add the time each entry spent in the layer's queue as `queue_latency_ms`
+    ///
+    /// Measured from capture until the background task picked the entry up.
+    /// Together with `timestamp` and `receiveTimestamp` this shows whether delays
+    /// are in the application, the queue or the backend. Batching may add up to
+    /// the flush interval on top of this value.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to add the field
+    pub fn queue_latency_field(mut self, enabled: bool) -> Self {
+        self.entry_config.queue_latency = enabled;
+        self
+    }
+
+    /// Emit entries when instrumented spans open and close
+    ///
+    /// Each span produces a `span {name} opened` entry carrying the span's
+    /// fields and a `span {name} closed` entry adding `elapsed_ms`. Both share
+    /// an `operation` id so the Logs Explorer can group them.
+    /// `shipped_targets` applies to spans as it does to events. Off by default since it doubles
+    /// the entry count of instrumented code.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to log span lifecycles
+    pub fn log_span_lifecycle(mut self, enabled: bool) -> Self {
+        self.config.log_span_lifecycle = enabled;
+        self
+    }
+
+    /// Write entries to the given log instead of `proxie`
+    ///
+    /// A log ID is written under the layer's project. A full resource name,
+    /// `projects/`, `organizations/`, `folders/` or `billingAccounts/`
+    /// followed by `{id}/logs/{log ID}`, writes to a log of that resource
+    /// instead, e.g. for centralized logging at the organization level. The
+    /// name can be changed later with `GcpLoggingHandle::set_log_name`.
+    ///
+    /// The name may be a template resolved for each entry as it is sent:
+    /// `{project}` is the project ID, `{env:NAME}` an environment variable,
+    /// `{field:NAME}` a top-level payload field, e.g. one recorded on an
+    /// enclosing span, and `{NAME}` a value set with `log_name_var`. Values
+    /// have characters a log ID cannot hold replaced with `_`, and missing
+    /// ones become `unknown`.
+    ///
+    /// # Arguments
+    /// * `log_name` - The log ID, e.g. `my-app`, full resource name, e.g.
+    ///   `organizations/123/logs/my-app`, or template, e.g.
+    ///   `projects/{project}/logs/{service}-{env:APP_ENV}`
+    pub fn log_name(self, log_name: impl Into<String>) -> Self {
+        self.entry_config.log_name.store(Arc::new(log_name.into()));
+        self
+    }
+
+    /// Set the value of a `{name}` placeholder in log name templates
+    ///
+    /// Applies to the layer's log name, severity routes and target log
+    /// names, including a log name set later through a handle.
+    ///
+    /// # Arguments
+    /// * `name` - The placeholder's name, e.g. `service` for `{service}`
+    /// * `value` - Its value, e.g. `checkout`
+    pub fn log_name_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.entry_config
+            .log_name_vars
+            .insert(name.into(), value.into());
+        self
+    }
+
+    /// Attach a label to every entry
+    ///
+    /// # Arguments
+    /// * `key` - Label key
+    /// * `value` - Label value
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.entry_config.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Also send a payload field as a label on the entries that carry it
+    ///
+    /// Labels are indexed, so filtering on them is cheaper than on payload
+    /// fields, e.g. `labels.request_id="ab-12"` to stitch a request's entries
+    /// together. Only top-level string fields are copied, whether recorded on
+    /// the event, an enclosing span or the task's `context`; the field stays
+    /// in the payload too.
+    ///
+    /// # Arguments
+    /// * `key` - The field name, also used as the label key
+    pub fn label_field(mut self, key: impl Into<String>) -> Self {
+        self.entry_config.label_fields.push(key.into());
+        self
+    }
+
+    /// Compute a label for every captured entry from the caller's context
+    ///
+    /// The resolver runs on the thread emitting the event or span entry, so
+    /// it can read task-locals, thread-locals or `context` fields. Entries for
+    /// which it returns None do not get the label. Resolved labels override
+    /// static and runtime labels with the same key. Entries emitted through a
+    /// handle are not captured and get no resolved labels.
+    ///
+    /// # Arguments
+    /// * `key` - Label key
+    /// * `resolver` - Computes the label's value, cheaply: it runs for every event
+    pub fn label_resolver(
+        mut self,
+        key: impl Into<String>,
+        resolver: impl Fn() -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config
+            .label_resolvers
+            .push((key.into(), Arc::new(resolver)));
+        self
+    }
+
+    /// Label every captured entry with the current tenant, as `tenant_id`
+    ///
+    /// Per-tenant log views, sinks and exclusion filters can then select
+    /// `labels.tenant_id="acme"`. Shorthand for `label_resolver(TENANT_LABEL, ..)`.
+    ///
+    /// # Arguments
+    /// * `resolver` - Returns the tenant of the current request, None outside one
+    pub fn tenant_resolver(
+        self,
+        resolver: impl Fn() -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.label_resolver(TENANT_LABEL, resolver)
+    }
+
+    /// Send entries at or above a severity to a different destination
+    ///
+    /// Routes apply to severity ranges: an entry uses the route with the
+    /// highest `min_severity` not above its own severity, e.g. a route at
+    /// `Severity::Default` for cheap debug output and one at `Severity::Error`
+    /// for the primary destination. Severities below every route keep the
+    /// layer's log name, resource and labels. The route's log name and
+    /// resource are validated like the layer's own.
+    ///
+    /// # Arguments
+    /// * `min_severity` - Lowest severity the route applies to
+    /// * `route` - Log name, resource and labels overriding the layer's
+    pub fn severity_route(
+        mut self,
+        min_severity: impl Into<Severity>,
+        route: SeverityRoute,
+    ) -> Self {
+        self.entry_config
+            .severity_routes
+            .insert(min_severity.into(), route);
+        self
+    }
+
+    /// Write the entries of a subsystem to a log of its own
+    ///
+    /// Events and spans whose target is `prefix` or one of its submodules go
+    /// to `log_name`, so e.g. payments logs can get a retention of their own
+    /// in a separate bucket; the most specific prefix wins. Everything else,
+    /// including entries emitted through a handle, keeps the layer's log name.
+    /// A severity route's log name overrides the mapping. The log name is
+    /// validated like the layer's own.
+    ///
+    /// # Arguments
+    /// * `prefix` - A target or module path, e.g. `myapp::payments`; a
+    ///   trailing `::*` is ignored
+    /// * `log_name` - The log ID or full resource name to write to
+    pub fn target_log_name(
+        mut self,
+        prefix: impl Into<String>,
+        log_name: impl Into<String>,
+    ) -> Self {
+        let prefix = prefix.into();
+        let prefix = prefix.strip_suffix("::*").unwrap_or(&prefix).to_string();
+        self.entry_config
+            .target_log_names
+            .insert(prefix, log_name.into());
+        self
+    }
+
+    /// Attach build information as labels on every entry
+    ///
+    /// Values missing from `info` are read from the `GIT_SHA`, `BUILD_ID` and
+    /// `BUILD_TIMESTAMP` environment variables at runtime. Typically called as
+    /// `.with_build_info(nano_gcp_logging::build_info!())`.
+    ///
+    /// # Arguments
+    /// * `info` - The build information, e.g. from `build_info!`
+    pub fn with_build_info(mut self, info: BuildInfo) -> Self {
+        for (key, value) in info.or(BuildInfo::from_env()).labels() {
+            self.entry_config.labels.insert(key.into(), value);
+        }
+        self
+    }
+
+    /// Attach labels computed by the application once, while the layer starts
+    ///
+    /// For metadata only the application can work out, such as a config hash,
+    /// the enabled feature flags or a deployment ID fetched from an internal
+    /// service. `discover` runs once during `build` (not for a disabled
+    /// layer), which waits for it, so keep it quick. It cannot fail: handle
+    /// errors and timeouts inside it, e.g. by returning fewer labels. Labels
+    /// set with `label` or `with_build_info` win over discovered ones with the
+    /// same key.
+    ///
+    /// # Arguments
+    /// * `discover` - Async function returning the labels to attach to every entry
+    pub fn startup_metadata<F, Fut>(mut self, discover: F) -> Self
+    where
+        F: FnOnce() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HashMap<String, String>> + Send + 'static,
+    {
+        self.startup_metadata = Some(Box::new(move || Box::pin(discover())));
+        self
+    }
+
+    /// Replace the whole batching policy
+    ///
+    /// # Arguments
+    /// * `policy` - Steady-state and backlog batching thresholds
+    pub fn batch_policy(mut self, policy: BatchPolicy) -> Self {
+        self.batch_policy = policy;
+        self
+    }
+
+    /// Set the number of entries per request while the queue is shallow
+    ///
+    /// # Arguments
+    /// * `size` - Entries per request
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.batch_policy.batch_size = size;
+        self
+    }
+
+    /// Set the longest time a partial batch waits before being sent
+    ///
+    /// # Arguments
+    /// * `interval` - The flush interval
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.batch_policy.flush_interval = interval;
+        self
+    }
+
+    /// Guarantee that no entry waits longer than `max_latency` to be sent
+    ///
+    /// Each entry's deadline is counted from its capture time, so the oldest
+    /// buffered entry triggers an early flush even when the batch is far from
+    /// full and the flush interval has not elapsed. The startup warmup (see
+    /// `batch_warmup`) is cut short the same way.
+    ///
+    /// # Arguments
+    /// * `max_latency` - Longest time an entry may stay buffered
+    pub fn max_latency(mut self, max_latency: Duration) -> Self {
+        self.batch_policy.max_latency = Some(max_latency);
+        self
+    }
+
+    /// Send the pending batch as soon as an entry at or above a severity is queued
+    ///
+    /// Other entries keep being batched by size and interval; a matching entry
+    /// (e.g. `Level::ERROR`) is uploaded right away together with whatever is
+    /// already pending, which keeps alerting latency low. This applies during
+    /// the startup warmup (see `batch_warmup`) too, so errors logged at
+    /// startup are not held back.
+    ///
+    /// # Arguments
+    /// * `severity` - Lowest severity that triggers an immediate flush
+    pub fn flush_on(mut self, severity: impl Into<Severity>) -> Self {
+        self.batch_policy.flush_on = Some(severity.into());
+        self
+    }
+
+    /// Coalesce the entries logged right after startup
+    ///
+    /// Until `warmup` has elapsed since the layer was built, a partial batch
+    /// is held back instead of being sent after `flush_interval`, and is sent
+    /// when the warmup ends or the batch fills. `flush_on` and `max_latency`
+    /// still send it early. Defaults to one second; pass None to send from
+    /// the start.
+    ///
+    /// # Arguments
+    /// * `warmup` - Grace period before the first partial batch is sent
+    pub fn batch_warmup(mut self, warmup: Option<Duration>) -> Self {
+        self.batch_policy.warmup = warmup;
+        self
+    }
+
+    /// Enable or disable the layer
+    ///
+    /// A disabled layer is a true no-op: no authentication or metadata calls are
+    /// made, no background task is spawned and `on_event` returns immediately.
+    /// Setting `NANO_GCP_LOGGING_DISABLED=1` has the same effect.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to ship logs to Cloud Logging
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Write events at or above a severity synchronously from `on_event`
+    ///
+    /// Matching events bypass the queue: the thread that emitted the event blocks
+    /// until the entry has been uploaded or `timeout` elapses, giving the last
+    /// error before a crash a better chance of reaching Cloud Logging. Uploads
+    /// run on a dedicated thread, so this is safe to use from async code, but
+    /// every matching event may stall its caller for up to `timeout`. Keep the
+    /// threshold high (e.g. `Severity::Error`) and the timeout short. An upload
+    /// still running at the timeout is abandoned and counted as failed.
+    ///
+    /// # Arguments
+    /// * `min_severity` - Lowest severity written synchronously
+    /// * `timeout` - Longest time a single write blocks the caller
+    pub fn sync_write(mut self, min_severity: Severity, timeout: Duration) -> Self {
+        self.sync_write = Some((min_severity, timeout));
+        self
+    }
+
+    /// Only ship events and spans matching per-target level directives
+    ///
+    /// Directives use the `tracing_subscriber::filter::Targets` syntax, e.g.
+    /// `"myapp=info,otherdep=off"` or `"warn,myapp=debug"` (a bare level is the
+    /// default for unlisted targets; without one, unlisted targets are not
+    /// shipped). They become a per-layer `Filter` (`ShippedTargets`) wrapping
+    /// the layer, so other layers in the subscriber stack, such as a stdout
+    /// `fmt` layer, still see every event, while spans of unshipped targets
+    /// never reach this layer and lend no fields to the events inside them.
+    /// Call it last, like `with_filter`: the returned builder only builds. To
+    /// combine the directives with another filter, pass both to `with_filter`,
+    /// e.g. `.with_filter(targets.and(LevelFilter::WARN))`. Invalid directives
+    /// fail the build with `ConfigError::InvalidFilter`.
+    ///
+    /// # Arguments
+    /// * `directives` - Comma-separated `target=level` directives
+    pub fn shipped_targets(
+        mut self,
+        directives: impl Into<String>,
+    ) -> FilteredLayerBuilder<ShippedTargets> {
+        let directives = directives.into();
+        let filter = ShippedTargets {
+            // Invalid directives fail the build, so the placeholder is never used.
+            targets: directives.parse().unwrap_or_default(),
+            global: self.config.global_filter,
+        };
+        self.shipped_targets = Some(directives);
+        self.with_filter(filter)
+    }
+
+    /// Replace the target prefixes whose events and spans are never shipped
+    ///
+    /// Defaults to `DEFAULT_IGNORED_TARGETS` (`hyper`, `hyper_util`,
+    /// `reqwest`, `gcp_auth` and `nano_gcp_logging`), which breaks the
+    /// feedback loop of uploads logging events about themselves. A target is
+    /// ignored when it is one of the prefixes or a module inside one, so
+    /// `hyper` covers `hyper::client` but not `hyperdrive`. Ignored targets
+    /// take precedence over `shipped_targets`; other layers still see their
+    /// events. Extend the defaults rather than dropping them, e.g. with
+    /// `DEFAULT_IGNORED_TARGETS.into_iter().chain(["h2"])`.
+    ///
+    /// # Arguments
+    /// * `prefixes` - Target prefixes to ignore, empty to ship every target
+    pub fn ignored_targets(
+        mut self,
+        prefixes: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.ignored_targets = prefixes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Apply `shipped_targets` and the ignored targets to the whole subscriber
+    ///
+    /// By default events this layer does not ship still reach other layers, so
+    /// each one costs a target match per event. With `global_filter(true)` the
+    /// layer returns `Interest::never()` for such call sites instead, and
+    /// `tracing` skips their events entirely: nothing is recorded or checked
+    /// again. Every layer in the stack then stops seeing them, including
+    /// `hyper` and `reqwest` events ignored by default, so only enable it when
+    /// this layer decides what the application logs. Call it before
+    /// `shipped_targets`, whose directives then apply globally too: their most
+    /// verbose level becomes the layer's `max_level_hint`, so more verbose
+    /// events are discarded at the macro. Since the per-layer filter wrapping
+    /// the layer hides its `Interest`, unshipped call sites are then checked
+    /// once per event instead, still before anything is recorded.
+    ///
+    /// # Arguments
+    /// * `global` - Whether unshipped call sites are disabled for every layer
+    pub fn global_filter(mut self, global: bool) -> Self {
+        self.config.global_filter = global;
+        self
+    }
+
+    /// Append text to the `User-Agent` sent with upload requests
+    ///
+    /// Requests always identify as `nano-gcp-logging/{version}`; the suffix
+    /// (e.g. `"my-app/2.3"`) is appended after a space to identify the
+    /// application in audit logs.
+    ///
+    /// # Arguments
+    /// * `suffix` - Text appended to the user agent
+    pub fn user_agent_suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.user_agent_suffix = Some(suffix.into());
+        self
+    }
+
+    /// Bill API quota for uploads to another project
+    ///
+    /// Sends `X-Goog-User-Project` with every upload, over REST and gRPC. The
+    /// credentials need `serviceusage.services.use` on that project.
+    ///
+    /// # Arguments
+    /// * `project_id` - The project charged for quota
+    pub fn quota_project(mut self, project_id: impl Into<String>) -> Self {
+        self.quota_project = Some(project_id.into());
+        self
+    }
+
+    /// Customize each REST upload request before it is sent
+    ///
+    /// Useful for headers the layer has no option for, e.g. those required by
+    /// a VPC Service Controls perimeter. The hook runs after every other header
+    /// is set, including `X-Goog-User-Project` and `Authorization`, so it can
+    /// replace them. It is not applied to gRPC uploads or metadata lookups.
+    ///
+    /// # Arguments
+    /// * `hook` - Callback receiving and returning the request
+    #[cfg(feature = "reqwest")]
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(reqwest::RequestBuilder) -> reqwest::RequestBuilder + Send + Sync + 'static,
+    ) -> Self {
+        self.request_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call back with each entry discarded instead of delivered
+    ///
+    /// By default discarded entries are only counted in `LogStats::dropped`.
+    /// The callback sees entries emitted after the background task stopped,
+    /// and those the task discards for lack of credentials, so it can e.g.
+    /// bump a metric or append them to a local file. Batches dropped after
+    /// serialization, by the circuit breaker or a full retry queue, are only
+    /// counted. The callback runs on the emitting thread or the background
+    /// task for every such entry, so it must be cheap and must not block.
+    ///
+    /// # Arguments
+    /// * `hook` - Callback receiving each discarded entry
+    pub fn on_drop(mut self, hook: impl Fn(&GcpLogEntry) + Send + Sync + 'static) -> Self {
+        self.on_drop = Some(Arc::new(hook));
+        self
+    }
+
+    /// Also send severe entries to the Error Reporting API
+    ///
+    /// Entries at or above `min_severity` (`ERROR` for
+    /// `ErrorReporting::new`) are reported as `ReportedErrorEvent`s with
+    /// their message, `stack_trace` field and source location, alongside
+    /// their normal delivery. Reports use the layer's credentials with the
+    /// `cloud-platform` scope (a `token_provider` must supply such tokens) and
+    /// go to the detected project. Nothing is reported without credentials
+    /// or with a sink, nor by `build_blocking`.
+    ///
+    /// # Arguments
+    /// * `config` - Service, version, threshold and queue capacity
+    pub fn error_reporting(mut self, config: ErrorReporting) -> Self {
+        self.error_reporting = Some(config);
+        self
+    }
+
+    /// Export span timings to Cloud Trace
+    ///
+    /// Each span the layer ships is written to Cloud Trace through
+    /// `traces:batchWrite` when it closes, giving basic distributed traces
+    /// without an OpenTelemetry pipeline. Spans recording a `traceparent` or
+    /// `cloud_trace_context` header join the caller's trace; entries logged
+    /// inside a span are linked to it unless an OpenTelemetry context is
+    /// available. Spans are sent with the layer's credentials, which need the
+    /// `cloud-platform` scope, to the detected project. Nothing is exported
+    /// without credentials or with a sink, nor by `build_blocking`, though
+    /// entries are still linked to the spans' IDs.
+    ///
+    /// # Arguments
+    /// * `config` - Sampling, batching and capacity settings
+    pub fn trace_export(mut self, config: TraceExport) -> Self {
+        self.config.trace_export = Some(config);
+        self
+    }
+
+    /// Push the layer's delivery metrics to Cloud Monitoring
+    ///
+    /// Writes queue depths, upload latency and the sent, failed and dropped
+    /// counts as custom metrics every `interval` and once more after a
+    /// shutdown, so logging health can be monitored and alerted on across a
+    /// fleet. Uses the layer's credentials, which need the `cloud-platform`
+    /// scope, and the detected project. Nothing is pushed without credentials
+    /// or with a sink, nor by `build_blocking`.
+    ///
+    /// # Arguments
+    /// * `config` - Job name, interval and metric prefix
+    pub fn metrics_export(mut self, config: MetricsExport) -> Self {
+        self.metrics_export = Some(config);
+        self
+    }
+
+    /// Write the layer's own warnings and errors to a rotated file
+    ///
+    /// By default they go to stderr, which on servers is often captured and
+    /// shipped back through the logging pipeline, so a failing upload would
+    /// produce more entries to upload. With this set, each diagnostic is
+    /// appended to the file as a timestamped line instead. The destination is
+    /// process-wide: it takes effect when the layer is built, replacing one
+    /// set by an earlier layer, and building fails if the file cannot be
+    /// opened. `QUIET_ENV_VAR` still silences warnings.
+    ///
+    /// # Arguments
+    /// * `config` - Path, rotation size and number of rotated files kept
+    pub fn diagnostics_file(mut self, config: DiagnosticsFile) -> Self {
+        self.diagnostics_file = Some(config);
+        self
+    }
+
+    /// Use the given HTTP client for metadata lookups and log uploads
+    ///
+    /// Shares one connection pool and consistent timeout/proxy settings with the
+    /// application. By default a client with reqwest's defaults is created. The
+    /// `sync_write` path runs on its own runtime and always uses a separate
+    /// default client.
+    ///
+    /// # Arguments
+    /// * `client` - The client to use
+    #[cfg(feature = "reqwest")]
+    pub fn http_client(mut self, client: reqwest::Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Configure the upload circuit breaker
+    ///
+    /// By default uploads pause for 30s after 5 consecutive failures, holding up
+    /// to 100 batches to send once the API recovers. Set `failure_threshold` to
+    /// 0 to disable the breaker.
+    ///
+    /// # Arguments
+    /// * `config` - Breaker thresholds and open-circuit policy
+    pub fn circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = config;
+        self
+    }
+
+    /// Configure how failed batches are retried
+    ///
+    /// By default a batch failing with a transport error, 429 or 5xx is retried
+    /// up to 5 times from a separate task, backing off from 1s to 30s, with at
+    /// most 100 batches waiting. Set `max_attempts` to 0 to disable retries.
+    ///
+    /// # Arguments
+    /// * `policy` - Retry queue capacity, backoff and overflow policy
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Upload batches through the gRPC `WriteLogEntries` API instead of REST
+    ///
+    /// Uses a persistent HTTP/2 channel to `logging.googleapis.com:443` with the
+    /// same credentials, which lowers per-request overhead for high-volume
+    /// services. Bodies sent in backlog mode are gzip-compressed as with REST.
+    /// Entries are encoded straight to protobuf when batched, so a
+    /// `wire_encoder` does not apply.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to upload through gRPC
+    #[cfg(feature = "grpc")]
+    pub fn grpc(mut self, enabled: bool) -> Self {
+        self.grpc = enabled;
+        self
+    }
+
+    /// Print entries to stdout when they cannot be uploaded for lack of credentials
+    ///
+    /// Instead of silently dropping entries during local development, their
+    /// message lines are printed. With the `colors` feature lines are colored by
+    /// severity (ERROR red, WARNING yellow, ...) when stdout is a terminal and
+    /// `NO_COLOR` is not set.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to print entries
+    pub fn stdout_fallback(mut self, enabled: bool) -> Self {
+        self.stdout_fallback = enabled;
+        self
+    }
+
+    /// Write entries to stdout as JSON that Cloud Run's logging agent parses
+    ///
+    /// Each entry becomes one line with `severity`, `message`, `time` and the
+    /// `logging.googleapis.com/trace`, `spanId`, `trace_sampled`,
+    /// `sourceLocation`, `labels`, `insertId` and `operation` keys, which the
+    /// agent lifts into the `LogEntry`; the payload fields are written next to
+    /// them and end up in `jsonPayload`. Nothing is uploaded and no
+    /// credentials are acquired, which makes this the zero-auth path on Cloud
+    /// Run (and on GKE, whose agent reads the same keys). Synchronous writes are
+    /// disabled. Off by default.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write structured stdout instead of uploading
+    pub fn structured_stdout(mut self, enabled: bool) -> Self {
+        self.structured_stdout = enabled;
+        self
+    }
+
+    /// Produce entries for another log aggregator instead of Cloud Logging
+    ///
+    /// With `OutputFormat::Ecs` or `OutputFormat::Gelf`, entries go to the
+    /// sink if one is installed, and otherwise to stdout as one JSON object
+    /// per line for a collector to ship. Nothing is uploaded, no credentials
+    /// are acquired and `structured_stdout` is ignored. Fields are collected
+    /// exactly as for Cloud Logging; see `OutputFormat` for the mappings.
+    ///
+    /// # Arguments
+    /// * `format` - The schema to produce, `OutputFormat::CloudLogging` by default
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// Run the background tasks on a dedicated thread with its own Tokio runtime
+    ///
+    /// By default `build` must be awaited inside a Tokio runtime, which then
+    /// runs the upload task for the layer's lifetime. With a dedicated
+    /// runtime, `build` can be awaited from any executor (async-std, smol,
+    /// ...) or a runtime that exits before logging does, and the
+    /// `nano-gcp-logging` thread exits once every layer and handle is dropped.
+    /// `GcpLoggingHandle::shutdown` can then be awaited from any executor too,
+    /// as its deadline is timed on the dedicated runtime. A client passed to
+    /// `http_client` should not be tied to another runtime. Off by default.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to start a dedicated thread
+    pub fn dedicated_runtime(mut self, enabled: bool) -> Self {
+        self.dedicated_runtime = enabled;
+        self
+    }
+
+    /// Hand every built entry to a sink instead of uploading it
+    ///
+    /// Lets tests assert on the exact JSON this crate produces for their events
+    /// (reserved fields, labels, severity, ...) without credentials or network
+    /// access. Synchronous writes are disabled while a sink is installed.
+    ///
+    /// # Arguments
+    /// * `sink` - Receiver of the built entries
+    #[cfg(feature = "testing")]
+    pub fn sink(mut self, sink: impl LogSink) -> Self {
+        self.sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Validate the configuration and start the layer
+    ///
+    /// # Returns
+    /// A Result containing the initialized GcpLoggingLayer or an error
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub async fn build(mut self) -> Result<GcpLoggingLayer, Box<dyn std::error::Error>> {
+        self.prepare()?;
+        if self.dedicated_runtime {
+            return dedicated::start(self).await;
+        }
+        let (layer, _tasks) = GcpLoggingLayer::start(self).await?;
+        Ok(layer)
+    }
+
+    /// Validate the configuration and start the layer without Tokio
+    ///
+    /// Entries are sent from a plain `nano-gcp-logging` thread with a blocking
+    /// HTTP client, so a synchronous application needs no runtime at all,
+    /// not even while starting. Differences from `build`:
+    ///
+    /// * Without a `token_provider`, tokens come from the metadata server's
+    ///   default service account; key files and `gcloud` credentials are not
+    ///   read, so off GCP entries are only sent with a provider. Provider and
+    ///   `startup_metadata` futures must not need a Tokio runtime.
+    /// * Failed batches are retried in place on the sender thread following
+    ///   `retry_policy`, holding back later entries meanwhile.
+    /// * `circuit_breaker`, `heartbeat_interval`, `sync_write`, `http_client`,
+    ///   `on_request`, `grpc`, `error_reporting`, `metrics_export` and
+    ///   `dedicated_runtime` are ignored, and spans are not exported to Cloud
+    ///   Trace.
+    ///
+    /// `GcpLoggingHandle::blocking_shutdown` works from any thread; `shutdown`
+    /// can be awaited from any executor, as the sender thread enforces its
+    /// deadline itself. Without the `reqwest` and `hyper` features, this is
+    /// the only way to start a layer and neither an async HTTP client nor
+    /// Tokio's I/O driver is built.
+    ///
+    /// # Returns
+    /// A Result containing the initialized GcpLoggingLayer or an error
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking(mut self) -> Result<GcpLoggingLayer, Box<dyn std::error::Error>> {
+        self.prepare()?;
+        blocking::start(self)
+    }
+
+    /// Wrap the layer built from this configuration in a per-layer filter
+    ///
+    /// The filter only decides what this layer ships, independently of the
+    /// console or other layers in the stack, e.g.
+    /// `.with_filter(LevelFilter::WARN)` ships warnings and errors while a
+    /// `fmt` layer keeps printing everything. It applies on top of the ignored
+    /// targets, and span fields, trace lookups and lifecycle entries only
+    /// consider spans it enables. Call it last: the returned builder only
+    /// builds.
+    ///
+    /// # Arguments
+    /// * `filter` - A `tracing_subscriber::layer::Filter`, e.g. `Targets`,
+    ///   `LevelFilter`, `EnvFilter` or `filter_fn(..)`
+    pub fn with_filter<F>(self, filter: F) -> FilteredLayerBuilder<F> {
+        FilteredLayerBuilder {
+            builder: self,
+            filter,
+        }
+    }
+
+    /// Validate the configuration and parse the shipped target directives
+    ///
+    /// The directives are kept for the layer itself only in `global_filter`
+    /// mode; otherwise the `ShippedTargets` filter wrapping it applies them.
+    fn prepare(&mut self) -> Result<(), ConfigError> {
+        self.validate()?;
+        if let Some(directives) = self.shipped_targets.take() {
+            let targets = directives
+                .parse::<Targets>()
+                .map_err(|e| ConfigError::InvalidFilter(e.to_string()))?;
+            self.config.targets = self.config.global_filter.then_some(targets);
+        }
+        Ok(())
+    }
+
+    /// Check that the configuration is complete
+    fn validate(&self) -> Result<(), ConfigError> {
+        validate_log_name(&self.entry_config.log_name.load())?;
+        let routes = self.entry_config.severity_routes.values();
+        for route in routes.clone() {
+            if let Some(log_name) = &route.log_name {
+                validate_log_name(log_name)?;
+            }
+        }
+        for log_name in self.entry_config.target_log_names.values() {
+            validate_log_name(log_name)?;
+        }
+        let resources = routes.filter_map(|route| route.resource.as_ref());
+        for resource in self.entry_config.resource.iter().chain(resources) {
+            if resource.resource_type.is_empty() {
+                return Err(ConfigError::MissingField("resource.type"));
+            }
+        }
+        match &self.instance {
+            Some(instance) if instance.project_id.is_empty() => {
+                Err(ConfigError::MissingField("instance.project_id"))
+            }
+            None if self.project_id.is_empty() => Err(ConfigError::MissingField("project_id")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Builder for a `GcpLoggingLayer` wrapped in a per-layer filter
+///
+/// Obtain one with `GcpLoggingLayerBuilder::with_filter`.
+pub struct FilteredLayerBuilder<F> {
+    /// Configuration of the wrapped layer
+    builder: GcpLoggingLayerBuilder,
+    /// Filter deciding which events and spans reach the layer
+    filter: F,
+}
+
+impl<F> FilteredLayerBuilder<F> {
+    /// Validate the configuration and start the filtered layer
+    ///
+    /// # Returns
+    /// A Result containing the filtered layer or an error
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub async fn build<S>(
+        self,
+    ) -> Result<Filtered<GcpLoggingLayer, F, S>, Box<dyn std::error::Error>> {
+        let layer = self.builder.build().await?;
+        Ok(Filtered::new(layer, self.filter))
+    }
+
+    /// Validate the configuration and start the filtered layer without Tokio
+    ///
+    /// See `GcpLoggingLayerBuilder::build_blocking`.
+    ///
+    /// # Returns
+    /// A Result containing the filtered layer or an error
+    #[cfg(feature = "blocking")]
+    pub fn build_blocking<S>(
+        self,
+    ) -> Result<Filtered<GcpLoggingLayer, F, S>, Box<dyn std::error::Error>> {
+        let layer = self.builder.build_blocking()?;
+        Ok(Filtered::new(layer, self.filter))
+    }
+}
+
+/// Per-layer filter shipping events and spans that match target directives
+///
+/// Created by `GcpLoggingLayerBuilder::shipped_targets`. Spans it rejects
+/// never reach the layer, so their fields are not added to the events inside
+/// them. In `global_filter` mode it lets everything through and the layer
+/// rejects unshipped events for the whole subscriber instead.
+#[derive(Debug, Clone)]
+pub struct ShippedTargets {
+    /// The parsed directives
+    targets: Targets,
+    /// Whether the layer applies the directives itself, for every layer
+    global: bool,
+}
+
+impl<S> Filter<S> for ShippedTargets {
+    /// Whether the directives ship events and spans with the given metadata
+    ///
+    /// # Arguments
+    /// * `metadata` - The event or span metadata
+    /// * `_cx` - The tracing context
+    fn enabled(&self, metadata: &Metadata<'_>, _cx: &Context<'_, S>) -> bool {
+        self.global
+            || self
+                .targets
+                .would_enable(metadata.target(), metadata.level())
+    }
+
+    /// Cache the decision per call site, except in `global_filter` mode
+    ///
+    /// # Arguments
+    /// * `metadata` - The call site's metadata
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        match self.global {
+            // The layer's `enabled` decides for every layer, once per event.
+            true => Interest::sometimes(),
+            false => Filter::<S>::callsite_enabled(&self.targets, metadata),
+        }
+    }
+
+    /// The most verbose level any directive ships
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Filter::<S>::max_level_hint(&self.targets)
+    }
+}
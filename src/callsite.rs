@@ -0,0 +1,75 @@
+//! Details derived from event call sites once and shared by all their events.
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+use tracing::callsite::Identifier;
+use tracing::Metadata;
+
+use crate::entry::SourceLocation;
+
+/// What the worker derives from a call site's metadata
+///
+/// A call site's target, file and line never change, so this is built the
+/// first time one of its events is turned into an entry and leaked: there is
+/// one per call site, and call sites live as long as the program.
+#[derive(Debug)]
+pub(crate) struct CallsiteInfo {
+    /// `{target} {file}:{line}`, the location shown in the default message line
+    pub(crate) location: String,
+    /// The call site's file and line, if it has a file
+    pub(crate) source_location: Option<SourceLocation>,
+}
+
+impl CallsiteInfo {
+    /// Derive the details of a call site
+    ///
+    /// # Arguments
+    /// * `metadata` - The call site's metadata
+    fn new(metadata: &'static Metadata<'static>) -> Self {
+        let target = metadata.target();
+        let file = metadata.file();
+        let line = metadata.line().unwrap_or(0);
+        let location = format!(
+            "{}{}{}:{}",
+            target,
+            if target.is_empty() { "" } else { " " },
+            file.unwrap_or("unknown_file"),
+            line
+        );
+        Self {
+            location,
+            source_location: file.map(|file| SourceLocation {
+                file: file.to_string(),
+                line,
+            }),
+        }
+    }
+}
+
+/// Details of every call site seen so far, keyed by its identifier
+static CALLSITES: LazyLock<RwLock<HashMap<Identifier, &'static CallsiteInfo>>> =
+    LazyLock::new(RwLock::default);
+
+/// Look up the details of a call site, deriving them on first use
+///
+/// # Arguments
+/// * `metadata` - The metadata of the event or span
+///
+/// # Returns
+/// The details, shared by every entry from the same call site
+pub(crate) fn info(metadata: &'static Metadata<'static>) -> &'static CallsiteInfo {
+    let id = metadata.callsite();
+    if let Some(info) = CALLSITES
+        .read()
+        .ok()
+        .and_then(|callsites| callsites.get(&id).copied())
+    {
+        return info;
+    }
+    let mut callsites = CALLSITES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    callsites
+        .entry(id)
+        .or_insert_with(|| Box::leak(Box::new(CallsiteInfo::new(metadata))))
+}
@@ -0,0 +1,339 @@
+//! Circuit breaker that pauses uploads while the Logging API keeps failing.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::batch::count_entries;
+use crate::metrics::Metrics;
+
+/// State of the upload circuit breaker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Uploads are attempted normally
+    Closed,
+    /// Uploads are paused after repeated failures
+    Open,
+    /// The cooldown elapsed and a single probe upload is being attempted
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Encode the state for storage in an atomic
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+
+    /// Decode a state stored with `to_u8`
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            1 => CircuitState::Open,
+            2 => CircuitState::HalfOpen,
+            _ => CircuitState::Closed,
+        }
+    }
+}
+
+/// What happens to batches flushed while the circuit is open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenCircuitPolicy {
+    /// Keep up to `buffer_capacity` batches and send them once the circuit closes
+    Buffer,
+    /// Discard batches without attempting to send them
+    Drop,
+}
+
+/// Thresholds for the upload circuit breaker
+///
+/// After `failure_threshold` consecutive failed uploads the circuit opens and
+/// no uploads are attempted for `cooldown`. The next upload is then a probe:
+/// success closes the circuit, failure reopens it for another cooldown.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit; 0 disables the breaker
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before probing
+    pub cooldown: Duration,
+    /// What happens to batches while the circuit is open
+    pub open_policy: OpenCircuitPolicy,
+    /// Batches kept with `OpenCircuitPolicy::Buffer`; the oldest are dropped first
+    pub buffer_capacity: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+            open_policy: OpenCircuitPolicy::Buffer,
+            buffer_capacity: 100,
+        }
+    }
+}
+
+/// Tracks consecutive failures and decides whether an upload may be attempted
+pub(crate) struct CircuitBreaker {
+    /// Thresholds
+    pub(crate) config: CircuitBreakerConfig,
+    /// Failures since the last success
+    consecutive_failures: u32,
+    /// When the circuit last opened
+    opened_at: Option<Instant>,
+    /// Current state, shared with handles
+    state: Arc<AtomicU8>,
+    /// Request bodies (and whether to compress them) held while open
+    held: VecDeque<(Vec<u8>, bool)>,
+    /// Counters recording the batches dropped while open
+    metrics: Arc<Metrics>,
+    /// Whether dropping batches for a full buffer has been reported
+    reported_eviction: bool,
+}
+
+impl CircuitBreaker {
+    /// Create a closed breaker
+    ///
+    /// # Arguments
+    /// * `config` - Thresholds
+    /// * `state` - Atomic the current state is published to
+    /// * `metrics` - Counters recording dropped entries
+    pub(crate) fn new(
+        config: CircuitBreakerConfig,
+        state: Arc<AtomicU8>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        state.store(CircuitState::Closed.to_u8(), Ordering::Relaxed);
+        Self {
+            config,
+            consecutive_failures: 0,
+            opened_at: None,
+            state,
+            held: VecDeque::new(),
+            metrics,
+            reported_eviction: false,
+        }
+    }
+
+    /// Whether an upload may be attempted now
+    ///
+    /// Moves an open circuit to half-open once the cooldown has elapsed.
+    pub(crate) fn allow_request(&mut self) -> bool {
+        match self.opened_at {
+            None => true,
+            Some(opened_at) if opened_at.elapsed() >= self.config.cooldown => {
+                self.publish(CircuitState::HalfOpen);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Record a successful upload, closing the circuit
+    pub(crate) fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        if self.opened_at.take().is_some() {
+            self.publish(CircuitState::Closed);
+        }
+    }
+
+    /// Record a failed upload, opening the circuit past the threshold
+    pub(crate) fn record_failure(&mut self) {
+        if self.config.failure_threshold == 0 {
+            return;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let probe_failed = self.opened_at.is_some();
+        if probe_failed || self.consecutive_failures >= self.config.failure_threshold {
+            if !probe_failed {
+                warning!(
+                    "{} consecutive log uploads failed; pausing uploads for {:?}",
+                    self.consecutive_failures,
+                    self.config.cooldown
+                );
+            }
+            self.opened_at = Some(Instant::now());
+            self.publish(CircuitState::Open);
+        }
+    }
+
+    /// Handle a batch flushed while the circuit is open
+    ///
+    /// # Arguments
+    /// * `body` - The request body
+    /// * `compress` - Whether the body should be compressed when sent
+    pub(crate) fn hold(&mut self, body: Vec<u8>, compress: bool) {
+        if self.config.failure_threshold == 0
+            || self.config.open_policy == OpenCircuitPolicy::Drop
+            || self.config.buffer_capacity == 0
+        {
+            self.discard(&body);
+            return;
+        }
+        if self.held.len() >= self.config.buffer_capacity {
+            if let Some((evicted, _)) = self.held.pop_front() {
+                self.discard(&evicted);
+                if !std::mem::replace(&mut self.reported_eviction, true) {
+                    warning!(
+                        "the circuit breaker buffer is full ({} batches); dropping the oldest batches until uploads resume",
+                        self.config.buffer_capacity
+                    );
+                }
+            }
+        }
+        self.held.push_back((body, compress));
+    }
+
+    /// Count a batch discarded instead of being held
+    ///
+    /// # Arguments
+    /// * `body` - The request body
+    fn discard(&self, body: &[u8]) {
+        self.metrics.circuit_dropped.fetch_add(1, Ordering::Relaxed);
+        Metrics::add(&self.metrics.dropped, count_entries(body));
+    }
+
+    /// Take the oldest held batch, if any
+    pub(crate) fn next_held(&mut self) -> Option<(Vec<u8>, bool)> {
+        self.held.pop_front()
+    }
+
+    /// Put a batch back at the front after a failed resend
+    pub(crate) fn requeue_held(&mut self, body: Vec<u8>, compress: bool) {
+        self.held.push_front((body, compress));
+    }
+
+    /// Publish the state to handles
+    fn publish(&self, state: CircuitState) {
+        self.state.store(state.to_u8(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request body holding the given number of entries
+    fn body(entries: usize) -> Vec<u8> {
+        let entries = vec!["{}"; entries].join(",");
+        format!(r#"{{"entries":[{}]}}"#, entries).into_bytes()
+    }
+
+    /// A closed breaker with its published state and counters
+    fn breaker(config: CircuitBreakerConfig) -> (CircuitBreaker, Arc<AtomicU8>, Arc<Metrics>) {
+        let state = Arc::new(AtomicU8::new(CircuitState::Open.to_u8()));
+        let metrics = Arc::new(Metrics::default());
+        let breaker = CircuitBreaker::new(config, state.clone(), metrics.clone());
+        (breaker, state, metrics)
+    }
+
+    /// The state published to handles
+    fn state(state: &AtomicU8) -> CircuitState {
+        CircuitState::from_u8(state.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_opens_probes_and_closes() {
+        let (mut breaker, published, _) = breaker(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(30),
+            ..CircuitBreakerConfig::default()
+        });
+        assert_eq!(state(&published), CircuitState::Closed);
+
+        breaker.record_failure();
+        assert!(breaker.allow_request());
+        assert_eq!(state(&published), CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(state(&published), CircuitState::Open);
+        assert!(!breaker.allow_request());
+
+        // The circuit stays open for the whole cooldown.
+        tokio::time::advance(Duration::from_secs(29)).await;
+        assert!(!breaker.allow_request());
+        assert_eq!(state(&published), CircuitState::Open);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breaker.allow_request());
+        assert_eq!(state(&published), CircuitState::HalfOpen);
+
+        // A failed probe reopens the circuit for another cooldown.
+        breaker.record_failure();
+        assert_eq!(state(&published), CircuitState::Open);
+        assert!(!breaker.allow_request());
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.allow_request());
+        assert_eq!(state(&published), CircuitState::HalfOpen);
+
+        breaker.record_success();
+        assert_eq!(state(&published), CircuitState::Closed);
+        assert!(breaker.allow_request());
+
+        // The failure count restarted with the success.
+        breaker.record_failure();
+        assert_eq!(state(&published), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_threshold_never_opens() {
+        let (mut breaker, published, metrics) = breaker(CircuitBreakerConfig {
+            failure_threshold: 0,
+            ..CircuitBreakerConfig::default()
+        });
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow_request());
+        assert_eq!(state(&published), CircuitState::Closed);
+
+        breaker.hold(body(2), false);
+        assert!(breaker.next_held().is_none());
+        assert_eq!(metrics.stats().dropped, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buffer_policy_evicts_oldest_and_counts_it() {
+        let (mut breaker, _, metrics) = breaker(CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_policy: OpenCircuitPolicy::Buffer,
+            buffer_capacity: 2,
+            ..CircuitBreakerConfig::default()
+        });
+        breaker.record_failure();
+        breaker.hold(body(1), false);
+        breaker.hold(body(2), true);
+        assert_eq!(metrics.snapshot().circuit_dropped, 0);
+
+        breaker.hold(body(3), false);
+        assert_eq!(metrics.snapshot().circuit_dropped, 1);
+        assert_eq!(metrics.stats().dropped, 1);
+
+        // The newest batches are kept in order, and a failed resend goes
+        // back to the front.
+        let (held, compress) = breaker.next_held().unwrap();
+        assert_eq!((count_entries(&held), compress), (2, true));
+        breaker.requeue_held(held, compress);
+        assert_eq!(count_entries(&breaker.next_held().unwrap().0), 2);
+        assert_eq!(count_entries(&breaker.next_held().unwrap().0), 3);
+        assert!(breaker.next_held().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drop_policy_discards_and_counts() {
+        let (mut breaker, _, metrics) = breaker(CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_policy: OpenCircuitPolicy::Drop,
+            ..CircuitBreakerConfig::default()
+        });
+        breaker.record_failure();
+        breaker.hold(body(4), false);
+        breaker.hold(body(1), false);
+
+        assert!(breaker.next_held().is_none());
+        assert_eq!(metrics.snapshot().circuit_dropped, 2);
+        assert_eq!(metrics.stats().dropped, 5);
+    }
+}
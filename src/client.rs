@@ -0,0 +1,438 @@
+//! HTTP client used for uploads and metadata lookups.
+//!
+//! This is reqwest when the `reqwest` feature is enabled (the default).
+//! Otherwise it is a minimal hyper + rustls client with the small part of
+//! reqwest's API the crate uses.
+#[cfg(feature = "reqwest")]
+pub(crate) use reqwest::{header, Client, Error, RequestBuilder, Response};
+
+#[cfg(not(feature = "reqwest"))]
+pub(crate) use http::header;
+#[cfg(not(feature = "reqwest"))]
+pub(crate) use lean::{Client, Error, RequestBuilder, Response};
+
+#[cfg(not(feature = "reqwest"))]
+mod lean {
+    use std::fmt;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+    use http::{HeaderMap, Method, StatusCode};
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::Bytes;
+    use hyper_rustls::HttpsConnector;
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use hyper_util::rt::TokioExecutor;
+    use serde::de::DeserializeOwned;
+    #[cfg(feature = "read")]
+    use serde::Serialize;
+
+    /// Errors raised while sending a request or reading its response
+    #[derive(Debug)]
+    pub(crate) enum Error {
+        /// The URL, a header or the body was invalid
+        Request(String),
+        /// The connection failed or was closed before a response arrived
+        Connect(hyper_util::client::legacy::Error),
+        /// The response body could not be read
+        Body(hyper::Error),
+        /// No response arrived within the request's timeout
+        Timeout,
+        /// The server answered with an error status
+        Status(StatusCode),
+        /// The response body was not the expected JSON
+        Json(serde_json::Error),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::Request(e) => write!(f, "invalid request: {}", e),
+                Error::Connect(e) => write!(f, "error sending request: {}", e),
+                Error::Body(e) => write!(f, "error reading response body: {}", e),
+                Error::Timeout => write!(f, "request timed out"),
+                Error::Status(status) => write!(f, "HTTP status {}", status),
+                Error::Json(e) => write!(f, "error decoding response body: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for Error {}
+
+    /// HTTP/1.1 client verifying TLS against the Mozilla root certificates
+    #[derive(Clone)]
+    pub(crate) struct Client {
+        /// Pooled hyper client
+        inner: hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    }
+
+    impl Default for Client {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Client {
+        /// Create a client with its own connection pool
+        pub(crate) fn new() -> Self {
+            let roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let tls = rustls::ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default TLS versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let connector = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls)
+                .https_or_http()
+                .enable_http1()
+                .build();
+            Self {
+                inner: hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+                    .build(connector),
+            }
+        }
+
+        /// Start a GET request
+        pub(crate) fn get(&self, url: impl Into<String>) -> RequestBuilder {
+            self.request(Method::GET, url.into())
+        }
+
+        /// Start a POST request
+        pub(crate) fn post(&self, url: impl Into<String>) -> RequestBuilder {
+            self.request(Method::POST, url.into())
+        }
+
+        fn request(&self, method: Method, url: String) -> RequestBuilder {
+            RequestBuilder {
+                client: self.clone(),
+                request: http::Request::builder().method(method),
+                url,
+                body: Vec::new(),
+                timeout: None,
+                error: None,
+            }
+        }
+    }
+
+    /// A request being built, sent with `send`
+    pub(crate) struct RequestBuilder {
+        /// Client the request is sent with
+        client: Client,
+        /// Method and headers
+        request: http::request::Builder,
+        /// URL
+        url: String,
+        /// Request body
+        body: Vec<u8>,
+        /// Time allowed for the whole exchange
+        timeout: Option<Duration>,
+        /// First error raised while building, returned by `send`
+        error: Option<Error>,
+    }
+
+    impl RequestBuilder {
+        /// Add a header
+        pub(crate) fn header<K, V>(mut self, key: K, value: V) -> Self
+        where
+            HeaderName: TryFrom<K>,
+            <HeaderName as TryFrom<K>>::Error: Into<http::Error>,
+            HeaderValue: TryFrom<V>,
+            <HeaderValue as TryFrom<V>>::Error: Into<http::Error>,
+        {
+            self.request = self.request.header(key, value);
+            self
+        }
+
+        /// Add an `Authorization: Bearer` header
+        pub(crate) fn bearer_auth(self, token: impl fmt::Display) -> Self {
+            self.header(AUTHORIZATION, format!("Bearer {}", token))
+        }
+
+        /// Send parameters as a URL-encoded form body
+        pub(crate) fn form<K: AsRef<str>, V: AsRef<str>>(self, pairs: &[(K, V)]) -> Self {
+            let body = pairs
+                .iter()
+                .map(|(key, value)| format!("{}={}", encode(key.as_ref()), encode(value.as_ref())))
+                .collect::<Vec<_>>()
+                .join("&");
+            self.header(
+                http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(body)
+        }
+
+        /// Set the request body
+        pub(crate) fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+            self.body = body.into();
+            self
+        }
+
+        /// Send a value as a JSON body
+        #[cfg(feature = "read")]
+        pub(crate) fn json(mut self, value: &impl Serialize) -> Self {
+            match serde_json::to_vec(value) {
+                Ok(body) => self
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(body),
+                Err(e) => {
+                    self.error.get_or_insert(Error::Json(e));
+                    self
+                }
+            }
+        }
+
+        /// Fail the request if no full response arrived within `timeout`
+        pub(crate) fn timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = Some(timeout);
+            self
+        }
+
+        /// Send the request and read the whole response
+        pub(crate) async fn send(self) -> Result<Response, Error> {
+            if let Some(e) = self.error {
+                return Err(e);
+            }
+            let request = self
+                .request
+                .uri(self.url)
+                .body(Full::new(Bytes::from(self.body)))
+                .map_err(|e| Error::Request(e.to_string()))?;
+            let client = self.client.inner;
+            let exchange = async move {
+                let response = client.request(request).await.map_err(Error::Connect)?;
+                let (parts, body) = response.into_parts();
+                let body = body.collect().await.map_err(Error::Body)?.to_bytes();
+                Ok(Response {
+                    status: parts.status,
+                    headers: parts.headers,
+                    body,
+                })
+            };
+            with_timeout(self.timeout, exchange).await
+        }
+    }
+
+    /// Run a request, bounded by a timeout if one was set
+    async fn with_timeout(
+        timeout: Option<Duration>,
+        exchange: impl Future<Output = Result<Response, Error>>,
+    ) -> Result<Response, Error> {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, exchange)
+                .await
+                .unwrap_or(Err(Error::Timeout)),
+            None => exchange.await,
+        }
+    }
+
+    /// Percent-encode everything but RFC 3986 unreserved characters
+    fn encode(text: &str) -> String {
+        let mut encoded = String::with_capacity(text.len());
+        for byte in text.bytes() {
+            if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+                encoded.push(byte as char);
+            } else {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        encoded
+    }
+
+    /// A response whose body has been read in full
+    pub(crate) struct Response {
+        /// Status code
+        status: StatusCode,
+        /// Response headers
+        headers: HeaderMap,
+        /// Response body
+        body: Bytes,
+    }
+
+    impl Response {
+        /// The status code
+        pub(crate) fn status(&self) -> StatusCode {
+            self.status
+        }
+
+        /// The response headers
+        pub(crate) fn headers(&self) -> &HeaderMap {
+            &self.headers
+        }
+
+        /// Turn 4xx and 5xx responses into an error
+        pub(crate) fn error_for_status(self) -> Result<Self, Error> {
+            match self.status.is_client_error() || self.status.is_server_error() {
+                true => Err(Error::Status(self.status)),
+                false => Ok(self),
+            }
+        }
+
+        /// The body as text, replacing invalid UTF-8
+        pub(crate) async fn text(self) -> Result<String, Error> {
+            Ok(String::from_utf8_lossy(&self.body).into_owned())
+        }
+
+        /// The body parsed as JSON
+        pub(crate) async fn json<T: DeserializeOwned>(self) -> Result<T, Error> {
+            serde_json::from_slice(&self.body).map_err(Error::Json)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+        use tokio::task::JoinHandle;
+
+        /// Answer one request with a canned response
+        ///
+        /// # Arguments
+        /// * `response` - The raw HTTP response, sent once the request is read
+        ///
+        /// # Returns
+        /// The server's URL, and a task returning the raw request it received
+        async fn serve(response: &'static str) -> (String, JoinHandle<String>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/path?q=1", listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut chunk = [0u8; 1024];
+                while !is_complete(&request) {
+                    let read = stream.read(&mut chunk).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&chunk[..read]);
+                }
+                stream.write_all(response.as_bytes()).await.unwrap();
+                String::from_utf8(request).unwrap()
+            });
+            (url, server)
+        }
+
+        /// Whether a raw request holds its headers and the whole body
+        fn is_complete(request: &[u8]) -> bool {
+            let text = String::from_utf8_lossy(request);
+            let Some((head, body)) = text.split_once("\r\n\r\n") else {
+                return false;
+            };
+            let length = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse().ok())
+                .unwrap_or(0);
+            body.len() >= length
+        }
+
+        #[test]
+        fn test_encode_keeps_only_unreserved_characters() {
+            assert_eq!(encode("aZ09-._~"), "aZ09-._~");
+            assert_eq!(encode("a b&c=d/é"), "a%20b%26c%3Dd%2F%C3%A9");
+        }
+
+        #[tokio::test]
+        async fn test_send_writes_method_headers_and_body_and_reads_the_response() {
+            let (url, server) = serve(
+                "HTTP/1.1 200 OK\r\nx-answer: yes\r\ncontent-length: 11\r\n\r\n{\"ok\":true}",
+            )
+            .await;
+            let response = Client::new()
+                .post(url)
+                .bearer_auth("secret")
+                .header(http::header::USER_AGENT, "tests")
+                .body("payload")
+                .send()
+                .await
+                .expect("The request should succeed");
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["x-answer"], "yes");
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body, serde_json::json!({"ok": true}));
+
+            let request = server.await.unwrap();
+            assert!(
+                request.starts_with("POST /path?q=1 HTTP/1.1\r\n"),
+                "{}",
+                request
+            );
+            let lowercase = request.to_ascii_lowercase();
+            assert!(
+                lowercase.contains("authorization: bearer secret\r\n"),
+                "{}",
+                request
+            );
+            assert!(lowercase.contains("user-agent: tests\r\n"), "{}", request);
+            assert!(request.ends_with("\r\n\r\npayload"), "{}", request);
+        }
+
+        #[tokio::test]
+        async fn test_form_sends_an_encoded_body() {
+            let (url, server) = serve("HTTP/1.1 204 No Content\r\n\r\n").await;
+            let response = Client::new()
+                .post(url)
+                .form(&[("access_token", "a/b c")])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+            let request = server.await.unwrap();
+            let lowercase = request.to_ascii_lowercase();
+            assert!(
+                lowercase.contains("content-type: application/x-www-form-urlencoded\r\n"),
+                "{}",
+                request
+            );
+            assert!(request.ends_with("access_token=a%2Fb%20c"), "{}", request);
+        }
+
+        #[tokio::test]
+        async fn test_error_statuses_become_errors() {
+            let (url, _server) =
+                serve("HTTP/1.1 404 Not Found\r\ncontent-length: 9\r\n\r\nnot found").await;
+            let response = Client::new().get(url).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            match response.error_for_status() {
+                Err(Error::Status(status)) => assert_eq!(status, StatusCode::NOT_FOUND),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("a 404 should be an error"),
+            }
+
+            let (url, _server) =
+                serve("HTTP/1.1 200 OK\r\ncontent-length: 9\r\n\r\nnot json!").await;
+            let response = Client::new().get(url).send().await.unwrap();
+            assert!(matches!(
+                response.json::<serde_json::Value>().await,
+                Err(Error::Json(_))
+            ));
+        }
+
+        #[tokio::test]
+        async fn test_send_fails_on_timeouts_refused_connections_and_bad_urls() {
+            let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", silent.local_addr().unwrap());
+            let result = Client::new()
+                .get(url)
+                .timeout(Duration::from_millis(100))
+                .send()
+                .await;
+            assert!(matches!(result, Err(Error::Timeout)));
+
+            let result = Client::new().get("http://127.0.0.1:9/").send().await;
+            assert!(matches!(result, Err(Error::Connect(_))));
+
+            let result = Client::new().get("not a url").send().await;
+            assert!(matches!(result, Err(Error::Request(_))));
+        }
+    }
+}
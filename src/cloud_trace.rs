@@ -0,0 +1,277 @@
+//! Export of span timings to Cloud Trace.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::request_id::random_u64;
+use crate::trace::{TraceContext, TRACE_HEADER_FIELDS};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::transport::Transport;
+
+/// Cloud Trace API, followed by `/projects/{project}/traces:batchWrite`
+const CLOUD_TRACE_URL: &str = "https://cloudtrace.googleapis.com/v2";
+
+/// Most attributes Cloud Trace keeps per span
+const MAX_ATTRIBUTES: usize = 32;
+
+/// Longest string attribute value Cloud Trace keeps, in bytes
+const MAX_ATTRIBUTE_BYTES: usize = 256;
+
+/// Controls how span timings are exported to Cloud Trace
+///
+/// Every span the layer ships becomes a Cloud Trace span when it closes,
+/// with its fields as attributes. A span recording a `traceparent` or
+/// `cloud_trace_context` header joins the caller's trace and follows its
+/// sampling decision; other root spans start a trace of their own, sampled
+/// with `sample_ratio`, and child spans inherit their parent's trace.
+/// Entries logged inside an exported span are linked to it.
+#[derive(Debug, Clone)]
+pub struct TraceExport {
+    /// Share of new traces exported, from 0.0 to 1.0
+    pub sample_ratio: f64,
+    /// Most spans sent in one `batchWrite` request
+    pub batch_size: usize,
+    /// Longest time a closed span waits for its batch to fill
+    pub flush_interval: Duration,
+    /// Closed spans kept waiting to be sent; further ones are dropped
+    pub capacity: usize,
+}
+
+impl Default for TraceExport {
+    fn default() -> Self {
+        Self {
+            sample_ratio: 1.0,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(5),
+            capacity: 10_000,
+        }
+    }
+}
+
+/// Trace and span IDs assigned to a span, stored in the span's extensions
+pub(crate) struct SpanIds {
+    /// 32-character hex trace ID
+    trace_id: String,
+    /// 16-character hex span ID
+    span_id: String,
+    /// ID of the enclosing span, None for the root of a trace
+    parent_span_id: Option<String>,
+    /// Whether the span is exported
+    sampled: bool,
+    /// When the span opened
+    start: DateTime<Utc>,
+}
+
+impl SpanIds {
+    /// Assign IDs to a span that just opened
+    ///
+    /// # Arguments
+    /// * `header` - Trace context recorded on the span itself, if any
+    /// * `parent` - IDs of the nearest enclosing span that has them, if any
+    /// * `sample_ratio` - Share of new traces exported
+    pub(crate) fn new(
+        header: Option<TraceContext>,
+        parent: Option<&SpanIds>,
+        sample_ratio: f64,
+    ) -> Self {
+        let (trace_id, parent_span_id, sampled) = match (header, parent) {
+            (Some(header), _) => (
+                header.trace_id,
+                Some(header.span_id).filter(|id| !id.is_empty()),
+                header.sampled,
+            ),
+            (None, Some(parent)) => (
+                parent.trace_id.clone(),
+                Some(parent.span_id.clone()),
+                parent.sampled,
+            ),
+            (None, None) => (
+                format!("{:016x}{:016x}", random_u64(), random_u64()),
+                None,
+                (random_u64() as f64 / u64::MAX as f64) < sample_ratio,
+            ),
+        };
+        Self {
+            trace_id,
+            span_id: format!("{:016x}", random_u64().max(1)),
+            parent_span_id,
+            sampled,
+            start: Utc::now(),
+        }
+    }
+
+    /// The trace context of entries logged inside the span
+    pub(crate) fn trace_context(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id.clone(),
+            span_id: self.span_id.clone(),
+            sampled: self.sampled,
+        }
+    }
+}
+
+/// Whether the layer already warned about spans dropped for a full queue
+static WARNED_FULL: AtomicBool = AtomicBool::new(false);
+
+/// Queues closed spans for the task sending them to Cloud Trace
+pub(crate) struct SpanExporter {
+    /// Project the spans are written to
+    project_id: String,
+    /// Closed spans, as Cloud Trace `Span`s, for the export task
+    spans: Sender<Value>,
+}
+
+impl SpanExporter {
+    /// Spawn the export task on the current Tokio runtime
+    ///
+    /// # Arguments
+    /// * `config` - Batching and capacity settings
+    /// * `transport` - Transport whose credentials and request hook are used
+    /// * `project_id` - The project the spans are written to
+    ///
+    /// # Returns
+    /// The exporter and its task, which ends once the exporter is dropped
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn spawn(
+        config: &TraceExport,
+        transport: Transport,
+        project_id: String,
+    ) -> (Self, JoinHandle<()>) {
+        let (spans, rx) = channel(config.capacity.max(1));
+        let url = format!(
+            "{}/projects/{}/traces:batchWrite",
+            CLOUD_TRACE_URL, project_id
+        );
+        let task = tokio::spawn(run(
+            rx,
+            transport,
+            url,
+            config.batch_size.max(1),
+            config.flush_interval,
+        ));
+        (Self { project_id, spans }, task)
+    }
+
+    /// Queue a closed span for export if it was sampled
+    ///
+    /// # Arguments
+    /// * `ids` - The span's IDs
+    /// * `name` - The span's name
+    /// * `fields` - The span's fields, sent as attributes
+    pub(crate) fn export(&self, ids: &SpanIds, name: &str, fields: &Map<String, Value>) {
+        if !ids.sampled {
+            return;
+        }
+        let mut span = json!({
+            "name": format!(
+                "projects/{}/traces/{}/spans/{}",
+                self.project_id, ids.trace_id, ids.span_id
+            ),
+            "spanId": ids.span_id,
+            "displayName": { "value": name },
+            "startTime": ids.start.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            "endTime": Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+            "attributes": attributes(fields),
+        });
+        if let Some(parent_span_id) = &ids.parent_span_id {
+            span["parentSpanId"] = parent_span_id.as_str().into();
+        }
+        if self.spans.try_send(span).is_err() && !WARNED_FULL.swap(true, Ordering::Relaxed) {
+            warning!("the Cloud Trace export queue is full; spans are dropped until it drains. Further drops are not reported.");
+        }
+    }
+}
+
+/// Convert span fields into Cloud Trace attributes
+///
+/// Integers and booleans keep their type; other values are sent as strings,
+/// truncated to what the API keeps. Trace header fields are left out.
+///
+/// # Arguments
+/// * `fields` - The span's fields
+///
+/// # Returns
+/// The `Attributes` object
+fn attributes(fields: &Map<String, Value>) -> Value {
+    let mut map = Map::new();
+    let mut dropped = 0;
+    for (key, value) in fields {
+        if TRACE_HEADER_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+        if map.len() == MAX_ATTRIBUTES {
+            dropped += 1;
+            continue;
+        }
+        let value = match value {
+            Value::Bool(value) => json!({ "boolValue": value }),
+            // int64 values are encoded as JSON strings.
+            Value::Number(number) if number.is_i64() => {
+                json!({ "intValue": number.to_string() })
+            }
+            Value::String(string) => truncatable(string),
+            other => truncatable(&other.to_string()),
+        };
+        map.insert(key.clone(), value);
+    }
+    json!({ "attributeMap": map, "droppedAttributesCount": dropped })
+}
+
+/// A string attribute value, truncated on a character boundary
+///
+/// # Arguments
+/// * `value` - The string
+fn truncatable(value: &str) -> Value {
+    let mut end = value.len().min(MAX_ATTRIBUTE_BYTES);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    json!({
+        "stringValue": {
+            "value": &value[..end],
+            "truncatedByteCount": value.len() - end,
+        }
+    })
+}
+
+/// Send closed spans in batches until the exporter is dropped
+///
+/// # Arguments
+/// * `rx` - Closed spans from the exporter
+/// * `transport` - Transport used for the requests
+/// * `url` - The project's `traces:batchWrite` URL
+/// * `batch_size` - Most spans per request
+/// * `flush_interval` - Longest time a span waits for its batch to fill
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+async fn run(
+    mut rx: Receiver<Value>,
+    transport: Transport,
+    url: String,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    while let Some(first) = rx.recv().await {
+        let mut spans = vec![first];
+        let deadline = Instant::now() + flush_interval;
+        while spans.len() < batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(span)) => spans.push(span),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let Ok(body) = serde_json::to_vec(&json!({ "spans": spans })) else {
+            continue;
+        };
+        match transport.post_api(url.clone(), body).await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => diagnostic!("Failed to export spans: HTTP {}", res.status()),
+            Err(e) => diagnostic!("Failed to export spans: {}", e),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Human-readable stdout fallback used when entries cannot be uploaded.
+use std::ffi::OsStr;
+use std::io::{IsTerminal, Write};
+
+use crate::entry::{EntryConfig, GcpLogEntry};
+use crate::Severity;
+
+/// Environment variable that disables colored output when set to any value
+const NO_COLOR_ENV_VAR: &str = "NO_COLOR";
+
+/// Prints entries to stdout, colored by severity when writing to a terminal
+pub(crate) struct Console {
+    /// Whether to emit ANSI color codes
+    color: bool,
+}
+
+impl Console {
+    /// Create a console writer, deciding on colors from the environment
+    ///
+    /// Colors require the `colors` feature, a terminal on stdout and an unset
+    /// (or empty) `NO_COLOR`, so piped output stays plain.
+    pub(crate) fn new() -> Self {
+        Self::with_colors(
+            std::env::var_os(NO_COLOR_ENV_VAR).as_deref(),
+            std::io::stdout().is_terminal(),
+        )
+    }
+
+    /// Create a console writer from the given environment
+    ///
+    /// # Arguments
+    /// * `no_color` - Value of `NO_COLOR`, if set
+    /// * `terminal` - Whether stdout is a terminal
+    fn with_colors(no_color: Option<&OsStr>, terminal: bool) -> Self {
+        let no_color = no_color.is_some_and(|v| !v.is_empty());
+        Self {
+            color: cfg!(feature = "colors") && !no_color && terminal,
+        }
+    }
+
+    /// Print an entry's message line
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry to print
+    /// * `config` - Options controlling the message line
+    pub(crate) fn print(&self, log_entry: &GcpLogEntry, config: &EntryConfig) {
+        let _ = writeln!(std::io::stdout().lock(), "{}", self.line(log_entry, config));
+    }
+
+    /// Format an entry's message line, colored if enabled
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry to format
+    /// * `config` - Options controlling the message line
+    fn line(&self, log_entry: &GcpLogEntry, config: &EntryConfig) -> String {
+        let line = log_entry.message_line(config);
+        if self.color {
+            paint(log_entry.severity, &line)
+        } else {
+            line.into_owned()
+        }
+    }
+}
+
+/// Color a line according to its severity
+#[cfg(feature = "colors")]
+fn paint(severity: Severity, line: &str) -> String {
+    use nu_ansi_term::{Color, Style};
+
+    let style = match severity {
+        Severity::Default => Style::new(),
+        Severity::Debug => Color::Blue.normal(),
+        Severity::Info => Color::Green.normal(),
+        Severity::Notice => Color::Cyan.normal(),
+        Severity::Warning => Color::Yellow.normal(),
+        Severity::Error => Color::Red.normal(),
+        Severity::Critical | Severity::Alert | Severity::Emergency => Color::Red.bold(),
+    };
+    style.paint(line).to_string()
+}
+
+/// Without the `colors` feature lines are never colored
+#[cfg(not(feature = "colors"))]
+fn paint(_severity: Severity, line: &str) -> String {
+    line.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Format an internal entry of the given severity
+    ///
+    /// # Arguments
+    /// * `console` - The console formatting the entry
+    /// * `severity` - Severity of the entry
+    fn line(console: &Console, severity: Severity) -> String {
+        let log_entry = GcpLogEntry::internal(severity, "hello");
+        console.line(&log_entry, &EntryConfig::default())
+    }
+
+    #[test]
+    fn test_piped_output_and_no_color_stay_plain() {
+        let piped = Console::with_colors(None, false);
+        assert_eq!(line(&piped, Severity::Error), "hello");
+        let no_color = Console::with_colors(Some(OsStr::new("1")), true);
+        assert_eq!(line(&no_color, Severity::Error), "hello");
+    }
+
+    #[cfg(not(feature = "colors"))]
+    #[test]
+    fn test_terminals_stay_plain_without_the_feature() {
+        let console = Console::with_colors(None, true);
+        assert_eq!(line(&console, Severity::Error), "hello");
+    }
+
+    #[cfg(feature = "colors")]
+    #[test]
+    fn test_terminals_get_severity_colors() {
+        // An empty NO_COLOR counts as unset.
+        let console = Console::with_colors(Some(OsStr::new("")), true);
+        assert_eq!(line(&console, Severity::Default), "hello");
+        assert_eq!(line(&console, Severity::Debug), "\x1b[34mhello\x1b[0m");
+        assert_eq!(line(&console, Severity::Info), "\x1b[32mhello\x1b[0m");
+        assert_eq!(line(&console, Severity::Notice), "\x1b[36mhello\x1b[0m");
+        assert_eq!(line(&console, Severity::Warning), "\x1b[33mhello\x1b[0m");
+        assert_eq!(line(&console, Severity::Error), "\x1b[31mhello\x1b[0m");
+        assert_eq!(line(&console, Severity::Critical), "\x1b[1;31mhello\x1b[0m");
+        assert_eq!(
+            line(&console, Severity::Emergency),
+            "\x1b[1;31mhello\x1b[0m"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Task-local context fields attached to every event, like a logging MDC.
+//!
+//! ```no_run
+//! # async fn handle(user_id: u64) {}
+//! # async fn example(user_id: u64) {
+//! tokio::spawn(nano_gcp_logging::context::scope(async move {
+//!     nano_gcp_logging::context::insert("user_id", user_id);
+//!     // Every event logged by this task now carries `user_id`.
+//!     handle(user_id).await;
+//! }));
+//! # }
+//! ```
+//!
+//! Fields live in the task-local context of the innermost `scope` the caller
+//! runs in. Outside any scope they live in a thread-local context instead,
+//! which suits synchronous code; a Tokio task can move between threads at
+//! every `.await`, so async code should always run inside a `scope`.
+use std::cell::RefCell;
+use std::future::Future;
+
+use serde_json::{Map, Value};
+
+tokio::task_local! {
+    /// Context of the tasks running inside `scope`
+    static TASK_CONTEXT: RefCell<Map<String, Value>>;
+}
+
+thread_local! {
+    /// Context of code running outside any `scope`
+    static THREAD_CONTEXT: RefCell<Map<String, Value>> = RefCell::new(Map::new());
+}
+
+/// Run a closure on the current context, task-local if in a scope
+///
+/// # Arguments
+/// * `f` - The closure, given the context
+fn with_current<R>(f: impl FnOnce(&RefCell<Map<String, Value>>) -> R) -> R {
+    let mut f = Some(f);
+    match TASK_CONTEXT.try_with(|context| f.take().map(|f| f(context))) {
+        Ok(Some(result)) => result,
+        // The closure only goes unused when there is no task-local context.
+        _ => THREAD_CONTEXT.with(|context| (f.take().expect("closure not run"))(context)),
+    }
+}
+
+/// Run a future with its own context, starting from a copy of the current one
+///
+/// Fields inserted inside the future do not leak out of it. The copy is taken
+/// when `scope` is called, so `tokio::spawn(context::scope(..))` hands the
+/// spawning task's fields to the new task.
+///
+/// # Arguments
+/// * `future` - The future to run, typically a request handler or task body
+pub fn scope<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    scope_with(snapshot(), future)
+}
+
+/// Run a future with the given context
+///
+/// # Arguments
+/// * `fields` - The future's context fields
+/// * `future` - The future to run
+pub(crate) fn scope_with<F: Future>(
+    fields: Map<String, Value>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    TASK_CONTEXT.scope(RefCell::new(fields), future)
+}
+
+/// Attach a field to every event logged from the current context
+///
+/// Replaces any field of the same name. Span fields and the event's own
+/// fields take precedence over context fields on collisions.
+///
+/// # Arguments
+/// * `key` - The field name
+/// * `value` - The field value
+pub fn insert(key: impl Into<String>, value: impl Into<Value>) {
+    with_current(|context| context.borrow_mut().insert(key.into(), value.into()));
+}
+
+/// Stop attaching a field to events logged from the current context
+///
+/// # Arguments
+/// * `key` - The field name
+///
+/// # Returns
+/// The field's value, None if it was not set
+pub fn remove(key: &str) -> Option<Value> {
+    with_current(|context| context.borrow_mut().remove(key))
+}
+
+/// Remove every field of the current context
+pub fn clear() {
+    with_current(|context| context.borrow_mut().clear());
+}
+
+/// Copy the fields of the current context
+pub fn snapshot() -> Map<String, Value> {
+    with_current(|context| context.borrow().clone())
+}
+
+/// Add the current context's fields to an event's span fields
+///
+/// Span fields win over context fields.
+///
+/// # Arguments
+/// * `fields` - The span fields collected for the event, updated in place
+pub(crate) fn add_context_fields(fields: &mut Map<String, Value>) {
+    with_current(|context| {
+        let context = context.borrow();
+        if context.is_empty() {
+            return;
+        }
+        let mut merged = context.clone();
+        merged.append(fields);
+        *fields = merged;
+    });
+}
@@ -0,0 +1,60 @@
+//! A thread of its own running the background tasks, for applications not on Tokio.
+use std::error::Error;
+
+use tokio::sync::oneshot;
+
+use crate::{GcpLoggingLayer, GcpLoggingLayerBuilder, THREAD_NAME};
+
+/// Start the layer on a dedicated thread with its own Tokio runtime
+///
+/// Metadata detection, authentication, uploads and retries all run there, so
+/// the returned future can be awaited from any executor (async-std, smol, a
+/// short-lived runtime, ...). The thread exits once the background tasks
+/// end, i.e. when every layer and handle is dropped or the layer has shut
+/// down.
+///
+/// # Arguments
+/// * `builder` - A validated builder
+///
+/// # Returns
+/// The layer, or the error that stopped it from starting
+pub(crate) async fn start(
+    builder: GcpLoggingLayerBuilder,
+) -> Result<GcpLoggingLayer, Box<dyn Error>> {
+    // Errors cross the thread as text, as `Box<dyn Error>` is not `Send`.
+    let (started, result) = oneshot::channel::<Result<GcpLoggingLayer, String>>();
+    std::thread::Builder::new()
+        .name(THREAD_NAME.into())
+        .spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    let _ = started.send(Err(format!("failed to start runtime: {}", e)));
+                    return;
+                }
+            };
+            runtime.block_on(async move {
+                let tasks = match GcpLoggingLayer::start(builder).await {
+                    Ok((layer, tasks)) => {
+                        let _ = started.send(Ok(layer));
+                        tasks
+                    }
+                    Err(e) => {
+                        let _ = started.send(Err(e.to_string()));
+                        return;
+                    }
+                };
+                for task in tasks {
+                    let _ = task.await;
+                }
+            });
+        })?;
+    match result.await {
+        Ok(Ok(layer)) => Ok(layer),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err("background thread exited while starting the layer".into()),
+    }
+}
@@ -0,0 +1,159 @@
+//! Destination of the layer's own warnings and errors.
+//!
+//! They go to stderr unless a diagnostics file was installed, in which case
+//! they are appended to it with a timestamp and the file is rotated by size.
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use chrono::{SecondsFormat, Utc};
+
+/// Writes the layer's diagnostics to a local file instead of stderr
+///
+/// On servers stderr is often captured and shipped to Cloud Logging itself,
+/// so upload failures would be logged through the very path that is failing.
+/// With this set, every warning and error of the layer is appended to `path`
+/// as one timestamped line. Once the file would grow past `max_bytes` it is
+/// renamed to `{path}.1`, older files shift to `{path}.2` and so on, and the
+/// oldest beyond `max_files` is deleted.
+#[derive(Debug, Clone)]
+pub struct DiagnosticsFile {
+    /// File the diagnostics are appended to
+    pub path: PathBuf,
+    /// Size at which the file is rotated, in bytes
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one; 0 truncates in place
+    pub max_files: usize,
+}
+
+impl DiagnosticsFile {
+    /// Write to the given file, rotated at 10 MiB with 3 older files kept
+    ///
+    /// # Arguments
+    /// * `path` - File the diagnostics are appended to
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            max_bytes: 10 * 1024 * 1024,
+            max_files: 3,
+        }
+    }
+}
+
+/// The open diagnostics file and its current size
+struct RotatingFile {
+    /// Path, size limit and number of rotated files
+    config: DiagnosticsFile,
+    /// The current file, opened for appending
+    file: File,
+    /// Bytes in the current file
+    size: u64,
+}
+
+impl RotatingFile {
+    /// Open the configured file for appending
+    ///
+    /// # Arguments
+    /// * `config` - Path, size limit and number of rotated files
+    fn open(config: DiagnosticsFile) -> io::Result<Self> {
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { config, file, size })
+    }
+
+    /// Append a line, rotating first if it would not fit
+    ///
+    /// # Arguments
+    /// * `line` - The line, including its newline
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the rotated files and start an empty current file
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        if self.config.max_files > 0 {
+            // Missing files are expected until every slot has been used.
+            let _ = fs::remove_file(rotated(path, self.config.max_files));
+            for index in (1..self.config.max_files).rev() {
+                let _ = fs::rename(rotated(path, index), rotated(path, index + 1));
+            }
+            fs::rename(path, rotated(path, 1))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        self.file = open_append(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Open a file for appending, creating it if needed
+///
+/// # Arguments
+/// * `path` - The file
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Path of a rotated file, e.g. `diagnostics.log.2`
+///
+/// # Arguments
+/// * `path` - The current file
+/// * `index` - 1 for the most recently rotated file
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", index));
+    name.into()
+}
+
+/// The installed diagnostics file, None to write to stderr
+static DESTINATION: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+/// Send the process's diagnostics to a file from now on
+///
+/// Replaces a file installed earlier.
+///
+/// # Arguments
+/// * `config` - Path, size limit and number of rotated files
+///
+/// # Returns
+/// An error if the file cannot be opened; diagnostics keep their destination
+pub(crate) fn install(config: DiagnosticsFile) -> io::Result<()> {
+    let file = RotatingFile::open(config)?;
+    *DESTINATION.lock().unwrap_or_else(PoisonError::into_inner) = Some(file);
+    Ok(())
+}
+
+/// Write one diagnostic line to the diagnostics file, or stderr without one
+///
+/// Falls back to stderr if the file cannot be written.
+///
+/// # Arguments
+/// * `message` - The diagnostic, without a trailing newline
+pub(crate) fn write(message: fmt::Arguments) {
+    let mut destination = DESTINATION.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(file) = destination.as_mut() else {
+        drop(destination);
+        eprintln!("{}", message);
+        return;
+    };
+    let line = format!(
+        "{} {}\n",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        message
+    );
+    if let Err(e) = file.write(line.as_bytes()) {
+        drop(destination);
+        eprintln!("{}", message);
+        eprintln!("Warning: could not write the diagnostics file: {}", e);
+    }
+}
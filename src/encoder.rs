@@ -0,0 +1,106 @@
+//! Encoders turning entries into the request bodies the transport uploads.
+use std::fmt;
+use std::io;
+
+use serde_json::Value;
+
+use crate::entry::{EntryView, GcpLogEntry};
+
+/// Encodes entries and joins them into request bodies
+///
+/// Install one with `GcpLoggingLayerBuilder::wire_encoder`. Each entry is
+/// encoded once when it is batched, so `max_batch_bytes` counts encoded
+/// bytes, and `encode_batch` joins a batch right before it is uploaded.
+/// Entries written to a sink are not encoded.
+///
+/// The REST transport reads bodies in the `entries:write` JSON shape
+/// `JsonEncoder` produces, as do the delivery counters; a custom encoder
+/// should wrap `JsonEncoder` (e.g. to capture what is sent in tests) unless
+/// the bodies never reach them. Uploads through gRPC use an encoder of their
+/// own.
+pub trait WireEncoder: Send + Sync + 'static {
+    /// Encode one entry
+    ///
+    /// # Arguments
+    /// * `entry` - The entry being batched
+    /// * `buffer` - An empty buffer receiving the encoded entry
+    fn encode_entry(&self, entry: &WireEntry<'_>, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Join encoded entries into a request body
+    ///
+    /// # Arguments
+    /// * `entries` - The entries encoded by `encode_entry`, in batch order
+    /// * `body` - An empty buffer receiving the body
+    fn encode_batch(&self, entries: &[Vec<u8>], body: &mut Vec<u8>);
+}
+
+impl fmt::Debug for dyn WireEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WireEncoder(..)")
+    }
+}
+
+/// An entry about to be encoded, with everything the layer resolved for it
+pub struct WireEntry<'a> {
+    /// The entry's `LogEntry` view
+    view: &'a EntryView<'a>,
+}
+
+impl<'a> WireEntry<'a> {
+    /// Wrap an entry's view
+    pub(crate) fn new(view: &'a EntryView<'a>) -> Self {
+        Self { view }
+    }
+
+    /// The entry's view
+    #[cfg(feature = "grpc")]
+    pub(crate) fn view(&self) -> &'a EntryView<'a> {
+        self.view
+    }
+
+    /// The queued entry, with its fields resolved
+    pub fn entry(&self) -> &GcpLogEntry {
+        self.view.log_entry()
+    }
+
+    /// The full `logName` the entry is written to
+    pub fn log_name(&self) -> &str {
+        self.view.log_name()
+    }
+
+    /// The entry's Cloud Logging `LogEntry` JSON
+    pub fn to_json(&self) -> Value {
+        self.view.to_value()
+    }
+
+    /// Write the entry's `LogEntry` JSON without building it as a value first
+    ///
+    /// # Arguments
+    /// * `writer` - Where the JSON is written
+    pub fn write_json(&self, writer: impl io::Write) -> serde_json::Result<()> {
+        self.view.write(writer)
+    }
+}
+
+/// `entries:write` JSON, the body the REST API takes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonEncoder;
+
+impl WireEncoder for JsonEncoder {
+    fn encode_entry(&self, entry: &WireEntry<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        entry.write_json(buffer).map_err(io::Error::from)
+    }
+
+    fn encode_batch(&self, entries: &[Vec<u8>], body: &mut Vec<u8>) {
+        let bytes: usize = entries.iter().map(Vec::len).sum();
+        body.reserve(bytes + entries.len() + 16);
+        body.extend_from_slice(b"{\"entries\":[");
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(entry);
+        }
+        body.extend_from_slice(b"]}");
+    }
+}
@@ -0,0 +1,1450 @@
+//! Log entries queued by the layer and their Cloud Logging JSON form.
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::ser::SerializeMap;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+use tracing::Metadata;
+
+use crate::callsite;
+use crate::encoder::{JsonEncoder, WireEncoder, WireEntry};
+use crate::error::ConfigError;
+use crate::limits::{
+    self, MAX_ENTRY_BYTES, MAX_LABELS, MAX_LABEL_KEY_BYTES, MAX_LABEL_VALUE_BYTES,
+};
+use crate::message::{FullFormatter, JsonOnlyFormatter, MessageFormatter};
+use crate::payload::{PayloadParts, PayloadSerializer};
+use crate::pool::MESSAGES;
+use crate::trace::TraceContext;
+use crate::visitor::PendingFields;
+use crate::{LogContextMetadata, MonitoredResource, Severity};
+
+/// Label attached to entries generated by the layer itself
+///
+/// Entries such as heartbeats carry `nano_gcp_logging/internal: "true"` so they
+/// can be filtered in or out in the Logs Explorer, e.g. with
+/// `-labels."nano_gcp_logging/internal"="true"`. Internal entries are handed
+/// straight to the background task and never pass through `on_event`.
+pub const INTERNAL_LABEL: &str = "nano_gcp_logging/internal";
+
+/// Label set by the resolver given to `GcpLoggingLayerBuilder::tenant_resolver`
+pub const TENANT_LABEL: &str = "tenant_id";
+
+/// Structured log entry for Google Cloud Logging
+///
+/// This is the queued form of every entry. It is only reachable from outside
+/// the crate with the `testing` feature, together with `build_entry`, so
+/// downstream tests can check how their entries are serialized.
+#[derive(Debug, Serialize)]
+pub struct GcpLogEntry {
+    /// Log message content
+    pub message: String,
+    /// Severity level of the log entry
+    pub severity: Severity,
+    /// Structured fields recorded on the event
+    pub fields: Map<String, Value>,
+    /// When the event was captured
+    pub timestamp: DateTime<Utc>,
+    /// Code location the entry originates from, sent as `sourceLocation`
+    pub source_location: Option<SourceLocation>,
+    /// Long-running operation the entry belongs to, sent as `operation`
+    pub operation: Option<Operation>,
+    /// Trace the entry was emitted in, sent as `trace`, `spanId` and `traceSampled`
+    pub trace: Option<TraceContext>,
+    /// Labels resolved when the entry was captured, overriding the layer's
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Labels set through `GcpLoggingHandle::set_label` when the entry was queued
+    ///
+    /// None for entries built without being queued, which get the labels set
+    /// at the time they are built.
+    #[serde(skip)]
+    pub runtime_labels: Option<Arc<HashMap<String, String>>>,
+    /// Whether the entry was generated by the layer itself
+    #[serde(skip)]
+    pub internal: bool,
+    /// The event or span the entry was captured from, if any
+    ///
+    /// Its target and location are formatted into the message line, and the
+    /// target added to the payload, when the entry is built rather than on
+    /// the caller's thread.
+    #[serde(skip)]
+    pub callsite: Option<&'static Metadata<'static>>,
+    /// Event fields still to be converted and merged into `fields`
+    ///
+    /// `build_entry` expects them resolved; the background task does so for
+    /// every entry it receives.
+    #[serde(skip)]
+    pub pending: PendingFields,
+}
+
+/// Source code location of an entry, as Cloud Logging's `LogEntrySourceLocation`
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceLocation {
+    /// Source file name
+    pub file: String,
+    /// Line within the source file, serialized as a string per the API's int64 encoding
+    #[serde(serialize_with = "serialize_line")]
+    pub line: u32,
+}
+
+/// Operation an entry belongs to, as Cloud Logging's `LogEntryOperation`
+///
+/// Entries sharing `id` and `producer` are grouped in the Logs Explorer.
+#[derive(Debug, Clone, Serialize)]
+pub struct Operation {
+    /// Identifier shared by every entry of the operation
+    pub id: String,
+    /// Arbitrary identifier of the operation's producer
+    pub producer: String,
+    /// Whether this is the first entry of the operation
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub first: bool,
+    /// Whether this is the last entry of the operation
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub last: bool,
+}
+
+/// Serialize a line number the way the API encodes int64 values
+fn serialize_line<S: serde::Serializer>(line: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(line)
+}
+
+impl GcpLogEntry {
+    /// Create an entry generated by the layer itself rather than the application
+    ///
+    /// # Arguments
+    /// * `severity` - The Cloud Logging severity
+    /// * `message` - The message content
+    pub(crate) fn internal(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            severity,
+            fields: Map::new(),
+            timestamp: Utc::now(),
+            source_location: None,
+            operation: None,
+            trace: None,
+            labels: BTreeMap::new(),
+            runtime_labels: None,
+            internal: true,
+            callsite: None,
+            pending: PendingFields::default(),
+        }
+    }
+
+    /// Convert the pending event fields and merge them into `fields`
+    pub(crate) fn resolved(mut self) -> Self {
+        std::mem::take(&mut self.pending).apply(&mut self.message, &mut self.fields);
+        self
+    }
+
+    /// Return the message's buffer to the pool emitting threads take from
+    ///
+    /// Called once the background task is done with the entry, so steady
+    /// logging reuses message allocations instead of making one per event.
+    pub(crate) fn recycle(self) {
+        MESSAGES.give(self.message);
+    }
+
+    /// The message as shown in the Logs Explorer summary line
+    ///
+    /// # Arguments
+    /// * `config` - Options controlling the entry shape
+    ///
+    /// # Returns
+    /// For captured events, the message prefixed with local time, severity,
+    /// target (unless disabled) and location; otherwise the message as-is
+    pub(crate) fn message_line(&self, config: &EntryConfig) -> Cow<'_, str> {
+        let Some(callsite) = self.callsite else {
+            return Cow::Borrowed(&self.message);
+        };
+        let parts = MessageParts {
+            timestamp: self.timestamp,
+            severity: self.severity,
+            target: callsite.target(),
+            file: callsite.file(),
+            line: callsite.line(),
+            location: &callsite::info(callsite).location,
+            message: &self.message,
+        };
+        match &config.message_format {
+            MessageFormat::Default => FullFormatter {
+                target: config.target_in_message,
+            }
+            .format(&parts),
+            MessageFormat::Plain => JsonOnlyFormatter.format(&parts),
+            MessageFormat::Custom(format) => Cow::Owned(format(&parts)),
+            MessageFormat::Formatter(formatter) => formatter.format(&parts),
+        }
+    }
+}
+
+/// Log name used unless configured otherwise
+pub(crate) const DEFAULT_LOG_NAME: &str = "proxie";
+
+/// Options applied by the worker when turning queued entries into JSON
+#[derive(Debug, Clone)]
+pub struct EntryConfig {
+    /// Add the numeric `severity_number` to the payload
+    pub severity_number: bool,
+    /// Set `timestamp` and a sequenced `insertId` to preserve emission order
+    pub preserve_order: bool,
+    /// Labels attached to every entry
+    pub labels: BTreeMap<String, String>,
+    /// Top-level payload fields also sent as labels when they hold a string
+    pub label_fields: Vec<String>,
+    /// Set `timestamp` to the event's capture time
+    pub event_timestamp: bool,
+    /// Add the time the entry spent queued as `queue_latency_ms`
+    pub queue_latency: bool,
+    /// Resource used verbatim instead of the detected `gce_instance`
+    pub resource: Option<MonitoredResource>,
+    /// Payload key carrying the target of captured events, None to omit it
+    pub target_field: Option<String>,
+    /// Include the target in the message line of captured events
+    pub target_in_message: bool,
+    /// How the message line of captured events is formatted
+    pub message_format: MessageFormat,
+    /// Where the injected metadata keys are placed in the payload
+    pub metadata_namespace: MetadataNamespace,
+    /// Destination overrides keyed by the lowest severity they apply to
+    pub severity_routes: BTreeMap<Severity, SeverityRoute>,
+    /// Log names of captured entries keyed by the target prefix they apply to
+    pub target_log_names: BTreeMap<String, String>,
+    /// Values of the `{name}` placeholders of log name templates
+    pub log_name_vars: BTreeMap<String, String>,
+    /// Serialized size above which the largest event fields are dropped
+    pub max_entry_bytes: Option<usize>,
+    /// Builds `jsonPayload` instead of the crate's layout, if set
+    pub payload_serializer: Option<Arc<dyn PayloadSerializer>>,
+    /// Encodes batched entries and joins them into request bodies
+    pub encoder: Arc<dyn WireEncoder>,
+    /// Log ID entries are written to, shared with handles so it can change at runtime
+    pub(crate) log_name: Arc<ArcSwap<String>>,
+    /// Labels set through handles, overriding `labels` with the same key
+    pub(crate) runtime_labels: Arc<ArcSwap<HashMap<String, String>>>,
+}
+
+impl Default for EntryConfig {
+    fn default() -> Self {
+        Self {
+            severity_number: false,
+            preserve_order: false,
+            labels: BTreeMap::new(),
+            label_fields: Vec::new(),
+            event_timestamp: false,
+            queue_latency: false,
+            resource: None,
+            target_field: Some("logger".into()),
+            target_in_message: true,
+            message_format: MessageFormat::Default,
+            metadata_namespace: MetadataNamespace::Flat,
+            severity_routes: BTreeMap::new(),
+            target_log_names: BTreeMap::new(),
+            log_name_vars: BTreeMap::new(),
+            max_entry_bytes: None,
+            payload_serializer: None,
+            encoder: Arc::new(JsonEncoder),
+            log_name: Arc::new(ArcSwap::from_pointee(DEFAULT_LOG_NAME.to_string())),
+            runtime_labels: Arc::default(),
+        }
+    }
+}
+
+/// How the message line of captured events is formatted
+///
+/// Entries emitted through a handle and internal entries always use their
+/// message as-is.
+#[derive(Clone, Default)]
+pub enum MessageFormat {
+    /// `[{local time}] {severity} [{target} {file}:{line}] [{message}]`
+    #[default]
+    Default,
+    /// The event's message alone, for fully structured logging
+    Plain,
+    /// A function building the line from the event's parts
+    Custom(Arc<dyn Fn(&MessageParts<'_>) -> String + Send + Sync>),
+    /// A formatter, e.g. `CompactFormatter`
+    Formatter(Arc<dyn MessageFormatter>),
+}
+
+impl std::fmt::Debug for MessageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageFormat::Default => f.write_str("Default"),
+            MessageFormat::Plain => f.write_str("Plain"),
+            MessageFormat::Custom(_) => f.write_str("Custom(..)"),
+            MessageFormat::Formatter(_) => f.write_str("Formatter(..)"),
+        }
+    }
+}
+
+/// The parts of a captured event, passed to `MessageFormat::Custom` and formatters
+#[derive(Debug, Clone, Copy)]
+pub struct MessageParts<'a> {
+    /// When the event was captured
+    pub timestamp: DateTime<Utc>,
+    /// Severity of the event
+    pub severity: Severity,
+    /// The event's target, usually its module path
+    pub target: &'a str,
+    /// Source file of the call site, if known
+    pub file: Option<&'a str>,
+    /// Line of the call site, if known
+    pub line: Option<u32>,
+    /// `{target} {file}:{line}`, rendered once per call site
+    pub location: &'a str,
+    /// The event's message
+    pub message: &'a str,
+}
+
+/// Destination overrides for entries in a severity range
+///
+/// Fields left unset keep the layer's log name and resource. Each entry
+/// carries its own `logName` and `resource`, so entries with different
+/// destinations still share upload requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeverityRoute {
+    /// Log ID or full resource name replacing the layer's log name
+    pub log_name: Option<String>,
+    /// Resource replacing the layer's resource
+    pub resource: Option<MonitoredResource>,
+    /// Labels added to the layer's labels, replacing those with the same key
+    pub labels: BTreeMap<String, String>,
+}
+
+impl EntryConfig {
+    /// Find the route applying to a severity
+    ///
+    /// # Arguments
+    /// * `severity` - The entry's severity
+    ///
+    /// # Returns
+    /// The route with the highest threshold not above `severity`, if any
+    fn route(&self, severity: Severity) -> Option<&SeverityRoute> {
+        self.severity_routes
+            .range(..=severity)
+            .next_back()
+            .map(|(_, route)| route)
+    }
+
+    /// Find the log name mapped to a target
+    ///
+    /// # Arguments
+    /// * `target` - The target of the captured event or span
+    ///
+    /// # Returns
+    /// The log name of the longest prefix equal to the target or to one of
+    /// its parent modules, if any
+    fn target_log_name(&self, target: &str) -> Option<&str> {
+        self.target_log_names
+            .iter()
+            .filter(|(prefix, _)| {
+                target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, log_name)| log_name.as_str())
+    }
+}
+
+/// Where the metadata the crate injects is placed in `jsonPayload`
+///
+/// Injected metadata is the target field, `container`, `instance`,
+/// `severity_number` and `queue_latency_ms`; `message` always stays at the top
+/// level, where Cloud Logging looks for the summary line. Whatever the
+/// placement, an event field whose key is taken by injected metadata is
+/// renamed with a `user_` prefix, so neither side is ever overwritten.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MetadataNamespace {
+    /// Top-level keys next to the event's fields
+    #[default]
+    Flat,
+    /// One object under the given key, e.g. `_meta: { container: .., instance: .. }`
+    Nested(String),
+    /// Top-level keys starting with the given prefix, e.g. `_container`
+    Prefixed(String),
+}
+
+/// Resource collections a full `logName` may be written under
+pub(crate) const LOG_PARENTS: [&str; 4] =
+    ["projects", "organizations", "folders", "billingAccounts"];
+
+/// Split a log name into the resource it belongs to and its log ID
+///
+/// A name starting with one of `LOG_PARENTS` must be a full resource name,
+/// `{collection}/{id}/logs/{log ID}`, whose log ID may already be
+/// URL-encoded. Any other name is a log ID in the layer's project.
+///
+/// # Arguments
+/// * `name` - A log ID or a full resource name
+///
+/// # Returns
+/// The parent resource, e.g. `organizations/123`, if the name has one, and
+/// the unencoded log ID
+fn split_log_name(name: &str) -> Result<(Option<&str>, String), ConfigError> {
+    let invalid = || ConfigError::InvalidLogName(name.to_string());
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let valid_id = |id: &str| {
+        let id_chars = id.chars().all(|c| c == '/' || valid_char(c));
+        !id.is_empty() && id.len() <= 512 && id_chars
+    };
+    let parent = match name.split_once('/') {
+        Some((collection, _)) if LOG_PARENTS.contains(&collection) => Some(collection),
+        _ => None,
+    };
+    let Some(collection) = parent else {
+        return valid_id(name)
+            .then(|| (None, name.to_string()))
+            .ok_or_else(invalid);
+    };
+    let rest = &name[collection.len() + 1..];
+    let (parent_id, log_id) = rest.split_once("/logs/").ok_or_else(invalid)?;
+    let log_id = log_id.replace("%2F", "/").replace("%2f", "/");
+    if parent_id.is_empty() || !parent_id.chars().all(valid_char) || !valid_id(&log_id) {
+        return Err(invalid());
+    }
+    Ok((
+        Some(&name[..collection.len() + 1 + parent_id.len()]),
+        log_id,
+    ))
+}
+
+/// Check that a log name is accepted by Cloud Logging
+///
+/// Log IDs are at most 512 characters of letters, digits, `/`, `_`, `-` and `.`.
+/// Full resource names such as `organizations/123/logs/audit` are checked
+/// for their form as well. Templates are checked with every placeholder
+/// standing for a plain word.
+///
+/// # Arguments
+/// * `name` - The log ID, e.g. `my-app` or `my-app/debug`, or full resource
+///   name, possibly with `{..}` placeholders
+pub(crate) fn validate_log_name(name: &str) -> Result<(), ConfigError> {
+    let expanded = expand_log_name(name, |_| Some("x".into()))
+        .ok_or_else(|| ConfigError::InvalidLogName(name.to_string()))?;
+    split_log_name(&expanded)
+        .map(|_| ())
+        .map_err(|_| ConfigError::InvalidLogName(name.to_string()))
+}
+
+/// Value used for a placeholder that resolves to nothing
+const UNRESOLVED_PLACEHOLDER: &str = "unknown";
+
+/// Replace the `{..}` placeholders of a log name template
+///
+/// Resolved values are made safe for a log ID: characters other than letters,
+/// digits, `_`, `-` and `.` become `_`, so a value can never add a path
+/// segment, and a missing or empty value becomes `unknown`.
+///
+/// # Arguments
+/// * `template` - The log name, with or without placeholders
+/// * `resolve` - The value of a placeholder, given its name
+///
+/// # Returns
+/// The log name, or None if a brace is unmatched or a placeholder is empty
+fn expand_log_name(
+    template: &str,
+    resolve: impl Fn(&str) -> Option<String>,
+) -> Option<Cow<'_, str>> {
+    if !template.contains(['{', '}']) {
+        return Some(Cow::Borrowed(template));
+    }
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        let (literal, placeholder) = rest.split_at(start);
+        let end = placeholder
+            .find('}')
+            .filter(|_| placeholder.starts_with('{'))?;
+        let name = &placeholder[1..end];
+        if name.is_empty() || name.contains('{') {
+            return None;
+        }
+        expanded.push_str(literal);
+        let value = resolve(name).filter(|value| !value.is_empty());
+        let value = value.as_deref().unwrap_or(UNRESOLVED_PLACEHOLDER);
+        expanded.extend(value.chars().map(|c| match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') => c,
+            _ => '_',
+        }));
+        rest = &placeholder[end + 1..];
+    }
+    expanded.push_str(rest);
+    Some(Cow::Owned(expanded))
+}
+
+/// The `logName` of an entry written to a log
+///
+/// # Arguments
+/// * `project_id` - The project short log IDs belong to
+/// * `name` - A validated log ID or full resource name
+fn full_log_name(project_id: &str, name: &str) -> String {
+    let (parent, log_id) = match split_log_name(name) {
+        Ok((Some(parent), log_id)) => (parent.to_string(), log_id),
+        _ => (format!("projects/{}", project_id), name.to_string()),
+    };
+    // Slashes are allowed in log IDs but must be URL-encoded in `logName`.
+    format!("{}/logs/{}", parent, log_id.replace('/', "%2F"))
+}
+
+/// Build the Cloud Logging `LogEntry` JSON for a queued entry
+///
+/// # Arguments
+/// * `metadata` - The instance/container metadata attached to every entry
+/// * `config` - Options controlling the entry shape
+/// * `log_entry` - The queued entry
+///
+/// # Returns
+/// The JSON value to place in the `entries` array of an `entries:write` request
+#[cfg(feature = "testing")]
+pub fn build_entry(
+    metadata: &LogContextMetadata,
+    config: &EntryConfig,
+    log_entry: &GcpLogEntry,
+) -> serde_json::Value {
+    let metadata = StaticMetadata::new(metadata.clone());
+    EntryView::new(&metadata, config, log_entry).to_value()
+}
+
+/// Serialize the Cloud Logging `LogEntry` JSON for a queued entry
+///
+/// Writes the same JSON as `build_entry` without building it as a value
+/// first, unless `max_entry_bytes` is set and fields may have to be dropped.
+///
+/// # Arguments
+/// * `metadata` - The instance/container metadata attached to every entry
+/// * `config` - Options controlling the entry shape
+/// * `log_entry` - The queued entry
+/// * `writer` - Where the JSON is written
+#[cfg(feature = "testing")]
+pub fn write_entry(
+    metadata: &LogContextMetadata,
+    config: &EntryConfig,
+    log_entry: &GcpLogEntry,
+    writer: impl std::io::Write,
+) -> serde_json::Result<()> {
+    let metadata = StaticMetadata::new(metadata.clone());
+    EntryView::new(&metadata, config, log_entry).write(writer)
+}
+
+/// Serialize an `entries:write` request body, as the background task does
+///
+/// The metadata is serialized once for all entries. The JSON is always the
+/// `JsonEncoder`'s, whatever `config.encoder` is.
+///
+/// # Arguments
+/// * `metadata` - The instance/container metadata attached to every entry
+/// * `config` - Options controlling the entry shape
+/// * `log_entries` - The queued entries
+/// * `writer` - Where the JSON is written
+#[cfg(feature = "testing")]
+pub fn write_entries<'e>(
+    metadata: &LogContextMetadata,
+    config: &EntryConfig,
+    log_entries: impl IntoIterator<Item = &'e GcpLogEntry>,
+    mut writer: impl std::io::Write,
+) -> serde_json::Result<()> {
+    let metadata = StaticMetadata::new(metadata.clone());
+    writer
+        .write_all(b"{\"entries\":[")
+        .map_err(serde_json::Error::io)?;
+    for (i, log_entry) in log_entries.into_iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",").map_err(serde_json::Error::io)?;
+        }
+        EntryView::new(&metadata, config, log_entry).write(&mut writer)?;
+    }
+    writer.write_all(b"]}").map_err(serde_json::Error::io)?;
+    Ok(())
+}
+
+/// Metadata attached to every entry, with its payload JSON serialized once
+///
+/// The `container` and `instance` payload objects never change, so they are
+/// serialized when the layer is built and spliced into each entry verbatim.
+#[derive(Debug)]
+pub(crate) struct StaticMetadata {
+    /// The metadata itself
+    pub(crate) context: LogContextMetadata,
+    /// The payload's `container` object, `null` without a container
+    container: Box<RawValue>,
+    /// The payload's `instance` object
+    instance: Box<RawValue>,
+}
+
+impl StaticMetadata {
+    /// Serialize the payload objects of the metadata
+    ///
+    /// # Arguments
+    /// * `context` - The instance/container metadata attached to every entry
+    pub(crate) fn new(context: LogContextMetadata) -> Self {
+        Self {
+            container: raw_json(&context.container),
+            instance: raw_json(&context.instance),
+            context,
+        }
+    }
+}
+
+/// Serialize a value once for splicing into entries, `null` if it cannot be
+fn raw_json(value: &impl Serialize) -> Box<RawValue> {
+    serde_json::value::to_raw_value(value).unwrap_or_else(|_| RawValue::NULL.to_owned())
+}
+
+/// The `gce_instance` resource used unless another one is configured
+#[derive(Serialize)]
+struct GceInstance<'a> {
+    /// Always `gce_instance`
+    #[serde(rename = "type")]
+    resource_type: &'static str,
+    /// The instance's labels
+    labels: GceLabels<'a>,
+}
+
+/// Labels of the `gce_instance` resource
+#[derive(Serialize)]
+struct GceLabels<'a> {
+    /// The instance's numeric ID
+    instance_id: &'a str,
+    /// The instance's zone
+    zone: &'a str,
+    /// The project the instance runs in
+    project_id: &'a str,
+}
+
+/// A queued entry seen as its `LogEntry` JSON, serialized without copying fields
+///
+/// Keys come out in the order `build_entry` has always produced: `logName`,
+/// `resource`, `severity`, `jsonPayload` (event fields, then injected
+/// metadata), then the optional keys.
+pub(crate) struct EntryView<'a> {
+    /// Metadata attached to every entry
+    metadata: &'a StaticMetadata,
+    /// Options controlling the entry shape
+    config: &'a EntryConfig,
+    /// The queued entry
+    log_entry: &'a GcpLogEntry,
+    /// Destination override for the entry's severity
+    route: Option<&'a SeverityRoute>,
+    /// Full `logName`
+    log_name: String,
+    /// The summary line sent as `message`
+    message: Cow<'a, str>,
+    /// The target field's key and the event's target
+    target: Option<(&'a str, &'static str)>,
+    /// Time the entry spent queued, in milliseconds
+    queue_latency_ms: f64,
+    /// Payload keys of the event fields, in field order, renamed on collision
+    keys: Vec<Cow<'a, str>>,
+    /// Labels set through handles when the entry was queued
+    runtime_labels: Arc<HashMap<String, String>>,
+    /// Sequenced `insertId`, if emission order is preserved
+    insert_id: Option<String>,
+    /// Whether the entry is cut down to `MAX_ENTRY_BYTES`, once found oversized
+    fit: Cell<bool>,
+}
+
+impl<'a> EntryView<'a> {
+    /// Resolve everything about an entry that is not borrowed as-is
+    ///
+    /// # Arguments
+    /// * `metadata` - The instance/container metadata attached to every entry
+    /// * `config` - Options controlling the entry shape
+    /// * `log_entry` - The queued entry
+    pub(crate) fn new(
+        metadata: &'a StaticMetadata,
+        config: &'a EntryConfig,
+        log_entry: &'a GcpLogEntry,
+    ) -> Self {
+        let route = config.route(log_entry.severity);
+        let project_id = &metadata.context.instance.project_id;
+        let target_log_name = || {
+            let target = log_entry.callsite?.target();
+            config.target_log_name(target)
+        };
+        let default_log_name = config.log_name.load();
+        let template = route
+            .and_then(|route| route.log_name.as_deref())
+            .or_else(target_log_name)
+            .unwrap_or(&default_log_name);
+        let placeholder = |name: &str| -> Option<String> {
+            if name == "project" {
+                return Some(project_id.clone());
+            }
+            if let Some(var) = name.strip_prefix("env:") {
+                return std::env::var(var).ok();
+            }
+            if let Some(field) = name.strip_prefix("field:") {
+                return match log_entry.fields.get(field)? {
+                    Value::String(value) => Some(value.clone()),
+                    Value::Number(value) => Some(value.to_string()),
+                    Value::Bool(value) => Some(value.to_string()),
+                    _ => None,
+                };
+            }
+            config.log_name_vars.get(name).cloned()
+        };
+        let log_name = match expand_log_name(template, placeholder) {
+            Some(log_name) => full_log_name(project_id, &log_name),
+            None => full_log_name(project_id, template),
+        };
+        let latency = (Utc::now() - log_entry.timestamp).num_microseconds();
+        let mut view = Self {
+            metadata,
+            config,
+            log_entry,
+            route,
+            log_name,
+            message: log_entry.message_line(config),
+            target: config
+                .target_field
+                .as_deref()
+                .zip(log_entry.callsite)
+                .map(|(key, callsite)| (key, callsite.target())),
+            queue_latency_ms: latency.unwrap_or_default().max(0) as f64 / 1000.0,
+            keys: Vec::new(),
+            runtime_labels: log_entry
+                .runtime_labels
+                .clone()
+                .unwrap_or_else(|| config.runtime_labels.load_full()),
+            insert_id: None,
+            fit: Cell::new(false),
+        };
+        view.keys = match config.payload_serializer {
+            // The serializer picks the keys; fields are dropped by their own.
+            Some(_) => log_entry.fields.keys().map(Cow::from).collect(),
+            None => view.user_keys(),
+        };
+        view
+    }
+
+    /// Stamp the entry with the next `insertId` for its log name
+    ///
+    /// # Arguments
+    /// * `sequencer` - Assigns insert IDs, None to send the entry without one
+    pub(crate) fn with_insert_id(mut self, sequencer: Option<&mut Sequencer>) -> Self {
+        self.insert_id = sequencer.map(|sequencer| sequencer.next_id(&self.log_name));
+        self
+    }
+
+    /// Serialized size the entry is cut down to, if any
+    ///
+    /// `max_entry_bytes`, or `MAX_ENTRY_BYTES` once the entry was found
+    /// oversized, whichever is smaller.
+    fn max_bytes(&self) -> Option<usize> {
+        let limit = self.fit.get().then_some(MAX_ENTRY_BYTES);
+        match (self.config.max_entry_bytes, limit) {
+            (Some(max), Some(limit)) => Some(max.min(limit)),
+            (max, limit) => max.or(limit),
+        }
+    }
+
+    /// The entry as a JSON value, with fields dropped to fit `max_entry_bytes`
+    ///
+    /// An entry cut down to `MAX_ENTRY_BYTES` also has its message truncated
+    /// if dropping every field is not enough.
+    pub(crate) fn to_value(&self) -> Value {
+        let mut entry = serde_json::to_value(self).unwrap_or_default();
+        if let Some(max) = self.max_bytes() {
+            drop_largest_fields(&mut entry, &self.keys, max);
+        }
+        if self.fit.get() {
+            truncate_message(&mut entry, MAX_ENTRY_BYTES);
+        }
+        entry
+    }
+
+    /// Write the entry's JSON
+    ///
+    /// # Arguments
+    /// * `writer` - Where the JSON is written
+    pub(crate) fn write(&self, writer: impl std::io::Write) -> serde_json::Result<()> {
+        match self.max_bytes() {
+            Some(_) => serde_json::to_writer(writer, &self.to_value()),
+            None => serde_json::to_writer(writer, self),
+        }
+    }
+
+    /// Encode the entry for a batch with the configured encoder
+    ///
+    /// An entry over `MAX_ENTRY_BYTES`, which would make the API reject the
+    /// whole batch, is encoded again without its largest fields and with its
+    /// message truncated as needed.
+    ///
+    /// # Arguments
+    /// * `buffer` - An empty buffer receiving the encoded entry
+    ///
+    /// # Returns
+    /// An `InvalidData` error if the entry cannot be cut down to the limit,
+    /// e.g. because of its labels, in which case it must be dropped
+    pub(crate) fn encode(&self, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        let encoder = &self.config.encoder;
+        encoder.encode_entry(&WireEntry::new(self), buffer)?;
+        if buffer.len() <= MAX_ENTRY_BYTES {
+            return Ok(());
+        }
+        let size = buffer.len();
+        self.fit.set(true);
+        buffer.clear();
+        encoder.encode_entry(&WireEntry::new(self), buffer)?;
+        if buffer.len() > MAX_ENTRY_BYTES {
+            limits::warn_rejected(buffer.len());
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("log entry of {} bytes exceeds {}", size, MAX_ENTRY_BYTES),
+            ));
+        }
+        limits::warn_repaired(size);
+        Ok(())
+    }
+
+    /// The queued entry
+    pub(crate) fn log_entry(&self) -> &'a GcpLogEntry {
+        self.log_entry
+    }
+
+    /// The full `logName`
+    pub(crate) fn log_name(&self) -> &str {
+        &self.log_name
+    }
+
+    /// The instance and container metadata attached to the entry
+    #[cfg(feature = "grpc")]
+    pub(crate) fn context(&self) -> &'a LogContextMetadata {
+        &self.metadata.context
+    }
+
+    /// The configured resource, None to use the detected `gce_instance`
+    pub(crate) fn resource(&self) -> Option<&'a MonitoredResource> {
+        let resource = self.route.and_then(|route| route.resource.as_ref());
+        resource.or(self.config.resource.as_ref())
+    }
+
+    /// Whether the entry carries its capture time as `timestamp`
+    pub(crate) fn sends_timestamp(&self) -> bool {
+        self.config.event_timestamp || self.config.preserve_order
+    }
+
+    /// The sequenced `insertId`, if emission order is preserved
+    #[cfg(feature = "grpc")]
+    pub(crate) fn insert_id(&self) -> Option<&str> {
+        self.insert_id.as_deref()
+    }
+
+    /// The `jsonPayload`, with fields dropped to fit `max_entry_bytes`
+    ///
+    /// # Arguments
+    /// * `trimmed` - The labels trimmed from the entry, as returned by `labels`
+    #[cfg(feature = "grpc")]
+    pub(crate) fn payload(&self, trimmed: &TrimmedLabels<'_>) -> Value {
+        match self.max_bytes() {
+            Some(_) => self.to_value()["jsonPayload"].take(),
+            None => serde_json::to_value(Payload(self, trimmed)).unwrap_or_default(),
+        }
+    }
+
+    /// Keys of the injected metadata other than `message`, unprefixed
+    fn metadata_keys(&self) -> impl Iterator<Item = &str> {
+        let target = self.target.map(|(key, _)| key);
+        let severity_number = self.config.severity_number.then_some("severity_number");
+        let queue_latency = self.config.queue_latency.then_some("queue_latency_ms");
+        target
+            .into_iter()
+            .chain(["container", "instance"])
+            .chain(severity_number)
+            .chain(queue_latency)
+    }
+
+    /// Whether a top-level payload key is taken by injected metadata
+    ///
+    /// `message` and the lists of trimmed labels stay top-level in every
+    /// namespace.
+    fn is_injected(&self, key: &str) -> bool {
+        matches!(key, "message" | "labels_truncated" | "labels_dropped")
+            || match &self.config.metadata_namespace {
+                MetadataNamespace::Flat => self.metadata_keys().any(|name| name == key),
+                MetadataNamespace::Nested(nested) => key == nested,
+                MetadataNamespace::Prefixed(prefix) => key
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| self.metadata_keys().any(|name| name == rest)),
+            }
+    }
+
+    /// Payload keys of the event fields
+    ///
+    /// A field whose key is taken by injected metadata gets `user_` prefixes
+    /// until the key is neither injected nor another field's, so no side is
+    /// overwritten and fields that do not collide keep their keys.
+    fn user_keys(&self) -> Vec<Cow<'a, str>> {
+        let fields = &self.log_entry.fields;
+        let mut keys: Vec<Cow<'a, str>> = Vec::with_capacity(fields.len());
+        for key in fields.keys() {
+            if !self.is_injected(key) {
+                keys.push(Cow::Borrowed(key));
+                continue;
+            }
+            let mut key = key.clone();
+            while self.is_injected(&key)
+                || fields.contains_key(&key)
+                || keys.iter().any(|taken| *taken == key)
+            {
+                key.insert_str(0, "user_");
+            }
+            keys.push(Cow::Owned(key));
+        }
+        keys
+    }
+
+    /// A payload key of injected metadata
+    ///
+    /// # Arguments
+    /// * `prefix` - The `Prefixed` namespace's prefix, empty otherwise
+    /// * `name` - The unprefixed key
+    fn metadata_key<'k>(prefix: &str, name: &'k str) -> Cow<'k, str> {
+        match prefix.is_empty() {
+            true => Cow::Borrowed(name),
+            false => Cow::Owned(format!("{}{}", prefix, name)),
+        }
+    }
+
+    /// Serialize the target field, which precedes the message
+    ///
+    /// # Arguments
+    /// * `map` - The map the entry is added to
+    /// * `prefix` - The `Prefixed` namespace's prefix, empty otherwise
+    fn serialize_target<M: SerializeMap>(&self, map: &mut M, prefix: &str) -> Result<(), M::Error> {
+        match self.target {
+            Some((name, target)) => map.serialize_entry(&Self::metadata_key(prefix, name), target),
+            None => Ok(()),
+        }
+    }
+
+    /// Serialize the injected metadata following the message
+    ///
+    /// # Arguments
+    /// * `map` - The map the entries are added to
+    /// * `prefix` - The `Prefixed` namespace's prefix, empty otherwise
+    fn serialize_metadata<M: SerializeMap>(
+        &self,
+        map: &mut M,
+        prefix: &str,
+    ) -> Result<(), M::Error> {
+        let key = |name| Self::metadata_key(prefix, name);
+        map.serialize_entry(&key("container"), &self.metadata.container)?;
+        map.serialize_entry(&key("instance"), &self.metadata.instance)?;
+        if self.config.severity_number {
+            map.serialize_entry(&key("severity_number"), &self.log_entry.severity.number())?;
+        }
+        if self.config.queue_latency {
+            map.serialize_entry(&key("queue_latency_ms"), &self.queue_latency_ms)?;
+        }
+        Ok(())
+    }
+
+    /// The entry as handed to a `PayloadSerializer`
+    fn payload_parts(&self) -> PayloadParts<'_> {
+        let log_entry = self.log_entry;
+        PayloadParts {
+            message: &self.message,
+            severity: log_entry.severity,
+            timestamp: log_entry.timestamp,
+            fields: &log_entry.fields,
+            target: log_entry.callsite.map(|callsite| callsite.target()),
+            source_location: log_entry.source_location.as_ref().or_else(|| {
+                let callsite = log_entry.callsite?;
+                callsite::info(callsite).source_location.as_ref()
+            }),
+            trace: log_entry.trace.as_ref(),
+            metadata: &self.metadata.context,
+            internal: log_entry.internal,
+        }
+    }
+
+    /// Labels of the entry, trimmed to Cloud Logging's limits
+    ///
+    /// Runtime labels override static ones with the same key, the route's
+    /// override both, label fields and the entry's own labels override all
+    /// three, and internal entries carry `INTERNAL_LABEL`.
+    ///
+    /// Keys and values over their length limit are truncated on a character
+    /// boundary, and labels beyond `MAX_LABELS` are dropped, last keys first,
+    /// so the same labels are always trimmed the same way. A label whose key
+    /// is cut to one an earlier key already has is dropped too.
+    ///
+    /// # Returns
+    /// The labels, and those that were trimmed
+    pub(crate) fn labels(&self) -> (BTreeMap<&str, &str>, TrimmedLabels<'_>) {
+        let mut labels: BTreeMap<&str, &str> = BTreeMap::new();
+        labels.extend(
+            self.config
+                .labels
+                .iter()
+                .chain(self.runtime_labels.iter())
+                .chain(self.route.into_iter().flat_map(|route| &route.labels))
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+        labels.extend(self.config.label_fields.iter().filter_map(|key| {
+            let value = self.log_entry.fields.get(key)?.as_str()?;
+            Some((key.as_str(), value))
+        }));
+        labels.extend(
+            self.log_entry
+                .labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+        if self.log_entry.internal {
+            labels.insert(INTERNAL_LABEL, "true");
+        }
+        let mut trimmed = TrimmedLabels::default();
+        let oversized = |(key, value): (&&str, &&str)| {
+            key.len() > MAX_LABEL_KEY_BYTES || value.len() > MAX_LABEL_VALUE_BYTES
+        };
+        if labels.iter().any(oversized) {
+            let mut cut_labels = BTreeMap::new();
+            for (key, value) in labels {
+                let cut = (
+                    truncate(key, MAX_LABEL_KEY_BYTES),
+                    truncate(value, MAX_LABEL_VALUE_BYTES),
+                );
+                if cut_labels.contains_key(cut.0) {
+                    trimmed.dropped.push(cut.0);
+                    continue;
+                }
+                if cut != (key, value) {
+                    trimmed.truncated.push(cut.0);
+                }
+                cut_labels.insert(cut.0, cut.1);
+            }
+            labels = cut_labels;
+        }
+        let mut overflow = Vec::new();
+        while labels.len() > MAX_LABELS {
+            overflow.extend(labels.pop_last().map(|(key, _)| key));
+        }
+        trimmed.dropped.extend(overflow.into_iter().rev());
+        if !trimmed.is_empty() {
+            limits::warn_labels();
+        }
+        (labels, trimmed)
+    }
+}
+
+/// Labels trimmed to fit Cloud Logging's limits, noted in the payload
+#[derive(Default)]
+pub(crate) struct TrimmedLabels<'a> {
+    /// Keys, as sent, of the labels whose key or value was truncated
+    truncated: Vec<&'a str>,
+    /// Keys of the labels left out beyond `MAX_LABELS`, or whose truncated
+    /// key collided with that of another label
+    dropped: Vec<&'a str>,
+}
+
+impl TrimmedLabels<'_> {
+    /// Whether every label fit
+    fn is_empty(&self) -> bool {
+        self.truncated.is_empty() && self.dropped.is_empty()
+    }
+
+    /// Add the trimmed labels to the payload
+    ///
+    /// # Arguments
+    /// * `map` - The payload
+    fn serialize<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        if !self.truncated.is_empty() {
+            map.serialize_entry("labels_truncated", &self.truncated)?;
+        }
+        if !self.dropped.is_empty() {
+            map.serialize_entry("labels_dropped", &self.dropped)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cut a string to at most `max` bytes, on a character boundary
+///
+/// # Arguments
+/// * `text` - The string
+/// * `max` - Most bytes kept
+fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// The `jsonPayload` of an entry, with the labels trimmed from it
+struct Payload<'v, 'a>(&'v EntryView<'a>, &'v TrimmedLabels<'v>);
+
+impl Serialize for Payload<'_, '_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let view = self.0;
+        if let Some(payload) = &view.config.payload_serializer {
+            return payload.payload(&view.payload_parts()).serialize(serializer);
+        }
+        let mut map = serializer.serialize_map(None)?;
+        // User fields come first; colliding ones were renamed by `user_keys`.
+        for (key, value) in view.keys.iter().zip(view.log_entry.fields.values()) {
+            map.serialize_entry(key, value)?;
+        }
+        match &view.config.metadata_namespace {
+            MetadataNamespace::Flat => {
+                view.serialize_target(&mut map, "")?;
+                map.serialize_entry("message", &view.message)?;
+                view.serialize_metadata(&mut map, "")?;
+            }
+            MetadataNamespace::Nested(key) => {
+                map.serialize_entry("message", &view.message)?;
+                map.serialize_entry(key, &NestedMetadata(view))?;
+            }
+            MetadataNamespace::Prefixed(prefix) => {
+                view.serialize_target(&mut map, prefix)?;
+                map.serialize_entry("message", &view.message)?;
+                view.serialize_metadata(&mut map, prefix)?;
+            }
+        }
+        self.1.serialize(&mut map)?;
+        map.end()
+    }
+}
+
+/// The injected metadata of the `Nested` namespace, as one object
+struct NestedMetadata<'v, 'a>(&'v EntryView<'a>);
+
+impl Serialize for NestedMetadata<'_, '_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        self.0.serialize_target(&mut map, "")?;
+        self.0.serialize_metadata(&mut map, "")?;
+        map.end()
+    }
+}
+
+impl Serialize for EntryView<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (metadata, log_entry) = (&self.metadata.context, self.log_entry);
+        let (labels, trimmed) = self.labels();
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("logName", &self.log_name)?;
+        match self.resource() {
+            Some(resource) => map.serialize_entry("resource", resource)?,
+            None => map.serialize_entry(
+                "resource",
+                &GceInstance {
+                    resource_type: "gce_instance",
+                    labels: GceLabels {
+                        instance_id: &metadata.instance.id,
+                        zone: &metadata.instance.zone,
+                        project_id: &metadata.instance.project_id,
+                    },
+                },
+            )?,
+        }
+        map.serialize_entry("severity", &log_entry.severity)?;
+        map.serialize_entry("jsonPayload", &Payload(self, &trimmed))?;
+        if self.sends_timestamp() {
+            let timestamp = log_entry
+                .timestamp
+                .to_rfc3339_opts(SecondsFormat::Nanos, true);
+            map.serialize_entry("timestamp", &timestamp)?;
+        }
+        if let Some(location) = &log_entry.source_location {
+            map.serialize_entry("sourceLocation", location)?;
+        }
+        if let Some(operation) = &log_entry.operation {
+            map.serialize_entry("operation", operation)?;
+        }
+        if let Some(trace) = &log_entry.trace {
+            let project_id = &metadata.instance.project_id;
+            let name = format_args!("projects/{}/traces/{}", project_id, trace.trace_id);
+            map.serialize_entry("trace", &name)?;
+            if !trace.span_id.is_empty() {
+                map.serialize_entry("spanId", &trace.span_id)?;
+            }
+            map.serialize_entry("traceSampled", &trace.sampled)?;
+        }
+        if !labels.is_empty() {
+            map.serialize_entry("labels", &labels)?;
+        }
+        if let Some(insert_id) = &self.insert_id {
+            map.serialize_entry("insertId", insert_id)?;
+        }
+        map.end()
+    }
+}
+
+/// Remove event fields, largest first, until the entry fits
+///
+/// Only the event's own fields are candidates: the message, severity and
+/// injected metadata are kept even if the entry still does not fit. Fields are
+/// ranked by serialized size, ties broken by key, and the removed keys are
+/// listed under `fields_dropped` in removal order.
+///
+/// # Arguments
+/// * `entry` - A JSON entry produced by `build_entry`
+/// * `user_keys` - Payload keys holding event fields
+/// * `max` - Largest serialized size of the entry, in bytes
+fn drop_largest_fields(entry: &mut Value, user_keys: &[Cow<'_, str>], max: usize) {
+    let size = |entry: &Value| serde_json::to_vec(entry).map_or(0, |bytes| bytes.len());
+    if size(entry) <= max {
+        return;
+    }
+    let Some(payload) = entry["jsonPayload"].as_object() else {
+        return;
+    };
+    let mut candidates: Vec<(usize, String)> = user_keys
+        .iter()
+        .filter_map(|key| Some((size(payload.get(key.as_ref())?), key.to_string())))
+        .collect();
+    candidates.sort_by(|(a_size, a_key), (b_size, b_key)| {
+        b_size.cmp(a_size).then_with(|| a_key.cmp(b_key))
+    });
+    let mut dropped = Vec::new();
+    for (_, key) in candidates {
+        if let Some(payload) = entry["jsonPayload"].as_object_mut() {
+            payload.remove(&key);
+            dropped.push(Value::from(key));
+            payload.insert("fields_dropped".into(), Value::Array(dropped.clone()));
+        }
+        if size(entry) <= max {
+            break;
+        }
+    }
+}
+
+/// Truncate an entry's message until the entry fits
+///
+/// The cut falls on a character boundary and is marked with `…`; the
+/// message's original length in bytes is added as `message_truncated`.
+///
+/// # Arguments
+/// * `entry` - A JSON entry produced by `build_entry`
+/// * `max` - Largest serialized size of the entry, in bytes
+fn truncate_message(entry: &mut Value, max: usize) {
+    let size = serde_json::to_vec(entry).map_or(0, |bytes| bytes.len());
+    let Some(payload) = entry["jsonPayload"].as_object_mut() else {
+        return;
+    };
+    let Some(Value::String(message)) = payload.get_mut("message") else {
+        return;
+    };
+    if size <= max {
+        return;
+    }
+    let original = message.len();
+    // Escapes take up to six bytes per character, so cut generously.
+    let mut end = original.saturating_sub(size - max + 64);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    message.truncate(end);
+    message.push('…');
+    payload.insert("message_truncated".into(), original.into());
+}
+
+/// Assigns `insertId`s that sort in emission order within each log name
+///
+/// Cloud Logging orders entries by `timestamp` and breaks ties with `insertId`,
+/// so a zero-padded, monotonically increasing sequence keeps entries emitted in
+/// the same instant in the order the layer received them. The prefix is unique
+/// per process, so ids never collide across processes (which would make the
+/// API deduplicate them); ordering across processes is not guaranteed.
+pub(crate) struct Sequencer {
+    /// Per-process prefix
+    prefix: String,
+    /// Next sequence number for each log name
+    next: HashMap<String, u64>,
+}
+
+impl Sequencer {
+    /// Create a sequencer with a fresh per-process prefix
+    pub(crate) fn new() -> Self {
+        let started = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        Self {
+            prefix: format!("{:x}-{:x}", std::process::id(), started),
+            next: HashMap::new(),
+        }
+    }
+
+    /// The next `insertId` for a log name
+    ///
+    /// # Arguments
+    /// * `log_name` - The entry's full `logName`
+    pub(crate) fn next_id(&mut self, log_name: &str) -> String {
+        let next = match self.next.get_mut(log_name) {
+            Some(next) => next,
+            None => self.next.entry(log_name.to_string()).or_insert(0),
+        };
+        let insert_id = format!("{}-{:020}", self.prefix, *next);
+        *next += 1;
+        insert_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InstanceMetadata;
+
+    /// Metadata of a GCE instance
+    fn metadata() -> StaticMetadata {
+        StaticMetadata::new(LogContextMetadata {
+            container: None,
+            instance: InstanceMetadata {
+                name: "logical-service".into(),
+                id: "1234".into(),
+                zone: "us-central1-a".into(),
+                region: "us-central1".into(),
+                project_id: "test-project".into(),
+            },
+        })
+    }
+
+    /// An entry carrying the given labels
+    ///
+    /// # Arguments
+    /// * `labels` - Keys and values of the entry's own labels
+    fn labelled_entry(labels: &[(String, String)]) -> GcpLogEntry {
+        let mut log_entry = GcpLogEntry::internal(Severity::Info, "labelled");
+        log_entry.internal = false;
+        log_entry.labels = labels.iter().cloned().collect();
+        log_entry
+    }
+
+    #[test]
+    fn test_truncate_keeps_short_text_and_cuts_on_a_char_boundary() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly", 7), "exactly");
+        assert_eq!(truncate("abcdef", 3), "abc");
+        // "é" takes two bytes, so cutting inside it keeps only "a".
+        assert_eq!(truncate("aé", 2), "a");
+        assert_eq!(truncate("aé", 3), "aé");
+        assert_eq!(truncate("€", 2), "");
+    }
+
+    #[test]
+    fn test_trimmed_labels_serialize_only_non_empty_lists() {
+        let value = |trimmed: &TrimmedLabels| {
+            let mut buffer = Vec::new();
+            let mut serializer = serde_json::Serializer::new(&mut buffer);
+            let mut map = serde::Serializer::serialize_map(&mut serializer, None).unwrap();
+            trimmed.serialize(&mut map).unwrap();
+            map.end().unwrap();
+            serde_json::from_slice::<Value>(&buffer).unwrap()
+        };
+
+        let empty = TrimmedLabels::default();
+        assert!(empty.is_empty());
+        assert_eq!(value(&empty), serde_json::json!({}));
+
+        let trimmed = TrimmedLabels {
+            truncated: vec!["long"],
+            dropped: vec!["x", "y"],
+        };
+        assert!(!trimmed.is_empty());
+        assert_eq!(
+            value(&trimmed),
+            serde_json::json!({"labels_truncated": ["long"], "labels_dropped": ["x", "y"]})
+        );
+    }
+
+    #[test]
+    fn test_labels_truncate_long_keys_and_values() {
+        let (metadata, config) = (metadata(), EntryConfig::default());
+        let long_key = "k".repeat(MAX_LABEL_KEY_BYTES + 1);
+        let long_value = "v".repeat(MAX_LABEL_VALUE_BYTES + 1);
+        let log_entry = labelled_entry(&[
+            (long_key.clone(), "short".into()),
+            ("plain".into(), long_value),
+            ("kept".into(), "as is".into()),
+        ]);
+        let view = EntryView::new(&metadata, &config, &log_entry);
+        let (labels, trimmed) = view.labels();
+
+        let cut_key = &long_key[..MAX_LABEL_KEY_BYTES];
+        assert_eq!(labels.len(), 3);
+        assert_eq!(labels[cut_key], "short");
+        assert_eq!(labels["plain"].len(), MAX_LABEL_VALUE_BYTES);
+        assert_eq!(labels["kept"], "as is");
+        assert_eq!(trimmed.truncated, [cut_key, "plain"]);
+        assert!(trimmed.dropped.is_empty());
+    }
+
+    #[test]
+    fn test_labels_whose_truncated_keys_collide_are_counted_as_dropped() {
+        let (metadata, config) = (metadata(), EntryConfig::default());
+        let prefix = "k".repeat(MAX_LABEL_KEY_BYTES);
+        let log_entry = labelled_entry(&[
+            (format!("{}a", prefix), "first".into()),
+            (format!("{}b", prefix), "second".into()),
+        ]);
+        let view = EntryView::new(&metadata, &config, &log_entry);
+        let (labels, trimmed) = view.labels();
+
+        // The first key in order wins; the other label is reported, not lost.
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[prefix.as_str()], "first");
+        assert_eq!(trimmed.truncated, [prefix.as_str()]);
+        assert_eq!(trimmed.dropped, [prefix.as_str()]);
+
+        let payload = view.to_value()["jsonPayload"].take();
+        assert_eq!(payload["labels_dropped"], serde_json::json!([prefix]));
+    }
+
+    #[test]
+    fn test_fields_named_like_trimmed_label_lists_are_renamed() {
+        let (metadata, config) = (metadata(), EntryConfig::default());
+        let long_key = "k".repeat(MAX_LABEL_KEY_BYTES + 1);
+        let mut log_entry = labelled_entry(&[(long_key, "value".into())]);
+        log_entry
+            .fields
+            .insert("labels_truncated".into(), Value::from("mine"));
+        log_entry
+            .fields
+            .insert("labels_dropped".into(), Value::from(0));
+        let payload =
+            EntryView::new(&metadata, &config, &log_entry).to_value()["jsonPayload"].take();
+
+        let cut_key = "k".repeat(MAX_LABEL_KEY_BYTES);
+        assert_eq!(payload["labels_truncated"], serde_json::json!([cut_key]));
+        assert_eq!(payload["user_labels_truncated"], "mine");
+        assert_eq!(payload["user_labels_dropped"], 0);
+        assert!(payload.get("labels_dropped").is_none());
+    }
+
+    #[test]
+    fn test_labels_beyond_the_limit_are_dropped_last_keys_first() {
+        let (metadata, config) = (metadata(), EntryConfig::default());
+        let log_entry = labelled_entry(
+            &(0..MAX_LABELS + 2)
+                .map(|index| (format!("key{:03}", index), "value".into()))
+                .collect::<Vec<_>>(),
+        );
+        let view = EntryView::new(&metadata, &config, &log_entry);
+        let (labels, trimmed) = view.labels();
+
+        assert_eq!(labels.len(), MAX_LABELS);
+        let last = format!("key{:03}", MAX_LABELS - 1);
+        assert_eq!(labels.keys().last(), Some(&last.as_str()));
+        let expected = [
+            format!("key{:03}", MAX_LABELS),
+            format!("key{:03}", MAX_LABELS + 1),
+        ];
+        assert_eq!(trimmed.dropped, expected);
+        assert!(trimmed.truncated.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+//! Startup probe telling whether the process runs on Google Cloud.
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::sync::OnceCell;
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::client;
+use crate::{resource, CGROUP_PATH};
+
+/// Environment variable replacing the metadata server host, e.g. `127.0.0.1:8080`
+///
+/// Google's client libraries honor it too, so one mock server serves both.
+pub const METADATA_HOST_ENV_VAR: &str = "GCE_METADATA_HOST";
+
+/// Metadata server host, answering with `Metadata-Flavor: Google` on GCP
+const DEFAULT_METADATA_HOST: &str = "metadata.google.internal";
+
+/// Longest time the probe waits for the metadata server
+///
+/// The server answers within milliseconds on GCP; elsewhere the name usually
+/// fails to resolve immediately, but some networks time out instead.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Environment variable Kubernetes sets in every pod
+const KUBERNETES_ENV_VAR: &str = "KUBERNETES_SERVICE_HOST";
+
+/// Result of the first probe, shared by every layer in the process
+static ENVIRONMENT: OnceCell<RuntimeEnvironment> = OnceCell::const_new();
+
+/// Whether the layer already warned that it runs outside GCP
+static WARNED_OFF_GCP: AtomicBool = AtomicBool::new(false);
+
+/// Google Cloud platform the process runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// A Compute Engine VM
+    ComputeEngine,
+    /// A pod in a GKE cluster
+    Gke,
+    /// A Cloud Run service
+    CloudRun,
+    /// Not on Google Cloud, e.g. a laptop, CI or another cloud
+    Other,
+}
+
+/// Where the process runs, as determined by the startup probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuntimeEnvironment {
+    /// Whether the metadata server is reachable
+    pub on_gcp: bool,
+    /// The platform, `Platform::Other` when not on GCP
+    pub platform: Platform,
+}
+
+impl RuntimeEnvironment {
+    /// Probe the environment, reusing the result of an earlier probe
+    ///
+    /// # Returns
+    /// The detected environment
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub async fn detect() -> Self {
+        Self::probe(&client::Client::new()).await
+    }
+
+    /// Probe the environment using an existing HTTP client
+    ///
+    /// Only the first call in a process queries the metadata server; later
+    /// calls return the cached result, whichever client they pass.
+    ///
+    /// # Arguments
+    /// * `client` - The client used to query the metadata server
+    ///
+    /// # Returns
+    /// The detected environment
+    #[cfg(feature = "reqwest")]
+    pub async fn detect_with_client(client: &reqwest::Client) -> Self {
+        Self::probe(client).await
+    }
+
+    /// Probe the environment once per process with the crate's HTTP client
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) async fn probe(client: &client::Client) -> Self {
+        *ENVIRONMENT
+            .get_or_init(|| async { Self::from_probe(metadata_server_reachable(client).await) })
+            .await
+    }
+
+    /// Probe the environment once per process with a blocking request
+    ///
+    /// Shares the cached result with `probe`.
+    ///
+    /// # Arguments
+    /// * `agent` - The blocking client used to query the metadata server
+    #[cfg(feature = "blocking")]
+    pub(crate) fn probe_blocking(agent: &ureq::Agent) -> Self {
+        if let Some(environment) = ENVIRONMENT.get() {
+            return *environment;
+        }
+        let response = agent
+            .get(metadata_root_url(&metadata_host()))
+            .header("Metadata-Flavor", "Google")
+            .config()
+            .timeout_global(Some(PROBE_TIMEOUT))
+            .build()
+            .call();
+        let on_gcp = response.is_ok_and(|response| {
+            response
+                .headers()
+                .get("Metadata-Flavor")
+                .is_some_and(|flavor| flavor == "Google")
+        });
+        let environment = Self::from_probe(on_gcp);
+        // A concurrent probe may have won; keep its result.
+        let _ = ENVIRONMENT.set(environment);
+        ENVIRONMENT.get().copied().unwrap_or(environment)
+    }
+
+    /// Determine the platform once the metadata server was probed
+    ///
+    /// # Arguments
+    /// * `on_gcp` - Whether the metadata server answered as Google's
+    fn from_probe(on_gcp: bool) -> Self {
+        let platform = if !on_gcp {
+            Platform::Other
+        } else if resource::on_cloud_run() {
+            Platform::CloudRun
+        } else if std::env::var_os(KUBERNETES_ENV_VAR).is_some() {
+            Platform::Gke
+        } else {
+            Platform::ComputeEngine
+        };
+        Self { on_gcp, platform }
+    }
+}
+
+/// Check whether the metadata server answers as Google's
+///
+/// # Arguments
+/// * `client` - The client used to query the metadata server
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+async fn metadata_server_reachable(client: &client::Client) -> bool {
+    let response = client
+        .get(metadata_root_url(&metadata_host()))
+        .header("Metadata-Flavor", "Google")
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await;
+    match response {
+        Ok(response) => response
+            .headers()
+            .get("Metadata-Flavor")
+            .is_some_and(|flavor| flavor == "Google"),
+        Err(_) => false,
+    }
+}
+
+/// Reads the metadata server while the layer starts
+///
+/// Implemented with the async client and with the blocking one, so both ways
+/// of starting detect the same metadata.
+pub(crate) trait MetadataServer {
+    /// The cgroup file container metadata is read from
+    fn cgroup_path(&self) -> &Path {
+        Path::new(CGROUP_PATH)
+    }
+
+    /// Whether the metadata server answers, i.e. the process runs on GCP
+    fn on_gcp(&self) -> impl Future<Output = bool>;
+
+    /// Retrieve a value
+    ///
+    /// # Arguments
+    /// * `path` - The metadata path, e.g. `instance/zone`
+    ///
+    /// # Returns
+    /// The value, None if the server could not be reached or did not find the path
+    fn get(&self, path: &str) -> impl Future<Output = Option<String>>;
+}
+
+/// The metadata server host, taken from `GCE_METADATA_HOST` when set
+pub(crate) fn metadata_host() -> String {
+    std::env::var(METADATA_HOST_ENV_VAR)
+        .ok()
+        .filter(|host| !host.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_METADATA_HOST.to_string())
+}
+
+/// The root URL of the metadata API served by a host
+///
+/// # Arguments
+/// * `host` - The host and optional port, e.g. `metadata.google.internal`
+pub(crate) fn metadata_root_url(host: &str) -> String {
+    format!(
+        "http://{}/computeMetadata/v1/",
+        host.trim().trim_end_matches('/')
+    )
+}
+
+/// Warn, once per process, that resource detection is unavailable
+pub(crate) fn warn_off_gcp() {
+    if !WARNED_OFF_GCP.swap(true, Ordering::Relaxed) {
+        warning!(
+            "metadata server unreachable, so not running on GCP; resource auto-detection is unavailable and entries use fallback metadata."
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Error types surfaced by the layer's public API.
+use std::fmt;
+
+/// Errors raised while validating a `GcpLoggingLayerBuilder` configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A required field was missing or empty
+    MissingField(&'static str),
+    /// A target filter directive string could not be parsed
+    InvalidFilter(String),
+    /// A log name contains characters Cloud Logging does not accept, or a
+    /// full resource name is not of the form `{collection}/{id}/logs/{log ID}`
+    InvalidLogName(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingField(field) => {
+                write!(f, "missing required configuration field `{}`", field)
+            }
+            ConfigError::InvalidFilter(reason) => {
+                write!(f, "invalid target filter directives: {}", reason)
+            }
+            ConfigError::InvalidLogName(name) => write!(
+                f,
+                "invalid log name `{}`: use up to 512 letters, digits, `/`, `_`, `-` or `.`, \
+                 or a full name like `organizations/{{id}}/logs/{{log ID}}`",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Errors returned when queueing an entry through `GcpLoggingHandle::emit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmitError {
+    /// The background task has stopped, so the entry can never be delivered
+    Closed,
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitError::Closed => write!(f, "the logging background task is no longer running"),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {}
@@ -0,0 +1,177 @@
+//! Direct reporting of severe entries to the Error Reporting API.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::SecondsFormat;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::task::JoinHandle;
+
+use crate::entry::GcpLogEntry;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::transport::Transport;
+use crate::visitor::STACK_TRACE_FIELD;
+use crate::Severity;
+
+/// Sends entries at or above a severity to Error Reporting as well as Cloud Logging
+///
+/// Error Reporting groups errors it finds in Cloud Logging on its own, but only
+/// for entries shaped the way it expects. With this enabled each such entry is
+/// also sent to the `projects.events.report` API, grouped by the event's
+/// source location and stack trace and attributed to `service` and `version`.
+/// Reports are sent by a task of their own and never retried; when more than
+/// `capacity` are waiting, further ones are dropped.
+#[derive(Debug, Clone)]
+pub struct ErrorReporting {
+    /// Service the errors are attributed to, e.g. the binary or Cloud Run service name
+    pub service: String,
+    /// Version of the service, e.g. `env!("CARGO_PKG_VERSION")`
+    pub version: Option<String>,
+    /// Lowest severity reported
+    pub min_severity: Severity,
+    /// Reports kept waiting to be sent
+    pub capacity: usize,
+}
+
+impl ErrorReporting {
+    /// Report `ERROR` and more severe entries for the given service
+    ///
+    /// # Arguments
+    /// * `service` - Service the errors are attributed to
+    pub fn new(service: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            version: None,
+            min_severity: Severity::Error,
+            capacity: 1000,
+        }
+    }
+}
+
+/// Error Reporting API, followed by `/projects/{project}/events:report`
+const ERROR_REPORTING_URL: &str = "https://clouderrorreporting.googleapis.com/v1beta1";
+
+/// Whether the layer already warned about reports dropped for a full queue
+static WARNED_FULL: AtomicBool = AtomicBool::new(false);
+
+/// Queues reports for the task sending them to Error Reporting
+#[derive(Clone)]
+pub(crate) struct ErrorReporter {
+    /// Service, version and severity threshold
+    config: ErrorReporting,
+    /// Serialized `ReportedErrorEvent`s for the reporting task
+    events: Sender<Vec<u8>>,
+}
+
+impl ErrorReporter {
+    /// Spawn the reporting task on the current Tokio runtime
+    ///
+    /// # Arguments
+    /// * `config` - Service, version, threshold and capacity
+    /// * `transport` - Transport whose credentials and request hook are used
+    /// * `project_id` - The project the errors are reported to
+    ///
+    /// # Returns
+    /// The reporter and its task, which ends once every reporter is dropped
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn spawn(
+        config: ErrorReporting,
+        transport: Transport,
+        project_id: String,
+    ) -> (Self, JoinHandle<()>) {
+        let (events, rx) = channel(config.capacity.max(1));
+        let url = format!(
+            "{}/projects/{}/events:report",
+            ERROR_REPORTING_URL, project_id
+        );
+        let task = tokio::spawn(run(rx, transport, url));
+        (Self { config, events }, task)
+    }
+
+    /// Queue a report of an entry if it is severe enough
+    ///
+    /// Entries generated by the layer, and those with neither a source
+    /// location nor a stack trace, which Error Reporting could not group, are
+    /// skipped.
+    ///
+    /// # Arguments
+    /// * `log_entry` - A resolved entry
+    pub(crate) fn report(&self, log_entry: &GcpLogEntry) {
+        if log_entry.internal || log_entry.severity < self.config.min_severity {
+            return;
+        }
+        let Some(event) = self.event(log_entry) else {
+            return;
+        };
+        let Ok(body) = serde_json::to_vec(&event) else {
+            return;
+        };
+        if self.events.try_send(body).is_err() && !WARNED_FULL.swap(true, Ordering::Relaxed) {
+            warning!("the Error Reporting queue is full; error reports are dropped until it drains. Further drops are not reported.");
+        }
+    }
+
+    /// Build the `ReportedErrorEvent` of an entry
+    ///
+    /// # Arguments
+    /// * `log_entry` - A resolved entry
+    ///
+    /// # Returns
+    /// The event, None if the entry has no location and no stack trace
+    fn event(&self, log_entry: &GcpLogEntry) -> Option<Value> {
+        let stack_trace = log_entry
+            .fields
+            .get(STACK_TRACE_FIELD)
+            .and_then(Value::as_str);
+        let location = match (&log_entry.source_location, log_entry.callsite) {
+            (Some(location), _) => Some((location.file.as_str(), location.line, None)),
+            (None, Some(callsite)) => callsite.file().map(|file| {
+                let function = callsite.module_path().unwrap_or(callsite.target());
+                (file, callsite.line().unwrap_or_default(), Some(function))
+            }),
+            (None, None) => None,
+        };
+        if location.is_none() && stack_trace.is_none() {
+            return None;
+        }
+        let message = match stack_trace {
+            Some(stack_trace) => format!("{}\n{}", log_entry.message, stack_trace),
+            None => log_entry.message.clone(),
+        };
+        let mut event = json!({
+            "eventTime": log_entry.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            "serviceContext": { "service": self.config.service },
+            "message": message,
+        });
+        if let Some(version) = &self.config.version {
+            event["serviceContext"]["version"] = version.as_str().into();
+        }
+        if let Some((file, line, function)) = location {
+            event["context"] = json!({
+                "reportLocation": {
+                    "filePath": file,
+                    "lineNumber": line,
+                    "functionName": function.unwrap_or("unknown"),
+                }
+            });
+        }
+        Some(event)
+    }
+}
+
+/// Send queued reports until every reporter is dropped
+///
+/// # Arguments
+/// * `rx` - Serialized events from the reporters
+/// * `transport` - Transport used for the requests
+/// * `url` - The project's `events:report` URL
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+async fn run(mut rx: Receiver<Vec<u8>>, transport: Transport, url: String) {
+    while let Some(body) = rx.recv().await {
+        match transport.post_api(url.clone(), body).await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => diagnostic!("Failed to report error: HTTP {}", res.status()),
+            Err(e) => diagnostic!("Failed to report error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! Entry schemas for log aggregators other than Cloud Logging.
+use std::io::Write;
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+use crate::sink::LogSink;
+
+/// ECS version the `Ecs` format follows, sent as `ecs.version`
+const ECS_VERSION: &str = "8.11.0";
+
+/// GELF version the `Gelf` format follows, sent as `version`
+const GELF_VERSION: &str = "1.1";
+
+/// Shape of the entries the layer produces
+///
+/// Cloud Logging uploads always use the `LogEntry` schema; the other formats
+/// are written to the sink, or to stdout as JSON lines for a collector such
+/// as Filebeat or a Graylog sidecar, and nothing is uploaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Cloud Logging `LogEntry` JSON, uploaded to the API
+    #[default]
+    CloudLogging,
+    /// Elastic Common Schema JSON with dotted keys, for Elasticsearch
+    Ecs,
+    /// Graylog Extended Log Format 1.1, for Graylog
+    Gelf,
+}
+
+impl OutputFormat {
+    /// Convert a built Cloud Logging entry into this format
+    ///
+    /// # Arguments
+    /// * `entry` - A JSON entry produced by `build_entry`
+    ///
+    /// # Returns
+    /// The entry in this format; `CloudLogging` returns it unchanged
+    pub fn render(self, entry: Value) -> Value {
+        match self {
+            OutputFormat::CloudLogging => entry,
+            OutputFormat::Ecs => to_ecs(entry),
+            OutputFormat::Gelf => to_gelf(entry),
+        }
+    }
+}
+
+/// Parts of a built entry shared by every format
+struct Parts {
+    /// The entry's `timestamp`, or now when it carries none
+    time: DateTime<Utc>,
+    /// The Cloud Logging severity name
+    severity: String,
+    /// The payload's `message`
+    message: String,
+    /// Every other payload field
+    payload: Map<String, Value>,
+    /// The entry's `labels`
+    labels: Map<String, Value>,
+    /// The monitored resource's labels
+    resource: Map<String, Value>,
+    /// The trace ID, without the `projects/*/traces/` prefix
+    trace_id: Option<String>,
+    /// The span ID
+    span_id: Option<String>,
+    /// The source file and line
+    location: Option<(String, Option<u64>)>,
+    /// The entry's `insertId`
+    insert_id: Option<String>,
+}
+
+impl Parts {
+    /// Split a built entry into its parts
+    fn from_entry(mut entry: Value) -> Self {
+        let mut take = |key: &str| entry.get_mut(key).map(Value::take);
+        let object = |value: Option<Value>| match value {
+            Some(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        let string = |value: Option<Value>| value.and_then(|v| v.as_str().map(str::to_string));
+        let time = string(take("timestamp"))
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+            .map_or_else(Utc::now, |time| time.with_timezone(&Utc));
+        let severity = string(take("severity")).unwrap_or_else(|| "DEFAULT".into());
+        let mut payload = object(take("jsonPayload"));
+        let message = match payload.remove("message") {
+            Some(Value::String(message)) => message,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        let resource =
+            object(take("resource").and_then(|mut r| r.get_mut("labels").map(Value::take)));
+        let trace_id = string(take("trace"))
+            .map(|trace| trace.rsplit('/').next().unwrap_or_default().to_string());
+        let location = take("sourceLocation").and_then(|location| {
+            let file = location.get("file")?.as_str()?.to_string();
+            let line = location.get("line").and_then(|line| match line {
+                Value::String(line) => line.parse().ok(),
+                line => line.as_u64(),
+            });
+            Some((file, line))
+        });
+        Self {
+            time,
+            severity,
+            message,
+            payload,
+            labels: object(take("labels")),
+            resource,
+            trace_id,
+            span_id: string(take("spanId")),
+            location,
+            insert_id: string(take("insertId")),
+        }
+    }
+}
+
+/// Insert payload fields, renaming those whose key is taken with a `user_` prefix
+fn insert_payload(line: &mut Map<String, Value>, payload: Map<String, Value>) {
+    for (mut key, value) in payload {
+        while line.contains_key(&key) {
+            key.insert_str(0, "user_");
+        }
+        line.insert(key, value);
+    }
+}
+
+/// Convert a built entry into Elastic Common Schema JSON
+///
+/// Mapping: `@timestamp`, `message`, `log.level` (lowercase severity),
+/// `log.origin.file.name`/`line`, `trace.id`, `span.id`, `event.id`
+/// (`insertId`), `labels`, and `cloud.*` from the resource's `project_id`,
+/// `zone`, `location`/`region` and `instance_id` labels. Payload fields are
+/// top-level keys.
+///
+/// # Arguments
+/// * `entry` - A JSON entry produced by `build_entry`
+fn to_ecs(entry: Value) -> Value {
+    let parts = Parts::from_entry(entry);
+    let mut line = Map::new();
+    let time = parts.time.to_rfc3339_opts(SecondsFormat::Millis, true);
+    line.insert("@timestamp".into(), time.into());
+    line.insert("log.level".into(), parts.severity.to_lowercase().into());
+    line.insert("message".into(), parts.message.into());
+    line.insert("ecs.version".into(), ECS_VERSION.into());
+    if let Some((file, number)) = parts.location {
+        line.insert("log.origin.file.name".into(), file.into());
+        if let Some(number) = number {
+            line.insert("log.origin.file.line".into(), number.into());
+        }
+    }
+    if let Some(trace_id) = parts.trace_id {
+        line.insert("trace.id".into(), trace_id.into());
+    }
+    if let Some(span_id) = parts.span_id {
+        line.insert("span.id".into(), span_id.into());
+    }
+    if let Some(insert_id) = parts.insert_id {
+        line.insert("event.id".into(), insert_id.into());
+    }
+    line.insert("cloud.provider".into(), "gcp".into());
+    let cloud = [
+        ("project_id", "cloud.project.id"),
+        ("zone", "cloud.availability_zone"),
+        ("location", "cloud.region"),
+        ("region", "cloud.region"),
+        ("instance_id", "cloud.instance.id"),
+    ];
+    for (label, key) in cloud {
+        if let Some(value) = parts.resource.get(label) {
+            line.insert(key.into(), value.clone());
+        }
+    }
+    if !parts.labels.is_empty() {
+        line.insert("labels".into(), Value::Object(parts.labels));
+    }
+    insert_payload(&mut line, parts.payload);
+    Value::Object(line)
+}
+
+/// The syslog level GELF uses for a Cloud Logging severity name
+fn syslog_level(severity: &str) -> u8 {
+    match severity {
+        "EMERGENCY" => 0,
+        "ALERT" => 1,
+        "CRITICAL" => 2,
+        "ERROR" => 3,
+        "WARNING" => 4,
+        "NOTICE" => 5,
+        "DEBUG" => 7,
+        _ => 6,
+    }
+}
+
+/// Make a key a valid GELF additional field name, i.e. `_` and `[\w.-]+`
+fn gelf_key(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("_{}", key)
+}
+
+/// GELF additional fields only hold strings and numbers
+fn gelf_value(value: Value) -> Value {
+    match value {
+        Value::String(_) | Value::Number(_) => value,
+        other => other.to_string().into(),
+    }
+}
+
+/// Convert a built entry into a GELF 1.1 message
+///
+/// Mapping: `host` (payload `instance.name`, else the resource's
+/// `instance_id`), `short_message` (first line of the message),
+/// `full_message` (the whole message, when it has several lines),
+/// `timestamp` (seconds since the epoch), `level` (syslog level of the
+/// severity), and additional fields `_file`, `_line`, `_trace_id`,
+/// `_span_id`, `_insert_id`, `_labels.{key}` and `_{field}` per payload
+/// field. Objects, arrays, booleans and nulls are sent as JSON strings;
+/// characters GELF does not allow in names become `_`, and the reserved
+/// `_id` becomes `_user_id`.
+///
+/// # Arguments
+/// * `entry` - A JSON entry produced by `build_entry`
+fn to_gelf(entry: Value) -> Value {
+    let parts = Parts::from_entry(entry);
+    let mut line = Map::new();
+    let host = parts
+        .payload
+        .get("instance")
+        .and_then(|instance| instance.get("name"))
+        .and_then(Value::as_str)
+        .filter(|name| !name.is_empty())
+        .or_else(|| parts.resource.get("instance_id").and_then(Value::as_str))
+        .unwrap_or("unknown")
+        .to_string();
+    line.insert("version".into(), GELF_VERSION.into());
+    line.insert("host".into(), host.into());
+    let short = parts.message.lines().next().unwrap_or_default().to_string();
+    if short != parts.message {
+        line.insert("full_message".into(), parts.message.clone().into());
+    }
+    line.insert("short_message".into(), short.into());
+    let seconds = parts.time.timestamp_millis() as f64 / 1000.0;
+    line.insert("timestamp".into(), seconds.into());
+    line.insert("level".into(), syslog_level(&parts.severity).into());
+    if let Some((file, number)) = parts.location {
+        line.insert("_file".into(), file.into());
+        if let Some(number) = number {
+            line.insert("_line".into(), number.into());
+        }
+    }
+    let ids = [
+        ("_trace_id", parts.trace_id),
+        ("_span_id", parts.span_id),
+        ("_insert_id", parts.insert_id),
+    ];
+    for (key, id) in ids {
+        if let Some(id) = id {
+            line.insert(key.into(), id.into());
+        }
+    }
+    for (key, value) in parts.labels {
+        line.insert(gelf_key(&format!("labels.{}", key)), gelf_value(value));
+    }
+    for (key, value) in parts.payload {
+        let mut key = gelf_key(&key);
+        while key == "_id" || line.contains_key(&key) {
+            key.insert_str(1, "user_");
+        }
+        line.insert(key, gelf_value(value));
+    }
+    Value::Object(line)
+}
+
+/// Renders entries in another format for the user's sink, or stdout
+pub(crate) struct FormattedSink {
+    /// The format entries are converted to
+    pub(crate) format: OutputFormat,
+    /// The sink receiving the converted entries, None for stdout
+    pub(crate) inner: Option<Arc<dyn LogSink>>,
+}
+
+impl LogSink for FormattedSink {
+    fn write(&self, entry: Value) {
+        let entry = self.format.render(entry);
+        match &self.inner {
+            Some(inner) => inner.write(entry),
+            None => {
+                let _ = writeln!(std::io::stdout().lock(), "{}", entry);
+            }
+        }
+    }
+}
@@ -0,0 +1,499 @@
+//! gRPC transport writing batches through `LoggingServiceV2/WriteLogEntries`.
+use std::collections::HashMap;
+use std::io;
+
+use googleapis_tonic_google_logging_v2::google::api::MonitoredResource;
+use googleapis_tonic_google_logging_v2::google::logging::r#type::LogSeverity;
+use googleapis_tonic_google_logging_v2::google::logging::v2::log_entry::Payload;
+use googleapis_tonic_google_logging_v2::google::logging::v2::{
+    LogEntry, LogEntryOperation, LogEntrySourceLocation, WriteLogEntriesResponse,
+};
+use prost::encoding::{decode_key, skip_field, DecodeContext};
+use prost::Message;
+use prost_types::value::Kind;
+use serde_json::Value;
+use tonic::client::Grpc;
+use tonic::codec::{Codec, CompressionEncoding, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, GrpcMethod, Status};
+
+use crate::encoder::{WireEncoder, WireEntry};
+use crate::entry::EntryView;
+use crate::transport::Delivery;
+
+/// Cloud Logging gRPC endpoint
+const LOGGING_ENDPOINT: &str = "https://logging.googleapis.com";
+
+/// Path of the `WriteLogEntries` method
+const WRITE_LOG_ENTRIES: &str = "/google.logging.v2.LoggingServiceV2/WriteLogEntries";
+
+/// Field number of `entries` in `WriteLogEntriesRequest`
+const ENTRIES_FIELD: u32 = 4;
+
+/// Client for the Logging API's gRPC service
+#[derive(Clone)]
+pub(crate) struct GrpcClient {
+    /// Channel to the endpoint, connected on first use
+    client: Grpc<Channel>,
+}
+
+impl GrpcClient {
+    /// Create a client whose channel connects on the first upload
+    ///
+    /// Must be called from within a Tokio runtime; like an HTTP client's
+    /// connection pool, the channel is bound to that runtime.
+    ///
+    /// # Arguments
+    /// * `user_agent` - `User-Agent` sent with every request
+    ///
+    /// # Returns
+    /// The client, or an error if the endpoint could not be configured
+    pub(crate) fn new(user_agent: &str) -> Result<Self, tonic::transport::Error> {
+        let channel = Endpoint::from_static(LOGGING_ENDPOINT)
+            .user_agent(user_agent)?
+            .tls_config(ClientTlsConfig::new().with_webpki_roots())?
+            .connect_lazy();
+        Ok(Self {
+            client: Grpc::new(channel),
+        })
+    }
+
+    /// Upload a request body and classify the outcome
+    ///
+    /// Permission errors map to `Delivery::Forbidden`; unavailability,
+    /// throttling, timeouts and internal errors may succeed later.
+    ///
+    /// # Arguments
+    /// * `body` - The uncompressed `WriteLogEntriesRequest` built by
+    ///   `ProtoEncoder`
+    /// * `compress` - Whether to gzip the request
+    /// * `token` - Access token, if authentication is available
+    /// * `quota_project` - Project billed for quota, sent as `x-goog-user-project`
+    ///
+    /// # Returns
+    /// The outcome of the upload
+    pub(crate) async fn deliver(
+        &self,
+        body: Vec<u8>,
+        compress: bool,
+        token: Option<&str>,
+        quota_project: Option<&str>,
+    ) -> Delivery {
+        let mut request = tonic::Request::new(body);
+        if let Some(token) = token {
+            match MetadataValue::try_from(format!("Bearer {}", token)) {
+                Ok(value) => {
+                    request.metadata_mut().insert("authorization", value);
+                }
+                Err(e) => {
+                    diagnostic!("Failed to send log entry: {}", e);
+                    return Delivery::Rejected;
+                }
+            }
+        }
+        if let Some(project) = quota_project {
+            match MetadataValue::try_from(project) {
+                Ok(value) => {
+                    request.metadata_mut().insert("x-goog-user-project", value);
+                }
+                Err(e) => {
+                    diagnostic!("Failed to send log entry: {}", e);
+                    return Delivery::Rejected;
+                }
+            }
+        }
+        request.extensions_mut().insert(GrpcMethod::new(
+            "google.logging.v2.LoggingServiceV2",
+            "WriteLogEntries",
+        ));
+        let mut client = self.client.clone();
+        if compress {
+            client = client.send_compressed(CompressionEncoding::Gzip);
+        }
+        let sent = match client.ready().await {
+            Ok(()) => {
+                let path = PathAndQuery::from_static(WRITE_LOG_ENTRIES);
+                client.unary(request, path, EncodedRequest).await.map(drop)
+            }
+            Err(e) => Err(Status::unavailable(e.to_string())),
+        };
+        match sent {
+            Ok(()) => Delivery::Sent,
+            Err(status) => {
+                diagnostic!(
+                    "Failed to send log entry: gRPC {:?}: {}",
+                    status.code(),
+                    status.message()
+                );
+                match status.code() {
+                    Code::PermissionDenied => Delivery::Forbidden,
+                    Code::Unavailable
+                    | Code::ResourceExhausted
+                    | Code::DeadlineExceeded
+                    | Code::Internal
+                    | Code::Aborted
+                    | Code::Unknown => Delivery::Retryable,
+                    _ => Delivery::Rejected,
+                }
+            }
+        }
+    }
+}
+
+/// Encodes entries as a `WriteLogEntriesRequest` protobuf, for `grpc(true)`
+///
+/// Each entry is written as one `entries` field, so joining a batch is a plain
+/// concatenation and the body goes out without being converted again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ProtoEncoder;
+
+impl WireEncoder for ProtoEncoder {
+    fn encode_entry(&self, entry: &WireEntry<'_>, buffer: &mut Vec<u8>) -> io::Result<()> {
+        prost::encoding::message::encode(ENTRIES_FIELD, &log_entry(entry.view()), buffer);
+        Ok(())
+    }
+
+    fn encode_batch(&self, entries: &[Vec<u8>], body: &mut Vec<u8>) {
+        body.reserve(entries.iter().map(Vec::len).sum());
+        for entry in entries {
+            body.extend_from_slice(entry);
+        }
+    }
+}
+
+/// Count the entries of a body built by `ProtoEncoder`
+///
+/// # Arguments
+/// * `body` - The `WriteLogEntriesRequest` protobuf
+pub(crate) fn count_entries(mut body: &[u8]) -> usize {
+    let mut entries = 0;
+    while !body.is_empty() {
+        let Ok((tag, wire_type)) = decode_key(&mut body) else {
+            break;
+        };
+        if tag == ENTRIES_FIELD {
+            entries += 1;
+        }
+        if skip_field(wire_type, tag, &mut body, DecodeContext::default()).is_err() {
+            break;
+        }
+    }
+    entries
+}
+
+/// Codec sending bodies already encoded by `ProtoEncoder`
+struct EncodedRequest;
+
+impl Codec for EncodedRequest {
+    type Encode = Vec<u8>;
+    type Decode = WriteLogEntriesResponse;
+    type Encoder = Self;
+    type Decoder = Self;
+
+    fn encoder(&mut self) -> Self {
+        Self
+    }
+
+    fn decoder(&mut self) -> Self {
+        Self
+    }
+}
+
+impl Encoder for EncodedRequest {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        use prost::bytes::BufMut;
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for EncodedRequest {
+    type Item = WriteLogEntriesResponse;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Status> {
+        Message::decode(src)
+            .map(Some)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+/// Build the protobuf `LogEntry` of an entry
+///
+/// Holds what the entry's `LogEntry` JSON holds, including the fields left
+/// out to fit `max_entry_bytes`.
+///
+/// # Arguments
+/// * `view` - The entry
+fn log_entry(view: &EntryView<'_>) -> LogEntry {
+    let log_entry = view.log_entry();
+    let context = view.context();
+    let resource = match view.resource() {
+        Some(resource) => MonitoredResource {
+            r#type: resource.resource_type.clone(),
+            labels: resource.labels.clone().into_iter().collect(),
+        },
+        None => MonitoredResource {
+            r#type: "gce_instance".into(),
+            labels: HashMap::from([
+                ("instance_id".into(), context.instance.id.clone()),
+                ("zone".into(), context.instance.zone.clone()),
+                ("project_id".into(), context.instance.project_id.clone()),
+            ]),
+        },
+    };
+    let timestamp = view.sends_timestamp().then(|| prost_types::Timestamp {
+        seconds: log_entry.timestamp.timestamp(),
+        nanos: log_entry.timestamp.timestamp_subsec_nanos() as i32,
+    });
+    let severity =
+        LogSeverity::from_str_name(log_entry.severity.as_str()).unwrap_or(LogSeverity::Default);
+    let (labels, trimmed) = view.labels();
+    let payload = match view.payload(&trimmed) {
+        Value::Object(payload) => Some(Payload::JsonPayload(to_struct(payload))),
+        _ => None,
+    };
+    let operation = log_entry
+        .operation
+        .as_ref()
+        .map(|operation| LogEntryOperation {
+            id: operation.id.clone(),
+            producer: operation.producer.clone(),
+            first: operation.first,
+            last: operation.last,
+        });
+    let source_location =
+        log_entry
+            .source_location
+            .as_ref()
+            .map(|location| LogEntrySourceLocation {
+                file: location.file.clone(),
+                line: location.line.into(),
+                function: String::new(),
+            });
+    let trace = log_entry.trace.as_ref();
+    LogEntry {
+        log_name: view.log_name().to_string(),
+        resource: Some(resource),
+        timestamp,
+        severity: severity as i32,
+        insert_id: view.insert_id().unwrap_or_default().to_string(),
+        labels: labels
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        operation,
+        trace: trace.map_or_else(String::new, |trace| {
+            format!(
+                "projects/{}/traces/{}",
+                context.instance.project_id, trace.trace_id
+            )
+        }),
+        span_id: trace.map_or_else(String::new, |trace| trace.span_id.clone()),
+        trace_sampled: trace.is_some_and(|trace| trace.sampled),
+        source_location,
+        payload,
+        ..Default::default()
+    }
+}
+
+/// Convert a JSON object into a protobuf `Struct`
+fn to_struct(object: serde_json::Map<String, Value>) -> prost_types::Struct {
+    prost_types::Struct {
+        fields: object
+            .into_iter()
+            .map(|(key, value)| (key, to_value(value)))
+            .collect(),
+    }
+}
+
+/// Convert a JSON value into a protobuf `Value`
+fn to_value(value: Value) -> prost_types::Value {
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(value) => Kind::BoolValue(value),
+        Value::Number(value) => Kind::NumberValue(value.as_f64().unwrap_or_default()),
+        Value::String(value) => Kind::StringValue(value),
+        Value::Array(values) => Kind::ListValue(prost_types::ListValue {
+            values: values.into_iter().map(to_value).collect(),
+        }),
+        Value::Object(object) => Kind::StructValue(to_struct(object)),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{
+        EntryConfig, GcpLogEntry, Operation, Sequencer, SourceLocation, StaticMetadata,
+    };
+    use crate::trace::TraceContext;
+    use crate::{InstanceMetadata, LogContextMetadata, Severity};
+    use googleapis_tonic_google_logging_v2::google::logging::v2::WriteLogEntriesRequest;
+
+    /// Metadata of a GCE instance
+    fn metadata() -> StaticMetadata {
+        StaticMetadata::new(LogContextMetadata {
+            container: None,
+            instance: InstanceMetadata {
+                name: "logical-service".into(),
+                id: "1234".into(),
+                zone: "us-central1-a".into(),
+                region: "us-central1".into(),
+                project_id: "test-project".into(),
+            },
+        })
+    }
+
+    /// An entry setting every optional `LogEntry` field
+    fn full_entry() -> GcpLogEntry {
+        let mut log_entry = GcpLogEntry::internal(Severity::Error, "checkout failed");
+        log_entry.internal = false;
+        log_entry.fields.insert("order_id".into(), 42.into());
+        log_entry.fields.insert(
+            "cart".into(),
+            serde_json::json!({"items": [1, 2], "paid": false}),
+        );
+        log_entry.source_location = Some(SourceLocation {
+            file: "src/main.rs".into(),
+            line: 7,
+        });
+        log_entry.operation = Some(Operation {
+            id: "op-1".into(),
+            producer: "checkout".into(),
+            first: true,
+            last: false,
+        });
+        log_entry.trace = Some(TraceContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+            sampled: true,
+        });
+        log_entry.labels.insert("team".into(), "payments".into());
+        log_entry
+    }
+
+    /// Encode entries into one body, as a batch would
+    fn body(metadata: &StaticMetadata, config: &EntryConfig, entries: &[GcpLogEntry]) -> Vec<u8> {
+        let mut sequencer = config.preserve_order.then(Sequencer::new);
+        let encoded: Vec<Vec<u8>> = entries
+            .iter()
+            .map(|log_entry| {
+                let view =
+                    EntryView::new(metadata, config, log_entry).with_insert_id(sequencer.as_mut());
+                let mut buffer = Vec::new();
+                ProtoEncoder
+                    .encode_entry(&WireEntry::new(&view), &mut buffer)
+                    .unwrap();
+                buffer
+            })
+            .collect();
+        let mut body = Vec::new();
+        ProtoEncoder.encode_batch(&encoded, &mut body);
+        body
+    }
+
+    #[test]
+    fn test_log_entry_holds_what_the_json_entry_holds() {
+        let metadata = metadata();
+        let config = EntryConfig {
+            preserve_order: true,
+            ..EntryConfig::default()
+        };
+        let log_entry = full_entry();
+        let body = body(&metadata, &config, std::slice::from_ref(&log_entry));
+        let request = WriteLogEntriesRequest::decode(body.as_slice()).unwrap();
+        assert_eq!(request.entries.len(), 1);
+        let entry = &request.entries[0];
+
+        assert_eq!(entry.log_name, "projects/test-project/logs/proxie");
+        assert_eq!(entry.severity, LogSeverity::Error as i32);
+        let resource = entry.resource.as_ref().unwrap();
+        assert_eq!(resource.r#type, "gce_instance");
+        assert_eq!(resource.labels["instance_id"], "1234");
+        assert_eq!(resource.labels["zone"], "us-central1-a");
+        assert_eq!(resource.labels["project_id"], "test-project");
+        let timestamp = entry.timestamp.as_ref().unwrap();
+        assert_eq!(timestamp.seconds, log_entry.timestamp.timestamp());
+        assert!(!entry.insert_id.is_empty());
+        assert_eq!(entry.labels["team"], "payments");
+        assert_eq!(
+            entry.trace,
+            "projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(entry.span_id, "00f067aa0ba902b7");
+        assert!(entry.trace_sampled);
+        let location = entry.source_location.as_ref().unwrap();
+        assert_eq!((location.file.as_str(), location.line), ("src/main.rs", 7));
+        let operation = entry.operation.as_ref().unwrap();
+        assert_eq!(operation.id, "op-1");
+        assert_eq!(operation.producer, "checkout");
+        assert!(operation.first && !operation.last);
+
+        let Some(Payload::JsonPayload(payload)) = &entry.payload else {
+            panic!("the payload should be a struct");
+        };
+        let field = |key: &str| payload.fields[key].kind.clone().unwrap();
+        assert_eq!(
+            field("message"),
+            Kind::StringValue("checkout failed".into())
+        );
+        assert_eq!(field("order_id"), Kind::NumberValue(42.0));
+        let Kind::StructValue(cart) = field("cart") else {
+            panic!("cart should be a struct");
+        };
+        assert_eq!(cart.fields["paid"].kind, Some(Kind::BoolValue(false)));
+        let Kind::StructValue(instance) = field("instance") else {
+            panic!("instance should be a struct");
+        };
+        assert_eq!(
+            instance.fields["id"].kind,
+            Some(Kind::StringValue("1234".into()))
+        );
+    }
+
+    #[test]
+    fn test_log_entry_leaves_unset_fields_empty() {
+        let metadata = metadata();
+        let config = EntryConfig {
+            resource: Some(crate::MonitoredResource::new("generic_task").label("job", "checkout")),
+            ..EntryConfig::default()
+        };
+        let log_entry = GcpLogEntry::internal(Severity::Info, "heartbeat");
+        let body = body(&metadata, &config, &[log_entry]);
+        let entry = &WriteLogEntriesRequest::decode(body.as_slice())
+            .unwrap()
+            .entries[0];
+
+        let resource = entry.resource.as_ref().unwrap();
+        assert_eq!(resource.r#type, "generic_task");
+        assert_eq!(resource.labels["job"], "checkout");
+        assert_eq!(entry.severity, LogSeverity::Info as i32);
+        assert!(entry.timestamp.is_none());
+        assert!(entry.insert_id.is_empty());
+        assert!(entry.trace.is_empty() && entry.span_id.is_empty());
+        assert!(entry.source_location.is_none() && entry.operation.is_none());
+        assert_eq!(entry.labels[crate::INTERNAL_LABEL], "true");
+    }
+
+    #[test]
+    fn test_batch_concatenates_entries_and_counts_them() {
+        let metadata = metadata();
+        let config = EntryConfig::default();
+        let entries = [full_entry(), full_entry(), full_entry()];
+        let body = body(&metadata, &config, &entries);
+
+        let request = WriteLogEntriesRequest::decode(body.as_slice()).unwrap();
+        assert_eq!(request.entries.len(), 3);
+        assert_eq!(count_entries(&body), 3);
+        assert_eq!(crate::batch::count_entries(&body), 3);
+        assert_eq!(crate::batch::count_entries(br#"{"entries":[{},{}]}"#), 2);
+        assert_eq!(count_entries(&[]), 0);
+    }
+}
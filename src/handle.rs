@@ -0,0 +1,439 @@
+//! Cloneable handle for interacting with a running layer.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use serde_json::{Map, Value};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::circuit::CircuitState;
+use crate::entry::{validate_log_name, EntryConfig, GcpLogEntry};
+use crate::error::{ConfigError, EmitError};
+use crate::executor;
+use crate::metrics::{DeliveryMetrics, LogStats, Metrics};
+use crate::shutdown::{Shutdown, ShutdownReport};
+use crate::sync_writer::SyncWriter;
+use crate::visitor::PendingFields;
+use crate::{Severity, TokenStatus};
+
+/// Extra time `shutdown` waits past its timeout for the task's final count
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(100);
+
+/// State shared between the layer, its handles and the background task
+pub(crate) struct SharedState {
+    /// Lifecycle of the access token, updated on every acquisition
+    pub(crate) token_status: Arc<Mutex<TokenStatus>>,
+    /// Upload circuit breaker state, published by the background task
+    pub(crate) circuit_state: Arc<AtomicU8>,
+    /// Delivery counters updated by the background tasks
+    pub(crate) metrics: Arc<Metrics>,
+    /// Log ID entries are written to, read for every entry
+    pub(crate) log_name: Arc<ArcSwap<String>>,
+    /// Labels set at runtime, attached to every queued entry
+    pub(crate) labels: Arc<ArcSwap<HashMap<String, String>>>,
+    /// Shutdown requests and their outcome
+    pub(crate) shutdown: Arc<Shutdown>,
+    /// Runtime of the background task, timing `shutdown` for any executor
+    pub(crate) runtime: Option<tokio::runtime::Handle>,
+}
+
+impl SharedState {
+    /// Create state sharing the runtime-adjustable parts of an entry config
+    ///
+    /// Called on the runtime the background task will run on, if any.
+    ///
+    /// # Arguments
+    /// * `config` - The config whose log name and runtime labels are shared
+    pub(crate) fn new(config: &EntryConfig) -> Self {
+        Self {
+            token_status: Arc::default(),
+            circuit_state: Arc::default(),
+            metrics: Arc::default(),
+            log_name: config.log_name.clone(),
+            labels: config.runtime_labels.clone(),
+            shutdown: Arc::new(Shutdown::new()),
+            runtime: tokio::runtime::Handle::try_current().ok(),
+        }
+    }
+}
+
+/// An entry emitted directly through `GcpLoggingHandle::emit`
+///
+/// Unlike events captured by the tracing pipeline, the message is sent as-is
+/// without the timestamp/target prefix.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    /// Severity of the entry
+    pub severity: Severity,
+    /// Message content
+    pub message: String,
+    /// Structured fields merged into `jsonPayload`
+    pub fields: Map<String, Value>,
+}
+
+impl LogEntry {
+    /// Create an entry without structured fields
+    ///
+    /// # Arguments
+    /// * `severity` - Severity of the entry
+    /// * `message` - Message content
+    pub fn new(severity: impl Into<Severity>, message: impl Into<String>) -> Self {
+        Self {
+            severity: severity.into(),
+            message: message.into(),
+            fields: Map::new(),
+        }
+    }
+
+    /// Add a structured field
+    ///
+    /// # Arguments
+    /// * `key` - Field name
+    /// * `value` - Field value
+    pub fn field(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.fields.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// Callback receiving each entry discarded before it reached Cloud Logging
+pub(crate) type DropHook = Arc<dyn Fn(&GcpLogEntry) + Send + Sync>;
+
+/// What the layer sends to the background task
+// Entries travel unboxed, as before, so queueing one does not allocate.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Message {
+    /// An entry to deliver
+    Entry(GcpLogEntry),
+    /// Send the partial batch now, following an entry at or above `flush_on`
+    Flush,
+}
+
+impl Message {
+    /// The entry carried by the message, if any
+    pub(crate) fn into_entry(self) -> Option<GcpLogEntry> {
+        match self {
+            Message::Entry(log_entry) => Some(log_entry),
+            Message::Flush => None,
+        }
+    }
+}
+
+/// Delivers entries to the background task, or synchronously when configured
+#[derive(Clone, Default)]
+pub(crate) struct EntrySender {
+    /// Channel to the background task, None when the layer is disabled
+    pub(crate) channel: Option<UnboundedSender<Message>>,
+    /// Blocking delivery path for critical entries, if enabled
+    pub(crate) sync_writer: Option<Arc<SyncWriter>>,
+    /// Whether the background task drops every entry for lack of credentials
+    pub(crate) drops_entries: bool,
+    /// Counters recording queued entries
+    pub(crate) metrics: Arc<Metrics>,
+    /// Callback receiving entries discarded instead of delivered, if set
+    pub(crate) on_drop: Option<DropHook>,
+    /// Lowest severity followed by a `Message::Flush`, if any
+    pub(crate) flush_on: Option<Severity>,
+    /// Labels set at runtime, attached to each entry as it is queued
+    pub(crate) runtime_labels: Arc<ArcSwap<HashMap<String, String>>>,
+}
+
+impl EntrySender {
+    /// Whether every entry is discarded unseen, so capturing them can be skipped
+    ///
+    /// Entries the background task would drop are still captured for the
+    /// `on_drop` callback.
+    pub(crate) fn discards_entries(&self) -> bool {
+        self.channel.is_none() || (self.drops_entries && self.on_drop.is_none())
+    }
+
+    /// Deliver an entry
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry to deliver
+    ///
+    /// # Returns
+    /// `Ok` once queued, written or discarded by a disabled layer, or
+    /// `EmitError::Closed` if the background task stopped
+    pub(crate) fn send(&self, mut log_entry: GcpLogEntry) -> Result<(), EmitError> {
+        let Some(channel) = &self.channel else {
+            return Ok(());
+        };
+        log_entry.runtime_labels = Some(self.runtime_labels.load_full());
+        if let Some(sync_writer) = &self.sync_writer {
+            if sync_writer.applies(log_entry.severity) {
+                Metrics::add(&self.metrics.queued, 1);
+                sync_writer.write(&log_entry.resolved());
+                return Ok(());
+            }
+        }
+        let severity = log_entry.severity;
+        if let Err(closed) = channel.send(Message::Entry(log_entry)) {
+            if let (Some(on_drop), Some(log_entry)) = (&self.on_drop, closed.0.into_entry()) {
+                on_drop(&log_entry.resolved());
+            }
+            return Err(EmitError::Closed);
+        }
+        Metrics::add(&self.metrics.queued, 1);
+        if self.flush_on.is_some_and(|min| severity >= min) {
+            // Queued behind the entry, so the batch it is sent in includes it.
+            let _ = channel.send(Message::Flush);
+        }
+        Ok(())
+    }
+}
+
+/// Handle to a running `GcpLoggingLayer`
+///
+/// The layer itself is moved into the subscriber; obtain a handle with
+/// `GcpLoggingLayer::handle` first to keep interacting with it afterwards.
+#[derive(Clone)]
+pub struct GcpLoggingHandle {
+    /// Delivery path shared with the layer
+    pub(crate) sender: EntrySender,
+    /// State shared with the layer
+    pub(crate) state: Arc<SharedState>,
+}
+
+impl GcpLoggingHandle {
+    /// Queue an entry outside of the tracing pipeline
+    ///
+    /// Unlike `on_event`, which can only report failures to stderr, this lets
+    /// the caller detect a dead logging backend and react (e.g. fail fast).
+    ///
+    /// # Arguments
+    /// * `entry` - The entry to queue
+    ///
+    /// # Returns
+    /// `Ok` once queued (or discarded by a disabled layer), or
+    /// `EmitError::Closed` if the background task stopped
+    pub fn emit(&self, entry: LogEntry) -> Result<(), EmitError> {
+        self.sender.send(GcpLogEntry {
+            message: entry.message,
+            severity: entry.severity,
+            fields: entry.fields,
+            timestamp: Utc::now(),
+            source_location: None,
+            operation: None,
+            trace: None,
+            labels: BTreeMap::new(),
+            runtime_labels: None,
+            internal: false,
+            callsite: None,
+            pending: PendingFields::default(),
+        })
+    }
+
+    /// Snapshot of the access token's lifecycle
+    ///
+    /// Useful to confirm that token refresh is working: `refresh_count` grows
+    /// and `expires_at` moves forward roughly every hour on long-running
+    /// processes. All fields are empty when no credentials are available.
+    ///
+    /// # Returns
+    /// A copy of the current TokenStatus
+    pub fn token_status(&self) -> TokenStatus {
+        self.state
+            .token_status
+            .lock()
+            .map(|status| status.clone())
+            .unwrap_or_default()
+    }
+
+    /// Current state of the upload circuit breaker
+    ///
+    /// # Returns
+    /// `CircuitState::Open` while uploads are paused after repeated failures
+    pub fn circuit_state(&self) -> CircuitState {
+        CircuitState::from_u8(self.state.circuit_state.load(Ordering::Relaxed))
+    }
+
+    /// Snapshot of the delivery counters, e.g. the retry queue depth
+    ///
+    /// # Returns
+    /// A copy of the current DeliveryMetrics
+    pub fn metrics(&self) -> DeliveryMetrics {
+        self.state.metrics.snapshot()
+    }
+
+    /// Snapshot of how many entries were queued, sent, failed or dropped
+    ///
+    /// Reads a handful of atomics without locking, so it is cheap enough for a
+    /// health endpoint or a periodic report. Counters start at zero when the
+    /// layer is built and only grow; a disabled layer reports all zeros.
+    ///
+    /// # Returns
+    /// A copy of the current LogStats
+    pub fn stats(&self) -> LogStats {
+        self.state.metrics.stats()
+    }
+
+    /// Deliver every queued entry and stop the background task
+    ///
+    /// Entries still queued or batched, batches held while the circuit is open
+    /// and batches waiting for a retry get one final upload attempt; entries
+    /// emitted afterwards are rejected with `EmitError::Closed`. Uploads still
+    /// running at `timeout` are abandoned. Calling it again returns the first
+    /// outcome.
+    ///
+    /// # Arguments
+    /// * `timeout` - Longest time spent delivering
+    ///
+    /// # Returns
+    /// How many pending entries were sent, dropped or left undelivered; all
+    /// zero for a disabled layer. None if the background task did not answer
+    /// in time, e.g. because its runtime was shut down.
+    pub async fn shutdown(&self, timeout: Duration) -> Option<ShutdownReport> {
+        let Some(channel) = &self.sender.channel else {
+            return Some(ShutdownReport::default());
+        };
+        let shutdown = &self.state.shutdown;
+        if channel.is_closed() {
+            return shutdown.outcome();
+        }
+        let stop = tokio::time::Instant::now() + timeout;
+        let mut finished = shutdown.request(stop);
+        // Give the task a moment past the deadline to count what it abandoned.
+        // The deadline is timed on the task's runtime, so the caller's
+        // executor needs no Tokio timer; a runtime that shut down cancels the
+        // timer at once. The blocking sender enforces the deadline itself.
+        let answer = stop + SHUTDOWN_GRACE;
+        let expired = async {
+            match &self.state.runtime {
+                Some(runtime) => {
+                    let timer = async move { tokio::time::sleep_until(answer).await };
+                    let _ = runtime.spawn(timer).await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            answered = finished.wait_for(Option::is_some) => {
+                if let Ok(outcome) = answered {
+                    return *outcome;
+                }
+            }
+            _ = expired => {}
+        }
+        shutdown.outcome()
+    }
+
+    /// Deliver every queued entry and stop, from code that cannot `await`
+    ///
+    /// Same as `shutdown`, for e.g. the end of a synchronous `main`. Outside a
+    /// Tokio runtime the calling thread waits for the background task (which
+    /// must still be running on its own runtime or thread). On a
+    /// multi-threaded runtime the current worker thread blocks in place. A
+    /// current-thread runtime cannot run the background task while blocked,
+    /// so there a warning is printed and None returned without waiting; use
+    /// `shutdown` instead.
+    ///
+    /// # Arguments
+    /// * `timeout` - Longest time spent delivering
+    ///
+    /// # Returns
+    /// What happened to the pending entries, as for `shutdown`
+    pub fn blocking_shutdown(&self, timeout: Duration) -> Option<ShutdownReport> {
+        use tokio::runtime::{Handle, RuntimeFlavor};
+
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::CurrentThread => {
+                warning!("blocking_shutdown cannot wait on a current-thread runtime; use shutdown().await instead.");
+                None
+            }
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            Ok(_) => tokio::task::block_in_place(|| executor::block_on(self.shutdown(timeout))),
+            _ => executor::block_on(self.shutdown(timeout)),
+        }
+    }
+
+    /// Wait for a termination signal, then shut down
+    ///
+    /// Covers the Kubernetes rolling deploy flow: the pod receives `SIGTERM`
+    /// (after any `preStop` hook) and has `terminationGracePeriodSeconds` to
+    /// exit, so pick a `timeout` well below it. Ctrl-C (`SIGINT`) also
+    /// triggers it; on non-Unix platforms only Ctrl-C is awaited. Run it on a
+    /// task of its own or `select!` it against the application's main future.
+    ///
+    /// # Arguments
+    /// * `timeout` - Longest time spent delivering once the signal arrived
+    ///
+    /// # Returns
+    /// The outcome of `shutdown`, or None if the signal handlers could not be
+    /// installed
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub async fn shutdown_on_signal(&self, timeout: Duration) -> Option<ShutdownReport> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = signal(SignalKind::terminate()).ok()?;
+            tokio::select! {
+                _ = terminate.recv() => {}
+                interrupted = tokio::signal::ctrl_c() => interrupted.ok()?,
+            }
+        }
+        #[cfg(not(unix))]
+        tokio::signal::ctrl_c().await.ok()?;
+        self.shutdown(timeout).await
+    }
+
+    /// Write subsequent entries to another log
+    ///
+    /// Takes effect for entries built after the call, including entries already
+    /// queued but not yet sent, e.g. to divert logs to a debug stream during an
+    /// incident without restarting.
+    ///
+    /// # Arguments
+    /// * `log_name` - The new log ID or full resource name, as for
+    ///   `GcpLoggingLayerBuilder::log_name`
+    ///
+    /// # Returns
+    /// `ConfigError::InvalidLogName` if Cloud Logging would reject the name
+    pub fn set_log_name(&self, log_name: impl Into<String>) -> Result<(), ConfigError> {
+        let log_name = log_name.into();
+        validate_log_name(&log_name)?;
+        self.state.log_name.store(Arc::new(log_name));
+        Ok(())
+    }
+
+    /// Attach a label to subsequent entries
+    ///
+    /// Useful to tag everything logged during an incident, e.g.
+    /// `incident=INC-123`, without redeploying. The label overrides a
+    /// build-time label with the same key; severity route labels still take
+    /// precedence. Labels are attached to an entry when it is queued, so only
+    /// entries queued after the call carry the label; entries still in flight
+    /// (queued, batched, held by the circuit breaker or awaiting a retry) keep
+    /// the labels they were queued with.
+    ///
+    /// # Arguments
+    /// * `key` - Label key
+    /// * `value` - Label value
+    pub fn set_label(&self, key: impl Into<String>, value: impl Into<String>) {
+        let (key, value) = (key.into(), value.into());
+        self.state.labels.rcu(|labels| {
+            let mut labels = HashMap::clone(labels);
+            labels.insert(key.clone(), value.clone());
+            labels
+        });
+    }
+
+    /// Stop attaching a label set with `set_label`
+    ///
+    /// Build-time labels are not affected. Takes effect for entries queued
+    /// after the call, as for `set_label`.
+    ///
+    /// # Arguments
+    /// * `key` - Label key
+    pub fn remove_label(&self, key: &str) {
+        self.state.labels.rcu(|labels| {
+            let mut labels = HashMap::clone(labels);
+            labels.remove(key);
+            labels
+        });
+    }
+}
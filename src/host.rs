@@ -0,0 +1,85 @@
+//! Stable per-host identifiers used when the instance ID is unknown.
+use std::path::PathBuf;
+
+/// Files holding the systemd/D-Bus machine ID, in lookup order
+const MACHINE_ID_PATHS: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+/// Files holding the host name, read when `HOSTNAME` is not set
+const HOSTNAME_PATHS: [&str; 2] = ["/etc/hostname", "/proc/sys/kernel/hostname"];
+
+/// Key mixed into hashed identifiers, so they cannot be matched against the
+/// same host's identifiers in other applications
+const APP_KEY: &[u8] = b"nano-gcp-logging/host-id";
+
+/// Where the fallback instance ID comes from off GCE
+///
+/// Every source falls back to `Hostname` when it yields nothing, so hosts stay
+/// distinguishable as long as they have distinct names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostIdSource {
+    /// A hash of the machine ID in `/etc/machine-id` or
+    /// `/var/lib/dbus/machine-id`
+    ///
+    /// The machine ID is confidential, so only an application-specific hash
+    /// of it is shipped, as `machine-id(5)` asks.
+    MachineId,
+    /// A hash of the host name, from `HOSTNAME` or `/etc/hostname`
+    Hostname,
+    /// The contents of the given file, e.g. a provisioning-time identifier
+    File(PathBuf),
+    /// A fixed identifier, e.g. from the deployment's configuration
+    Fixed(String),
+}
+
+impl HostIdSource {
+    /// Determine the identifier, following the fallback chain
+    ///
+    /// # Returns
+    /// The identifier, or None if neither the source nor the host name is
+    /// available
+    pub fn resolve(&self) -> Option<String> {
+        let primary = match self {
+            HostIdSource::MachineId => MACHINE_ID_PATHS
+                .iter()
+                .find_map(read_trimmed)
+                .map(|id| app_specific_hash(&id)),
+            HostIdSource::Hostname => None,
+            HostIdSource::File(path) => read_trimmed(path),
+            HostIdSource::Fixed(id) => Some(id.trim().to_string()).filter(|id| !id.is_empty()),
+        };
+        primary.or_else(hostname_hash)
+    }
+}
+
+/// Read a file, None if it is missing or blank
+fn read_trimmed(path: impl AsRef<std::path::Path>) -> Option<String> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    Some(contents.trim().to_string()).filter(|contents| !contents.is_empty())
+}
+
+/// Hash the host name into a stable hexadecimal identifier
+fn hostname_hash() -> Option<String> {
+    let hostname = std::env::var("HOSTNAME")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| HOSTNAME_PATHS.iter().find_map(read_trimmed))?;
+    Some(app_specific_hash(&hostname))
+}
+
+/// Hash a host identifier, keyed for this crate, into 16 hexadecimal digits
+///
+/// FNV-1a is used rather than `DefaultHasher`, whose output may change between
+/// Rust releases and would then split one host's logs across two IDs.
+///
+/// # Arguments
+/// * `id` - The identifier to hash
+fn app_specific_hash(id: &str) -> String {
+    let hash = APP_KEY
+        .iter()
+        .chain(id.as_bytes())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
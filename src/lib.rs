@@ -3,19 +3,180 @@
 //! This layer captures log events, enriches them with metadata
 //! about the running environment, and sends them to Google Cloud Logging.
 //! (C) 2025 Enzo Lombardi
-use chrono::Local;
-use gcp_auth::AuthenticationManager;
+// Without an async client only `build_blocking` starts a layer, so the parts
+// serving the async worker go unused.
+#![cfg_attr(
+    not(any(feature = "reqwest", feature = "hyper")),
+    allow(dead_code, unused_imports)
+)]
+use chrono::Utc;
 
 use serde::Serialize;
-use std::time::Duration;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tracing::{Event, Subscriber};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::sync::mpsc::unbounded_channel;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::task::JoinHandle;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Dispatch, Event, Metadata, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::Layer;
 
+/// Print an error of the layer to its diagnostics file, or stderr without one
+macro_rules! diagnostic {
+    ($($arg:tt)*) => {
+        $crate::diagnostics::write(format_args!($($arg)*))
+    };
+}
+
+/// Print a warning like `diagnostic!` unless `QUIET_ENV_VAR` is set
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if !$crate::quiet_by_env() {
+            diagnostic!("Warning: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+#[cfg(not(any(feature = "reqwest", feature = "hyper", feature = "blocking")))]
+compile_error!(
+    "enable the `reqwest` (default) or `hyper` feature to choose an HTTP client, or `blocking`"
+);
+
+mod auth;
+mod batch;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod build_info;
+mod builder;
+mod callsite;
+mod circuit;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+mod client;
+mod cloud_trace;
+mod console;
+pub mod context;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+mod dedicated;
+mod diagnostics;
+mod encoder;
+mod entry;
+mod environment;
+mod error;
+mod error_reporting;
+mod executor;
+mod format;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handle;
+mod host;
+mod limits;
+mod message;
+mod metrics;
+mod monitoring;
+mod panic;
+mod payload;
+mod pipeline;
+mod pool;
+#[cfg(all(feature = "read", any(feature = "reqwest", feature = "hyper")))]
+mod read;
+mod request_id;
+mod resource;
+mod retry;
+mod severity;
+mod shutdown;
+mod sink;
+mod span;
+mod structured;
+mod sync_writer;
+mod trace;
+mod transport;
+mod visitor;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+mod worker;
+
+pub use auth::{TokenError, TokenFuture, TokenProvider, TokenStatus};
+pub use batch::BatchPolicy;
+pub use build_info::{BuildInfo, BUILD_ID_ENV_VAR, BUILD_TIMESTAMP_ENV_VAR, GIT_SHA_ENV_VAR};
+pub use builder::{FilteredLayerBuilder, GcpLoggingLayerBuilder, ShippedTargets};
+pub use circuit::{CircuitBreakerConfig, CircuitState, OpenCircuitPolicy};
+pub use cloud_trace::TraceExport;
+pub use diagnostics::DiagnosticsFile;
+pub use encoder::{JsonEncoder, WireEncoder, WireEntry};
+pub use entry::TENANT_LABEL;
+#[cfg(feature = "testing")]
+pub use entry::{build_entry, write_entries, write_entry, EntryConfig};
+pub use entry::{GcpLogEntry, Operation, SourceLocation};
+pub use entry::{MessageFormat, MessageParts, MetadataNamespace, SeverityRoute, INTERNAL_LABEL};
+pub use environment::{Platform, RuntimeEnvironment, METADATA_HOST_ENV_VAR};
+pub use error::{ConfigError, EmitError};
+pub use error_reporting::ErrorReporting;
+pub use format::OutputFormat;
+pub use handle::{GcpLoggingHandle, LogEntry};
+pub use host::HostIdSource;
+pub use limits::{
+    MAX_ENTRY_BYTES, MAX_LABELS, MAX_LABEL_KEY_BYTES, MAX_LABEL_VALUE_BYTES, MAX_REQUEST_BYTES,
+};
+pub use message::{CompactFormatter, FullFormatter, JsonOnlyFormatter, MessageFormatter};
+pub use metrics::{DeliveryMetrics, LogStats};
+pub use monitoring::MetricsExport;
+pub use payload::{PayloadParts, PayloadSerializer};
+#[cfg(all(feature = "read", any(feature = "reqwest", feature = "hyper")))]
+pub use read::fetch_recent;
+pub use request_id::{RequestId, REQUEST_ID_FIELD, REQUEST_ID_HEADER};
+pub use resource::{
+    MonitoredResource, ResourceType, CLOUD_RUN_CONFIGURATION_ENV_VAR, CLOUD_RUN_REVISION_ENV_VAR,
+    CLOUD_RUN_SERVICE_ENV_VAR,
+};
+pub use retry::{RetryOverflow, RetryPolicy};
+pub use severity::Severity;
+pub use shutdown::ShutdownReport;
+#[cfg(feature = "testing")]
+pub use sink::{LogSink, TestSink};
+pub use span::SpanFieldMode;
+#[cfg(feature = "testing")]
+pub use structured::to_structured_log;
+pub use trace::{TraceContext, CLOUD_TRACE_CONTEXT_FIELD, TRACEPARENT_FIELD};
+pub use visitor::PendingFields;
+pub use visitor::{BYTES_SUFFIX, COUNT_SUFFIX, DURATION_SUFFIX, HUMAN_SUFFIX, TIMESTAMP_SUFFIX};
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use auth::TokenSource;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use circuit::CircuitBreaker;
+use cloud_trace::SpanExporter;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use console::Console;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use entry::StaticMetadata;
+use environment::MetadataServer;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use error_reporting::ErrorReporter;
+use format::FormattedSink;
+use handle::{EntrySender, SharedState};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use monitoring::MetricsExporter;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use pipeline::Pipeline;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use retry::RetryQueue;
+use structured::StructuredStdout;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use sync_writer::SyncWriter;
+use trace::TraceLookup;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use transport::{ForbiddenReport, Transport};
+use visitor::{FieldOptions, FieldVisitor};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use worker::Worker;
+
 /// Metadata for a container, capturing its ID and name
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ContainerMetadata {
     /// Unique identifier of the container
     pub id: String,
@@ -24,20 +185,81 @@ pub struct ContainerMetadata {
 }
 
 /// Metadata for a Google Compute Engine instance
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct InstanceMetadata {
     /// Name of the instance
     pub name: String,
     /// Unique identifier of the instance
     pub id: String,
-    /// Zone where the instance is located
+    /// Zone where the instance is located, e.g. `us-central1-a`
     pub zone: String,
+    /// Region containing the zone, e.g. `us-central1`
+    pub region: String,
     /// Google Cloud project ID
     pub project_id: String,
 }
 
+/// Where the container ID is read from
+pub(crate) const CGROUP_PATH: &str = "/proc/self/cgroup";
+
+impl ContainerMetadata {
+    /// Read the container metadata from a cgroup file
+    ///
+    /// The ID is the last path segment of the first cgroup entry and the name
+    /// comes from `$HOSTNAME`, which container runtimes set to the container
+    /// (or pod) name.
+    ///
+    /// # Arguments
+    /// * `cgroup_path` - The cgroup file, normally `/proc/self/cgroup`
+    ///
+    /// # Returns
+    /// None if the file is missing or has no entry with a path
+    pub fn from_cgroup(cgroup_path: impl AsRef<std::path::Path>) -> Option<Self> {
+        let id = std::fs::read_to_string(cgroup_path)
+            .ok()?
+            .lines()
+            .find_map(|line| line.rfind('/').map(|pos| line[pos + 1..].to_string()))?;
+        Some(Self {
+            id,
+            name: std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".into()),
+        })
+    }
+}
+
+/// Detect the container metadata of this process
+///
+/// # Arguments
+/// * `collect` - Whether to read cgroups; None reads them only if the file exists
+/// * `cgroup_path` - The cgroup file, normally `/proc/self/cgroup`
+fn detect_container(
+    collect: Option<bool>,
+    cgroup_path: &std::path::Path,
+) -> Option<ContainerMetadata> {
+    match collect {
+        Some(false) => None,
+        None if !cgroup_path.exists() => None,
+        _ => ContainerMetadata::from_cgroup(cgroup_path),
+    }
+}
+
+impl InstanceMetadata {
+    /// Placeholder metadata used when the instance cannot be detected
+    ///
+    /// # Arguments
+    /// * `project_id` - The Google Cloud project ID, kept as given
+    fn unknown(project_id: String) -> Self {
+        Self {
+            name: "unknown".into(),
+            id: "0".into(),
+            zone: "".into(),
+            region: "".into(),
+            project_id,
+        }
+    }
+}
+
 /// Comprehensive logging context metadata
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct LogContextMetadata {
     /// Optional container metadata
     pub container: Option<ContainerMetadata>,
@@ -45,21 +267,114 @@ pub struct LogContextMetadata {
     pub instance: InstanceMetadata,
 }
 
-/// Structured log entry for Google Cloud Logging
-#[derive(Debug, Serialize)]
-struct GcpLogEntry {
-    /// Log message content
-    message: String,
-    /// Severity level of the log entry
-    severity: String,
-}
-
 /// Custom logging layer for sending logs to Google Cloud Logging
+///
+/// A disabled layer (see `GcpLoggingLayerBuilder::enabled`) still implements
+/// `Layer`, so it can stay in the subscriber stack and simply ignore events.
 pub struct GcpLoggingLayer {
-    /// Channel for sending log entries
-    channel: UnboundedSender<GcpLogEntry>,
+    /// Delivery path for log entries, discarding them when the layer is disabled
+    sender: EntrySender,
+    /// Options applied while capturing events
+    config: LayerConfig,
+    /// State shared with handles and the background task
+    state: Arc<SharedState>,
+    /// Reads the trace context of the span an event is emitted in
+    trace: TraceLookup,
+    /// Sends closed spans to Cloud Trace, if enabled
+    span_exporter: Option<SpanExporter>,
 }
 
+/// Options consulted by `on_event` for every captured event
+pub(crate) struct LayerConfig {
+    /// Parse `Debug`-only field values that look like JSON
+    pub(crate) parse_json_debug: bool,
+    /// Per-target level directives deciding which events are shipped
+    pub(crate) targets: Option<Targets>,
+    /// Emit entries when spans open and close
+    pub(crate) log_span_lifecycle: bool,
+    /// How the fields of enclosing spans are added to event payloads
+    pub(crate) span_fields: SpanFieldMode,
+    /// Append a `key=value` rendering of the fields to the message
+    pub(crate) summarize_fields: bool,
+    /// Most fields collected per event, None for no limit
+    pub(crate) max_fields: Option<usize>,
+    /// Severity used when an event's metadata carries no level
+    pub(crate) default_severity: Severity,
+    /// Split multi-line messages into at most this many entries, None to keep them whole
+    pub(crate) split_lines: Option<usize>,
+    /// Target prefixes whose events and spans are never shipped
+    pub(crate) ignored_targets: Vec<String>,
+    /// Disable unshipped call sites for the whole subscriber rather than only this layer
+    pub(crate) global_filter: bool,
+    /// Keep `_bytes` and `_count` fields numeric and add `_human` companions
+    pub(crate) human_units: bool,
+    /// Labels computed from the caller's context for every captured entry
+    pub(crate) label_resolvers: Vec<(String, LabelResolver)>,
+    /// Merge the keys of messages that are JSON objects into the payload
+    pub(crate) parse_json_messages: bool,
+    /// Remove ANSI escape sequences from messages
+    pub(crate) strip_ansi: bool,
+    /// Keep stack traces in one entry, under `stack_trace`
+    pub(crate) group_stack_traces: bool,
+    /// Assign Cloud Trace IDs to spans and export them, if enabled
+    pub(crate) trace_export: Option<TraceExport>,
+}
+
+/// Callback computing a label's value on the thread capturing an entry
+pub(crate) type LabelResolver = Arc<dyn Fn() -> Option<String> + Send + Sync>;
+
+impl LayerConfig {
+    /// How captured field values are converted
+    ///
+    /// # Arguments
+    /// * `event` - Whether the fields are an event's, which are limited,
+    ///   summarized and merged with span fields, rather than a span's
+    pub(crate) fn field_options(&self, event: bool) -> FieldOptions {
+        FieldOptions {
+            parse_json_debug: self.parse_json_debug,
+            human_units: self.human_units,
+            max_fields: self.max_fields.filter(|_| event),
+            summarize: self.summarize_fields && event,
+            span_context: self.span_fields == SpanFieldMode::Nested,
+            // Span fields are mostly `#[instrument]` arguments, recorded with `Debug`.
+            unquote_debug: !event,
+            parse_json_message: self.parse_json_messages && event,
+            strip_ansi: self.strip_ansi && event,
+            group_stack_traces: self.group_stack_traces && event,
+        }
+    }
+}
+
+/// Target prefixes ignored unless configured otherwise
+///
+/// The HTTP and auth stacks used for uploads can emit events of their own; if
+/// this layer shipped them, a failing upload could log an event whose upload
+/// fails in turn. The crate's own target is included for the same reason.
+pub const DEFAULT_IGNORED_TARGETS: [&str; 5] = [
+    "hyper",
+    "hyper_util",
+    "reqwest",
+    "gcp_auth",
+    "nano_gcp_logging",
+];
+
+/// Whether a target is a module path or its descendant
+///
+/// # Arguments
+/// * `target` - The event or span target, e.g. `hyper::client::pool`
+/// * `prefix` - The module path, e.g. `hyper`, which `hyper_util` is not in
+fn within(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Name of the thread running the background tasks, or the blocking sender
+pub(crate) const THREAD_NAME: &str = "nano-gcp-logging";
+
+/// Number of multi-line events split so far, making their operation ids unique
+static SPLIT_EVENTS: AtomicU64 = AtomicU64::new(0);
+
 impl GcpLoggingLayer {
     /// Create a new GcpLoggingLayer with authentication and log metadata
     ///
@@ -68,133 +383,372 @@ impl GcpLoggingLayer {
     ///
     /// # Returns
     /// A Result containing the initialized GcpLoggingLayer or an error
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
     pub async fn new(project_id: String) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::builder(project_id).build().await
+    }
+
+    /// Create a builder to customize the layer before it starts
+    ///
+    /// # Arguments
+    /// * `project_id` - The Google Cloud project ID
+    pub fn builder(project_id: impl Into<String>) -> GcpLoggingLayerBuilder {
+        GcpLoggingLayerBuilder::new(project_id)
+    }
+
+    /// Start the layer from a validated builder
+    ///
+    /// Background tasks are spawned on the current Tokio runtime.
+    ///
+    /// # Returns
+    /// The layer and its background tasks, which end once every sender is
+    /// dropped or the layer has shut down
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) async fn start(
+        builder: GcpLoggingLayerBuilder,
+    ) -> Result<(Self, Vec<JoinHandle<()>>), Box<dyn std::error::Error>> {
+        let GcpLoggingLayerBuilder {
+            project_id,
+            instance,
+            container,
+            heartbeat_interval,
+            config,
+            batch_policy,
+            mut entry_config,
+            enabled,
+            sync_write,
+            shipped_targets: _,
+            dedicated_runtime: _,
+            user_agent_suffix,
+            http_client,
+            circuit_breaker,
+            stdout_fallback,
+            structured_stdout,
+            output_format,
+            sink,
+            retry_policy,
+            #[cfg(feature = "grpc")]
+            grpc,
+            host_id,
+            token_provider,
+            quota_project,
+            request_hook,
+            startup_metadata,
+            collect_container,
+            on_drop,
+            error_reporting,
+            metrics_export,
+            diagnostics_file,
+        } = builder;
+
+        // A disabled layer skips auth, metadata and the background task entirely.
+        if !enabled || disabled_by_env() {
+            let layer = Self {
+                sender: EntrySender::default(),
+                config,
+                state: Arc::new(SharedState::new(&entry_config)),
+                trace: TraceLookup::default(),
+                span_exporter: None,
+            };
+            return Ok((layer, Vec::new()));
+        }
+        if let Some(diagnostics_file) = diagnostics_file {
+            diagnostics::install(diagnostics_file)?;
+        }
+
+        if let Some(discover) = startup_metadata {
+            for (key, value) in discover().await {
+                entry_config.labels.entry(key).or_insert(value);
+            }
+        }
+
         // Try to initialize authentication, but allow initialization to succeed
         // even if authentication is not available (e.g. in tests or local dev).
-        // In such cases we proceed with an empty token and continue sending logs
-        // best-effort (requests will be unauthenticated).
-        let token = match AuthenticationManager::new().await {
-            Ok(auth) => match auth
-                .get_token(&["https://www.googleapis.com/auth/logging.write"])
-                .await
-            {
-                // Convert the acquired Token to a String representation so `token`
-                // has a consistent `String` type across all match arms.
-                Ok(tok) => tok.as_str().to_string(),
-                Err(e) => {
-                    eprintln!(
-                        "Warning: failed to acquire GCP token: {}. Proceeding without auth.",
-                        e
-                    );
-                    String::new()
-                }
-            },
-            Err(e) => {
-                eprintln!("Warning: failed to initialize AuthenticationManager: {}. Proceeding without auth.", e);
-                String::new()
+        // In such cases the background task drops entries instead of sending them.
+        let client = http_client.unwrap_or_default();
+        let state = Arc::new(SharedState::new(&entry_config));
+        // Structured stdout needs no credentials; Cloud Run's agent does the upload.
+        // Neither do other formats, which are never uploaded.
+        let reformat = output_format != OutputFormat::CloudLogging;
+        let auth = if structured_stdout || reformat {
+            None
+        } else {
+            TokenSource::acquire(token_provider, state.token_status.clone()).await
+        };
+        let sink = local_sink(sink, output_format, structured_stdout);
+
+        // With nowhere to deliver entries, capturing events would be wasted work.
+        let drops_entries = auth.is_none() && !stdout_fallback && sink.is_none();
+        if drops_entries {
+            warning!("no GCP auth token available; log entries will not be sent. Set up authentication to enable sending.");
+        }
+
+        // Attempt to collect metadata, but fall back to sensible defaults when
+        // the metadata server cannot be reached.
+        let metadata_root = environment::metadata_root_url(&environment::metadata_host());
+        let server = AsyncMetadata {
+            client: &client,
+            root: &metadata_root,
+        };
+        let metadata = resolve_metadata(
+            project_id,
+            instance,
+            container,
+            collect_container,
+            host_id,
+            &mut entry_config,
+            &server,
+        )
+        .await;
+
+        let (channel, rx) = unbounded_channel::<handle::Message>();
+
+        let transport = Transport::new(client, auth, user_agent_suffix.as_deref())
+            .with_request_options(quota_project, request_hook)
+            .with_metrics(state.metrics.clone());
+        #[cfg(feature = "grpc")]
+        let transport = if grpc {
+            transport.with_grpc()
+        } else {
+            transport
+        };
+        // Entries are encoded for the API the bodies are sent to.
+        #[cfg(feature = "grpc")]
+        if transport.uses_grpc() {
+            entry_config.encoder = Arc::new(grpc::ProtoEncoder);
+        }
+
+        let metadata = Arc::new(StaticMetadata::new(metadata));
+
+        // Reports are only sent along with uploads.
+        let mut tasks = Vec::new();
+        let error_reporter = match error_reporting {
+            Some(config) if transport.is_authenticated() && sink.is_none() => {
+                let project_id = metadata.context.instance.project_id.clone();
+                let (reporter, task) = ErrorReporter::spawn(config, transport.clone(), project_id);
+                tasks.push(task);
+                Some(reporter)
+            }
+            _ => None,
+        };
+        let span_exporter = match &config.trace_export {
+            Some(export) if transport.is_authenticated() && sink.is_none() => {
+                let project_id = metadata.context.instance.project_id.clone();
+                let (exporter, task) = SpanExporter::spawn(export, transport.clone(), project_id);
+                tasks.push(task);
+                Some(exporter)
             }
+            _ => None,
         };
+        if let Some(export) =
+            metrics_export.filter(|_| transport.is_authenticated() && sink.is_none())
+        {
+            let exporter =
+                MetricsExporter::new(export, transport.clone(), &metadata.context.instance);
+            tasks.push(exporter.spawn(state.shutdown.clone()));
+        }
 
-        // Attempt to collect metadata, but fall back to sensible defaults on error.
-        // We clone project_id to allow creating a fallback instance that still
-        // contains the provided project id in case metadata lookup fails.
-        let metadata = match collect_log_metadata(project_id.clone()).await {
-            Ok(m) => m,
-            Err(e) => {
-                eprintln!(
-                    "Warning: failed to collect metadata: {}. Using fallback values.",
-                    e
-                );
-                LogContextMetadata {
-                    container: None,
-                    instance: InstanceMetadata {
-                        name: "unknown".into(),
-                        id: "0".into(),
-                        zone: "".into(),
-                        project_id,
-                    },
-                }
+        // Critical events bypass the queue; without auth they could not be sent
+        // anyway, and with a sink nothing is uploaded.
+        let sync_writer = match sync_write {
+            Some((min_severity, timeout)) if transport.is_authenticated() && sink.is_none() => {
+                Some(Arc::new(
+                    SyncWriter::start(
+                        &transport,
+                        metadata.clone(),
+                        entry_config.clone(),
+                        min_severity,
+                        timeout,
+                    )?
+                    .with_error_reporter(error_reporter.clone()),
+                ))
             }
+            _ => None,
         };
 
-        let (channel, mut rx) = unbounded_channel::<GcpLogEntry>();
-        let client = reqwest::Client::new();
+        let retry_queue = Arc::new(RetryQueue::new(retry_policy, state.metrics.clone()));
+        let retry_task = retry_queue.spawn(transport.clone(), state.circuit_state.clone());
 
         // Spawn the background task that drains the channel and sends logs.
-        // If we don't have a token (empty string), skip sending entries to avoid noisy errors.
-        tokio::spawn(async move {
-            // If token is empty we will not attempt HTTP requests; warn once.
-            let mut warned_no_auth = false;
-            let skip_sending = token.is_empty();
-
-            loop {
-                let log_entry = rx.recv().await;
-                if log_entry.is_none() {
-                    tokio::time::sleep(Duration::from_millis(1)).await;
-                    continue;
-                }
-                let log_entry = log_entry.unwrap();
-
-                if skip_sending {
-                    if !warned_no_auth {
-                        eprintln!("Warning: no GCP auth token available; log entries will not be sent. Set up authentication to enable sending.");
-                        warned_no_auth = true;
-                    }
-                    // Drop the entry without attempting to send it.
-                    continue;
-                }
-
-                let entry = serde_json::json!({
-                    "logName": format!("projects/{}/logs/proxie", metadata.instance.project_id),
-                    "resource": {
-                        "type": "gce_instance",
-                        "labels": {
-                            "instance_id": metadata.instance.id,
-                            "zone": metadata.instance.zone,
-                            "project_id": metadata.instance.project_id
-                        }
-                    },
-                    "severity": log_entry.severity,
-                    "jsonPayload": {
-                        "message": log_entry.message,
-                        "container": metadata.container,
-                        "instance": metadata.instance
-                    }
-                });
-                let body = serde_json::json!({ "entries": [entry] });
-
-                // Build the request and conditionally add auth if available.
-                let mut req = client
-                    .post("https://logging.googleapis.com/v2/entries:write")
-                    .json(&body);
-                if !token.is_empty() {
-                    req = req.bearer_auth(token.as_str());
-                }
+        // If we don't have a token (empty string), it skips sending entries to avoid noisy errors.
+        let skip_sending = !transport.is_authenticated();
+        let flush_on = batch_policy.flush_on;
+        let pipeline = Pipeline::new(metadata, entry_config, batch_policy, state.metrics.clone())
+            .with_local_delivery(
+                sink,
+                stdout_fallback.then(Console::new),
+                on_drop.clone(),
+                skip_sending,
+            );
+        let worker_task = Worker {
+            transport,
+            pipeline,
+            heartbeat_interval,
+            circuit_breaker: CircuitBreaker::new(
+                circuit_breaker,
+                state.circuit_state.clone(),
+                state.metrics.clone(),
+            ),
+            forbidden: ForbiddenReport::default(),
+            retry_queue,
+            shutdown: state.shutdown.clone(),
+            error_reporter,
+        }
+        .spawn(rx);
 
-                let res = req.send().await;
-                if let Err(e) = res {
-                    eprintln!("Failed to send log entry: {}", e);
-                }
-            }
-        });
+        let layer = Self {
+            sender: EntrySender {
+                channel: Some(channel),
+                sync_writer,
+                drops_entries,
+                metrics: state.metrics.clone(),
+                on_drop,
+                flush_on,
+                runtime_labels: state.labels.clone(),
+            },
+            config,
+            state,
+            trace: TraceLookup::default(),
+            span_exporter,
+        };
+        tasks.extend([worker_task, retry_task]);
+        Ok((layer, tasks))
+    }
 
-        Ok(Self { channel })
+    /// Get a handle for interacting with the layer after it is moved into a subscriber
+    ///
+    /// # Returns
+    /// A cloneable GcpLoggingHandle sharing this layer's channel
+    pub fn handle(&self) -> GcpLoggingHandle {
+        GcpLoggingHandle {
+            sender: self.sender.clone(),
+            state: self.state.clone(),
+        }
     }
 
     /// Map tracing log level to Google Cloud Logging severity
     ///
+    /// `tracing` metadata always carries a level today, but events forwarded by
+    /// bridges are not guaranteed to, so a missing level falls back to the
+    /// configured default instead of being guessed.
+    ///
+    /// # Arguments
+    /// * `level` - The tracing log level, if any
+    ///
+    /// # Returns
+    /// The corresponding Cloud Logging severity
+    fn map_level_to_severity(&self, level: Option<&tracing::Level>) -> Severity {
+        level.map_or(self.config.default_severity, Severity::from)
+    }
+
+    /// Queue an event's entry, split into one entry per line if enabled
+    ///
+    /// The pieces share an `operation` so the Logs Explorer groups them, and
+    /// each keeps the event's fields. The last piece holds whatever remains
+    /// once `split_lines` entries are reached.
+    ///
     /// # Arguments
-    /// * `level` - The tracing log level
+    /// * `log_entry` - The entry captured from the event
+    fn send_event(&self, log_entry: entry::GcpLogEntry) {
+        let max = match self.config.split_lines {
+            // Pretty-printed JSON and stack traces are kept whole rather than split.
+            Some(max)
+                if log_entry.message.contains('\n')
+                    && !(self.config.parse_json_messages
+                        && visitor::looks_like_json_object(&log_entry.message))
+                    && !(self.config.group_stack_traces
+                        && visitor::stack_trace_start(&log_entry.message).is_some()) =>
+            {
+                max
+            }
+            _ => {
+                let result = self.sender.send(log_entry);
+                if result.is_err() {
+                    diagnostic!("Error {:?}", result);
+                }
+                return;
+            }
+        };
+        let id = format!(
+            "{:x}-split-{:x}",
+            std::process::id(),
+            SPLIT_EVENTS.fetch_add(1, Ordering::Relaxed)
+        );
+        let producer = log_entry
+            .callsite
+            .map_or_else(String::new, |callsite| callsite.target().to_string());
+        let lines: Vec<&str> = log_entry.message.splitn(max, '\n').collect();
+        for (index, line) in lines.iter().enumerate() {
+            let last = index + 1 == lines.len();
+            let pending = log_entry.pending.clone();
+            let result = self.sender.send(entry::GcpLogEntry {
+                message: line.trim_end_matches('\r').to_string(),
+                severity: log_entry.severity,
+                fields: log_entry.fields.clone(),
+                timestamp: log_entry.timestamp,
+                source_location: log_entry.source_location.clone(),
+                operation: Some(entry::Operation {
+                    id: id.clone(),
+                    producer: producer.clone(),
+                    first: index == 0,
+                    last,
+                }),
+                trace: log_entry.trace.clone(),
+                labels: log_entry.labels.clone(),
+                runtime_labels: None,
+                internal: log_entry.internal,
+                callsite: log_entry.callsite,
+                // The field summary follows the end of the message.
+                pending: if last {
+                    pending
+                } else {
+                    pending.without_summary()
+                },
+            });
+            if result.is_err() {
+                diagnostic!("Error {:?}", result);
+            }
+        }
+    }
+
+    /// Compute the labels of an entry being captured
+    ///
+    /// Runs on the caller's thread, where task-locals and thread-locals
+    /// holding e.g. the current tenant are still reachable.
     ///
     /// # Returns
-    /// A static string representing the corresponding severity level
-    fn map_level_to_severity(level: &tracing::Level) -> &'static str {
-        match *level {
-            tracing::Level::ERROR => "ERROR",
-            tracing::Level::WARN => "WARNING",
-            tracing::Level::INFO => "INFO",
-            tracing::Level::DEBUG => "DEBUG",
-            tracing::Level::TRACE => "DEBUG",
+    /// The labels whose resolver returned a value
+    pub(crate) fn resolve_labels(&self) -> BTreeMap<String, String> {
+        self.config
+            .label_resolvers
+            .iter()
+            .filter_map(|(key, resolve)| Some((key.clone(), resolve()?)))
+            .collect()
+    }
+
+    /// Check whether events or spans with the given metadata are shipped
+    ///
+    /// Ignored targets are applied here rather than in `Layer::enabled`, which
+    /// would disable the event for every layer in the stack, unless
+    /// `global_filter` asks for exactly that. Directives are only checked here
+    /// in `global_filter` mode; otherwise `ShippedTargets` filters them out
+    /// before they reach the layer.
+    ///
+    /// # Arguments
+    /// * `metadata` - The event or span metadata
+    fn ships(&self, metadata: &Metadata<'_>) -> bool {
+        let target = metadata.target();
+        if self
+            .config
+            .ignored_targets
+            .iter()
+            .any(|prefix| within(target, prefix))
+        {
+            return false;
+        }
+        match &self.config.targets {
+            Some(targets) => targets.would_enable(metadata.target(), metadata.level()),
+            None => true,
         }
     }
 }
@@ -203,42 +757,167 @@ impl<S> Layer<S> for GcpLoggingLayer
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
-    /// Process log events and send them to Google Cloud Logging
+    /// Remember the subscriber so span trace contexts can be looked up
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.trace.register(subscriber);
+    }
+
+    /// With `global_filter`, disable call sites that are never shipped once and for all
+    ///
+    /// Targets and directives never change after the layer is built, so the
+    /// decision holds for every event of the call site and `tracing` skips
+    /// them without recording or even checking them again.
     ///
     /// # Arguments
-    /// * `event` - The log event to process
+    /// * `metadata` - The call site's metadata
+    ///
+    /// # Returns
+    /// `Interest::never()` for unshipped call sites in global mode, otherwise
+    /// `Interest::always()`
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if self.config.global_filter && !self.ships(metadata) {
+            Interest::never()
+        } else {
+            Interest::always()
+        }
+    }
+
+    /// With `global_filter`, reject events and spans that are never shipped
+    ///
+    /// Only consulted for call sites whose interest was not cached, e.g. after
+    /// `tracing::callsite::rebuild_interest_cache`.
+    ///
+    /// # Arguments
+    /// * `metadata` - The event or span metadata
     /// * `_ctx` - The tracing context
-    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
-        let mut message = "**UNDEFINED**".to_string();
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        !self.config.global_filter || self.ships(metadata)
+    }
 
-        event.record(
-            &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
-                if field.name() == "message" {
-                    message = format!("{:?}", value);
-                }
-            },
-        );
-        let now = Local::now().format("%Y-%m-%d %H:%M:%S,%3f").to_string();
+    /// With `global_filter`, the most verbose level any directive ships
+    ///
+    /// The subscriber combines the hints of its layers into a global maximum,
+    /// so `tracing` skips e.g. `TRACE` events at the macro when no directive
+    /// enables them. Outside global mode there is no hint, since other layers
+    /// may want those levels.
+    ///
+    /// # Returns
+    /// The filter's most verbose level, None without `global_filter` or `filter`
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let targets = self
+            .config
+            .targets
+            .as_ref()
+            .filter(|_| self.config.global_filter)?;
+        Layer::<S>::max_level_hint(targets)
+    }
 
-        let metadata = event.metadata();
-        let severity = Self::map_level_to_severity(metadata.level()).to_string();
-        let message = format!(
-            "[{}] {} [{} {}:{}] [{}]",
-            now,
-            severity,
-            metadata.target(),
-            metadata.file().unwrap_or("unknown_file"),
-            metadata.line().unwrap_or(0),
-            message
-        );
+    /// Record a new span's fields and emit its opening entry if enabled
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.sender.discards_entries() {
+            self.open_span(attrs, id, ctx);
+        }
+    }
 
-        let log_entry = GcpLogEntry { severity, message };
+    /// Keep fields recorded after a span opened
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if !self.sender.discards_entries() {
+            self.record_span(id, values, ctx);
+        }
+    }
 
-        let result = self.channel.send(log_entry);
-        if result.is_err() {
-            eprintln!("Error {:?}", result);
+    /// Emit an entry with the elapsed time and export the span when it closes
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if self.sender.discards_entries() {
+            return;
+        }
+        if self.config.log_span_lifecycle {
+            self.close_span(&id, &ctx);
+        }
+        if let Some(exporter) = &self.span_exporter {
+            self.export_span(exporter, &id, &ctx);
         }
     }
+
+    /// Process log events and send them to Google Cloud Logging
+    ///
+    /// # Arguments
+    /// * `event` - The log event to process
+    /// * `ctx` - The tracing context
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if self.sender.discards_entries() {
+            return;
+        }
+
+        let metadata = event.metadata();
+        // In global mode, unshipped events never reach the layer.
+        if !self.config.global_filter && !self.ships(metadata) {
+            return;
+        }
+
+        // Only rendering happens here; the background task converts the
+        // fields and merges them with the span fields collected below.
+        let mut visitor = FieldVisitor::new().max_fields(self.config.max_fields);
+        event.record(&mut visitor);
+        let header_trace = self.header_trace(event, &ctx, &mut visitor);
+        let message = visitor
+            .message
+            .take()
+            .unwrap_or_else(|| "**UNDEFINED**".to_string());
+        let mut fields = serde_json::Map::new();
+        self.add_span_fields(event, &ctx, &mut fields);
+        context::add_context_fields(&mut fields);
+        let trace = self
+            .trace
+            .lookup(ctx.event_span(event).map(|span| span.id()).as_ref())
+            .or_else(|| self.span_trace(event, &ctx))
+            .or(header_trace);
+        let timestamp = Utc::now();
+        let severity = self.map_level_to_severity(Some(metadata.level()));
+
+        let log_entry = entry::GcpLogEntry {
+            severity,
+            message,
+            fields,
+            timestamp,
+            source_location: None,
+            operation: None,
+            trace,
+            labels: self.resolve_labels(),
+            runtime_labels: None,
+            internal: false,
+            callsite: Some(metadata),
+            pending: visitor.into_pending(self.config.field_options(true)),
+        };
+
+        self.send_event(log_entry);
+    }
+}
+
+/// Environment variable that turns the layer into a no-op when set to `1` or `true`
+pub const DISABLED_ENV_VAR: &str = "NANO_GCP_LOGGING_DISABLED";
+
+/// Environment variable silencing the layer's stderr warnings when set to `1` or `true`
+///
+/// Missing credentials or metadata are worth a warning in production but are
+/// expected in tests and CI. Upload errors are still reported.
+pub const QUIET_ENV_VAR: &str = "NANO_GCP_LOGGING_QUIET";
+
+/// Check whether the layer has been disabled through `DISABLED_ENV_VAR`
+fn disabled_by_env() -> bool {
+    env_flag(DISABLED_ENV_VAR)
+}
+
+/// Check whether warnings have been silenced through `QUIET_ENV_VAR`
+pub(crate) fn quiet_by_env() -> bool {
+    env_flag(QUIET_ENV_VAR)
+}
+
+/// Check whether a boolean environment variable is set to `1` or `true`
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
 }
 
 /// Collect comprehensive log metadata for the current instance
@@ -248,78 +927,363 @@ where
 ///
 /// # Returns
 /// A Result containing the LogContextMetadata or an error
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
 pub async fn collect_log_metadata(
     project_id: String,
 ) -> Result<LogContextMetadata, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+    let root = environment::metadata_root_url(&environment::metadata_host());
+    let client = client::Client::new();
+    let server = AsyncMetadata {
+        client: &client,
+        root: &root,
+    };
+    let container = detect_container(None, server.cgroup_path());
+    Ok(collect_metadata(project_id, &server, container).await)
+}
 
-    let container_id = get_container_id();
-    let container_name = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".into());
+/// Collect comprehensive log metadata using an existing HTTP client
+///
+/// Lets callers share one client (and its timeout/proxy settings and
+/// connection pool) between metadata lookups and log uploads. The metadata
+/// server host is taken from `GCE_METADATA_HOST` when set.
+///
+/// # Arguments
+/// * `project_id` - The Google Cloud project ID
+/// * `client` - The client used to query the metadata server
+///
+/// # Returns
+/// A Result containing the LogContextMetadata or an error
+#[cfg(feature = "reqwest")]
+pub async fn collect_log_metadata_with_client(
+    project_id: String,
+    client: &reqwest::Client,
+) -> Result<LogContextMetadata, Box<dyn std::error::Error>> {
+    let root = environment::metadata_root_url(&environment::metadata_host());
+    let server = AsyncMetadata {
+        client,
+        root: &root,
+    };
+    let container = detect_container(None, server.cgroup_path());
+    Ok(collect_metadata(project_id, &server, container).await)
+}
 
-    let container_metadata = container_id.clone().map(|id| ContainerMetadata {
-        id,
-        name: container_name,
-    });
+/// Collect comprehensive log metadata from the metadata server on a given host
+///
+/// Lets tests serve fake instance data from a mock server.
+///
+/// # Arguments
+/// * `project_id` - The Google Cloud project ID
+/// * `client` - The client used to query the metadata server
+/// * `host` - The metadata server host and optional port, e.g. `127.0.0.1:8080`
+///
+/// # Returns
+/// A Result containing the LogContextMetadata or an error
+#[cfg(feature = "reqwest")]
+pub async fn collect_log_metadata_from_host(
+    project_id: String,
+    client: &reqwest::Client,
+    host: &str,
+) -> Result<LogContextMetadata, Box<dyn std::error::Error>> {
+    let root = environment::metadata_root_url(host);
+    let server = AsyncMetadata {
+        client,
+        root: &root,
+    };
+    let container = detect_container(None, server.cgroup_path());
+    Ok(collect_metadata(project_id, &server, container).await)
+}
 
-    let instance_name = get_metadata(&client, "instance/name")
-        .await
-        .unwrap_or_default();
-    let instance_id = get_metadata(&client, "instance/id")
-        .await
-        .unwrap_or_default();
-    let zone_path = get_metadata(&client, "instance/zone")
-        .await
-        .unwrap_or_default();
-    let zone = zone_path.split('/').last().unwrap_or("").to_string();
+/// Collect log metadata, querying the metadata server for the instance
+///
+/// # Arguments
+/// * `project_id` - The Google Cloud project ID
+/// * `server` - The metadata server
+/// * `container` - The container metadata attached to entries, if any
+async fn collect_metadata(
+    project_id: String,
+    server: &impl MetadataServer,
+    container: Option<ContainerMetadata>,
+) -> LogContextMetadata {
+    let instance_name = server.get("instance/name").await.unwrap_or_default();
+    let instance_id = server.get("instance/id").await.unwrap_or_default();
+    let zone_path = server.get("instance/zone").await.unwrap_or_default();
+    let zone = zone_path.split('/').next_back().unwrap_or("").to_string();
+    let region = region_from_zone(&zone);
 
-    Ok(LogContextMetadata {
-        container: container_metadata,
+    LogContextMetadata {
+        container,
         instance: InstanceMetadata {
             name: instance_name,
             id: instance_id,
             zone,
+            region,
             project_id,
         },
-    })
+    }
 }
 
-/// Retrieve the current container ID from cgroup
+/// Work out the metadata attached to entries, detecting what was not configured
 ///
-/// # Returns
-/// An optional container ID string
-fn get_container_id() -> Option<String> {
-    std::fs::read_to_string("/proc/self/cgroup")
-        .ok()?
-        .lines()
-        .find_map(|line| {
-            if let Some(pos) = line.rfind('/') {
-                Some(line[pos + 1..].to_string())
-            } else {
-                None
+/// An explicit instance skips the metadata server. Otherwise it is queried,
+/// with placeholders off GCP. Container metadata not configured is read from
+/// cgroups in every case.
+///
+/// # Arguments
+/// * `project_id` - The Google Cloud project ID, kept as given
+/// * `instance` - Instance metadata set on the builder, if any
+/// * `container` - Container metadata set on the builder, if any
+/// * `collect_container` - Whether to read container metadata, None for auto
+/// * `host_id` - Where to read a stable ID for hosts the server does not name
+/// * `entry_config` - Entry options, given the Cloud Run resource when detected
+/// * `server` - The metadata server
+async fn resolve_metadata(
+    project_id: String,
+    instance: Option<InstanceMetadata>,
+    container: Option<ContainerMetadata>,
+    collect_container: Option<bool>,
+    host_id: Option<HostIdSource>,
+    entry_config: &mut entry::EntryConfig,
+    server: &impl MetadataServer,
+) -> LogContextMetadata {
+    let detect_instance = instance.is_none();
+    // Cgroups describe the process wherever it runs, on GCP or not.
+    let container = container.or_else(|| detect_container(collect_container, server.cgroup_path()));
+    let mut metadata = if let Some(instance) = instance {
+        LogContextMetadata {
+            container,
+            instance,
+        }
+    } else if entry_config.resource.is_some() {
+        // The resource is used verbatim, so there is nothing to detect.
+        LogContextMetadata {
+            container,
+            instance: InstanceMetadata::unknown(project_id),
+        }
+    } else if !server.on_gcp().await {
+        // Off GCP every metadata query would fail, so skip them altogether.
+        environment::warn_off_gcp();
+        LogContextMetadata {
+            container,
+            instance: InstanceMetadata::unknown(project_id),
+        }
+    } else {
+        collect_metadata(project_id, server, container).await
+    };
+
+    // Cloud Run serves GCE-style metadata too, but its logs belong to the revision.
+    if entry_config.resource.is_none() && resource::on_cloud_run() {
+        if detect_instance && server.on_gcp().await {
+            let region = server.get("instance/region").await;
+            if let Some(region) = region.as_deref().and_then(|r| r.rsplit('/').next()) {
+                metadata.instance.region = region.to_string();
             }
-        })
+        }
+        entry_config.resource =
+            MonitoredResource::cloud_run(&metadata.instance.project_id, &metadata.instance.region);
+    }
+
+    // The placeholder ID would make every host off GCE look the same.
+    let id_unknown = metadata.instance.id.is_empty() || metadata.instance.id == "0";
+    if let Some(source) = host_id.filter(|_| detect_instance && id_unknown) {
+        if let Some(id) = source.resolve() {
+            metadata.instance.id = id;
+        }
+    }
+    metadata
+}
+
+/// The sink entries are written to instead of being uploaded, if any
+///
+/// # Arguments
+/// * `sink` - Sink set on the builder, if any
+/// * `output_format` - Schema of the entries; only Cloud Logging's is uploaded
+/// * `structured_stdout` - Whether entries are printed for Cloud Run's agent
+fn local_sink(
+    sink: Option<Arc<dyn sink::LogSink>>,
+    output_format: OutputFormat,
+    structured_stdout: bool,
+) -> Option<Arc<dyn sink::LogSink>> {
+    match sink {
+        inner if output_format != OutputFormat::CloudLogging => Some(Arc::new(FormattedSink {
+            format: output_format,
+            inner,
+        })),
+        None if structured_stdout => Some(Arc::new(StructuredStdout)),
+        sink => sink,
+    }
+}
+
+/// Derive the region from a zone name
+///
+/// Zones are named `{region}-{letter}` (e.g. `us-central1-a`), so the trailing
+/// single-letter suffix is stripped. Values without such a suffix (e.g. a
+/// region name or an empty string) are returned unchanged.
+///
+/// # Arguments
+/// * `zone` - The zone name
+///
+/// # Returns
+/// The region name
+pub fn region_from_zone(zone: &str) -> String {
+    match zone.rsplit_once('-') {
+        Some((region, suffix))
+            if suffix.len() == 1
+                && suffix.chars().all(|c| c.is_ascii_alphabetic())
+                && region.ends_with(|c: char| c.is_ascii_digit()) =>
+        {
+            region.to_string()
+        }
+        _ => zone.to_string(),
+    }
+}
+
+/// The metadata server, queried with the async client
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+struct AsyncMetadata<'a> {
+    /// The HTTP client
+    client: &'a client::Client,
+    /// The root URL of the metadata API
+    root: &'a str,
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+impl MetadataServer for AsyncMetadata<'_> {
+    async fn on_gcp(&self) -> bool {
+        RuntimeEnvironment::probe(self.client).await.on_gcp
+    }
+
+    async fn get(&self, path: &str) -> Option<String> {
+        get_metadata(self.client, self.root, path).await
+    }
 }
 
 /// Retrieve metadata from Google Cloud metadata service
 ///
 /// # Arguments
-/// * `client` - A reqwest client
+/// * `client` - The HTTP client
+/// * `root` - The root URL of the metadata API
 /// * `path` - The metadata path to retrieve
 ///
 /// # Returns
-/// An optional string containing the metadata value
-async fn get_metadata(client: &reqwest::Client, path: &str) -> Option<String> {
-    let url = format!(
-        "http://metadata.google.internal/computeMetadata/v1/{}",
-        path
-    );
+/// An optional string containing the metadata value, None if the server
+/// could not be reached or did not find the path
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+async fn get_metadata(client: &client::Client, root: &str, path: &str) -> Option<String> {
     client
-        .get(&url)
+        .get(format!("{}{}", root, path))
         .header("Metadata-Flavor", "Google")
         .send()
         .await
         .ok()?
+        .error_for_status()
+        .ok()?
         .text()
         .await
         .ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    /// A metadata server that is unreachable, as off GCP
+    struct OffGcp {
+        /// The cgroup file read instead of `/proc/self/cgroup`
+        cgroup_path: PathBuf,
+    }
+
+    impl MetadataServer for OffGcp {
+        fn cgroup_path(&self) -> &Path {
+            &self.cgroup_path
+        }
+
+        async fn on_gcp(&self) -> bool {
+            false
+        }
+
+        async fn get(&self, _path: &str) -> Option<String> {
+            None
+        }
+    }
+
+    /// Resolve the metadata off GCP with the given configuration
+    ///
+    /// # Arguments
+    /// * `server` - The unreachable metadata server
+    /// * `instance` - Instance metadata set on the builder, if any
+    /// * `container` - Container metadata set on the builder, if any
+    /// * `collect_container` - Whether to read container metadata, None for auto
+    async fn resolve(
+        server: &OffGcp,
+        instance: Option<InstanceMetadata>,
+        container: Option<ContainerMetadata>,
+        collect_container: Option<bool>,
+    ) -> LogContextMetadata {
+        let mut entry_config = entry::EntryConfig::default();
+        resolve_metadata(
+            "test-project".into(),
+            instance,
+            container,
+            collect_container,
+            None,
+            &mut entry_config,
+            server,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_containers_are_detected_off_gcp() {
+        let cgroup_path =
+            std::env::temp_dir().join(format!("nano-gcp-logging-cgroup-{}", std::process::id()));
+        std::fs::write(&cgroup_path, "0::/kubepods/pod-1/abc123\n").unwrap();
+        let server = OffGcp { cgroup_path };
+
+        let detected = resolve(&server, None, None, None).await;
+        assert_eq!(detected.instance.name, "unknown");
+        assert_eq!(detected.container.map(|c| c.id).as_deref(), Some("abc123"));
+
+        // An explicit instance leaves container detection alone.
+        let instance = InstanceMetadata::unknown("test-project".into());
+        let detected = resolve(&server, Some(instance), None, Some(true)).await;
+        assert_eq!(detected.container.map(|c| c.id).as_deref(), Some("abc123"));
+
+        let disabled = resolve(&server, None, None, Some(false)).await;
+        assert!(disabled.container.is_none());
+
+        let configured = ContainerMetadata {
+            id: "configured".into(),
+            name: "app".into(),
+        };
+        let configured = resolve(&server, None, Some(configured), None).await;
+        assert_eq!(configured.container.unwrap().id, "configured");
+
+        std::fs::remove_file(&server.cgroup_path).unwrap();
+        let missing = resolve(&server, None, None, None).await;
+        assert!(missing.container.is_none());
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    #[tokio::test]
+    async fn test_missing_level_falls_back_to_the_default_severity() {
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(InstanceMetadata::unknown("test-project".into()))
+            .default_severity(Severity::Notice)
+            .build()
+            .await
+            .expect("Layer should build without credentials");
+        assert_eq!(layer.map_level_to_severity(None), Severity::Notice);
+        assert_eq!(
+            layer.map_level_to_severity(Some(&tracing::Level::WARN)),
+            Severity::Warning
+        );
+
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(InstanceMetadata::unknown("test-project".into()))
+            .build()
+            .await
+            .expect("Layer should build without credentials");
+        assert_eq!(layer.map_level_to_severity(None), Severity::Default);
+    }
+}
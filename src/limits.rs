@@ -0,0 +1,77 @@
+//! Cloud Logging limits enforced before entries are sent.
+//!
+//! The API rejects a whole `entries:write` request when one of its entries is
+//! over a limit, so entries are repaired (or, failing that, dropped) on the
+//! client instead, with a warning on stderr the first time.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Largest `LogEntry` the API accepts, in bytes
+pub const MAX_ENTRY_BYTES: usize = 256 * 1024;
+
+/// Largest `entries:write` request the API accepts, in bytes
+pub const MAX_REQUEST_BYTES: usize = 10 * 1024 * 1024;
+
+/// Most labels an entry may carry
+pub const MAX_LABELS: usize = 64;
+
+/// Longest label key, in bytes
+pub const MAX_LABEL_KEY_BYTES: usize = 512;
+
+/// Longest label value, in bytes
+pub const MAX_LABEL_VALUE_BYTES: usize = 64 * 1024;
+
+/// Room left in a request for the envelope and entry separators
+const REQUEST_OVERHEAD: usize = 64 * 1024;
+
+/// Largest request body a batch may grow to
+///
+/// # Arguments
+/// * `configured` - `BatchPolicy::max_batch_bytes`
+pub(crate) fn max_batch_bytes(configured: usize) -> usize {
+    configured.min(MAX_REQUEST_BYTES - REQUEST_OVERHEAD)
+}
+
+/// Whether the layer already warned about an entry repaired to fit
+static WARNED_REPAIRED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the layer already warned about an entry dropped for its size
+static WARNED_REJECTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the layer already warned about labels trimmed to the limits
+static WARNED_LABELS: AtomicBool = AtomicBool::new(false);
+
+/// Warn, once per process, that an oversized entry was cut down
+///
+/// # Arguments
+/// * `size` - The entry's encoded size before the repair
+pub(crate) fn warn_repaired(size: usize) {
+    if !WARNED_REPAIRED.swap(true, Ordering::Relaxed) {
+        warning!(
+            "a log entry of {} bytes exceeded Cloud Logging's {} byte limit; its largest fields were dropped (listed in `fields_dropped`) and its message truncated as needed. Further repairs are not reported.",
+            size, MAX_ENTRY_BYTES
+        );
+    }
+}
+
+/// Warn, once per process, that an entry was dropped because it could not fit
+///
+/// # Arguments
+/// * `size` - The entry's encoded size after the repair
+pub(crate) fn warn_rejected(size: usize) {
+    if !WARNED_REJECTED.swap(true, Ordering::Relaxed) {
+        warning!(
+            "a log entry was dropped: it is still {} bytes without its fields, over Cloud Logging's {} byte limit. Further drops are not reported.",
+            size, MAX_ENTRY_BYTES
+        );
+    }
+}
+
+/// Warn, once per process, that labels were trimmed to the limits
+pub(crate) fn warn_labels() {
+    if !WARNED_LABELS.swap(true, Ordering::Relaxed) {
+        warning!(
+            "labels of a log entry were trimmed: Cloud Logging allows {} labels with keys up to {} bytes and values up to {} bytes. The entry lists them under `labels_truncated` and `labels_dropped`; further occurrences are not reported.",
+            MAX_LABELS, MAX_LABEL_KEY_BYTES, MAX_LABEL_VALUE_BYTES
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Formatters building the message line of captured events.
+use std::borrow::Cow;
+
+use chrono::Local;
+
+use crate::entry::MessageParts;
+
+/// Builds the message line shown in the Logs Explorer summary
+///
+/// Install one with `GcpLoggingLayerBuilder::message_formatter`. The line only
+/// replaces `jsonPayload.message`; fields, severity and source location are
+/// sent as structured data whatever the formatter does. It runs on the
+/// background task for every captured event, so keep it cheap.
+pub trait MessageFormatter: Send + Sync + 'static {
+    /// Build the message line of a captured event
+    ///
+    /// # Arguments
+    /// * `parts` - The event's timestamp, severity, target, location and message
+    ///
+    /// # Returns
+    /// The line, borrowing the message when it is sent unchanged
+    fn format<'a>(&self, parts: &MessageParts<'a>) -> Cow<'a, str>;
+}
+
+/// `[{local time}] {severity} [{target} {file}:{line}] [{message}]`, the default line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullFormatter {
+    /// Include the target before the location
+    pub target: bool,
+}
+
+impl Default for FullFormatter {
+    fn default() -> Self {
+        Self { target: true }
+    }
+}
+
+impl MessageFormatter for FullFormatter {
+    fn format<'a>(&self, parts: &MessageParts<'a>) -> Cow<'a, str> {
+        let now = parts
+            .timestamp
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S,%3f");
+        let location = if self.target {
+            parts.location
+        } else {
+            parts
+                .location
+                .strip_prefix(parts.target)
+                .map_or(parts.location, str::trim_start)
+        };
+        Cow::Owned(format!(
+            "[{}] {} [{}] [{}]",
+            now,
+            parts.severity.as_str(),
+            location,
+            parts.message
+        ))
+    }
+}
+
+/// `{severity} {target}: {message}`, leaving time and location to the entry's fields
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactFormatter;
+
+impl MessageFormatter for CompactFormatter {
+    fn format<'a>(&self, parts: &MessageParts<'a>) -> Cow<'a, str> {
+        Cow::Owned(format!(
+            "{} {}: {}",
+            parts.severity.as_str(),
+            parts.target,
+            parts.message
+        ))
+    }
+}
+
+/// The bare message, for setups reading everything else from `jsonPayload`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonOnlyFormatter;
+
+impl MessageFormatter for JsonOnlyFormatter {
+    fn format<'a>(&self, parts: &MessageParts<'a>) -> Cow<'a, str> {
+        Cow::Borrowed(parts.message)
+    }
+}
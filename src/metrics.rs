@@ -0,0 +1,139 @@
+//! Delivery counters shared between the background tasks and handles.
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::transport::Delivery;
+
+/// Snapshot of the layer's delivery counters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryMetrics {
+    /// Failed batches currently waiting in the retry queue
+    pub retry_queue_depth: usize,
+    /// Batches given up on after exhausting their retries or overflowing the retry queue
+    pub retry_dropped: u64,
+    /// Batches discarded while the circuit breaker was open, by
+    /// `OpenCircuitPolicy::Drop` or to make room in a full buffer
+    pub circuit_dropped: u64,
+}
+
+/// Snapshot of the entries that went through the layer since it started
+///
+/// Every counter counts entries, except `bytes_sent`. Entries still queued,
+/// batched or waiting for a retry appear in `queued` only, so
+/// `queued - sent_ok - failed - dropped` approximates what is in flight.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogStats {
+    /// Entries handed to the background task or the synchronous writer
+    pub queued: u64,
+    /// Entries acknowledged by the API with a 2xx, or written to the sink
+    pub sent_ok: u64,
+    /// Entries given up on after an upload attempt: rejected by the API,
+    /// out of retries, evicted from a full retry queue, still waiting for a
+    /// retry when the layer stopped or not acknowledged by a synchronous write
+    pub failed: u64,
+    /// Entries discarded without an upload attempt: without credentials
+    /// (including those printed by the stdout fallback) or while the circuit
+    /// breaker was open
+    pub dropped: u64,
+    /// Entries resent by the retry task, once per attempt
+    pub retried: u64,
+    /// Uncompressed request body bytes acknowledged by the API
+    pub bytes_sent: u64,
+}
+
+/// Live counters behind `DeliveryMetrics` and `LogStats`
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// Failed batches currently waiting in the retry queue
+    pub(crate) retry_queue_depth: AtomicUsize,
+    /// Batches given up on by the retry queue
+    pub(crate) retry_dropped: AtomicU64,
+    /// Batches discarded by the circuit breaker
+    pub(crate) circuit_dropped: AtomicU64,
+    /// Entries handed to the background task or the synchronous writer
+    pub(crate) queued: AtomicU64,
+    /// Entries acknowledged by the API or written to the sink
+    pub(crate) sent_ok: AtomicU64,
+    /// Entries given up on after an upload attempt
+    pub(crate) failed: AtomicU64,
+    /// Entries discarded without an upload attempt
+    pub(crate) dropped: AtomicU64,
+    /// Entries resent by the retry task
+    pub(crate) retried: AtomicU64,
+    /// Request body bytes acknowledged by the API
+    pub(crate) bytes_sent: AtomicU64,
+    /// Uploads acknowledged since the metrics were last exported
+    pub(crate) uploads: AtomicU64,
+    /// Total duration of those uploads, in microseconds
+    pub(crate) upload_latency_us: AtomicU64,
+}
+
+impl Metrics {
+    /// Take a snapshot of the counters
+    pub(crate) fn snapshot(&self) -> DeliveryMetrics {
+        DeliveryMetrics {
+            retry_queue_depth: self.retry_queue_depth.load(Ordering::Relaxed),
+            retry_dropped: self.retry_dropped.load(Ordering::Relaxed),
+            circuit_dropped: self.circuit_dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Take a snapshot of the entry counters
+    pub(crate) fn stats(&self) -> LogStats {
+        LogStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            sent_ok: self.sent_ok.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Add entries to a counter
+    ///
+    /// # Arguments
+    /// * `counter` - One of the entry counters
+    /// * `entries` - How many entries to add
+    pub(crate) fn add(counter: &AtomicU64, entries: usize) {
+        counter.fetch_add(entries as u64, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of an upload
+    ///
+    /// Retryable failures are left to whoever gives up on the batch.
+    ///
+    /// # Arguments
+    /// * `delivery` - The outcome
+    /// * `entries` - Entries in the request
+    /// * `bytes` - Size of the uncompressed request body
+    /// * `latency` - How long the upload took
+    pub(crate) fn record(
+        &self,
+        delivery: Delivery,
+        entries: usize,
+        bytes: usize,
+        latency: Duration,
+    ) {
+        match delivery {
+            Delivery::Sent => {
+                Self::add(&self.sent_ok, entries);
+                Self::add(&self.bytes_sent, bytes);
+                Self::add(&self.uploads, 1);
+                let latency = latency.as_micros().try_into().unwrap_or(usize::MAX);
+                Self::add(&self.upload_latency_us, latency);
+            }
+            Delivery::Rejected | Delivery::Forbidden => Self::add(&self.failed, entries),
+            Delivery::Retryable => {}
+        }
+    }
+
+    /// Record a batch given up on after its retries or for lack of room
+    ///
+    /// # Arguments
+    /// * `entries` - Entries in the batch
+    pub(crate) fn give_up(&self, entries: usize) {
+        self.retry_dropped.fetch_add(1, Ordering::Relaxed);
+        Self::add(&self.failed, entries);
+    }
+}
@@ -0,0 +1,224 @@
+//! Export of the layer's delivery metrics to Cloud Monitoring.
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::task::JoinHandle;
+
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::transport::Transport;
+use crate::InstanceMetadata;
+
+/// Cloud Monitoring API, followed by `/projects/{project}/timeSeries`
+const MONITORING_URL: &str = "https://monitoring.googleapis.com/v3";
+
+/// Shortest interval between pushes; Cloud Monitoring rejects points written
+/// more often than every 5 seconds
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Controls how the layer's delivery metrics are pushed to Cloud Monitoring
+///
+/// Every `interval`, and once more after a shutdown, the layer writes its
+/// counters as custom metrics named `{prefix}/{metric}`: the gauges
+/// `queue_depth` (entries captured but not yet sent or given up on),
+/// `retry_queue_depth` (batches) and `send_latency` (mean milliseconds of the
+/// uploads since the previous push, omitted without uploads), and the
+/// cumulative counts `sent`, `failed`, `dropped` and `bytes_sent` since the
+/// layer started. They are attached to a `generic_task` resource whose
+/// `job` is configured here and whose `task_id` identifies the process, so
+/// the fleet can be monitored per job or per process.
+#[derive(Debug, Clone)]
+pub struct MetricsExport {
+    /// `job` label of the `generic_task` resource, e.g. the service name
+    pub job: String,
+    /// Time between pushes, at least 10 seconds
+    pub interval: Duration,
+    /// Prefix of the metric types
+    pub prefix: String,
+}
+
+impl MetricsExport {
+    /// Push the metrics of the given job every minute
+    ///
+    /// # Arguments
+    /// * `job` - `job` label of the `generic_task` resource
+    pub fn new(job: impl Into<String>) -> Self {
+        Self {
+            job: job.into(),
+            interval: Duration::from_secs(60),
+            prefix: "custom.googleapis.com/nano_gcp_logging".into(),
+        }
+    }
+}
+
+/// Pushes the counters of a layer to Cloud Monitoring
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+pub(crate) struct MetricsExporter {
+    /// Job, interval and metric prefix
+    config: MetricsExport,
+    /// Transport whose credentials and request hook are used
+    transport: Transport,
+    /// The layer's counters
+    metrics: Arc<Metrics>,
+    /// The `generic_task` resource the time series belong to
+    resource: Value,
+    /// The project's `timeSeries` URL
+    url: String,
+    /// Start of the cumulative metrics' intervals
+    start: DateTime<Utc>,
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+impl MetricsExporter {
+    /// Create an exporter for the layer's counters
+    ///
+    /// # Arguments
+    /// * `config` - Job, interval and metric prefix
+    /// * `transport` - Transport whose credentials and request hook are used
+    /// * `instance` - The instance the layer runs on
+    pub(crate) fn new(
+        config: MetricsExport,
+        transport: Transport,
+        instance: &InstanceMetadata,
+    ) -> Self {
+        let location = [&instance.region, &instance.zone]
+            .into_iter()
+            .find(|location| !location.is_empty())
+            .map_or("global", String::as_str);
+        let host = match instance.name.is_empty() {
+            true => &instance.id,
+            false => &instance.name,
+        };
+        let resource = json!({
+            "type": "generic_task",
+            "labels": {
+                "project_id": instance.project_id,
+                "location": location,
+                "namespace": env!("CARGO_PKG_NAME"),
+                "job": config.job,
+                "task_id": format!("{}-{}", host, std::process::id()),
+            }
+        });
+        Self {
+            url: format!(
+                "{}/projects/{}/timeSeries",
+                MONITORING_URL, instance.project_id
+            ),
+            metrics: transport.metrics.clone(),
+            config,
+            transport,
+            resource,
+            start: Utc::now(),
+        }
+    }
+
+    /// Spawn the task pushing the metrics on the current Tokio runtime
+    ///
+    /// # Arguments
+    /// * `shutdown` - The layer's shutdown; the task pushes once more and
+    ///   stops after it finished
+    pub(crate) fn spawn(self, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let period = self.config.interval.max(MIN_INTERVAL);
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => self.push().await,
+                    _ = shutdown.finished() => {
+                        self.push().await;
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Write the current values of every metric
+    async fn push(&self) {
+        let body = json!({ "timeSeries": self.time_series(Utc::now()) });
+        let Ok(body) = serde_json::to_vec(&body) else {
+            return;
+        };
+        match self.transport.post_api(self.url.clone(), body).await {
+            Ok(res) if res.status().is_success() => {}
+            Ok(res) => diagnostic!("Failed to export metrics: HTTP {}", res.status()),
+            Err(e) => diagnostic!("Failed to export metrics: {}", e),
+        }
+    }
+
+    /// Build the time series of every metric, with one point each
+    ///
+    /// # Arguments
+    /// * `now` - End of the points' intervals
+    fn time_series(&self, now: DateTime<Utc>) -> Vec<Value> {
+        // Cumulative intervals must not be empty.
+        let now = now.max(self.start + chrono::Duration::milliseconds(1));
+        let end = now.to_rfc3339_opts(SecondsFormat::Micros, true);
+        let start = self.start.to_rfc3339_opts(SecondsFormat::Micros, true);
+        let stats = self.metrics.stats();
+        let retry_queue_depth = self.metrics.snapshot().retry_queue_depth as u64;
+        let in_flight = stats
+            .queued
+            .saturating_sub(stats.sent_ok + stats.failed + stats.dropped);
+
+        let gauge = |value: Value| json!({ "interval": { "endTime": end }, "value": value });
+        let cumulative = |count: u64| {
+            json!({
+                "interval": { "startTime": start, "endTime": end },
+                "value": { "int64Value": count.to_string() },
+            })
+        };
+        let mut series = vec![
+            (
+                "queue_depth",
+                "GAUGE",
+                "INT64",
+                gauge(json!({ "int64Value": in_flight.to_string() })),
+            ),
+            (
+                "retry_queue_depth",
+                "GAUGE",
+                "INT64",
+                gauge(json!({ "int64Value": retry_queue_depth.to_string() })),
+            ),
+            ("sent", "CUMULATIVE", "INT64", cumulative(stats.sent_ok)),
+            ("failed", "CUMULATIVE", "INT64", cumulative(stats.failed)),
+            ("dropped", "CUMULATIVE", "INT64", cumulative(stats.dropped)),
+            (
+                "bytes_sent",
+                "CUMULATIVE",
+                "INT64",
+                cumulative(stats.bytes_sent),
+            ),
+        ];
+        let uploads = self.metrics.uploads.swap(0, Ordering::Relaxed);
+        let latency_us = self.metrics.upload_latency_us.swap(0, Ordering::Relaxed);
+        if uploads > 0 {
+            let mean_ms = latency_us as f64 / uploads as f64 / 1000.0;
+            series.push((
+                "send_latency",
+                "GAUGE",
+                "DOUBLE",
+                gauge(json!({ "doubleValue": mean_ms })),
+            ));
+        }
+        series
+            .into_iter()
+            .map(|(name, kind, value_type, point)| {
+                json!({
+                    "metric": { "type": format!("{}/{}", self.config.prefix, name) },
+                    "resource": self.resource,
+                    "metricKind": kind,
+                    "valueType": value_type,
+                    "points": [point],
+                })
+            })
+            .collect()
+    }
+}
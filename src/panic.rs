@@ -0,0 +1,84 @@
+//! Panic hook forwarding panics to Cloud Logging.
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::panic::PanicHookInfo;
+
+use chrono::Utc;
+use serde_json::Map;
+
+use crate::entry::{GcpLogEntry, SourceLocation};
+use crate::visitor::PendingFields;
+use crate::{GcpLoggingHandle, Severity};
+
+impl GcpLoggingHandle {
+    /// Install a panic hook forwarding panics to Cloud Logging
+    ///
+    /// Each panic is sent as a `CRITICAL` entry with the panic location in
+    /// `sourceLocation` and a captured backtrace in the `stack_trace` payload
+    /// field, which Error Reporting recognizes. The previously installed hook
+    /// (by default the one printing to stderr) still runs afterwards.
+    ///
+    /// The entry is queued like any other, so a panic that aborts the process
+    /// may still lose it; combine with `sync_write(Severity::Critical, ..)` to
+    /// upload it before the hook returns.
+    pub fn install_panic_hook(&self) {
+        let handle = self.clone();
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            handle.report_panic(info);
+            previous(info);
+        }));
+    }
+
+    /// Send a panic as a CRITICAL entry
+    fn report_panic(&self, info: &PanicHookInfo<'_>) {
+        let payload = info.payload();
+        let reason = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let thread = std::thread::current();
+        let message = match info.location() {
+            Some(location) => format!(
+                "thread '{}' panicked at {}:{}:{}: {}",
+                thread.name().unwrap_or("<unnamed>"),
+                location.file(),
+                location.line(),
+                location.column(),
+                reason
+            ),
+            None => format!(
+                "thread '{}' panicked: {}",
+                thread.name().unwrap_or("<unnamed>"),
+                reason
+            ),
+        };
+
+        let mut fields = Map::new();
+        fields.insert(
+            "stack_trace".into(),
+            format!("{}\n{}", message, Backtrace::force_capture()).into(),
+        );
+        let result = self.sender.send(GcpLogEntry {
+            message,
+            severity: Severity::Critical,
+            fields,
+            timestamp: Utc::now(),
+            source_location: info.location().map(|location| SourceLocation {
+                file: location.file().to_string(),
+                line: location.line(),
+            }),
+            operation: None,
+            trace: crate::trace::current(),
+            labels: BTreeMap::new(),
+            runtime_labels: None,
+            internal: false,
+            callsite: None,
+            pending: PendingFields::default(),
+        });
+        if result.is_err() {
+            diagnostic!("Error {:?}", result);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Serializers taking over the shape of `jsonPayload`.
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+use crate::entry::SourceLocation;
+use crate::trace::TraceContext;
+use crate::{LogContextMetadata, Severity};
+
+/// Builds the `jsonPayload` of every entry in place of the crate's layout
+///
+/// Install one with `GcpLoggingLayerBuilder::payload_serializer`. The
+/// serializer decides every payload key: nothing is injected and no field is
+/// renamed, so include the message under `message` if the Logs Explorer
+/// should show a summary line. The rest of the `LogEntry` (severity,
+/// timestamps, labels, trace, resource) is still built by the crate. It runs
+/// on the background task for every entry, including internal ones.
+pub trait PayloadSerializer: Send + Sync + 'static {
+    /// Build the payload of an entry
+    ///
+    /// # Arguments
+    /// * `parts` - The captured event and the metadata attached to it
+    ///
+    /// # Returns
+    /// The `jsonPayload` object
+    fn payload(&self, parts: &PayloadParts<'_>) -> Map<String, Value>;
+}
+
+impl fmt::Debug for dyn PayloadSerializer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PayloadSerializer(..)")
+    }
+}
+
+/// The parts of an entry, passed to a `PayloadSerializer`
+#[derive(Debug, Clone, Copy)]
+pub struct PayloadParts<'a> {
+    /// The message line, as built by the message format
+    pub message: &'a str,
+    /// Severity of the entry
+    pub severity: Severity,
+    /// When the event was captured
+    pub timestamp: DateTime<Utc>,
+    /// The event's fields, merged with those of enclosing spans as configured
+    pub fields: &'a Map<String, Value>,
+    /// The event's target, None for entries emitted through a handle
+    pub target: Option<&'a str>,
+    /// Code location the entry originates from, if known
+    pub source_location: Option<&'a SourceLocation>,
+    /// Trace of the span the event was emitted in, if any
+    pub trace: Option<&'a TraceContext>,
+    /// The instance and container metadata the layer detected
+    pub metadata: &'a LogContextMetadata,
+    /// Whether the entry was generated by the layer itself, e.g. a heartbeat
+    pub internal: bool,
+}
@@ -0,0 +1,544 @@
+//! Batching shared by the async worker and the blocking sender.
+use std::sync::Arc;
+use std::time::Duration;
+
+use smallvec::SmallVec;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Instant;
+
+use crate::batch::{Batch, BatchPolicy};
+use crate::console::Console;
+use crate::entry::{EntryConfig, EntryView, GcpLogEntry, Sequencer, StaticMetadata};
+use crate::handle::{DropHook, Message};
+use crate::limits;
+use crate::metrics::Metrics;
+use crate::shutdown::ShutdownReport;
+use crate::sink::LogSink;
+
+/// A request body ready to upload, and whether to compress it
+pub(crate) type Upload = (Vec<u8>, bool);
+
+/// Turns received entries into request bodies and decides when to send them
+///
+/// The async worker and the blocking sender only differ in how they wait and
+/// upload; which entries share a request, when a partial batch is due and
+/// what happens to entries that are not uploaded is decided here.
+pub(crate) struct Pipeline {
+    /// Metadata attached to every entry
+    pub(crate) metadata: Arc<StaticMetadata>,
+    /// Options controlling the entry shape
+    pub(crate) entry_config: EntryConfig,
+    /// How entries are grouped into requests
+    batch_policy: BatchPolicy,
+    /// Counters updated with the outcome of entries not uploaded
+    metrics: Arc<Metrics>,
+    /// Destination replacing uploads, if installed
+    sink: Option<Arc<dyn LogSink>>,
+    /// Prints entries to stdout when they cannot be uploaded, if enabled
+    console: Option<Console>,
+    /// Callback receiving entries dropped instead of uploaded, if set
+    on_drop: Option<DropHook>,
+    /// Whether uploads are impossible for lack of a token
+    skip_sending: bool,
+    /// Entries waiting for the next upload
+    batch: Batch,
+    /// Assigns ordered `insertId`s, if enabled
+    sequencer: Option<Sequencer>,
+    /// When the partial batch is sent, None while the batch is empty
+    deadline: Option<Instant>,
+    /// Earliest `max_latency` deadline among the entries in the batch
+    due: Option<Instant>,
+    /// Until then partial batches wait for `flush_on` or `max_latency`, so
+    /// startup logs coalesce
+    warmup_end: Option<Instant>,
+    /// Whether a `Message::Flush` arrived since the last `settle`
+    urgent: bool,
+}
+
+impl Pipeline {
+    /// Create a pipeline with an empty batch, starting the warmup
+    ///
+    /// # Arguments
+    /// * `metadata` - Metadata attached to every entry
+    /// * `entry_config` - Options controlling the entry shape
+    /// * `batch_policy` - How entries are grouped into requests
+    /// * `metrics` - Counters updated with the outcome of entries not uploaded
+    pub(crate) fn new(
+        metadata: Arc<StaticMetadata>,
+        entry_config: EntryConfig,
+        batch_policy: BatchPolicy,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            sequencer: entry_config.preserve_order.then(Sequencer::new),
+            warmup_end: batch_policy.warmup.map(|warmup| Instant::now() + warmup),
+            metadata,
+            entry_config,
+            batch_policy,
+            metrics,
+            sink: None,
+            console: None,
+            on_drop: None,
+            skip_sending: false,
+            batch: Batch::default(),
+            deadline: None,
+            due: None,
+            urgent: false,
+        }
+    }
+
+    /// Deliver entries somewhere other than the Logging API
+    ///
+    /// # Arguments
+    /// * `sink` - Destination replacing uploads, if installed
+    /// * `console` - Prints entries that cannot be uploaded, if enabled
+    /// * `on_drop` - Callback receiving entries dropped instead of uploaded
+    /// * `skip_sending` - Whether uploads are impossible for lack of a token
+    pub(crate) fn with_local_delivery(
+        mut self,
+        sink: Option<Arc<dyn LogSink>>,
+        console: Option<Console>,
+        on_drop: Option<DropHook>,
+        skip_sending: bool,
+    ) -> Self {
+        self.sink = sink;
+        self.console = console;
+        self.on_drop = on_drop;
+        self.skip_sending = skip_sending;
+        self
+    }
+
+    /// Write an entry to the sink, or discard it for lack of credentials
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry
+    ///
+    /// # Returns
+    /// Whether the entry reached the sink, or None if it is to be uploaded
+    pub(crate) fn deliver_locally(&mut self, log_entry: &GcpLogEntry) -> Option<bool> {
+        if let Some(sink) = &self.sink {
+            let entry = EntryView::new(&self.metadata, &self.entry_config, log_entry)
+                .with_insert_id(self.sequencer.as_mut())
+                .to_value();
+            sink.write(entry);
+            Metrics::add(&self.metrics.sent_ok, 1);
+            return Some(true);
+        }
+        if !self.skip_sending {
+            return None;
+        }
+        if let Some(console) = &self.console {
+            console.print(log_entry, &self.entry_config);
+        }
+        if let Some(on_drop) = &self.on_drop {
+            on_drop(log_entry);
+        }
+        Metrics::add(&self.metrics.dropped, 1);
+        Some(false)
+    }
+
+    /// Whether the queue is deep enough for backlog mode
+    ///
+    /// # Arguments
+    /// * `queued` - Entries waiting in the channel
+    pub(crate) fn backlog(&self, queued: usize) -> bool {
+        queued >= self.batch_policy.backlog_threshold
+    }
+
+    /// Add an entry to the batch
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry to upload
+    /// * `backlog` - Whether the queue is in backlog mode, growing batches
+    ///
+    /// # Returns
+    /// The bodies that must be uploaded now, oldest first: the batch the
+    /// entry did not fit in, and the batch it filled
+    pub(crate) fn add(&mut self, log_entry: &GcpLogEntry, backlog: bool) -> SmallVec<[Upload; 2]> {
+        let mut uploads = SmallVec::new();
+        if let Some(serialized) = self.serialize(log_entry) {
+            let max_bytes = limits::max_batch_bytes(self.batch_policy.max_batch_bytes);
+            if self.batch.would_overflow(serialized.len(), max_bytes) {
+                uploads.extend(self.take(backlog));
+            }
+            self.batch.push(serialized);
+        }
+        if let Some(max_latency) = self.batch_policy.max_latency {
+            let entry_due = due_at(log_entry, max_latency);
+            self.due = Some(self.due.map_or(entry_due, |due| due.min(entry_due)));
+        }
+        let target = match backlog {
+            true => self.batch_policy.backlog_batch_size,
+            false => self.batch_policy.batch_size,
+        };
+        if self.batch.len() >= target.max(1) {
+            uploads.extend(self.take(backlog));
+        }
+        uploads
+    }
+
+    /// Have the next `settle` send the partial batch, after a `Message::Flush`
+    pub(crate) fn request_flush(&mut self) {
+        self.urgent = true;
+    }
+
+    /// Take the next entry already queued, noting flush requests on the way
+    ///
+    /// # Arguments
+    /// * `rx` - The receiving end of the layer's channel
+    ///
+    /// # Returns
+    /// The entry, None once nothing is queued
+    pub(crate) fn next_queued(
+        &mut self,
+        rx: &mut UnboundedReceiver<Message>,
+    ) -> Option<GcpLogEntry> {
+        loop {
+            match rx.try_recv().ok()? {
+                Message::Entry(log_entry) => return Some(log_entry.resolved()),
+                Message::Flush => self.request_flush(),
+            }
+        }
+    }
+
+    /// Finish a round of added entries and schedule the partial batch
+    ///
+    /// # Arguments
+    /// * `backlog` - Whether the round was in backlog mode
+    ///
+    /// # Returns
+    /// The partial batch if a `Message::Flush` asked for it to be sent now
+    pub(crate) fn settle(&mut self, backlog: bool) -> Option<Upload> {
+        if self.warmup_end.is_some_and(|end| Instant::now() >= end) {
+            self.warmup_end = None;
+        }
+        // Severe entries skip the wait, even during the warmup, so alerts are
+        // not delayed by batching.
+        let urgent = std::mem::take(&mut self.urgent);
+        let upload = urgent.then(|| self.take(backlog)).flatten();
+
+        self.deadline = if self.batch.is_empty() {
+            None
+        } else {
+            let wait = self.warmup_end.unwrap_or_else(|| {
+                self.deadline
+                    .unwrap_or_else(|| Instant::now() + self.batch_policy.flush_interval)
+            });
+            // `max_latency` cuts the warmup short as well.
+            Some(self.due.map_or(wait, |due| due.min(wait)))
+        };
+        upload
+    }
+
+    /// When the partial batch must be sent, None while there is none
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Take the partial batch once its deadline passed
+    ///
+    /// # Returns
+    /// The batch, None if it is empty
+    pub(crate) fn expire(&mut self) -> Option<Upload> {
+        self.deadline = None;
+        self.take(false)
+    }
+
+    /// Turn the entries still queued at shutdown into request bodies
+    ///
+    /// Entries written to the sink or dropped are counted in `report` right
+    /// away; the others are batched without waiting for deadlines.
+    ///
+    /// # Arguments
+    /// * `entries` - The queued entries, oldest first
+    /// * `report` - Outcome of the shutdown so far
+    /// * `bodies` - Receives each body with its number of entries
+    /// * `on_upload` - Called with each entry before it is batched
+    pub(crate) fn drain(
+        &mut self,
+        entries: impl Iterator<Item = GcpLogEntry>,
+        report: &mut ShutdownReport,
+        bodies: &mut Vec<(usize, Vec<u8>, bool)>,
+        mut on_upload: impl FnMut(&GcpLogEntry),
+    ) {
+        let batch_size = self.batch_policy.batch_size.max(1);
+        let mut push = |pipeline: &mut Self| {
+            let entries = pipeline.batch.len();
+            if let Some((body, compress)) = pipeline.take(false) {
+                bodies.push((entries, body, compress));
+            }
+        };
+        for log_entry in entries.map(GcpLogEntry::resolved) {
+            match self.deliver_locally(&log_entry) {
+                Some(true) => report.sent += 1,
+                Some(false) => report.dropped += 1,
+                None => {
+                    on_upload(&log_entry);
+                    let Some(serialized) = self.serialize(&log_entry) else {
+                        report.dropped += 1;
+                        continue;
+                    };
+                    let max_bytes = limits::max_batch_bytes(self.batch_policy.max_batch_bytes);
+                    if self.batch.would_overflow(serialized.len(), max_bytes) {
+                        push(self);
+                    }
+                    self.batch.push(serialized);
+                    if self.batch.len() >= batch_size {
+                        push(self);
+                    }
+                }
+            }
+        }
+        push(self);
+    }
+
+    /// Take the batch as a request body, restarting the `max_latency` clock
+    ///
+    /// # Arguments
+    /// * `backlog` - Whether to compress the body, if backlogs are compressed
+    ///
+    /// # Returns
+    /// The body, None if the batch is empty
+    fn take(&mut self, backlog: bool) -> Option<Upload> {
+        self.due = None;
+        if self.batch.is_empty() {
+            return None;
+        }
+        let body = self.batch.take_body(&*self.entry_config.encoder);
+        Some((body, backlog && self.batch_policy.gzip_backlog))
+    }
+
+    /// Serialize an entry for inclusion in the batch
+    ///
+    /// The entry is written straight into a reused buffer rather than built
+    /// as a JSON value first.
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry to serialize
+    ///
+    /// # Returns
+    /// The encoded entry, or None if it was dropped for failing to encode,
+    /// e.g. because it cannot be cut down to `MAX_ENTRY_BYTES`
+    fn serialize(&mut self, log_entry: &GcpLogEntry) -> Option<Vec<u8>> {
+        let mut buffer = self.batch.buffer();
+        let view = EntryView::new(&self.metadata, &self.entry_config, log_entry);
+        if view
+            .with_insert_id(self.sequencer.as_mut())
+            .encode(&mut buffer)
+            .is_ok()
+        {
+            return Some(buffer);
+        }
+        if let Some(on_drop) = &self.on_drop {
+            on_drop(log_entry);
+        }
+        Metrics::add(&self.metrics.dropped, 1);
+        None
+    }
+}
+
+/// When an entry must be sent to stay within `max_latency` of its capture
+///
+/// # Arguments
+/// * `log_entry` - The buffered entry
+/// * `max_latency` - Longest time the entry may wait
+fn due_at(log_entry: &GcpLogEntry, max_latency: Duration) -> Instant {
+    let waited = (chrono::Utc::now() - log_entry.timestamp)
+        .to_std()
+        .unwrap_or_default();
+    Instant::now() + max_latency.saturating_sub(waited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::severity::Severity;
+    use crate::{InstanceMetadata, LogContextMetadata};
+
+    /// A pipeline uploading batches of 10 entries, without warmup
+    ///
+    /// # Arguments
+    /// * `max_latency` - Longest time an entry may wait, if limited
+    fn pipeline(max_latency: Option<Duration>) -> Pipeline {
+        pipeline_with(BatchPolicy {
+            batch_size: 10,
+            flush_interval: Duration::from_secs(10),
+            max_latency,
+            warmup: None,
+            ..BatchPolicy::default()
+        })
+    }
+
+    /// A pipeline batching entries as given
+    ///
+    /// # Arguments
+    /// * `batch_policy` - How entries are grouped into requests
+    fn pipeline_with(batch_policy: BatchPolicy) -> Pipeline {
+        let metadata = StaticMetadata::new(LogContextMetadata {
+            container: None,
+            instance: InstanceMetadata {
+                name: "logical-service".into(),
+                id: "1234".into(),
+                zone: "us-central1-a".into(),
+                region: "us-central1".into(),
+                project_id: "test-project".into(),
+            },
+        });
+        Pipeline::new(
+            Arc::new(metadata),
+            EntryConfig::default(),
+            batch_policy,
+            Arc::default(),
+        )
+    }
+
+    /// Assert that a deadline is 100ms after an entry was added
+    ///
+    /// The time an entry already waited is measured on the wall clock, which
+    /// runs on while Tokio's is paused, so a few milliseconds are tolerated.
+    ///
+    /// # Arguments
+    /// * `deadline` - The pipeline's deadline
+    /// * `added` - When the entry was added
+    #[track_caller]
+    fn assert_within_latency(deadline: Instant, added: Instant) {
+        let latency = deadline - added;
+        assert!(
+            latency <= Duration::from_millis(100) && latency > Duration::from_millis(90),
+            "{:?}",
+            latency
+        );
+    }
+
+    /// Number of entries in a request body
+    ///
+    /// # Arguments
+    /// * `upload` - The body and whether it is compressed
+    fn entries((body, _): &Upload) -> usize {
+        let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+        body["entries"].as_array().map_or(0, Vec::len)
+    }
+
+    #[test]
+    fn test_deep_queues_grow_and_compress_batches() {
+        let mut pipeline = pipeline_with(BatchPolicy {
+            batch_size: 2,
+            backlog_threshold: 3,
+            backlog_batch_size: 4,
+            gzip_backlog: true,
+            warmup: None,
+            ..BatchPolicy::default()
+        });
+        assert!(!pipeline.backlog(2));
+        assert!(pipeline.backlog(3));
+        let entry = GcpLogEntry::internal(Severity::Info, "queued");
+
+        // A shallow queue sends small, uncompressed batches.
+        assert!(pipeline.add(&entry, false).is_empty());
+        let uploads = pipeline.add(&entry, false);
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(entries(&uploads[0]), 2);
+        assert!(!uploads[0].1);
+
+        // A backlog fills larger batches and asks for them to be compressed.
+        for _ in 0..3 {
+            assert!(pipeline.add(&entry, true).is_empty());
+        }
+        let uploads = pipeline.add(&entry, true);
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(entries(&uploads[0]), 4);
+        assert!(uploads[0].1);
+
+        // Back below the threshold, batches are small again.
+        assert!(pipeline.add(&entry, false).is_empty());
+        assert_eq!(pipeline.add(&entry, false).len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_batch_is_due_at_max_latency_before_flush_interval() {
+        let mut pipeline = pipeline(Some(Duration::from_millis(100)));
+        let start = Instant::now();
+        let entry = GcpLogEntry::internal(Severity::Info, "waiting");
+        assert!(pipeline.add(&entry, false).is_empty());
+        assert!(pipeline.settle(false).is_none());
+        let deadline = pipeline
+            .deadline()
+            .expect("The partial batch should be scheduled");
+        assert_within_latency(deadline, start);
+
+        // A later entry does not push back the earliest entry's deadline.
+        tokio::time::advance(Duration::from_millis(60)).await;
+        let later = GcpLogEntry::internal(Severity::Info, "later");
+        assert!(pipeline.add(&later, false).is_empty());
+        assert!(pipeline.settle(false).is_none());
+        assert_eq!(pipeline.deadline(), Some(deadline));
+
+        tokio::time::sleep_until(pipeline.deadline().unwrap()).await;
+        let upload = pipeline.expire().expect("The partial batch should be sent");
+        assert_eq!(entries(&upload), 2);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(pipeline.deadline(), None);
+
+        // The next batch starts a new `max_latency` clock.
+        assert!(pipeline.add(&entry, false).is_empty());
+        assert!(pipeline.settle(false).is_none());
+        assert_within_latency(pipeline.deadline().unwrap(), Instant::now());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_batch_waits_for_flush_interval_without_max_latency() {
+        let mut pipeline = pipeline(None);
+        let start = Instant::now();
+        let entry = GcpLogEntry::internal(Severity::Info, "waiting");
+        assert!(pipeline.add(&entry, false).is_empty());
+        assert!(pipeline.settle(false).is_none());
+        assert_eq!(pipeline.deadline(), Some(start + Duration::from_secs(10)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_requests_cut_the_warmup_short() {
+        let mut pipeline = pipeline_with(BatchPolicy {
+            batch_size: 10,
+            flush_interval: Duration::from_secs(10),
+            warmup: Some(Duration::from_secs(1)),
+            ..BatchPolicy::default()
+        });
+        let start = Instant::now();
+        let entry = GcpLogEntry::internal(Severity::Info, "starting");
+        assert!(pipeline.add(&entry, false).is_empty());
+        assert!(pipeline.settle(false).is_none());
+        // Without a flush request the partial batch waits for the warmup.
+        assert_eq!(pipeline.deadline(), Some(start + Duration::from_secs(1)));
+
+        let severe = GcpLogEntry::internal(Severity::Error, "failed to start");
+        assert!(pipeline.add(&severe, false).is_empty());
+        pipeline.request_flush();
+        let upload = pipeline.settle(false).expect("The flush should be sent");
+        assert_eq!(entries(&upload), 2);
+        assert_eq!(pipeline.deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_latency_cuts_the_warmup_short() {
+        let mut pipeline = pipeline_with(BatchPolicy {
+            batch_size: 10,
+            flush_interval: Duration::from_secs(10),
+            max_latency: Some(Duration::from_millis(100)),
+            warmup: Some(Duration::from_secs(1)),
+            ..BatchPolicy::default()
+        });
+        let start = Instant::now();
+        let entry = GcpLogEntry::internal(Severity::Info, "starting");
+        assert!(pipeline.add(&entry, false).is_empty());
+        assert!(pipeline.settle(false).is_none());
+        let deadline = pipeline
+            .deadline()
+            .expect("The partial batch should be scheduled");
+        assert_within_latency(deadline, start);
+
+        tokio::time::sleep_until(deadline).await;
+        let upload = pipeline.expire().expect("The partial batch should be sent");
+        assert_eq!(entries(&upload), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
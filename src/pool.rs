@@ -0,0 +1,136 @@
+//! Buffers recycled between emitting threads and the background task.
+use std::sync::LazyLock;
+
+use crossbeam_queue::ArrayQueue;
+
+/// Buffers that grew larger than this are freed rather than pooled
+pub(crate) const MAX_POOLED_CAPACITY: usize = 16 * 1024;
+
+/// Most buffers a pool keeps
+pub(crate) const MAX_POOLED: usize = 1024;
+
+/// A growable buffer that can be emptied for reuse
+pub(crate) trait Buffer: Default {
+    /// Empty the buffer, keeping its allocation
+    fn clear(&mut self);
+    /// Bytes allocated for the buffer
+    fn capacity(&self) -> usize;
+}
+
+impl Buffer for String {
+    fn clear(&mut self) {
+        String::clear(self)
+    }
+
+    fn capacity(&self) -> usize {
+        String::capacity(self)
+    }
+}
+
+impl Buffer for Vec<u8> {
+    fn clear(&mut self) {
+        Vec::clear(self)
+    }
+
+    fn capacity(&self) -> usize {
+        Vec::capacity(self)
+    }
+}
+
+/// Free buffers shared between threads
+///
+/// Backed by a lock-free queue, so emitting threads taking buffers never wait
+/// on the background task returning them.
+pub(crate) struct Pool<T> {
+    /// Buffers ready to be taken, all empty
+    free: ArrayQueue<T>,
+}
+
+impl<T: Buffer> Pool<T> {
+    /// Create an empty pool
+    ///
+    /// # Arguments
+    /// * `capacity` - Most buffers the pool keeps
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            free: ArrayQueue::new(capacity),
+        }
+    }
+
+    /// Take an empty buffer, allocating only when the pool is empty
+    pub(crate) fn take(&self) -> T {
+        self.free.pop().unwrap_or_default()
+    }
+
+    /// Return a buffer for reuse
+    ///
+    /// Buffers that never allocated or grew past `MAX_POOLED_CAPACITY` are
+    /// dropped, as are those returned to a full pool.
+    ///
+    /// # Arguments
+    /// * `buffer` - The buffer, emptied here
+    pub(crate) fn give(&self, mut buffer: T) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buffer.clear();
+        let _ = self.free.push(buffer);
+    }
+}
+
+/// Message strings, filled on emitting threads and returned by the background task
+pub(crate) static MESSAGES: LazyLock<Pool<String>> = LazyLock::new(|| Pool::new(MAX_POOLED));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_returned_buffers_are_reused() {
+        let pool = Pool::<String>::new(2);
+        let mut message = pool.take();
+        message.push_str("request handled");
+        let (address, capacity) = (message.as_ptr(), message.capacity());
+        pool.give(message);
+
+        let message = pool.take();
+        assert!(message.is_empty());
+        assert_eq!((message.as_ptr(), message.capacity()), (address, capacity));
+        // The pool is empty again, so the next buffer is new.
+        assert_eq!(pool.take().capacity(), 0);
+    }
+
+    #[test]
+    fn test_unallocated_and_oversized_buffers_are_not_pooled() {
+        let pool = Pool::<Vec<u8>>::new(2);
+        pool.give(Vec::new());
+        pool.give(Vec::with_capacity(MAX_POOLED_CAPACITY + 1));
+        assert_eq!(pool.take().capacity(), 0);
+    }
+
+    #[test]
+    fn test_full_pool_drops_returned_buffers() {
+        let pool = Pool::<String>::new(2);
+        for _ in 0..3 {
+            pool.give(String::with_capacity(8));
+        }
+        assert!(pool.take().capacity() > 0);
+        assert!(pool.take().capacity() > 0);
+        assert_eq!(pool.take().capacity(), 0);
+    }
+
+    #[test]
+    fn test_buffers_move_between_threads() {
+        let pool = std::sync::Arc::new(Pool::<String>::new(MAX_POOLED));
+        let giver = pool.clone();
+        std::thread::spawn(move || {
+            for _ in 0..100 {
+                giver.give(String::from("from the background task"));
+            }
+        })
+        .join()
+        .unwrap();
+        let reused = (0..100).filter(|_| pool.take().capacity() > 0).count();
+        assert_eq!(reused, 100);
+    }
+}
@@ -0,0 +1,99 @@
+//! Minimal read-back helper around the `entries:list` API.
+use gcp_auth::AuthenticationManager;
+use serde_json::Value;
+
+use crate::client;
+use crate::entry::LOG_PARENTS;
+
+/// Cloud Logging `entries:list` endpoint
+const ENTRIES_LIST_URL: &str = "https://logging.googleapis.com/v2/entries:list";
+
+/// OAuth scope required to read log entries
+const LOGGING_READ_SCOPE: &str = "https://www.googleapis.com/auth/logging.read";
+
+/// Fetch the most recent entries matching a filter
+///
+/// Uses the same application default credentials as the layer, but requires
+/// the `logging.read` scope (e.g. `roles/logging.viewer`). Handy for
+/// integration tests that write and read back, or for a self-check.
+///
+/// Entries written to an organization, folder or billing account log, e.g.
+/// for centralized logging, are read by passing its resource name, such as
+/// `organizations/123`, instead of a project ID.
+///
+/// # Arguments
+/// * `project_id` - The Google Cloud project ID to read from, or the resource
+///   name of a project, organization, folder or billing account
+/// * `filter` - A Logging query, e.g. `logName="projects/p/logs/proxie"`
+/// * `limit` - Maximum number of entries to return
+///
+/// # Returns
+/// A Result containing the entries, newest first, or an error
+pub async fn fetch_recent(
+    project_id: &str,
+    filter: &str,
+    limit: usize,
+) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let auth = AuthenticationManager::new().await?;
+    let token = auth.get_token(&[LOGGING_READ_SCOPE]).await?;
+
+    let body = serde_json::json!({
+        "resourceNames": [resource_name(project_id)],
+        "filter": filter,
+        "orderBy": "timestamp desc",
+        "pageSize": limit,
+    });
+    let res = client::Client::new()
+        .post(ENTRIES_LIST_URL)
+        .bearer_auth(token.as_str())
+        .json(&body)
+        .send()
+        .await?;
+
+    let status = res.status();
+    let mut response: Value = res.json().await?;
+    if !status.is_success() {
+        return Err(format!("entries:list failed with {}: {}", status, response).into());
+    }
+    match response.get_mut("entries").map(Value::take) {
+        Some(Value::Array(entries)) => Ok(entries),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// The resource name entries are listed from
+///
+/// # Arguments
+/// * `parent` - A project ID or a resource name such as `folders/456`
+fn resource_name(parent: &str) -> String {
+    match parent.split_once('/') {
+        Some((collection, _)) if LOG_PARENTS.contains(&collection) => parent.to_string(),
+        _ => format!("projects/{}", parent),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_ids_become_project_resource_names() {
+        assert_eq!(resource_name("my-project"), "projects/my-project");
+        assert_eq!(resource_name("projects/my-project"), "projects/my-project");
+    }
+
+    #[test]
+    fn test_other_parents_are_kept() {
+        assert_eq!(resource_name("folders/456"), "folders/456");
+        assert_eq!(resource_name("organizations/123"), "organizations/123");
+        assert_eq!(
+            resource_name("billingAccounts/0A1B2C-3D4E5F-6A7B8C"),
+            "billingAccounts/0A1B2C-3D4E5F-6A7B8C"
+        );
+    }
+
+    #[test]
+    fn test_unknown_collections_are_treated_as_project_ids() {
+        assert_eq!(resource_name("x/y"), "projects/x/y");
+    }
+}
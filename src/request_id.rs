@@ -0,0 +1,137 @@
+//! Request IDs stitching together the entries logged while serving a request.
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::context;
+
+/// Field, and label with `label_field`, carrying the request ID
+pub const REQUEST_ID_FIELD: &str = "request_id";
+
+/// Header conventionally carrying a request ID between services
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming request ID accepted, in bytes
+const MAX_LEN: usize = 128;
+
+/// Number of random numbers generated so far, so no two are hashed alike
+static GENERATED: AtomicU64 = AtomicU64::new(0);
+
+/// The ID of the request being served
+///
+/// Taken from the caller's `x-request-id` header when it has a usable one,
+/// so entries of every service a request goes through share it, and
+/// generated otherwise. Running the request's handler inside `scope` adds the
+/// ID as `request_id` to every entry logged by that task; with
+/// `.label_field(REQUEST_ID_FIELD)` on the builder it is sent as a label too,
+/// so `labels.request_id="…"` finds the whole request.
+///
+/// ```no_run
+/// # use nano_gcp_logging::{RequestId, REQUEST_ID_HEADER};
+/// # async fn handle() {}
+/// # async fn serve(headers: std::collections::HashMap<String, String>) {
+/// let id = RequestId::from_header(headers.get(REQUEST_ID_HEADER).map(String::as_str));
+/// id.scope(handle()).await;
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Generate a new random request ID
+    ///
+    /// # Returns
+    /// 32 lowercase hex digits
+    pub fn generate() -> Self {
+        Self(format!("{:016x}{:016x}", random_u64(), random_u64()))
+    }
+
+    /// Use the ID from an incoming header, or generate one
+    ///
+    /// # Arguments
+    /// * `header` - The value of the `x-request-id` header, if present
+    ///
+    /// # Returns
+    /// The trimmed header value if it is non-empty, at most 128 bytes and
+    /// printable ASCII, otherwise a generated ID
+    pub fn from_header(header: Option<&str>) -> Self {
+        match header.map(str::trim) {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_LEN
+                    && id.bytes().all(|byte| byte.is_ascii_graphic()) =>
+            {
+                Self(id.to_string())
+            }
+            _ => Self::generate(),
+        }
+    }
+
+    /// The ID, e.g. to echo in a response header
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Run a future with the ID in its `context`
+    ///
+    /// The future's context starts from a copy of the current one, as with
+    /// `context::scope`.
+    ///
+    /// # Arguments
+    /// * `future` - The request's handler
+    pub fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        let mut fields = context::snapshot();
+        fields.insert(REQUEST_ID_FIELD.into(), self.0.clone().into());
+        context::scope_with(fields, future)
+    }
+
+    /// Run a closure with the ID in the current context, restoring it afterwards
+    ///
+    /// For synchronous handlers; async ones should use `scope`.
+    ///
+    /// # Arguments
+    /// * `f` - The request's handler
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        /// Puts back the context's previous request ID, even on panic
+        struct Restore(Option<serde_json::Value>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                match self.0.take() {
+                    Some(previous) => context::insert(REQUEST_ID_FIELD, previous),
+                    None => {
+                        context::remove(REQUEST_ID_FIELD);
+                    }
+                }
+            }
+        }
+
+        let _restore = Restore(context::remove(REQUEST_ID_FIELD));
+        context::insert(REQUEST_ID_FIELD, self.0.as_str());
+        f()
+    }
+}
+
+/// Generate a random number, unique within the process
+///
+/// Used for IDs rather than anything needing cryptographic randomness.
+pub(crate) fn random_u64() -> u64 {
+    let count = GENERATED.fetch_add(1, Ordering::Relaxed);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    // Each `RandomState` is keyed differently, and randomly per process.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(count);
+    hasher.write_u128(nanos);
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
@@ -0,0 +1,418 @@
+//! Monitored resource attached to every entry.
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::error::ConfigError;
+
+/// Environment variable Cloud Run sets to the service name
+pub const CLOUD_RUN_SERVICE_ENV_VAR: &str = "K_SERVICE";
+
+/// Environment variable Cloud Run sets to the revision name
+pub const CLOUD_RUN_REVISION_ENV_VAR: &str = "K_REVISION";
+
+/// Environment variable Cloud Run sets to the configuration name
+pub const CLOUD_RUN_CONFIGURATION_ENV_VAR: &str = "K_CONFIGURATION";
+
+/// Monitored resource types with the labels Cloud Logging requires for each
+///
+/// Every variant carries exactly the labels its type needs, so an entry can
+/// never be rejected for missing or misspelled resource labels. The
+/// constructors, e.g. `ResourceType::generic_task`, also reject empty labels.
+/// Converts into a `MonitoredResource`, which remains the escape hatch for
+/// other types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResourceType {
+    /// A Compute Engine VM instance
+    GceInstance {
+        /// Google Cloud project ID
+        project_id: String,
+        /// Numeric instance ID
+        instance_id: String,
+        /// Zone of the instance, e.g. `us-central1-a`
+        zone: String,
+    },
+    /// A container in a GKE (or other Kubernetes) cluster
+    K8sContainer {
+        /// Google Cloud project ID
+        project_id: String,
+        /// Cluster location, a region or zone
+        location: String,
+        /// Name of the cluster
+        cluster_name: String,
+        /// Namespace of the pod
+        namespace_name: String,
+        /// Name of the pod
+        pod_name: String,
+        /// Name of the container within the pod
+        container_name: String,
+    },
+    /// A Cloud Run service revision
+    CloudRunRevision {
+        /// Google Cloud project ID
+        project_id: String,
+        /// Name of the service
+        service_name: String,
+        /// Name of the revision
+        revision_name: String,
+        /// Name of the configuration the revision belongs to
+        configuration_name: String,
+        /// Region the service runs in
+        location: String,
+    },
+    /// Not tied to any specific resource
+    Global {
+        /// Google Cloud project ID
+        project_id: String,
+    },
+    /// A task of a user-defined job, e.g. a batch worker
+    GenericTask {
+        /// Google Cloud project ID
+        project_id: String,
+        /// Where the task runs, e.g. a region or data center
+        location: String,
+        /// Namespace grouping jobs, e.g. an environment
+        namespace: String,
+        /// Name of the job
+        job: String,
+        /// Identifier of the task within the job
+        task_id: String,
+    },
+    /// A user-defined machine or node
+    GenericNode {
+        /// Google Cloud project ID
+        project_id: String,
+        /// Where the node runs, e.g. a region or data center
+        location: String,
+        /// Namespace grouping nodes, e.g. an environment
+        namespace: String,
+        /// Identifier of the node
+        node_id: String,
+    },
+}
+
+impl ResourceType {
+    /// A Compute Engine VM instance
+    ///
+    /// # Arguments
+    /// * `project_id` - Google Cloud project ID
+    /// * `instance_id` - Numeric instance ID
+    /// * `zone` - Zone of the instance, e.g. `us-central1-a`
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` naming an empty label
+    pub fn gce_instance(
+        project_id: impl Into<String>,
+        instance_id: impl Into<String>,
+        zone: impl Into<String>,
+    ) -> Result<Self, ConfigError> {
+        ResourceType::GceInstance {
+            project_id: project_id.into(),
+            instance_id: instance_id.into(),
+            zone: zone.into(),
+        }
+        .validated()
+    }
+
+    /// A container in a GKE (or other Kubernetes) cluster
+    ///
+    /// # Arguments
+    /// * `project_id` - Google Cloud project ID
+    /// * `location` - Cluster location, a region or zone
+    /// * `cluster_name` - Name of the cluster
+    /// * `namespace_name` - Namespace of the pod
+    /// * `pod_name` - Name of the pod
+    /// * `container_name` - Name of the container within the pod
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` naming an empty label
+    pub fn k8s_container(
+        project_id: impl Into<String>,
+        location: impl Into<String>,
+        cluster_name: impl Into<String>,
+        namespace_name: impl Into<String>,
+        pod_name: impl Into<String>,
+        container_name: impl Into<String>,
+    ) -> Result<Self, ConfigError> {
+        ResourceType::K8sContainer {
+            project_id: project_id.into(),
+            location: location.into(),
+            cluster_name: cluster_name.into(),
+            namespace_name: namespace_name.into(),
+            pod_name: pod_name.into(),
+            container_name: container_name.into(),
+        }
+        .validated()
+    }
+
+    /// A Cloud Run service revision
+    ///
+    /// # Arguments
+    /// * `project_id` - Google Cloud project ID
+    /// * `service_name` - Name of the service
+    /// * `revision_name` - Name of the revision
+    /// * `configuration_name` - Name of the configuration the revision belongs to
+    /// * `location` - Region the service runs in
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` naming an empty label
+    pub fn cloud_run_revision(
+        project_id: impl Into<String>,
+        service_name: impl Into<String>,
+        revision_name: impl Into<String>,
+        configuration_name: impl Into<String>,
+        location: impl Into<String>,
+    ) -> Result<Self, ConfigError> {
+        ResourceType::CloudRunRevision {
+            project_id: project_id.into(),
+            service_name: service_name.into(),
+            revision_name: revision_name.into(),
+            configuration_name: configuration_name.into(),
+            location: location.into(),
+        }
+        .validated()
+    }
+
+    /// A resource not tied to anything specific
+    ///
+    /// # Arguments
+    /// * `project_id` - Google Cloud project ID
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` if the project is empty
+    pub fn global(project_id: impl Into<String>) -> Result<Self, ConfigError> {
+        ResourceType::Global {
+            project_id: project_id.into(),
+        }
+        .validated()
+    }
+
+    /// A task of a user-defined job, e.g. a batch worker
+    ///
+    /// # Arguments
+    /// * `project_id` - Google Cloud project ID
+    /// * `location` - Where the task runs, e.g. a region or data center
+    /// * `namespace` - Namespace grouping jobs, e.g. an environment
+    /// * `job` - Name of the job
+    /// * `task_id` - Identifier of the task within the job
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` naming an empty label
+    pub fn generic_task(
+        project_id: impl Into<String>,
+        location: impl Into<String>,
+        namespace: impl Into<String>,
+        job: impl Into<String>,
+        task_id: impl Into<String>,
+    ) -> Result<Self, ConfigError> {
+        ResourceType::GenericTask {
+            project_id: project_id.into(),
+            location: location.into(),
+            namespace: namespace.into(),
+            job: job.into(),
+            task_id: task_id.into(),
+        }
+        .validated()
+    }
+
+    /// A user-defined machine or node
+    ///
+    /// # Arguments
+    /// * `project_id` - Google Cloud project ID
+    /// * `location` - Where the node runs, e.g. a region or data center
+    /// * `namespace` - Namespace grouping nodes, e.g. an environment
+    /// * `node_id` - Identifier of the node
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` naming an empty label
+    pub fn generic_node(
+        project_id: impl Into<String>,
+        location: impl Into<String>,
+        namespace: impl Into<String>,
+        node_id: impl Into<String>,
+    ) -> Result<Self, ConfigError> {
+        ResourceType::GenericNode {
+            project_id: project_id.into(),
+            location: location.into(),
+            namespace: namespace.into(),
+            node_id: node_id.into(),
+        }
+        .validated()
+    }
+
+    /// The `resource.type` Cloud Logging uses for this variant
+    ///
+    /// # Returns
+    /// A static string such as `"k8s_container"`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceType::GceInstance { .. } => "gce_instance",
+            ResourceType::K8sContainer { .. } => "k8s_container",
+            ResourceType::CloudRunRevision { .. } => "cloud_run_revision",
+            ResourceType::Global { .. } => "global",
+            ResourceType::GenericTask { .. } => "generic_task",
+            ResourceType::GenericNode { .. } => "generic_node",
+        }
+    }
+
+    /// The labels of the resource, keyed as Cloud Logging expects
+    fn labels(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            ResourceType::GceInstance {
+                project_id,
+                instance_id,
+                zone,
+            } => vec![
+                ("project_id", project_id),
+                ("instance_id", instance_id),
+                ("zone", zone),
+            ],
+            ResourceType::K8sContainer {
+                project_id,
+                location,
+                cluster_name,
+                namespace_name,
+                pod_name,
+                container_name,
+            } => vec![
+                ("project_id", project_id),
+                ("location", location),
+                ("cluster_name", cluster_name),
+                ("namespace_name", namespace_name),
+                ("pod_name", pod_name),
+                ("container_name", container_name),
+            ],
+            ResourceType::CloudRunRevision {
+                project_id,
+                service_name,
+                revision_name,
+                configuration_name,
+                location,
+            } => vec![
+                ("project_id", project_id),
+                ("service_name", service_name),
+                ("revision_name", revision_name),
+                ("configuration_name", configuration_name),
+                ("location", location),
+            ],
+            ResourceType::Global { project_id } => vec![("project_id", project_id)],
+            ResourceType::GenericTask {
+                project_id,
+                location,
+                namespace,
+                job,
+                task_id,
+            } => vec![
+                ("project_id", project_id),
+                ("location", location),
+                ("namespace", namespace),
+                ("job", job),
+                ("task_id", task_id),
+            ],
+            ResourceType::GenericNode {
+                project_id,
+                location,
+                namespace,
+                node_id,
+            } => vec![
+                ("project_id", project_id),
+                ("location", location),
+                ("namespace", namespace),
+                ("node_id", node_id),
+            ],
+        }
+    }
+
+    /// Reject the resource if one of its labels is empty
+    ///
+    /// # Returns
+    /// The resource, or `ConfigError::MissingField` naming the first empty label
+    fn validated(self) -> Result<Self, ConfigError> {
+        match self
+            .labels()
+            .into_iter()
+            .find(|(_, value)| value.is_empty())
+        {
+            Some((key, _)) => Err(ConfigError::MissingField(key)),
+            None => Ok(self),
+        }
+    }
+}
+
+impl From<ResourceType> for MonitoredResource {
+    fn from(resource: ResourceType) -> Self {
+        let base = MonitoredResource::new(resource.as_str());
+        resource
+            .labels()
+            .into_iter()
+            .fold(base, |base, (key, value)| base.label(key, value))
+    }
+}
+
+/// Cloud Logging `MonitoredResource` an entry is attached to
+///
+/// Set with `GcpLoggingLayerBuilder::resource` to replace the detected
+/// `gce_instance` resource verbatim. Prefer building one from a
+/// `ResourceType`; construct it directly for types without a variant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MonitoredResource {
+    /// Resource type, e.g. `generic_task` or `k8s_container`
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    /// Labels identifying the resource, as required by its type
+    pub labels: BTreeMap<String, String>,
+}
+
+impl MonitoredResource {
+    /// Create a resource without labels
+    ///
+    /// # Arguments
+    /// * `resource_type` - The monitored resource type
+    pub fn new(resource_type: impl Into<String>) -> Self {
+        Self {
+            resource_type: resource_type.into(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// Add a resource label
+    ///
+    /// # Arguments
+    /// * `key` - Label key
+    /// * `value` - Label value
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Build the `cloud_run_revision` resource when running on Cloud Run
+    ///
+    /// Cloud Run is detected through `CLOUD_RUN_SERVICE_ENV_VAR`; the revision
+    /// and configuration come from their own variables.
+    ///
+    /// # Arguments
+    /// * `project_id` - The Google Cloud project ID
+    /// * `location` - The region the service runs in
+    ///
+    /// # Returns
+    /// The resource, or None when not running on Cloud Run
+    pub(crate) fn cloud_run(project_id: &str, location: &str) -> Option<Self> {
+        let service_name = std::env::var(CLOUD_RUN_SERVICE_ENV_VAR).ok()?;
+        let env = |name| std::env::var(name).unwrap_or_default();
+        Some(
+            ResourceType::CloudRunRevision {
+                project_id: project_id.to_string(),
+                service_name,
+                revision_name: env(CLOUD_RUN_REVISION_ENV_VAR),
+                configuration_name: env(CLOUD_RUN_CONFIGURATION_ENV_VAR),
+                location: location.to_string(),
+            }
+            .into(),
+        )
+    }
+}
+
+/// Whether the process runs on Cloud Run
+pub(crate) fn on_cloud_run() -> bool {
+    std::env::var_os(CLOUD_RUN_SERVICE_ENV_VAR).is_some()
+}
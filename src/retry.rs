@@ -0,0 +1,468 @@
+//! Bounded queue of failed batches, retried with backoff off the live path.
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::task::JoinHandle;
+
+use crate::batch::count_entries;
+use crate::circuit::CircuitState;
+use crate::metrics::Metrics;
+use crate::transport::Delivery;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::transport::Transport;
+
+/// Which batch is discarded when the retry queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOverflow {
+    /// Discard the oldest waiting batch to make room for the new one
+    DropOldest,
+    /// Discard the batch that just failed
+    DropNewest,
+}
+
+/// Controls how failed batches are retried
+///
+/// Batches that fail with a transport error, 429 or 5xx are moved to a retry
+/// queue served by its own task, so fresh entries keep flowing while retries
+/// back off. The delay doubles from `initial_backoff` up to `max_backoff`, and
+/// a batch is dropped after `max_attempts` retries.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Failed batches kept waiting for a retry
+    pub capacity: usize,
+    /// Delay before the first retry of a batch
+    pub initial_backoff: Duration,
+    /// Upper bound on the delay between retries
+    pub max_backoff: Duration,
+    /// Retries attempted before a batch is dropped; 0 disables retries
+    pub max_attempts: u32,
+    /// What to discard when the queue is full
+    pub overflow: RetryOverflow,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: 5,
+            overflow: RetryOverflow::DropOldest,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before a retry
+    ///
+    /// # Arguments
+    /// * `attempts` - Retries already made for the batch
+    pub(crate) fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Whether a batch that failed again is given up on
+    ///
+    /// # Arguments
+    /// * `attempts` - Retries already made for the batch
+    pub(crate) fn exhausted(&self, attempts: u32) -> bool {
+        attempts >= self.max_attempts
+    }
+}
+
+/// A failed batch waiting to be resent
+struct RetryItem {
+    /// The uncompressed request body
+    body: Vec<u8>,
+    /// Whether the body should be compressed when sent
+    compress: bool,
+    /// Retries already made
+    attempts: u32,
+}
+
+/// Failed batches shared between the worker and the retry task
+pub(crate) struct RetryQueue {
+    /// Backoff and capacity settings
+    policy: RetryPolicy,
+    /// Waiting batches, oldest first
+    items: Mutex<VecDeque<RetryItem>>,
+    /// Wakes the retry task when a batch is queued or the queue closes
+    notify: Notify,
+    /// Set once the worker stops
+    closed: AtomicBool,
+    /// Depth and drop counters
+    metrics: Arc<Metrics>,
+}
+
+impl RetryQueue {
+    /// Create an empty queue
+    ///
+    /// # Arguments
+    /// * `policy` - Backoff and capacity settings
+    /// * `metrics` - Counters updated as batches are queued and dropped
+    pub(crate) fn new(policy: RetryPolicy, metrics: Arc<Metrics>) -> Self {
+        Self {
+            policy,
+            items: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Queue a batch that failed on the live path
+    ///
+    /// # Arguments
+    /// * `body` - The uncompressed request body
+    /// * `compress` - Whether the body should be compressed when sent
+    pub(crate) fn push(&self, body: Vec<u8>, compress: bool) {
+        if self.policy.exhausted(0) {
+            self.metrics.give_up(count_entries(&body));
+            return;
+        }
+        self.enqueue(RetryItem {
+            body,
+            compress,
+            attempts: 0,
+        });
+    }
+
+    /// Stop the retry task, giving up on the batches still waiting
+    pub(crate) fn close(&self) {
+        for (body, _) in self.drain() {
+            self.metrics.give_up(count_entries(&body));
+        }
+    }
+
+    /// Stop the retry task and take every waiting batch
+    ///
+    /// # Returns
+    /// The request bodies, oldest first, with whether to compress them
+    pub(crate) fn drain(&self) -> Vec<(Vec<u8>, bool)> {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+        let Ok(mut items) = self.items.lock() else {
+            return Vec::new();
+        };
+        self.metrics.retry_queue_depth.store(0, Ordering::Relaxed);
+        items
+            .drain(..)
+            .map(|item| (item.body, item.compress))
+            .collect()
+    }
+
+    /// Add a batch at the back, applying the overflow policy
+    fn enqueue(&self, item: RetryItem) {
+        let Ok(mut items) = self.items.lock() else {
+            return;
+        };
+        if items.len() >= self.policy.capacity {
+            let evicted = match self.policy.overflow {
+                RetryOverflow::DropNewest => None,
+                RetryOverflow::DropOldest => items.pop_front(),
+            };
+            let Some(evicted) = evicted else {
+                self.metrics.give_up(count_entries(&item.body));
+                return;
+            };
+            self.metrics.give_up(count_entries(&evicted.body));
+        }
+        items.push_back(item);
+        self.metrics
+            .retry_queue_depth
+            .store(items.len(), Ordering::Relaxed);
+        drop(items);
+        self.notify.notify_one();
+    }
+
+    /// Put a batch back at the front, keeping it ahead of newer failures
+    ///
+    /// A batch whose resend was in flight when the queue closed is given up on.
+    fn requeue(&self, item: RetryItem) {
+        if self.closed.load(Ordering::Relaxed) {
+            self.metrics.give_up(count_entries(&item.body));
+            return;
+        }
+        if let Ok(mut items) = self.items.lock() {
+            items.push_front(item);
+            self.metrics
+                .retry_queue_depth
+                .store(items.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Wait for the oldest batch and its backoff, or None once the queue is closed
+    async fn next(&self) -> Option<RetryItem> {
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            let attempts = self
+                .items
+                .lock()
+                .ok()
+                .and_then(|items| items.front().map(|item| item.attempts));
+            let Some(attempts) = attempts else {
+                self.notify.notified().await;
+                continue;
+            };
+            // The batch stays queued while backing off, so `drain` can still take it.
+            tokio::time::sleep(self.policy.backoff(attempts)).await;
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            if let Ok(mut items) = self.items.lock() {
+                if let Some(item) = items.pop_front() {
+                    self.metrics
+                        .retry_queue_depth
+                        .store(items.len(), Ordering::Relaxed);
+                    return Some(item);
+                }
+            }
+        }
+    }
+
+    /// Spawn the task resending queued batches on the current Tokio runtime
+    ///
+    /// # Arguments
+    /// * `transport` - Transport used for resends
+    /// * `circuit_state` - Breaker state; resends wait while the circuit is open
+    ///
+    /// # Returns
+    /// The task, which ends once the queue is closed
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn spawn(
+        self: &Arc<Self>,
+        transport: Transport,
+        circuit_state: Arc<AtomicU8>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(self.clone().run(circuit_state, move |body, compress| {
+            let transport = transport.clone();
+            async move { transport.deliver(body, compress).await }
+        }))
+    }
+
+    /// Resend queued batches until the queue is closed
+    ///
+    /// # Arguments
+    /// * `circuit_state` - Breaker state; resends wait while the circuit is open
+    /// * `deliver` - Uploads a body, given whether to compress it
+    async fn run<F, Fut>(self: Arc<Self>, circuit_state: Arc<AtomicU8>, mut deliver: F)
+    where
+        F: FnMut(Vec<u8>, bool) -> Fut,
+        Fut: Future<Output = Delivery>,
+    {
+        while let Some(mut item) = self.next().await {
+            if CircuitState::from_u8(circuit_state.load(Ordering::Relaxed)) == CircuitState::Open {
+                // The worker is already probing; retrying now would only add load.
+                self.requeue(item);
+                continue;
+            }
+            let entries = count_entries(&item.body);
+            Metrics::add(&self.metrics.retried, entries);
+            if deliver(item.body.clone(), item.compress).await != Delivery::Retryable {
+                continue;
+            }
+            item.attempts += 1;
+            if self.policy.exhausted(item.attempts) {
+                self.metrics.give_up(entries);
+            } else {
+                self.requeue(item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::DeliveryMetrics;
+    use tokio::time::Instant;
+
+    /// A request body holding the given number of entries
+    fn body(entries: usize) -> Vec<u8> {
+        let entries = vec!["{}"; entries].join(",");
+        format!(r#"{{"entries":[{}]}}"#, entries).into_bytes()
+    }
+
+    /// Resends recorded with the time they were made
+    type Attempts = Arc<Mutex<Vec<(Instant, Vec<u8>)>>>;
+
+    /// Run the retry task against an upload answering `delivery` every time
+    ///
+    /// # Returns
+    /// The queue, its counters, the breaker state and the recorded resends
+    fn start(
+        policy: RetryPolicy,
+        delivery: Delivery,
+    ) -> (Arc<RetryQueue>, Arc<Metrics>, Arc<AtomicU8>, Attempts) {
+        let metrics = Arc::new(Metrics::default());
+        let queue = Arc::new(RetryQueue::new(policy, metrics.clone()));
+        let state = Arc::new(AtomicU8::new(CircuitState::Closed.to_u8()));
+        let attempts = Attempts::default();
+        let recorded = attempts.clone();
+        tokio::spawn(queue.clone().run(state.clone(), move |body, _| {
+            recorded.lock().unwrap().push((Instant::now(), body));
+            std::future::ready(delivery)
+        }));
+        (queue, metrics, state, attempts)
+    }
+
+    /// Times of the resends, relative to `start`
+    fn offsets(attempts: &Attempts, start: Instant) -> Vec<Duration> {
+        let attempts = attempts.lock().unwrap();
+        attempts.iter().map(|(at, _)| *at - start).collect()
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = (0..5).map(|attempts| policy.backoff(attempts)).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_back_off_and_give_up_after_max_attempts() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(3),
+            max_attempts: 4,
+            ..RetryPolicy::default()
+        };
+        let (queue, metrics, _, attempts) = start(policy, Delivery::Retryable);
+        let start = Instant::now();
+        queue.push(body(3), false);
+        assert_eq!(metrics.snapshot().retry_queue_depth, 1);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(
+            offsets(&attempts, start),
+            [1, 3, 6, 9].map(Duration::from_secs)
+        );
+        assert_eq!(metrics.snapshot().retry_queue_depth, 0);
+        assert_eq!(metrics.snapshot().retry_dropped, 1);
+        let stats = metrics.stats();
+        assert_eq!(stats.retried, 12);
+        assert_eq!(stats.failed, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_successful_retry_leaves_the_queue() {
+        let (queue, metrics, _, attempts) = start(RetryPolicy::default(), Delivery::Sent);
+        queue.push(body(2), true);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(attempts.lock().unwrap().len(), 1);
+        assert_eq!(metrics.snapshot(), DeliveryMetrics::default());
+        assert_eq!(metrics.stats().failed, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_retries_gives_up_right_away() {
+        let policy = RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        };
+        let (queue, metrics, _, attempts) = start(policy, Delivery::Sent);
+        queue.push(body(2), false);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(attempts.lock().unwrap().is_empty());
+        assert_eq!(metrics.snapshot().retry_dropped, 1);
+        assert_eq!(metrics.stats().failed, 2);
+    }
+
+    #[test]
+    fn test_full_queue_drops_the_oldest_batch() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = RetryQueue::new(
+            RetryPolicy {
+                capacity: 2,
+                overflow: RetryOverflow::DropOldest,
+                ..RetryPolicy::default()
+            },
+            metrics.clone(),
+        );
+        queue.push(body(1), false);
+        queue.push(body(2), false);
+        queue.push(body(3), false);
+        assert_eq!(metrics.snapshot().retry_queue_depth, 2);
+        assert_eq!(metrics.snapshot().retry_dropped, 1);
+        assert_eq!(metrics.stats().failed, 1);
+        let kept: Vec<_> = queue.drain().into_iter().map(|(body, _)| body).collect();
+        assert_eq!(kept, [body(2), body(3)]);
+    }
+
+    #[test]
+    fn test_full_queue_drops_the_newest_batch() {
+        let metrics = Arc::new(Metrics::default());
+        let queue = RetryQueue::new(
+            RetryPolicy {
+                capacity: 2,
+                overflow: RetryOverflow::DropNewest,
+                ..RetryPolicy::default()
+            },
+            metrics.clone(),
+        );
+        queue.push(body(1), false);
+        queue.push(body(2), false);
+        queue.push(body(3), false);
+        assert_eq!(metrics.snapshot().retry_queue_depth, 2);
+        assert_eq!(metrics.snapshot().retry_dropped, 1);
+        assert_eq!(metrics.stats().failed, 3);
+        let kept: Vec<_> = queue.drain().into_iter().map(|(body, _)| body).collect();
+        assert_eq!(kept, [body(1), body(2)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_wait_while_the_circuit_is_open() {
+        let (queue, metrics, state, attempts) = start(RetryPolicy::default(), Delivery::Sent);
+        state.store(CircuitState::Open.to_u8(), Ordering::Relaxed);
+        queue.push(body(1), false);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert!(attempts.lock().unwrap().is_empty());
+        assert_eq!(metrics.snapshot().retry_queue_depth, 1);
+        assert_eq!(metrics.stats().retried, 0);
+
+        // The batch is resent after its next backoff once the circuit closes.
+        state.store(CircuitState::Closed.to_u8(), Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(attempts.lock().unwrap().len(), 1);
+        assert_eq!(metrics.snapshot().retry_queue_depth, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_gives_up_on_waiting_batches() {
+        let (queue, metrics, _, attempts) = start(RetryPolicy::default(), Delivery::Sent);
+        queue.push(body(2), false);
+        queue.push(body(3), false);
+        queue.close();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(attempts.lock().unwrap().is_empty());
+        assert_eq!(metrics.snapshot().retry_queue_depth, 0);
+        assert_eq!(metrics.snapshot().retry_dropped, 2);
+        assert_eq!(metrics.stats().failed, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_hands_over_waiting_batches() {
+        let (queue, metrics, _, attempts) = start(RetryPolicy::default(), Delivery::Sent);
+        queue.push(body(2), true);
+        assert_eq!(queue.drain(), [(body(2), true)]);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(attempts.lock().unwrap().is_empty());
+        assert_eq!(metrics.snapshot(), DeliveryMetrics::default());
+    }
+}
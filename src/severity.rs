@@ -0,0 +1,88 @@
+//! Cloud Logging severities and their mapping from `tracing` levels.
+use serde::Serialize;
+
+/// Severity of a log entry, as defined by Cloud Logging's `LogSeverity`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Severity {
+    /// The entry has no assigned severity level
+    Default,
+    /// Debug or trace information
+    Debug,
+    /// Routine information
+    Info,
+    /// Normal but significant events
+    Notice,
+    /// Events that might cause problems
+    Warning,
+    /// Events that are likely to cause problems
+    Error,
+    /// Events that cause more severe problems or outages
+    Critical,
+    /// A person must take an action immediately
+    Alert,
+    /// One or more systems are unusable
+    Emergency,
+}
+
+impl Severity {
+    /// The name Cloud Logging uses for this severity
+    ///
+    /// # Returns
+    /// A static string such as `"WARNING"`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Default => "DEFAULT",
+            Severity::Debug => "DEBUG",
+            Severity::Info => "INFO",
+            Severity::Notice => "NOTICE",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+            Severity::Critical => "CRITICAL",
+            Severity::Alert => "ALERT",
+            Severity::Emergency => "EMERGENCY",
+        }
+    }
+}
+
+impl Severity {
+    /// The numeric value Cloud Logging assigns to this severity
+    ///
+    /// Values follow `LogSeverity` (`DEBUG` = 100 up to `EMERGENCY` = 800), so
+    /// larger numbers are more severe. `tracing`'s `TRACE` and `DEBUG` levels
+    /// both map to `DEBUG` (100).
+    ///
+    /// # Returns
+    /// The numeric severity
+    pub fn number(&self) -> u16 {
+        match self {
+            Severity::Default => 0,
+            Severity::Debug => 100,
+            Severity::Info => 200,
+            Severity::Notice => 300,
+            Severity::Warning => 400,
+            Severity::Error => 500,
+            Severity::Critical => 600,
+            Severity::Alert => 700,
+            Severity::Emergency => 800,
+        }
+    }
+}
+
+impl From<&tracing::Level> for Severity {
+    fn from(level: &tracing::Level) -> Self {
+        match *level {
+            tracing::Level::ERROR => Severity::Error,
+            tracing::Level::WARN => Severity::Warning,
+            tracing::Level::INFO => Severity::Info,
+            tracing::Level::DEBUG => Severity::Debug,
+            tracing::Level::TRACE => Severity::Debug,
+        }
+    }
+}
+
+impl From<tracing::Level> for Severity {
+    fn from(level: tracing::Level) -> Self {
+        Severity::from(&level)
+    }
+}
@@ -0,0 +1,116 @@
+//! Coordination between `GcpLoggingHandle::shutdown` and the background task.
+use std::sync::Mutex;
+
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::transport::Delivery;
+
+/// What happened to the entries still pending when shutdown was requested
+///
+/// Only entries handled by the shutdown itself are counted; entries sent or
+/// dropped earlier are not.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Entries accepted by the API, or written to the sink
+    pub sent: usize,
+    /// Entries rejected by the API or discarded for lack of credentials,
+    /// including those printed by the stdout fallback
+    pub dropped: usize,
+    /// Entries still undelivered when the timeout elapsed or the last attempt
+    /// failed in a way that could have succeeded later
+    pub remaining: usize,
+}
+
+impl ShutdownReport {
+    /// Entries that did not reach Cloud Logging
+    ///
+    /// # Returns
+    /// `dropped + remaining`
+    pub fn undelivered(&self) -> usize {
+        self.dropped + self.remaining
+    }
+
+    /// Count the entries of a request by the outcome of its final upload
+    ///
+    /// # Arguments
+    /// * `entries` - Entries in the request
+    /// * `delivery` - The outcome, `Delivery::Retryable` if it was not attempted
+    pub(crate) fn record(&mut self, entries: usize, delivery: Delivery) {
+        match delivery {
+            Delivery::Sent => self.sent += entries,
+            Delivery::Rejected | Delivery::Forbidden => self.dropped += entries,
+            Delivery::Retryable => self.remaining += entries,
+        }
+    }
+}
+
+/// A shutdown request and its outcome, shared by handles and the worker
+pub(crate) struct Shutdown {
+    /// Deadline of the first request, None until shutdown is requested
+    deadline: Mutex<Option<Instant>>,
+    /// Wakes the worker when shutdown is requested
+    requested: Notify,
+    /// Outcome, published once the worker stopped
+    finished: watch::Sender<Option<ShutdownReport>>,
+}
+
+impl Shutdown {
+    /// Create a coordinator with no pending request
+    pub(crate) fn new() -> Self {
+        Self {
+            deadline: Mutex::new(None),
+            requested: Notify::new(),
+            finished: watch::Sender::new(None),
+        }
+    }
+
+    /// Ask the worker to drain and stop
+    ///
+    /// Later requests keep the first deadline.
+    ///
+    /// # Arguments
+    /// * `deadline` - When the worker stops trying to deliver
+    ///
+    /// # Returns
+    /// A receiver seeing the outcome once the worker stopped
+    pub(crate) fn request(&self, deadline: Instant) -> watch::Receiver<Option<ShutdownReport>> {
+        if let Ok(mut requested) = self.deadline.lock() {
+            requested.get_or_insert(deadline);
+        }
+        self.requested.notify_one();
+        self.finished.subscribe()
+    }
+
+    /// Wait until shutdown is requested
+    ///
+    /// # Returns
+    /// The deadline of the request
+    pub(crate) async fn requested(&self) -> Instant {
+        loop {
+            if let Some(deadline) = self.deadline.lock().ok().and_then(|deadline| *deadline) {
+                return deadline;
+            }
+            self.requested.notified().await;
+        }
+    }
+
+    /// Publish the outcome of the shutdown
+    ///
+    /// # Arguments
+    /// * `report` - What happened to the pending entries
+    pub(crate) fn finish(&self, report: ShutdownReport) {
+        self.finished.send_replace(Some(report));
+    }
+
+    /// Wait until a shutdown finished
+    pub(crate) async fn finished(&self) {
+        let mut finished = self.finished.subscribe();
+        let _ = finished.wait_for(Option::is_some).await;
+    }
+
+    /// The outcome of an earlier shutdown, if it finished
+    pub(crate) fn outcome(&self) -> Option<ShutdownReport> {
+        *self.finished.borrow()
+    }
+}
@@ -0,0 +1,112 @@
+//! Destination for built entries replacing uploads, used by downstream tests.
+#[cfg(feature = "testing")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "testing")]
+use std::time::Duration;
+
+use serde_json::Value;
+#[cfg(feature = "testing")]
+use tokio::sync::Notify;
+
+/// Receives every entry in its final Cloud Logging JSON form
+///
+/// Installed with `GcpLoggingLayerBuilder::sink` (behind the `testing`
+/// feature), a sink replaces uploads entirely: entries are handed over one by
+/// one from the background task, whether or not credentials are available.
+pub trait LogSink: Send + Sync + 'static {
+    /// Accept one entry
+    ///
+    /// # Arguments
+    /// * `entry` - The JSON that would be placed in an `entries:write` request
+    fn write(&self, entry: Value);
+}
+
+/// Sink recording every entry, for asserting on what the layer ships
+///
+/// Clones share the recorded entries, so install one clone with
+/// `GcpLoggingLayerBuilder::sink` and keep another to inspect.
+#[cfg(feature = "testing")]
+#[derive(Clone, Default)]
+pub struct TestSink {
+    /// Entries received so far, oldest first
+    entries: Arc<Mutex<Vec<Value>>>,
+    /// Wakes `wait_for` whenever an entry arrives
+    written: Arc<Notify>,
+}
+
+#[cfg(feature = "testing")]
+impl TestSink {
+    /// Create an empty sink
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The entries received so far, oldest first
+    pub fn entries(&self) -> Vec<Value> {
+        self.entries
+            .lock()
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+
+    /// Wait until at least `count` entries were received
+    ///
+    /// Entries are built on the background task, so they arrive shortly after
+    /// being emitted.
+    ///
+    /// # Arguments
+    /// * `count` - The number of entries to wait for
+    /// * `timeout` - How long to wait at most
+    ///
+    /// # Returns
+    /// The entries received, fewer than `count` if the timeout elapsed
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> Vec<Value> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let written = self.written.notified();
+            tokio::pin!(written);
+            // Register before checking so an entry written in between wakes us.
+            written.as_mut().enable();
+            let entries = self.entries();
+            if entries.len() >= count {
+                return entries;
+            }
+            if tokio::time::timeout_at(deadline, written).await.is_err() {
+                return self.entries();
+            }
+        }
+    }
+
+    /// Assert that an entry matching `predicate` was received
+    ///
+    /// # Arguments
+    /// * `predicate` - Test applied to each entry's JSON
+    ///
+    /// # Returns
+    /// The first matching entry
+    ///
+    /// # Panics
+    /// If no entry matches, listing the entries received
+    #[track_caller]
+    pub fn assert_contains(&self, predicate: impl Fn(&Value) -> bool) -> Value {
+        let entries = self.entries();
+        match entries.iter().find(|entry| predicate(entry)) {
+            Some(entry) => entry.clone(),
+            None => panic!(
+                "no matching entry among the {} received:\n{}",
+                entries.len(),
+                serde_json::to_string_pretty(&entries).unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+impl LogSink for TestSink {
+    fn write(&self, entry: Value) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.push(entry);
+        }
+        self.written.notify_waiters();
+    }
+}
@@ -0,0 +1,344 @@
+//! Span fields attached to events, and optional span open/close entries.
+use std::time::Instant;
+
+use chrono::Utc;
+use serde_json::{Map, Value};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::cloud_trace::{SpanExporter, SpanIds};
+use crate::entry::{GcpLogEntry, Operation};
+use crate::trace::{TraceContext, TRACE_HEADER_FIELDS};
+use crate::visitor::{FieldVisitor, PendingFields};
+use crate::GcpLoggingLayer;
+
+/// Payload key of the span fields in `SpanFieldMode::Nested`
+pub(crate) const SPAN_CONTEXT_FIELD: &str = "context";
+
+/// How the fields of the spans an event is emitted in are added to its payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpanFieldMode {
+    /// Merge span fields into the payload; inner spans override outer ones and
+    /// event fields override both
+    #[default]
+    Flatten,
+    /// Group span fields under `context`, keyed by span name from outermost to
+    /// innermost, e.g. `context: { request: { path: .. }, query: { table: .. } }`
+    ///
+    /// Spans of the same name share an object, inner ones overriding outer
+    /// ones. An event field named `context` is renamed `user_context`.
+    Nested,
+    /// Leave span fields out of event payloads
+    Omit,
+    /// Merge span fields into the payload under keys prefixed with the span
+    /// name, e.g. `request.path` and `query.table` with a `.` separator
+    ///
+    /// Spans of the same name share keys, inner ones overriding outer ones.
+    /// An event field whose name equals a derived key, e.g. `request.path`,
+    /// overrides the span field.
+    Prefixed {
+        /// Placed between the span name and the field name
+        separator: &'static str,
+    },
+}
+
+impl SpanFieldMode {
+    /// `SpanFieldMode::Prefixed` with the `.` separator
+    pub fn prefixed() -> Self {
+        SpanFieldMode::Prefixed { separator: "." }
+    }
+}
+
+/// Fields recorded on a span so far, stored in the span's extensions
+struct SpanFields(Map<String, Value>);
+
+/// When a span with lifecycle logging opened, stored in the span's extensions
+struct SpanTiming(Instant);
+
+impl GcpLoggingLayer {
+    /// Record a new span's fields, emitting its opening entry if enabled
+    ///
+    /// # Arguments
+    /// * `attrs` - The new span's attributes
+    /// * `id` - The new span's id
+    /// * `ctx` - The tracing context
+    pub(crate) fn open_span<S>(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::new();
+        attrs.record(&mut visitor);
+        let fields = visitor
+            .into_pending(self.config.field_options(false))
+            .convert();
+        let ids = match &self.config.trace_export {
+            Some(export) if self.ships(span.metadata()) => {
+                let header = TRACE_HEADER_FIELDS.iter().find_map(|field| {
+                    TraceContext::from_header_field(field, fields.get(*field)?.as_str()?)
+                });
+                let parent = span.scope().skip(1).find_map(|ancestor| {
+                    let extensions = ancestor.extensions();
+                    let ids = extensions.get::<SpanIds>()?;
+                    Some(SpanIds::new(header.clone(), Some(ids), export.sample_ratio))
+                });
+                Some(parent.unwrap_or_else(|| SpanIds::new(header, None, export.sample_ratio)))
+            }
+            _ => None,
+        };
+        let mut extensions = span.extensions_mut();
+        if self.config.log_span_lifecycle && self.ships(span.metadata()) {
+            self.send_span_entry(span.metadata(), id, &fields, None);
+            extensions.insert(SpanTiming(Instant::now()));
+        }
+        if let Some(ids) = ids {
+            extensions.insert(ids);
+        }
+        extensions.insert(SpanFields(fields));
+    }
+
+    /// Add fields recorded after creation to a span
+    ///
+    /// # Arguments
+    /// * `id` - The span's id
+    /// * `values` - The newly recorded values
+    /// * `ctx` - The tracing context
+    pub(crate) fn record_span<S>(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut visitor = FieldVisitor::new();
+            values.record(&mut visitor);
+            fields.extend(
+                visitor
+                    .into_pending(self.config.field_options(false))
+                    .convert(),
+            );
+        }
+    }
+
+    /// Emit the closing entry of a timed span with its elapsed duration
+    ///
+    /// # Arguments
+    /// * `id` - The closed span's id
+    /// * `ctx` - The tracing context
+    pub(crate) fn close_span<S>(&self, id: &Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let (Some(SpanTiming(opened)), Some(SpanFields(fields))) = (
+            extensions.get::<SpanTiming>(),
+            extensions.get::<SpanFields>(),
+        ) else {
+            return;
+        };
+        self.send_span_entry(span.metadata(), id, fields, Some(*opened));
+    }
+
+    /// Send a closing span to Cloud Trace
+    ///
+    /// # Arguments
+    /// * `exporter` - The layer's span exporter
+    /// * `id` - The closed span's id
+    /// * `ctx` - The tracing context
+    pub(crate) fn export_span<S>(&self, exporter: &SpanExporter, id: &Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        if let (Some(ids), Some(SpanFields(fields))) =
+            (extensions.get::<SpanIds>(), extensions.get::<SpanFields>())
+        {
+            exporter.export(ids, span.name(), fields);
+        }
+    }
+
+    /// Read the Cloud Trace IDs of the innermost exported span around an event
+    ///
+    /// # Arguments
+    /// * `event` - The event being captured
+    /// * `ctx` - The tracing context
+    ///
+    /// # Returns
+    /// The span's trace context, None unless `trace_export` is enabled
+    pub(crate) fn span_trace<S>(
+        &self,
+        event: &Event<'_>,
+        ctx: &Context<'_, S>,
+    ) -> Option<TraceContext>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        self.config.trace_export.as_ref()?;
+        ctx.event_scope(event)?.find_map(|span| {
+            span.extensions()
+                .get::<SpanIds>()
+                .map(SpanIds::trace_context)
+        })
+    }
+
+    /// Add the fields of the spans an event is emitted in to its payload
+    ///
+    /// # Arguments
+    /// * `event` - The event being captured
+    /// * `ctx` - The tracing context
+    /// * `fields` - The event's own fields, updated in place
+    pub(crate) fn add_span_fields<S>(
+        &self,
+        event: &Event<'_>,
+        ctx: &Context<'_, S>,
+        fields: &mut Map<String, Value>,
+    ) where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if self.config.span_fields == SpanFieldMode::Omit {
+            return;
+        }
+        let Some(scope) = ctx.event_scope(event) else {
+            return;
+        };
+        let mut collected = Map::new();
+        let mut nested = Map::new();
+        for span in scope.from_root() {
+            let extensions = span.extensions();
+            let Some(SpanFields(span_fields)) = extensions.get::<SpanFields>() else {
+                continue;
+            };
+            let mut span_fields = span_fields.clone();
+            for field in TRACE_HEADER_FIELDS {
+                span_fields.remove(field);
+            }
+            match self.config.span_fields {
+                SpanFieldMode::Omit => {}
+                SpanFieldMode::Flatten => collected.extend(span_fields),
+                SpanFieldMode::Nested => match nested.get_mut(span.name()) {
+                    Some(Value::Object(outer)) => outer.extend(span_fields),
+                    _ => {
+                        nested.insert(span.name().into(), Value::Object(span_fields));
+                    }
+                },
+                SpanFieldMode::Prefixed { separator } => {
+                    let prefixed = span_fields.into_iter().map(|(key, value)| {
+                        (format!("{}{}{}", span.name(), separator, key), value)
+                    });
+                    collected.extend(prefixed);
+                }
+            }
+        }
+        if !nested.is_empty() {
+            collected.insert(SPAN_CONTEXT_FIELD.into(), Value::Object(nested));
+        }
+        collected.append(fields);
+        *fields = collected;
+    }
+
+    /// Take the trace context from a `traceparent` or `X-Cloud-Trace-Context` field
+    ///
+    /// The event's own fields win over those of enclosing spans, innermost
+    /// first, and `traceparent` wins over `cloud_trace_context`; malformed
+    /// values are skipped. The fields are removed from the event's payload.
+    ///
+    /// # Arguments
+    /// * `event` - The event being captured
+    /// * `ctx` - The tracing context
+    /// * `visitor` - The event's own fields, updated in place
+    ///
+    /// # Returns
+    /// The parsed trace context, if a valid header was recorded
+    pub(crate) fn header_trace<S>(
+        &self,
+        event: &Event<'_>,
+        ctx: &Context<'_, S>,
+        visitor: &mut FieldVisitor,
+    ) -> Option<TraceContext>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut own = None;
+        for field in TRACE_HEADER_FIELDS {
+            if let Some(value) = visitor.take(field) {
+                own = own.or_else(|| TraceContext::from_header_field(field, value.as_str()?));
+            }
+        }
+        if own.is_some() {
+            return own;
+        }
+        ctx.event_scope(event)?.find_map(|span| {
+            let extensions = span.extensions();
+            let SpanFields(span_fields) = extensions.get::<SpanFields>()?;
+            TRACE_HEADER_FIELDS.iter().find_map(|field| {
+                TraceContext::from_header_field(field, span_fields.get(*field)?.as_str()?)
+            })
+        })
+    }
+
+    /// Queue an entry marking the start or end of a span
+    ///
+    /// # Arguments
+    /// * `metadata` - The span's metadata
+    /// * `id` - The span's id
+    /// * `fields` - The span's fields
+    /// * `opened` - When the span opened, for the closing entry
+    fn send_span_entry(
+        &self,
+        metadata: &'static tracing::Metadata<'static>,
+        id: &Id,
+        fields: &Map<String, Value>,
+        opened: Option<Instant>,
+    ) {
+        let closed = opened.is_some();
+        let mut fields = fields.clone();
+        fields.insert("span".into(), metadata.name().into());
+        let action = if let Some(opened) = opened {
+            let elapsed = opened.elapsed().as_secs_f64() * 1000.0;
+            fields.insert("elapsed_ms".into(), elapsed.into());
+            "closed"
+        } else {
+            "opened"
+        };
+        let timestamp = Utc::now();
+        let severity = self.map_level_to_severity(Some(metadata.level()));
+        let message = format!("span {} {}", metadata.name(), action);
+        // Span ids are only unique within a process (and are reused once a
+        // span closes), so the process id keeps operations apart.
+        let operation = Operation {
+            id: format!("{:x}-{:x}", std::process::id(), id.into_u64()),
+            producer: metadata.target().to_string(),
+            first: !closed,
+            last: closed,
+        };
+        let result = self.sender.send(GcpLogEntry {
+            message,
+            severity,
+            fields,
+            timestamp,
+            source_location: None,
+            operation: Some(operation),
+            trace: None,
+            labels: self.resolve_labels(),
+            runtime_labels: None,
+            internal: false,
+            callsite: Some(metadata),
+            pending: PendingFields::default(),
+        });
+        if result.is_err() {
+            diagnostic!("Error {:?}", result);
+        }
+    }
+}
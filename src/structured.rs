@@ -0,0 +1,62 @@
+//! Stdout JSON in the format Cloud Run's structured logging agent parses.
+use std::io::Write;
+
+use serde_json::{Map, Value};
+
+use crate::sink::LogSink;
+
+/// Top-level keys of a built entry and the stdout keys they move to
+///
+/// `severity` and `message` keep their names; `logName` and `resource` are
+/// dropped because Cloud Run sets them itself.
+const SPECIAL_KEYS: [(&str, &str); 9] = [
+    ("severity", "severity"),
+    ("timestamp", "time"),
+    ("trace", "logging.googleapis.com/trace"),
+    ("spanId", "logging.googleapis.com/spanId"),
+    ("traceSampled", "logging.googleapis.com/trace_sampled"),
+    ("sourceLocation", "logging.googleapis.com/sourceLocation"),
+    ("labels", "logging.googleapis.com/labels"),
+    ("insertId", "logging.googleapis.com/insertId"),
+    ("operation", "logging.googleapis.com/operation"),
+];
+
+/// Convert a built entry into one line of Cloud Run structured logging JSON
+///
+/// The payload fields become top-level keys, which Cloud Run's agent places
+/// back in `jsonPayload`, next to `severity`, `message`, `time` and the
+/// `logging.googleapis.com/*` keys it lifts into the `LogEntry`. A payload
+/// field whose key is one of those is renamed with a `user_` prefix.
+///
+/// # Arguments
+/// * `entry` - A JSON entry produced by `build_entry`
+///
+/// # Returns
+/// The object to print on a single line of stdout
+pub fn to_structured_log(mut entry: Value) -> Value {
+    let mut line = Map::new();
+    for (from, to) in SPECIAL_KEYS {
+        if let Some(value) = entry.get_mut(from).map(Value::take) {
+            line.insert(to.into(), value);
+        }
+    }
+    if let Some(Value::Object(payload)) = entry.get_mut("jsonPayload").map(Value::take) {
+        for (mut key, value) in payload {
+            while line.contains_key(&key) {
+                key.insert_str(0, "user_");
+            }
+            line.insert(key, value);
+        }
+    }
+    Value::Object(line)
+}
+
+/// Writes entries to stdout as Cloud Run structured logging JSON, one per line
+pub(crate) struct StructuredStdout;
+
+impl LogSink for StructuredStdout {
+    fn write(&self, entry: Value) {
+        let line = to_structured_log(entry);
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+}
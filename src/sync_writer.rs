@@ -0,0 +1,153 @@
+//! Blocking delivery path for critical events written from `on_event`.
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::batch::Batch;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::client;
+use crate::entry::{EntryConfig, EntryView, GcpLogEntry, StaticMetadata};
+use crate::error_reporting::ErrorReporter;
+use crate::metrics::Metrics;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::transport::{Delivery, Transport};
+use crate::Severity;
+
+/// A request handed to the writer thread, with the time the caller stops
+/// waiting and a channel to acknowledge it
+type SyncRequest = (Vec<u8>, Instant, SyncSender<()>);
+
+/// Writes entries at or above a severity synchronously, bypassing the queue
+///
+/// Uploads run on a dedicated thread with its own single-threaded runtime, so
+/// the calling thread can block on them from any context, including from a
+/// thread of the application's Tokio runtime.
+pub(crate) struct SyncWriter {
+    /// Requests for the writer thread
+    requests: Sender<SyncRequest>,
+    /// Lowest severity written synchronously
+    min_severity: Severity,
+    /// Longest time `on_event` blocks waiting for an upload
+    timeout: Duration,
+    /// Metadata attached to every entry
+    metadata: Arc<StaticMetadata>,
+    /// Options controlling the entry shape
+    entry_config: EntryConfig,
+    /// Reports severe entries to Error Reporting, if enabled
+    error_reporter: Option<ErrorReporter>,
+}
+
+impl SyncWriter {
+    /// Start the writer thread
+    ///
+    /// # Arguments
+    /// * `transport` - Transport whose credentials are used for uploads
+    /// * `metadata` - Metadata attached to every entry
+    /// * `entry_config` - Options controlling the entry shape
+    /// * `min_severity` - Lowest severity written synchronously
+    /// * `timeout` - Longest time a write blocks the caller
+    ///
+    /// # Returns
+    /// The writer, or an error if the thread could not be spawned
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    pub(crate) fn start(
+        transport: &Transport,
+        metadata: Arc<StaticMetadata>,
+        entry_config: EntryConfig,
+        min_severity: Severity,
+        timeout: Duration,
+    ) -> std::io::Result<Self> {
+        let (requests, rx) = channel::<SyncRequest>();
+        let transport = transport.clone();
+        std::thread::Builder::new()
+            .name("nano-gcp-logging-sync".into())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        diagnostic!("Failed to start synchronous log writer: {}", e);
+                        return;
+                    }
+                };
+                let transport = {
+                    let _runtime = runtime.enter();
+                    transport.with_client(client::Client::new())
+                };
+                while let Ok((body, deadline, ack)) = rx.recv() {
+                    // The upload is abandoned with the caller, so a late success
+                    // is never counted on top of the failure.
+                    let deadline = tokio::time::Instant::from_std(deadline);
+                    let delivery = runtime.block_on(async {
+                        tokio::time::timeout_at(deadline, transport.deliver(body, false)).await
+                    });
+                    match delivery {
+                        // Failures are reported by `deliver`; the caller only waits.
+                        Ok(Delivery::Retryable) | Err(_) => {
+                            // Nothing resends synchronous writes.
+                            Metrics::add(&transport.metrics.failed, 1);
+                        }
+                        Ok(_) => {
+                            let _ = ack.send(());
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            requests,
+            min_severity,
+            timeout,
+            metadata,
+            entry_config,
+            error_reporter: None,
+        })
+    }
+
+    /// Report severe entries to Error Reporting as they are written
+    ///
+    /// # Arguments
+    /// * `error_reporter` - The layer's reporter, if enabled
+    pub(crate) fn with_error_reporter(mut self, error_reporter: Option<ErrorReporter>) -> Self {
+        self.error_reporter = error_reporter;
+        self
+    }
+
+    /// Whether entries of the given severity are written synchronously
+    pub(crate) fn applies(&self, severity: Severity) -> bool {
+        severity >= self.min_severity
+    }
+
+    /// Upload an entry, blocking until it is sent or the timeout elapses
+    ///
+    /// # Arguments
+    /// * `log_entry` - The entry to upload
+    pub(crate) fn write(&self, log_entry: &GcpLogEntry) {
+        if let Some(error_reporter) = &self.error_reporter {
+            error_reporter.report(log_entry);
+        }
+        let mut batch = Batch::default();
+        let mut buffer = batch.buffer();
+        let view = EntryView::new(&self.metadata, &self.entry_config, log_entry);
+        if view.encode(&mut buffer).is_err() {
+            return;
+        }
+        batch.push(buffer);
+
+        let (ack, done) = sync_channel(1);
+        let body = batch.take_body(&*self.entry_config.encoder);
+        let deadline = Instant::now() + self.timeout;
+        if self.requests.send((body, deadline, ack)).is_err() {
+            diagnostic!("Synchronous log writer is not running; entry dropped");
+            return;
+        }
+        if done.recv_timeout(self.timeout).is_err() {
+            warning!(
+                "synchronous log write did not complete within {:?}",
+                self.timeout
+            );
+        }
+    }
+}
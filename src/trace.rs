@@ -0,0 +1,201 @@
+//! Trace context linking entries to Cloud Trace.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+use tracing::span::Id;
+use tracing::Dispatch;
+
+/// Trace and span an entry was emitted in
+///
+/// Sent as the entry's `trace`, `spanId` and `traceSampled` fields (the API
+/// equivalents of the `logging.googleapis.com/trace*` payload keys), which
+/// let the console link logs and traces in both directions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceContext {
+    /// 32-character hex trace ID
+    pub trace_id: String,
+    /// 16-character hex span ID, empty when unknown
+    pub span_id: String,
+    /// Whether the trace was sampled, i.e. exported to the tracing backend
+    pub sampled: bool,
+}
+
+/// Field carrying an `X-Cloud-Trace-Context` header value
+///
+/// Record the incoming request's header on the request span (or an event),
+/// e.g. `info_span!("request", cloud_trace_context = %header)`, and entries
+/// inside it are linked to that trace. It is used when no OpenTelemetry
+/// context is available and is never shipped as a payload field.
+pub const CLOUD_TRACE_CONTEXT_FIELD: &str = "cloud_trace_context";
+
+/// Field carrying a W3C `traceparent` header value
+///
+/// Works like `CLOUD_TRACE_CONTEXT_FIELD` for upstreams using W3C trace
+/// context propagation, e.g. `info_span!("request", traceparent = %header)`,
+/// and wins over it when both are recorded on the same span or event.
+pub const TRACEPARENT_FIELD: &str = "traceparent";
+
+/// Fields carrying trace headers, in order of precedence
+pub(crate) const TRACE_HEADER_FIELDS: [&str; 2] = [TRACEPARENT_FIELD, CLOUD_TRACE_CONTEXT_FIELD];
+
+/// Whether a malformed `traceparent` value was already reported
+static WARNED_MALFORMED_TRACEPARENT: AtomicBool = AtomicBool::new(false);
+
+impl TraceContext {
+    /// Parse an `X-Cloud-Trace-Context` header, as sent by Cloud Run and load balancers
+    ///
+    /// The header has the form `TRACE_ID/SPAN_ID;o=OPTIONS`, where the span ID
+    /// is decimal and `o=1` marks the trace as sampled. The span ID and options
+    /// are optional.
+    ///
+    /// # Arguments
+    /// * `header` - The header value
+    ///
+    /// # Returns
+    /// The trace context, or None when the trace ID is not 32 hex characters
+    pub fn from_cloud_trace_header(header: &str) -> Option<Self> {
+        let (ids, options) = header.trim().split_once(';').unwrap_or((header.trim(), ""));
+        let (trace_id, span_id) = ids.split_once('/').unwrap_or((ids, ""));
+        if trace_id.len() != 32 || !trace_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let span_id = match span_id {
+            "" => String::new(),
+            decimal => format!("{:016x}", decimal.parse::<u64>().ok()?),
+        };
+        Some(Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id,
+            sampled: options.trim() == "o=1",
+        })
+    }
+
+    /// Parse a W3C `traceparent` header, as propagated by OpenTelemetry
+    ///
+    /// The header has the form `VERSION-TRACE_ID-SPAN_ID-FLAGS` in lowercase
+    /// hex, e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`,
+    /// where bit 0 of the flags marks the trace as sampled. Versions after
+    /// `00` may append further fields, which are ignored.
+    ///
+    /// # Arguments
+    /// * `header` - The header value
+    ///
+    /// # Returns
+    /// The trace context, or None when the header is malformed or carries an
+    /// all-zero trace or span ID
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let hex = |part: &str, len: usize| {
+            part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        let zero = |part: &str| part.bytes().all(|b| b == b'0');
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        let [version, trace_id, span_id, flags, rest @ ..] = parts.as_slice() else {
+            return None;
+        };
+        let version_ok =
+            hex(version, 2) && *version != "ff" && (*version != "00" || rest.is_empty());
+        if !version_ok || !hex(trace_id, 32) || !hex(span_id, 16) || !hex(flags, 2) {
+            return None;
+        }
+        if zero(trace_id) || zero(span_id) {
+            return None;
+        }
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+        })
+    }
+
+    /// Parse the value of one of the trace header fields
+    ///
+    /// A malformed `traceparent` is reported once per process.
+    ///
+    /// # Arguments
+    /// * `field` - One of `TRACE_HEADER_FIELDS`
+    /// * `value` - The recorded value
+    pub(crate) fn from_header_field(field: &str, value: &str) -> Option<Self> {
+        if field != TRACEPARENT_FIELD {
+            return Self::from_cloud_trace_header(value);
+        }
+        let parsed = Self::from_traceparent(value);
+        if parsed.is_none() && !WARNED_MALFORMED_TRACEPARENT.swap(true, Ordering::Relaxed) {
+            warning!(
+                "ignoring malformed traceparent `{}`; expected `00-{{trace id}}-{{span id}}-{{flags}}`.",
+                value
+            );
+        }
+        parsed
+    }
+}
+
+/// Finds the OpenTelemetry context of spans from inside the layer
+///
+/// With the `opentelemetry` feature the context is read from the span data
+/// kept by a `tracing_opentelemetry` layer in the same subscriber. Without a
+/// valid context (or without the feature) entries carry no trace fields.
+#[derive(Default)]
+pub(crate) struct TraceLookup {
+    /// The subscriber the layer was registered with
+    #[cfg(feature = "opentelemetry")]
+    dispatch: std::sync::OnceLock<tracing::dispatcher::WeakDispatch>,
+}
+
+impl TraceLookup {
+    /// Remember the subscriber the layer belongs to
+    ///
+    /// Inside subscriber callbacks the default dispatcher is not available, so
+    /// span contexts have to be looked up through the registered one.
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+    pub(crate) fn register(&self, dispatch: &Dispatch) {
+        #[cfg(feature = "opentelemetry")]
+        let _ = self.dispatch.set(dispatch.downgrade());
+    }
+
+    /// Read the trace context of a span
+    ///
+    /// # Arguments
+    /// * `span` - The span an event was emitted in, if any
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+    pub(crate) fn lookup(&self, span: Option<&Id>) -> Option<TraceContext> {
+        #[cfg(feature = "opentelemetry")]
+        {
+            let dispatch = self.dispatch.get()?.upgrade()?;
+            let context = tracing_opentelemetry::get_otel_context(span?, &dispatch)?;
+            from_context(&context)
+        }
+        #[cfg(not(feature = "opentelemetry"))]
+        None
+    }
+}
+
+/// Read the trace context of the current span from outside the subscriber
+///
+/// Used where no subscriber callback is on the stack, e.g. in the panic hook.
+pub(crate) fn current() -> Option<TraceContext> {
+    #[cfg(feature = "opentelemetry")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        from_context(&tracing::Span::current().context())
+    }
+    #[cfg(not(feature = "opentelemetry"))]
+    None
+}
+
+/// Extract the trace context of an OpenTelemetry context, if it is valid
+#[cfg(feature = "opentelemetry")]
+fn from_context(context: &opentelemetry::Context) -> Option<TraceContext> {
+    use opentelemetry::trace::TraceContextExt;
+
+    let span = context.span();
+    let span_context = span.span_context();
+    if !span_context.is_valid() {
+        return None;
+    }
+    Some(TraceContext {
+        trace_id: span_context.trace_id().to_string(),
+        span_id: span_context.span_id().to_string(),
+        sampled: span_context.is_sampled(),
+    })
+}
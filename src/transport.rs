@@ -0,0 +1,500 @@
+//! HTTP transport used to upload batches to the Logging API.
+//!
+//! Without an async client only the outcome handling shared with the blocking
+//! sender is built.
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use std::fmt;
+use std::future::Future;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use std::sync::Arc;
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::auth::{TokenError, TokenSource, CLOUD_PLATFORM_SCOPE};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::batch::{count_entries, gzip};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::client;
+#[cfg(all(feature = "grpc", any(feature = "reqwest", feature = "hyper")))]
+use crate::grpc::GrpcClient;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use crate::metrics::Metrics;
+
+/// Cloud Logging `entries:write` endpoint
+pub(crate) const ENTRIES_WRITE_URL: &str = "https://logging.googleapis.com/v2/entries:write";
+
+/// Google endpoint describing an access token, including the principal's email
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+const TOKEN_INFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+/// Header attributing API quota and billing to another project
+pub(crate) const QUOTA_PROJECT_HEADER: &str = "x-goog-user-project";
+
+/// Callback customizing each upload request before it is sent
+///
+/// Receives the request with every header the layer sets, including
+/// `Authorization`, so it runs last and may add or replace any of them.
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+pub(crate) type RequestHook =
+    Arc<dyn Fn(client::RequestBuilder) -> client::RequestBuilder + Send + Sync>;
+
+/// Errors raised while uploading a request
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[derive(Debug)]
+pub(crate) enum TransportError {
+    /// No valid access token could be obtained
+    Auth(TokenError),
+    /// The HTTP request failed
+    Http(client::Error),
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Auth(e) => write!(f, "failed to refresh GCP token: {}", e),
+            TransportError::Http(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Outcome of a single upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delivery {
+    /// The API accepted the entries
+    Sent,
+    /// The API rejected the entries; resending would fail again
+    Rejected,
+    /// The credentials lack permission to write entries
+    Forbidden,
+    /// The upload failed in a way that may succeed later
+    Retryable,
+}
+
+/// Sends request bodies to the Logging API with the layer's credentials
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[derive(Clone)]
+pub(crate) struct Transport {
+    /// HTTP client used for uploads
+    client: client::Client,
+    /// Token source, None when authentication is unavailable
+    auth: Option<Arc<TokenSource>>,
+    /// `User-Agent` sent with every request
+    user_agent: String,
+    /// Project billed for API quota instead of the credentials' project
+    quota_project: Option<String>,
+    /// Callback applied to each REST upload request before sending
+    request_hook: Option<RequestHook>,
+    /// Counters updated with the outcome of every upload
+    pub(crate) metrics: Arc<Metrics>,
+    /// gRPC client replacing REST uploads, if enabled
+    #[cfg(feature = "grpc")]
+    grpc: Option<GrpcClient>,
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+impl Transport {
+    /// Create a transport
+    ///
+    /// # Arguments
+    /// * `client` - HTTP client used for uploads
+    /// * `auth` - Token source, None when authentication is unavailable
+    /// * `user_agent_suffix` - Optional text appended to the crate's user agent
+    pub(crate) fn new(
+        client: client::Client,
+        auth: Option<TokenSource>,
+        user_agent_suffix: Option<&str>,
+    ) -> Self {
+        Self {
+            client,
+            auth: auth.map(Arc::new),
+            user_agent: user_agent(user_agent_suffix),
+            quota_project: None,
+            request_hook: None,
+            metrics: Arc::default(),
+            #[cfg(feature = "grpc")]
+            grpc: None,
+        }
+    }
+
+    /// Customize upload requests
+    ///
+    /// # Arguments
+    /// * `quota_project` - Project sent as `X-Goog-User-Project`, if any
+    /// * `request_hook` - Callback applied to each REST upload, if any
+    pub(crate) fn with_request_options(
+        mut self,
+        quota_project: Option<String>,
+        request_hook: Option<RequestHook>,
+    ) -> Self {
+        self.quota_project = quota_project;
+        self.request_hook = request_hook;
+        self
+    }
+
+    /// Record upload outcomes in the layer's counters
+    ///
+    /// # Arguments
+    /// * `metrics` - Counters shared with handles
+    pub(crate) fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Switch uploads to the gRPC API
+    ///
+    /// Falls back to REST with a warning if the channel cannot be configured.
+    #[cfg(feature = "grpc")]
+    pub(crate) fn with_grpc(mut self) -> Self {
+        match GrpcClient::new(&self.user_agent) {
+            Ok(client) => self.grpc = Some(client),
+            Err(e) => warning!("failed to configure gRPC uploads: {}. Using REST.", e),
+        }
+        self
+    }
+
+    /// Create a copy of this transport using a different HTTP client
+    ///
+    /// Needed when uploading from another runtime, since a client's connection
+    /// pool is bound to the runtime it was first used on. A gRPC channel is
+    /// recreated for the same reason, so this must run inside that runtime.
+    pub(crate) fn with_client(&self, client: client::Client) -> Self {
+        Self {
+            client,
+            auth: self.auth.clone(),
+            user_agent: self.user_agent.clone(),
+            quota_project: self.quota_project.clone(),
+            request_hook: self.request_hook.clone(),
+            metrics: self.metrics.clone(),
+            #[cfg(feature = "grpc")]
+            grpc: self
+                .grpc
+                .as_ref()
+                .and_then(|_| GrpcClient::new(&self.user_agent).ok()),
+        }
+    }
+
+    /// Whether uploads go through the gRPC API
+    #[cfg(feature = "grpc")]
+    pub(crate) fn uses_grpc(&self) -> bool {
+        self.grpc.is_some()
+    }
+
+    /// Whether a token source is available
+    pub(crate) fn is_authenticated(&self) -> bool {
+        self.auth.is_some()
+    }
+
+    /// Upload an `entries:write` request body
+    ///
+    /// Headers are applied in order: content headers and `User-Agent`, the
+    /// quota project, the bearer token, then the request hook.
+    ///
+    /// # Arguments
+    /// * `body` - The uncompressed JSON body
+    /// * `compress` - Whether to gzip the body
+    ///
+    /// # Returns
+    /// The HTTP response, or the error that prevented sending
+    pub(crate) async fn write_entries(
+        &self,
+        body: Vec<u8>,
+        compress: bool,
+    ) -> Result<client::Response, TransportError> {
+        let mut req = self
+            .client
+            .post(ENTRIES_WRITE_URL)
+            .header(client::header::CONTENT_TYPE, "application/json")
+            .header(client::header::USER_AGENT, self.user_agent.as_str());
+        let compressed = if compress { gzip(&body) } else { None };
+        req = match compressed {
+            Some(compressed) => req
+                .header(client::header::CONTENT_ENCODING, "gzip")
+                .body(compressed),
+            None => req.body(body),
+        };
+        if let Some(project) = &self.quota_project {
+            req = req.header(QUOTA_PROJECT_HEADER, project);
+        }
+        // Conditionally add auth if available.
+        if let Some(auth) = &self.auth {
+            let token = auth.token().await.map_err(TransportError::Auth)?;
+            req = req.bearer_auth(token);
+        }
+        if let Some(hook) = &self.request_hook {
+            req = hook(req);
+        }
+        req.send().await.map_err(TransportError::Http)
+    }
+
+    /// Send a JSON request to another Google API, e.g. Error Reporting
+    ///
+    /// Sent with the same headers as uploads, including the request hook, but
+    /// with a token for the `cloud-platform` scope.
+    ///
+    /// # Arguments
+    /// * `url` - The API method's URL
+    /// * `body` - The JSON request
+    ///
+    /// # Returns
+    /// The HTTP response, or the error that prevented sending
+    pub(crate) async fn post_api(
+        &self,
+        url: String,
+        body: Vec<u8>,
+    ) -> Result<client::Response, TransportError> {
+        let mut req = self
+            .client
+            .post(url)
+            .header(client::header::CONTENT_TYPE, "application/json")
+            .header(client::header::USER_AGENT, self.user_agent.as_str())
+            .body(body);
+        if let Some(project) = &self.quota_project {
+            req = req.header(QUOTA_PROJECT_HEADER, project);
+        }
+        if let Some(auth) = &self.auth {
+            let token = auth
+                .token_for(CLOUD_PLATFORM_SCOPE)
+                .await
+                .map_err(TransportError::Auth)?;
+            req = req.bearer_auth(token);
+        }
+        if let Some(hook) = &self.request_hook {
+            req = hook(req);
+        }
+        req.send().await.map_err(TransportError::Http)
+    }
+
+    /// Upload a request body and classify the outcome
+    ///
+    /// Transport errors, throttling (429) and server errors (5xx) may succeed
+    /// later; other rejections are permanent and are not retried. Goes through
+    /// the gRPC API instead when it was enabled.
+    ///
+    /// # Arguments
+    /// * `body` - The uncompressed JSON body
+    /// * `compress` - Whether to gzip the body
+    ///
+    /// # Returns
+    /// The outcome of the upload
+    pub(crate) async fn deliver(&self, body: Vec<u8>, compress: bool) -> Delivery {
+        let (entries, bytes) = (count_entries(&body), body.len());
+        let started = std::time::Instant::now();
+        let delivery = self.upload(body, compress).await;
+        self.metrics
+            .record(delivery, entries, bytes, started.elapsed());
+        delivery
+    }
+
+    /// Upload a request body over gRPC or REST without recording the outcome
+    async fn upload(&self, body: Vec<u8>, compress: bool) -> Delivery {
+        #[cfg(feature = "grpc")]
+        if let Some(grpc) = &self.grpc {
+            let token = match &self.auth {
+                Some(auth) => match auth.token().await {
+                    Ok(token) => Some(token),
+                    Err(e) => {
+                        diagnostic!("Failed to send log entry: {}", TransportError::Auth(e));
+                        return Delivery::Retryable;
+                    }
+                },
+                None => None,
+            };
+            return grpc
+                .deliver(
+                    body,
+                    compress,
+                    token.as_deref(),
+                    self.quota_project.as_deref(),
+                )
+                .await;
+        }
+        match self.write_entries(body, compress).await {
+            Ok(res) if res.status().is_success() => Delivery::Sent,
+            Ok(res) => {
+                diagnostic!("Failed to send log entry: HTTP {}", res.status());
+                classify(res.status().as_u16())
+            }
+            Err(e) => {
+                diagnostic!("Failed to send log entry: {}", e);
+                Delivery::Retryable
+            }
+        }
+    }
+
+    /// Look up the email of the principal the access token belongs to
+    ///
+    /// Only used to explain permission errors, so every failure maps to None.
+    ///
+    /// # Returns
+    /// The service account or user email, if it could be determined
+    pub(crate) async fn principal(&self) -> Option<String> {
+        self.principal_from(TOKEN_INFO_URL).await
+    }
+
+    /// Look up the principal at a tokeninfo endpoint
+    ///
+    /// The token goes in a form body rather than the URL, so it does not end
+    /// up in proxy or server access logs.
+    ///
+    /// # Arguments
+    /// * `url` - The tokeninfo endpoint
+    async fn principal_from(&self, url: &str) -> Option<String> {
+        let token = self.auth.as_ref()?.token().await.ok()?;
+        let info: serde_json::Value = self
+            .client
+            .post(url)
+            .form(&[("access_token", token.as_str())])
+            .send()
+            .await
+            .ok()?
+            .json()
+            .await
+            .ok()?;
+        info["email"].as_str().map(str::to_string)
+    }
+}
+
+/// Classify the status of an `entries:write` response
+///
+/// Throttling (429) and server errors (5xx) may succeed later; other
+/// rejections are permanent and are not retried.
+///
+/// # Arguments
+/// * `status` - The HTTP status code
+pub(crate) fn classify(status: u16) -> Delivery {
+    match status {
+        200..=299 => Delivery::Sent,
+        403 => Delivery::Forbidden,
+        429 | 500..=599 => Delivery::Retryable,
+        _ => Delivery::Rejected,
+    }
+}
+
+/// Explains the first 403 from the Logging API on stderr
+///
+/// Permission errors do not resolve by retrying, so they are rejected like
+/// other client errors; this only makes the cause obvious.
+#[derive(Default)]
+pub(crate) struct ForbiddenReport {
+    /// Whether a permission error has already been explained
+    reported: bool,
+}
+
+impl ForbiddenReport {
+    /// Warn about a 403, unless one was explained already
+    ///
+    /// # Arguments
+    /// * `project_id` - Project the entries were written to
+    /// * `principal` - Looks up who the credentials belong to, only awaited
+    ///   for the first report
+    pub(crate) async fn report(
+        &mut self,
+        project_id: &str,
+        principal: impl Future<Output = Option<String>>,
+    ) {
+        if std::mem::replace(&mut self.reported, true) {
+            return;
+        }
+        let principal = principal
+            .await
+            .unwrap_or_else(|| "the service account used by this process".to_string());
+        warning!(
+            "Cloud Logging rejected log entries with 403 Forbidden. Grant roles/logging.logWriter on project {} to {}; entries are dropped until then.",
+            project_id, principal
+        );
+    }
+}
+
+/// Build the `User-Agent` identifying this crate's traffic
+///
+/// # Arguments
+/// * `suffix` - Optional text appended after the crate name and version
+///
+/// # Returns
+/// E.g. `nano-gcp-logging/0.1.0 my-app/2.3`
+pub(crate) fn user_agent(suffix: Option<&str>) -> String {
+    let base = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+    match suffix.map(str::trim).filter(|s| !s.is_empty()) {
+        Some(suffix) => format!("{} {}", base, suffix),
+        None => base.to_string(),
+    }
+}
+
+#[cfg(all(test, any(feature = "reqwest", feature = "hyper")))]
+mod tests {
+    use super::*;
+    use crate::auth::{TokenFuture, TokenProvider};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    struct StaticToken;
+
+    impl TokenProvider for StaticToken {
+        fn token(&self) -> TokenFuture<'_> {
+            Box::pin(async { Ok("ya29.secret/token".to_string()) })
+        }
+    }
+
+    /// Answer one request like tokeninfo and hand back what was received
+    async fn mock_token_info(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            request.extend_from_slice(&chunk[..read]);
+            let text = String::from_utf8_lossy(&request).to_ascii_lowercase();
+            let Some(head_end) = text.find("\r\n\r\n") else {
+                continue;
+            };
+            let length = text
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .and_then(|length| length.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if read == 0 || request.len() >= head_end + 4 + length {
+                break;
+            }
+        }
+        let body = r#"{"email":"writer@my-project.iam.gserviceaccount.com"}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[test]
+    fn test_user_agent_appends_a_trimmed_suffix() {
+        let base = format!("nano-gcp-logging/{}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(user_agent(None), base);
+        assert_eq!(user_agent(Some("  ")), base);
+        assert_eq!(
+            user_agent(Some(" checkout/2.1 ")),
+            format!("{} checkout/2.1", base)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_principal_sends_the_token_in_a_form_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/tokeninfo", listener.local_addr().unwrap());
+        let server = tokio::spawn(mock_token_info(listener));
+        let auth = TokenSource::acquire(Some(Arc::new(StaticToken)), Arc::default()).await;
+        let transport = Transport::new(client::Client::new(), auth, None);
+
+        assert_eq!(
+            transport.principal_from(&url).await.as_deref(),
+            Some("writer@my-project.iam.gserviceaccount.com")
+        );
+        let request = server.await.unwrap();
+        let request_line = request.lines().next().unwrap();
+        assert_eq!(request_line, "POST /tokeninfo HTTP/1.1");
+        assert!(request
+            .to_ascii_lowercase()
+            .contains("content-type: application/x-www-form-urlencoded"));
+        assert!(request.ends_with("\r\n\r\naccess_token=ya29.secret%2Ftoken"));
+    }
+}
@@ -0,0 +1,803 @@
+//! Field visitor collecting an event's message and structured fields.
+use std::cell::RefCell;
+use std::fmt::Write;
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use smallvec::SmallVec;
+use tracing::field::{Field, Visit};
+
+use crate::pool::MESSAGES;
+use crate::span::SPAN_CONTEXT_FIELD;
+
+/// How recorded field values are converted into their shipped form
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FieldOptions {
+    /// Parse `Debug`-only values that look like JSON into structured values
+    pub(crate) parse_json_debug: bool,
+    /// Keep sizes and counts numeric and add human-readable companions
+    pub(crate) human_units: bool,
+    /// Most fields kept, including companions, None for no limit
+    pub(crate) max_fields: Option<usize>,
+    /// Append a `key=value` rendering of the fields to the message
+    pub(crate) summarize: bool,
+    /// Whether span fields are listed under `SPAN_CONTEXT_FIELD`
+    pub(crate) span_context: bool,
+    /// Send `Debug`-rendered strings without their quotes
+    pub(crate) unquote_debug: bool,
+    /// Merge the keys of a message that is a JSON object into the fields
+    pub(crate) parse_json_message: bool,
+    /// Remove ANSI escape sequences from the message
+    pub(crate) strip_ansi: bool,
+    /// Move a stack trace at the end of the message into a `stack_trace` field
+    pub(crate) group_stack_traces: bool,
+}
+
+/// Fields an event can record before `RawFields` spills onto the heap
+const INLINE_FIELDS: usize = 8;
+
+/// Bytes a `Text` holds without allocating
+const INLINE_TEXT: usize = 22;
+
+/// Field values in recording order, stored inline for typical events
+type RawFields = SmallVec<[(&'static str, RawValue); INLINE_FIELDS]>;
+
+/// A string stored inline when short, so small field values do not allocate
+#[derive(Clone)]
+pub(crate) enum Text {
+    /// Up to `INLINE_TEXT` bytes of UTF-8, in place
+    Inline {
+        /// Bytes used
+        len: u8,
+        /// The string's bytes, followed by unused space
+        bytes: [u8; INLINE_TEXT],
+    },
+    /// A longer string
+    Heap(String),
+}
+
+impl Text {
+    /// The string
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Text::Inline { len, bytes } => {
+                std::str::from_utf8(&bytes[..*len as usize]).unwrap_or_default()
+            }
+            Text::Heap(string) => string,
+        }
+    }
+
+    /// Convert into an owned `String`
+    fn into_string(self) -> String {
+        match self {
+            Text::Heap(string) => string,
+            inline => inline.as_str().to_string(),
+        }
+    }
+}
+
+impl From<&str> for Text {
+    fn from(text: &str) -> Self {
+        if text.len() > INLINE_TEXT {
+            return Text::Heap(text.to_string());
+        }
+        let mut bytes = [0; INLINE_TEXT];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        Text::Inline {
+            len: text.len() as u8,
+            bytes,
+        }
+    }
+}
+
+impl std::fmt::Debug for Text {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// A field value as recorded, before conversion
+#[derive(Debug, Clone)]
+pub(crate) enum RawValue {
+    /// Recorded with `record_bool`
+    Bool(bool),
+    /// Recorded with `record_i64`
+    I64(i64),
+    /// Recorded with `record_u64`
+    U64(u64),
+    /// Recorded with `record_f64`
+    F64(f64),
+    /// Recorded with `record_str`
+    Str(Text),
+    /// The rendering of a value recorded with `record_debug`
+    Debug(Text),
+}
+
+impl RawValue {
+    /// The recorded or rendered string, None for other values
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            RawValue::Str(text) | RawValue::Debug(text) => Some(text.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Convert into the shipped JSON value, as configured
+    ///
+    /// # Arguments
+    /// * `name` - The field name
+    /// * `options` - The enabled conversions
+    fn into_value(self, name: &str, options: &FieldOptions) -> Value {
+        match self {
+            RawValue::Bool(value) => value.into(),
+            RawValue::I64(value) => value.into(),
+            RawValue::U64(value) => value.into(),
+            // Non-finite floats have no JSON representation, keep them readable.
+            RawValue::F64(value) => serde_json::Number::from_f64(value)
+                .map(Value::Number)
+                .unwrap_or_else(|| Value::String(value.to_string())),
+            RawValue::Str(text) => options
+                .convert(name, text.as_str())
+                .unwrap_or_else(|| text.into_string().into()),
+            RawValue::Debug(text) => options
+                .convert(name, text.as_str())
+                .or_else(|| {
+                    options
+                        .parse_json_debug
+                        .then(|| parse_json_like(text.as_str()))
+                        .flatten()
+                })
+                .or_else(|| {
+                    options
+                        .unquote_debug
+                        .then(|| unquote(text.as_str()))
+                        .flatten()
+                })
+                .unwrap_or_else(|| text.into_string().into()),
+        }
+    }
+}
+
+/// Collects the `message` and every other field of an event
+///
+/// Values are only rendered while recording, into inline storage when they
+/// are small; `into_pending` defers their conversion so it can run off the
+/// caller's thread. An event with a static message and a few numbers or short
+/// strings is captured without allocating, the message buffer coming from the
+/// pool.
+pub(crate) struct FieldVisitor {
+    /// The event's `message` field, if recorded
+    pub(crate) message: Option<String>,
+    /// All other fields, as recorded
+    fields: RawFields,
+    /// Most fields collected, None for no limit
+    max_fields: Option<usize>,
+    /// Whether fields were dropped because of `max_fields`
+    truncated: bool,
+}
+
+impl FieldVisitor {
+    /// Create a visitor
+    pub(crate) fn new() -> Self {
+        Self {
+            message: None,
+            fields: SmallVec::new(),
+            max_fields: None,
+            truncated: false,
+        }
+    }
+
+    /// Stop collecting fields once `max_fields` are stored
+    ///
+    /// # Arguments
+    /// * `max_fields` - Most fields kept, None for no limit
+    pub(crate) fn max_fields(mut self, max_fields: Option<usize>) -> Self {
+        self.max_fields = max_fields;
+        self
+    }
+
+    /// Remove a recorded field
+    ///
+    /// # Arguments
+    /// * `name` - The field name
+    ///
+    /// # Returns
+    /// The recorded value, if any
+    pub(crate) fn take(&mut self, name: &str) -> Option<RawValue> {
+        let index = self.fields.iter().position(|(field, _)| *field == name)?;
+        Some(self.fields.remove(index).1)
+    }
+
+    /// Hand over the recorded fields for later conversion
+    ///
+    /// # Arguments
+    /// * `options` - How to convert them
+    pub(crate) fn into_pending(self, options: FieldOptions) -> PendingFields {
+        PendingFields {
+            fields: self.fields,
+            truncated: self.truncated,
+            options,
+        }
+    }
+
+    /// Store a value, unless `max_fields` are already stored
+    fn insert(&mut self, field: &Field, value: RawValue) {
+        let name = field.name();
+        if let Some((_, slot)) = self.fields.iter_mut().find(|(field, _)| *field == name) {
+            *slot = value;
+        } else if self.max_fields.is_some_and(|max| self.fields.len() >= max) {
+            self.truncated = true;
+        } else {
+            self.fields.push((name, value));
+        }
+    }
+}
+
+/// Store a value under a name, unless `max_fields` are already stored
+///
+/// # Returns
+/// False when the value was dropped
+fn insert_capped(
+    fields: &mut Map<String, Value>,
+    max_fields: Option<usize>,
+    name: &str,
+    value: Value,
+) -> bool {
+    let full = max_fields.is_some_and(|max| fields.len() >= max);
+    if full && !fields.contains_key(name) {
+        return false;
+    }
+    fields.insert(name.to_string(), value);
+    true
+}
+
+impl Visit for FieldVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, RawValue::F64(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, RawValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, RawValue::U64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, RawValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            let mut message = MESSAGES.take();
+            message.push_str(value);
+            self.message = Some(message);
+        } else {
+            self.insert(field, RawValue::Str(value.into()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let mut message = MESSAGES.take();
+            let _ = write!(message, "{:?}", value);
+            self.message = Some(message);
+            return;
+        }
+        self.insert(field, RawValue::Debug(render(value)));
+    }
+}
+
+/// Capacity above which a thread's rendering buffer is released after use
+const MAX_RETAINED_BUFFER: usize = 16 * 1024;
+
+thread_local! {
+    /// Buffer values are rendered into, reused across events on a thread
+    static RENDER_BUFFER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// Render a field value's `Debug` output with at most one allocation
+///
+/// `format!` grows its string as it writes; rendering into a buffer reused
+/// across events leaves one exactly sized copy per value, kept inline when
+/// short. Messages are rendered straight into a pooled string instead. A
+/// value whose `Debug` logs in turn gets a fresh buffer.
+///
+/// # Arguments
+/// * `value` - The recorded value
+fn render(value: &dyn std::fmt::Debug) -> Text {
+    RENDER_BUFFER.with(|buffer| {
+        let Ok(mut buffer) = buffer.try_borrow_mut() else {
+            return Text::Heap(format!("{:?}", value));
+        };
+        buffer.clear();
+        let _ = write!(buffer, "{:?}", value);
+        let rendered = Text::from(buffer.as_str());
+        if buffer.capacity() > MAX_RETAINED_BUFFER {
+            *buffer = String::new();
+        }
+        rendered
+    })
+}
+
+/// Event fields recorded on the caller's thread, converted when the entry is built
+///
+/// `on_event` only renders field values. Suffix conversions, JSON parsing of
+/// `Debug` values, human-readable companions, `max_fields` and the
+/// `summarize_fields` message suffix are applied by the background task, which
+/// then merges the result with the entry's span fields. Entries built any
+/// other way use `PendingFields::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct PendingFields {
+    /// Recorded values in recording order
+    fields: RawFields,
+    /// Whether fields were already dropped while recording
+    truncated: bool,
+    /// How to convert the fields
+    options: FieldOptions,
+}
+
+impl PendingFields {
+    /// Skip the `summarize_fields` message suffix
+    ///
+    /// Used for all but the last piece of a split message.
+    pub(crate) fn without_summary(mut self) -> Self {
+        self.options.summarize = false;
+        self
+    }
+
+    /// Convert the recorded values into their shipped form
+    ///
+    /// # Returns
+    /// The fields, followed by `fields_truncated` if any were dropped
+    pub(crate) fn convert(self) -> Map<String, Value> {
+        let options = self.options;
+        // A field recorded under a companion's name wins over the companion.
+        let recorded: Vec<&str> = match options.human_units {
+            true => self
+                .fields
+                .iter()
+                .map(|(name, _)| *name)
+                .filter(|name| name.ends_with(HUMAN_SUFFIX))
+                .collect(),
+            false => Vec::new(),
+        };
+        let mut fields = Map::new();
+        let mut truncated = self.truncated;
+        for (name, raw) in self.fields {
+            let value = raw.into_value(name, &options);
+            let human = options
+                .human_units
+                .then(|| humanize(name, &value))
+                .flatten();
+            truncated |= !insert_capped(&mut fields, options.max_fields, name, value);
+            if let Some(human) = human {
+                let name = format!("{}{}", name, HUMAN_SUFFIX);
+                if !recorded.contains(&name.as_str()) {
+                    truncated |=
+                        !insert_capped(&mut fields, options.max_fields, &name, human.into());
+                }
+            }
+        }
+        if truncated {
+            fields.insert("fields_truncated".into(), true.into());
+        }
+        fields
+    }
+
+    /// Convert the fields and merge them with the entry's span fields
+    ///
+    /// # Arguments
+    /// * `message` - The entry's message, extended with the field summary if enabled
+    /// * `span_fields` - The entry's span fields, replaced by the merged payload
+    pub(crate) fn apply(self, message: &mut String, span_fields: &mut Map<String, Value>) {
+        let options = self.options;
+        let mut fields = self.convert();
+        if options.strip_ansi && message.contains(ESC) {
+            *message = strip_ansi(message);
+        }
+        if options.parse_json_message {
+            if let Some(mut parsed) = parse_json_message(message) {
+                *message = take_message(&mut parsed);
+                // The event's own fields win over the message's keys.
+                parsed.append(&mut fields);
+                fields = parsed;
+            }
+        }
+        if options.group_stack_traces && !fields.contains_key(STACK_TRACE_FIELD) {
+            if let Some(start) = stack_trace_start(message) {
+                let trace = message[start..].trim_end().to_string();
+                let head = message[..start].trim_end();
+                *message = match head.is_empty() {
+                    true => trace.lines().next().unwrap_or_default().to_string(),
+                    false => head.to_string(),
+                };
+                fields.insert(STACK_TRACE_FIELD.into(), trace.into());
+            }
+        }
+        if options.summarize && !fields.is_empty() {
+            *message = format!("{} {}", message, summarize(&fields));
+        }
+        if options.span_context && span_fields.contains_key(SPAN_CONTEXT_FIELD) {
+            // The span list keeps its key, so the event's field is renamed.
+            if let Some(value) = fields.remove(SPAN_CONTEXT_FIELD) {
+                let mut key = SPAN_CONTEXT_FIELD.to_string();
+                while span_fields.contains_key(&key) || fields.contains_key(&key) {
+                    key.insert_str(0, "user_");
+                }
+                fields.insert(key, value);
+            }
+        }
+        // The event's own fields win over span and context fields.
+        span_fields.append(&mut fields);
+    }
+}
+
+impl FieldOptions {
+    /// Convert a rendered value by its field name suffix, as configured
+    fn convert(&self, name: &str, rendered: &str) -> Option<Value> {
+        convert_suffixed(name, rendered).or_else(|| {
+            self.human_units
+                .then(|| parse_unit_number(name, rendered))
+                .flatten()
+        })
+    }
+}
+
+/// Field name suffix marking a duration, converted to numeric milliseconds
+pub const DURATION_SUFFIX: &str = "_ms";
+
+/// Field name suffix marking a point in time, converted to RFC 3339
+pub const TIMESTAMP_SUFFIX: &str = "_at";
+
+/// Field name suffix marking a size in bytes, e.g. `content_length_bytes`
+pub const BYTES_SUFFIX: &str = "_bytes";
+
+/// Field name suffix marking a count, e.g. `retry_count`
+pub const COUNT_SUFFIX: &str = "_count";
+
+/// Appended to the name of a size or count for its human-readable companion
+pub const HUMAN_SUFFIX: &str = "_human";
+
+/// Binary units of `BYTES_SUFFIX` companions
+const BYTE_UNITS: [&str; 7] = [" B", " KiB", " MiB", " GiB", " TiB", " PiB", " EiB"];
+
+/// Decimal units of `COUNT_SUFFIX` companions
+const COUNT_UNITS: [&str; 7] = ["", "k", "M", "G", "T", "P", "E"];
+
+/// Parse the rendering of a size or count as a number
+///
+/// `?len` renders a `usize` as a string, e.g. `"1234"`, which would otherwise
+/// be shipped as one.
+///
+/// # Arguments
+/// * `name` - The field name
+/// * `rendered` - The string or `Debug` rendering of the value
+///
+/// # Returns
+/// The number, or None when the name has no unit suffix or the value is not
+/// a finite number
+fn parse_unit_number(name: &str, rendered: &str) -> Option<Value> {
+    if !name.ends_with(BYTES_SUFFIX) && !name.ends_with(COUNT_SUFFIX) {
+        return None;
+    }
+    let rendered = rendered.trim();
+    if let Ok(value) = rendered.parse::<u64>() {
+        return Some(value.into());
+    }
+    if let Ok(value) = rendered.parse::<i64>() {
+        return Some(value.into());
+    }
+    let value = rendered.parse::<f64>().ok()?;
+    serde_json::Number::from_f64(value).map(Value::Number)
+}
+
+/// Render a numeric size or count for people, e.g. `1.2 MiB` or `3.4k`
+///
+/// # Arguments
+/// * `name` - The field name, ending in `BYTES_SUFFIX` or `COUNT_SUFFIX`
+/// * `value` - The recorded value
+///
+/// # Returns
+/// The companion value, or None for other names and non-numeric values
+fn humanize(name: &str, value: &Value) -> Option<String> {
+    let (base, units) = if name.ends_with(BYTES_SUFFIX) {
+        (1024.0, BYTE_UNITS)
+    } else if name.ends_with(COUNT_SUFFIX) {
+        (1000.0, COUNT_UNITS)
+    } else {
+        return None;
+    };
+    let value = value.as_f64()?;
+    let sign = if value < 0.0 { "-" } else { "" };
+    let mut scaled = value.abs();
+    let mut unit = 0;
+    while scaled >= base && unit + 1 < units.len() {
+        scaled /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        Some(format!("{}{}{}", sign, scaled, units[0]))
+    } else {
+        Some(format!("{}{:.1}{}", sign, scaled, units[unit]))
+    }
+}
+
+/// Convert a rendered value according to its field name suffix
+///
+/// Tracing erases value types, so `elapsed_ms = ?duration` arrives as
+/// `"1.5s"` and `created_at = %datetime` as `"2024-05-01 12:00:00 UTC"`.
+/// Fields ending in `DURATION_SUFFIX` that render as a `Duration` become a
+/// number of milliseconds; fields ending in `TIMESTAMP_SUFFIX` that render as
+/// a `chrono` date-time or `SystemTime` become an RFC 3339 UTC string.
+///
+/// # Arguments
+/// * `name` - The field name
+/// * `rendered` - The string or `Debug` rendering of the value
+///
+/// # Returns
+/// The converted value, or None when the suffix is absent or the value does
+/// not parse, in which case it is kept as recorded
+fn convert_suffixed(name: &str, rendered: &str) -> Option<Value> {
+    if name.ends_with(DURATION_SUFFIX) {
+        parse_duration_ms(rendered)
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+    } else if name.ends_with(TIMESTAMP_SUFFIX) {
+        parse_timestamp(rendered).map(|t| t.to_rfc3339_opts(SecondsFormat::AutoSi, true).into())
+    } else {
+        None
+    }
+}
+
+/// Parse the `Debug` rendering of a `Duration`, e.g. `1.5s` or `250µs`
+///
+/// # Returns
+/// The duration in milliseconds
+fn parse_duration_ms(rendered: &str) -> Option<f64> {
+    let rendered = rendered.trim();
+    let split = rendered.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (amount, unit) = rendered.split_at(split);
+    let amount: f64 = amount.parse().ok()?;
+    let per_ms = match unit {
+        "s" => 1_000.0,
+        "ms" => 1.0,
+        "µs" | "us" => 1e-3,
+        "ns" => 1e-6,
+        _ => return None,
+    };
+    Some(amount * per_ms)
+}
+
+/// Parse a rendered point in time
+///
+/// Accepts RFC 3339 (`chrono`'s `Debug`), `chrono`'s `Display` form
+/// (`2024-05-01 12:00:00.250 UTC`, or with a numeric offset) and the `Debug`
+/// rendering of `SystemTime` on Unix (`SystemTime { tv_sec: .., tv_nsec: .. }`).
+fn parse_timestamp(rendered: &str) -> Option<DateTime<Utc>> {
+    let rendered = rendered.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(rendered) {
+        return Some(t.with_timezone(&Utc));
+    }
+    if let Some(naive) = rendered.strip_suffix(" UTC") {
+        if let Ok(t) = NaiveDateTime::parse_from_str(naive, "%Y-%m-%d %H:%M:%S%.f") {
+            return Some(t.and_utc());
+        }
+    }
+    if let Ok(t) = DateTime::parse_from_str(rendered, "%Y-%m-%d %H:%M:%S%.f %:z") {
+        return Some(t.with_timezone(&Utc));
+    }
+    let fields = rendered.strip_prefix("SystemTime {")?.strip_suffix('}')?;
+    let mut secs = None;
+    let mut nanos = None;
+    for part in fields.split(',') {
+        match part.split_once(':') {
+            Some((key, value)) if key.trim() == "tv_sec" => secs = value.trim().parse().ok(),
+            Some((key, value)) if key.trim() == "tv_nsec" => nanos = value.trim().parse().ok(),
+            _ => {}
+        }
+    }
+    DateTime::from_timestamp(secs?, nanos?)
+}
+
+/// Parse a string as JSON when it looks like an object or array
+///
+/// # Arguments
+/// * `rendered` - The `Debug` rendering of a field value
+///
+/// # Returns
+/// The parsed value, or None when the string is not a JSON object or array
+fn parse_json_like(rendered: &str) -> Option<Value> {
+    let trimmed = rendered.trim_start();
+    if !(trimmed.starts_with('{') || trimmed.starts_with('[')) {
+        return None;
+    }
+    serde_json::from_str(rendered).ok()
+}
+
+/// Field holding a stack trace, as set by the panic hook
+pub(crate) const STACK_TRACE_FIELD: &str = "stack_trace";
+
+/// Lines opening a stack trace, after leading whitespace
+const STACK_TRACE_HEADERS: [&str; 4] = [
+    "stack backtrace:",
+    "Stack backtrace:",
+    "Traceback (most recent call last):",
+    "goroutine ",
+];
+
+/// Find where a stack trace starts in a multi-line message
+///
+/// Recognizes Rust backtraces (`stack backtrace:` and numbered frames),
+/// Python tracebacks, Go goroutine dumps, and indented `at …` frames as
+/// printed by Java, JavaScript and Rust. Only lines after the first count as
+/// frames, so a one-line message is never a stack trace.
+///
+/// # Arguments
+/// * `message` - The event's message
+///
+/// # Returns
+/// The byte offset of the trace's first line, None if there is no trace
+pub(crate) fn stack_trace_start(message: &str) -> Option<usize> {
+    let first_line = message.find('\n')?;
+    let mut offset = 0;
+    for line in message.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        if STACK_TRACE_HEADERS
+            .iter()
+            .any(|header| trimmed.starts_with(header))
+            && (!trimmed.starts_with("goroutine ") || trimmed.ends_with(':'))
+        {
+            return Some(start);
+        }
+        // Frames are indented, which also skips the message's own first line.
+        if start <= first_line || !line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let numbered = trimmed.split_once(": ").is_some_and(|(index, _)| {
+            !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit())
+        });
+        if trimmed.starts_with("at ") || numbered {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// Character starting every ANSI escape sequence
+const ESC: char = '\u{1b}';
+
+/// Remove ANSI escape sequences, such as color codes, from a message
+///
+/// Handles control sequences (`ESC [ … final`), operating system commands
+/// such as hyperlinks (`ESC ] … BEL` or `ESC ] … ESC \`) and two-character
+/// escapes. An unterminated sequence is removed up to the end of the message.
+///
+/// # Arguments
+/// * `message` - The message, containing at least one `ESC`
+///
+/// # Returns
+/// The message without escape sequences
+fn strip_ansi(message: &str) -> String {
+    let mut stripped = String::with_capacity(message.len());
+    let mut chars = message.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Parameters and intermediates, up to a final byte in `@`..=`~`.
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' {
+                        break;
+                    }
+                    if c == ESC && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// Parse a message that is a JSON object, as bridged loggers often send
+///
+/// # Arguments
+/// * `message` - The event's message
+///
+/// # Returns
+/// The object's keys, or None when the message is not a JSON object
+pub(crate) fn parse_json_message(message: &str) -> Option<Map<String, Value>> {
+    if !looks_like_json_object(message) {
+        return None;
+    }
+    serde_json::from_str(message).ok()
+}
+
+/// Whether a message starts and ends like a JSON object, without parsing it
+///
+/// # Arguments
+/// * `message` - The event's message
+pub(crate) fn looks_like_json_object(message: &str) -> bool {
+    let trimmed = message.trim();
+    trimmed.starts_with('{') && trimmed.ends_with('}')
+}
+
+/// Remove the message from the keys of a parsed JSON message
+///
+/// # Arguments
+/// * `parsed` - The message's keys
+///
+/// # Returns
+/// The string under `message`, else `msg`, else an empty message
+fn take_message(parsed: &mut Map<String, Value>) -> String {
+    for key in ["message", "msg"] {
+        if let Some(Value::String(_)) = parsed.get(key) {
+            if let Some(Value::String(message)) = parsed.remove(key) {
+                return message;
+            }
+        }
+    }
+    String::new()
+}
+
+/// Strip the quotes `Debug` puts around a string
+///
+/// `#[instrument]` records function arguments with `Debug`, so a `&str`
+/// argument would otherwise arrive as `"\"ab-12\""`.
+///
+/// # Arguments
+/// * `rendered` - The `Debug` rendering of a field value
+///
+/// # Returns
+/// The string, or None when the rendering is not a quoted string JSON can
+/// read, e.g. one with `\u{..}` escapes
+fn unquote(rendered: &str) -> Option<Value> {
+    if rendered.len() < 2 || !rendered.starts_with('"') || !rendered.ends_with('"') {
+        return None;
+    }
+    serde_json::from_str::<String>(rendered)
+        .ok()
+        .map(Value::from)
+}
+
+/// Render fields as a compact `key=value` list for display
+///
+/// Strings are shown bare unless they are empty or contain whitespace, `=` or
+/// quotes, in which case they are quoted; other values use their JSON form.
+///
+/// # Arguments
+/// * `fields` - The structured fields of an event
+///
+/// # Returns
+/// The space-separated rendering, empty when there are no fields
+pub(crate) fn summarize(fields: &Map<String, Value>) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| match value {
+            Value::String(s)
+                if !s.is_empty()
+                    && !s.contains(|c: char| c.is_whitespace() || c == '=' || c == '"') =>
+            {
+                format!("{}={}", key, s)
+            }
+            value => format!("{}={}", key, value),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,230 @@
+//! Background task that drains the log channel and uploads entries.
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep_until, Instant, Interval};
+
+use crate::batch::count_entries;
+use crate::circuit::CircuitBreaker;
+use crate::entry::GcpLogEntry;
+use crate::error_reporting::ErrorReporter;
+use crate::handle::Message;
+use crate::pipeline::{Pipeline, Upload};
+use crate::retry::RetryQueue;
+use crate::shutdown::{Shutdown, ShutdownReport};
+use crate::transport::{Delivery, ForbiddenReport, Transport};
+use crate::Severity;
+
+/// State owned by the background sender task
+pub(crate) struct Worker {
+    /// Transport used for uploads
+    pub(crate) transport: Transport,
+    /// Batches entries and delivers those that are not uploaded
+    pub(crate) pipeline: Pipeline,
+    /// Interval between internal heartbeat entries, if enabled
+    pub(crate) heartbeat_interval: Option<Duration>,
+    /// Pauses uploads while the API keeps failing
+    pub(crate) circuit_breaker: CircuitBreaker,
+    /// Explains the first permission error on stderr
+    pub(crate) forbidden: ForbiddenReport,
+    /// Failed batches waiting to be resent by the retry task
+    pub(crate) retry_queue: Arc<RetryQueue>,
+    /// Shutdown requests from handles
+    pub(crate) shutdown: Arc<Shutdown>,
+    /// Reports severe entries to Error Reporting, if enabled
+    pub(crate) error_reporter: Option<ErrorReporter>,
+}
+
+impl Worker {
+    /// Spawn the worker on the current Tokio runtime
+    ///
+    /// # Arguments
+    /// * `rx` - The receiving end of the layer's channel
+    ///
+    /// # Returns
+    /// The task, which ends once every sender is dropped or the layer has shut down
+    pub(crate) fn spawn(self, rx: UnboundedReceiver<Message>) -> JoinHandle<()> {
+        tokio::spawn(self.run(rx))
+    }
+
+    /// Drain the channel until every sender is dropped or shutdown is requested
+    async fn run(mut self, mut rx: UnboundedReceiver<Message>) {
+        let mut heartbeat = self
+            .heartbeat_interval
+            .map(|period| interval_at(Instant::now() + period, period));
+        let shutdown = self.shutdown.clone();
+
+        loop {
+            // Internal entries are produced here rather than through tracing so
+            // they can never re-enter `on_event`.
+            let log_entry = tokio::select! {
+                message = rx.recv() => match message {
+                    Some(Message::Entry(entry)) => Some(entry.resolved()),
+                    Some(Message::Flush) => {
+                        self.pipeline.request_flush();
+                        if let Some(upload) = self.pipeline.settle(false) {
+                            self.flush(upload).await;
+                        }
+                        continue;
+                    }
+                    None => break,
+                },
+                _ = tick(&mut heartbeat) => {
+                    Some(GcpLogEntry::internal(Severity::Info, "nano-gcp-logging heartbeat"))
+                }
+                _ = wait_until(self.pipeline.deadline()) => None,
+                stop = shutdown.requested() => {
+                    let report = self.drain(&mut rx, stop).await;
+                    shutdown.finish(report);
+                    break;
+                }
+            };
+
+            let Some(log_entry) = log_entry else {
+                // The flush interval elapsed with a partial batch.
+                if let Some(upload) = self.pipeline.expire() {
+                    self.flush(upload).await;
+                }
+                continue;
+            };
+            // Without a token only the console fallback gets entries.
+            if self.pipeline.deliver_locally(&log_entry).is_some() {
+                log_entry.recycle();
+                continue;
+            }
+
+            // Pull in whatever else is already queued, growing the batch when
+            // the queue is deep so a backlog drains in fewer, larger requests.
+            let backlog = self.pipeline.backlog(rx.len());
+            let mut next = Some(log_entry);
+            while let Some(log_entry) = next.take() {
+                if let Some(error_reporter) = &self.error_reporter {
+                    error_reporter.report(&log_entry);
+                }
+                for upload in self.pipeline.add(&log_entry, backlog) {
+                    self.flush(upload).await;
+                }
+                log_entry.recycle();
+                if backlog {
+                    next = self.pipeline.next_queued(&mut rx);
+                }
+            }
+            if let Some(upload) = self.pipeline.settle(backlog) {
+                self.flush(upload).await;
+            }
+        }
+
+        if let Some(upload) = self.pipeline.expire() {
+            self.flush(upload).await;
+        }
+        self.retry_queue.close();
+    }
+
+    /// Deliver everything still queued for a shutdown
+    ///
+    /// Closes the channel, so later entries are rejected with
+    /// `EmitError::Closed`. Batches held by the circuit breaker or waiting for
+    /// a retry get a final attempt first, as they are the oldest.
+    ///
+    /// # Arguments
+    /// * `rx` - The receiving end of the layer's channel
+    /// * `stop` - When to give up on uploads
+    ///
+    /// # Returns
+    /// What happened to each pending entry
+    async fn drain(
+        &mut self,
+        rx: &mut UnboundedReceiver<Message>,
+        stop: Instant,
+    ) -> ShutdownReport {
+        rx.close();
+        let mut report = ShutdownReport::default();
+        let mut bodies = Vec::new();
+        while let Some((body, compress)) = self.circuit_breaker.next_held() {
+            bodies.push((count_entries(&body), body, compress));
+        }
+        for (body, compress) in self.retry_queue.drain() {
+            bodies.push((count_entries(&body), body, compress));
+        }
+        let error_reporter = &self.error_reporter;
+        self.pipeline.drain(
+            std::iter::from_fn(|| rx.try_recv().ok()).filter_map(Message::into_entry),
+            &mut report,
+            &mut bodies,
+            |log_entry| {
+                if let Some(error_reporter) = error_reporter {
+                    error_reporter.report(log_entry);
+                }
+            },
+        );
+        for (entries, body, compress) in bodies {
+            let delivery =
+                tokio::time::timeout_at(stop, self.transport.deliver(body, compress)).await;
+            report.record(entries, delivery.unwrap_or(Delivery::Retryable));
+        }
+        report
+    }
+
+    /// Upload a request body to the Logging API
+    ///
+    /// # Arguments
+    /// * `upload` - The body and whether to gzip it
+    async fn flush(&mut self, (body, compress): Upload) {
+        if !self.circuit_breaker.allow_request() {
+            self.circuit_breaker.hold(body, compress);
+            return;
+        }
+        if self.send(body.clone(), compress).await == Delivery::Retryable {
+            // Retries back off on their own task so fresh entries keep flowing.
+            self.retry_queue.push(body, compress);
+            return;
+        }
+        // The API answered: send what was held while the circuit was open.
+        while let Some((held, compress)) = self.circuit_breaker.next_held() {
+            if self.send(held.clone(), compress).await == Delivery::Retryable {
+                self.circuit_breaker.requeue_held(held, compress);
+                break;
+            }
+        }
+    }
+
+    /// Upload a request body, recording the outcome with the circuit breaker
+    ///
+    /// # Returns
+    /// The outcome of the upload
+    async fn send(&mut self, body: Vec<u8>, compress: bool) -> Delivery {
+        let delivery = self.transport.deliver(body, compress).await;
+        match delivery {
+            Delivery::Retryable => self.circuit_breaker.record_failure(),
+            Delivery::Forbidden => {
+                let project_id = &self.pipeline.metadata.context.instance.project_id;
+                self.forbidden
+                    .report(project_id, self.transport.principal())
+                    .await;
+                self.circuit_breaker.record_success();
+            }
+            _ => self.circuit_breaker.record_success(),
+        }
+        delivery
+    }
+}
+
+/// Wait for the next tick of an optional interval, forever if it is disabled
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until an optional deadline, forever if there is none
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
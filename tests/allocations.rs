@@ -0,0 +1,108 @@
+// Tests that capturing small events does not allocate on the emitting thread.
+#![cfg(all(feature = "testing", any(feature = "reqwest", feature = "hyper")))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use nano_gcp_logging::{GcpLoggingLayer, InstanceMetadata, LogSink};
+use serde_json::Value;
+use tracing::Dispatch;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Allocator counting the allocations made by each thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Sink counting the entries it receives
+#[derive(Clone, Default)]
+struct CountingSink(Arc<AtomicUsize>);
+
+impl LogSink for CountingSink {
+    fn write(&self, _entry: Value) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl CountingSink {
+    /// Spin until `count` entries were written
+    fn wait_for(&self, count: usize) {
+        while self.0.load(Ordering::SeqCst) < count {
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Allocations made by the current thread while running `f`
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_small_events_are_captured_without_allocating() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let sink = CountingSink::default();
+    let layer = runtime
+        .block_on(
+            GcpLoggingLayer::builder("test-project")
+                .instance_metadata(InstanceMetadata {
+                    name: "logical-service".into(),
+                    id: "1234".into(),
+                    zone: "us-central1-a".into(),
+                    region: "us-central1".into(),
+                    project_id: "test-project".into(),
+                })
+                .sink(sink.clone())
+                .build(),
+        )
+        .expect("Layer with a sink should build");
+    let dispatch = Dispatch::new(Registry::default().with(layer));
+    assert!(allocations_during(|| drop(std::hint::black_box(Box::new(1)))) > 0);
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let emit = |i: usize| tracing::info!(user_id = i, path = "/checkout", "request handled");
+        // Registers the callsite, then fills the message pool and the channel.
+        let warmup = 256;
+        for i in 0..warmup {
+            emit(i);
+        }
+        sink.wait_for(warmup);
+
+        let mut allocated = Vec::new();
+        for i in 0..1_000 {
+            allocated.push(allocations_during(|| emit(i)));
+            sink.wait_for(warmup + i + 1);
+        }
+        let events = allocated.iter().filter(|&&count| count > 0).count();
+        assert_eq!(events, 0, "allocations per event: {:?}", allocated);
+    });
+}
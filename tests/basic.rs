@@ -1,5 +1,6 @@
 // A basic test for the GcpLoggingLayer to ensure it initializes correctly
 // and can handle log events without panicking.
+#![cfg(any(feature = "reqwest", feature = "hyper"))]
 
 use nano_gcp_logging::{collect_log_metadata, GcpLoggingLayer};
 use tracing::{error, info, warn};
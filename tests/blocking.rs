@@ -0,0 +1,108 @@
+// Tests for `build_blocking`, which runs the sender without a Tokio runtime.
+#![cfg(feature = "blocking")]
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Once;
+use std::time::Duration;
+
+use nano_gcp_logging::{GcpLoggingLayer, METADATA_HOST_ENV_VAR};
+#[cfg(feature = "testing")]
+use nano_gcp_logging::{LogEntry, Severity, TestSink};
+#[cfg(feature = "testing")]
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Serve fake instance data and a token like the metadata server, on a plain thread
+///
+/// # Returns
+/// The host and port to query
+fn mock_metadata_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut request = vec![0u8; 4096];
+            let read = stream.read(&mut request).unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..read]).to_string();
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let flavored = request
+                .to_ascii_lowercase()
+                .contains("metadata-flavor: google");
+            let body = match path.strip_prefix("/computeMetadata/v1/") {
+                Some("") if flavored => Some("instance/"),
+                Some("instance/name") if flavored => Some("mock-vm"),
+                Some("instance/id") if flavored => Some("4520031799277581759"),
+                Some("instance/zone") if flavored => Some("projects/1/zones/us-east1-c"),
+                Some("instance/service-accounts/default/token") if flavored => {
+                    Some(r#"{"access_token":"mock-token","expires_in":3599,"token_type":"Bearer"}"#)
+                }
+                _ => None,
+            };
+            let response = match body {
+                Some(body) => format!(
+                    "HTTP/1.1 200 OK\r\nmetadata-flavor: Google\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 404 Not Found\r\ncontent-length: 9\r\nconnection: close\r\n\r\nnot found"
+                    .to_string(),
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    host
+}
+
+/// Point metadata lookups at a mock server shared by every test in this binary
+fn use_mock_metadata_server() {
+    static START: Once = Once::new();
+    START.call_once(|| std::env::set_var(METADATA_HOST_ENV_VAR, mock_metadata_server()));
+}
+
+#[test]
+fn test_build_blocking_detects_metadata_and_auth_without_tokio() {
+    assert!(tokio::runtime::Handle::try_current().is_err());
+    use_mock_metadata_server();
+    let layer = GcpLoggingLayer::builder("mock-project")
+        .build_blocking()
+        .expect("Layer should build without a runtime");
+    let handle = layer.handle();
+    assert!(handle.token_status().acquired_at.is_some());
+    let report = handle.blocking_shutdown(Duration::from_secs(5));
+    assert!(report.is_some_and(|report| report.remaining == 0));
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_build_blocking_ships_events_and_emitted_entries() {
+    use_mock_metadata_server();
+    let sink = TestSink::new();
+    let layer = GcpLoggingLayer::builder("mock-project")
+        .sink(sink.clone())
+        .build_blocking()
+        .expect("Layer should build without a runtime");
+    let handle = layer.handle();
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::warn!(order_id = 42, "payment retried");
+    });
+    handle
+        .emit(LogEntry::new(Severity::Error, "boom"))
+        .expect("Entry should be queued");
+
+    for _ in 0..100 {
+        if sink.entries().len() >= 2 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let entries = sink.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["jsonPayload"]["order_id"], 42);
+    assert_eq!(entries[0]["jsonPayload"]["instance"]["name"], "mock-vm");
+    assert_eq!(entries[0]["jsonPayload"]["instance"]["region"], "us-east1");
+    assert_eq!(entries[1]["severity"], "ERROR");
+    let report = handle.blocking_shutdown(Duration::from_secs(5));
+    assert!(report.is_some_and(|report| report.remaining == 0));
+}
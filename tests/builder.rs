@@ -0,0 +1,190 @@
+// Tests for GcpLoggingLayerBuilder configuration and validation.
+
+use nano_gcp_logging::{ConfigError, MonitoredResource, ResourceType};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use nano_gcp_logging::{GcpLoggingLayer, InstanceMetadata, LogEntry, Severity};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+fn instance(project_id: &str) -> InstanceMetadata {
+    InstanceMetadata {
+        name: "logical-service".into(),
+        id: "1234".into(),
+        zone: "us-central1-a".into(),
+        region: "us-central1".into(),
+        project_id: project_id.into(),
+    }
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_builder_rejects_instance_without_project_id() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(instance(""))
+        .build()
+        .await;
+    assert!(
+        layer.is_err(),
+        "Override without project_id should be rejected"
+    );
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_disabled_layer_is_noop() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .enabled(false)
+        .build()
+        .await
+        .expect("Disabled layer should still build");
+    let handle = layer.handle();
+
+    // The layer stays usable in the subscriber stack and ignores events.
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!("ignored by a disabled layer");
+    });
+    assert!(handle
+        .emit(LogEntry::new(Severity::Info, "ignored"))
+        .is_ok());
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_builder_rejects_invalid_filter() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .shipped_targets("myapp=loud")
+        .build::<Registry>()
+        .await;
+    assert!(layer.is_err(), "Unparseable directives should be rejected");
+
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .shipped_targets("myapp=info,otherdep=off")
+        .build::<Registry>()
+        .await;
+    assert!(layer.is_ok(), "Valid directives should be accepted");
+}
+
+#[test]
+fn test_build_info_labels() {
+    let info = nano_gcp_logging::build_info!();
+    assert_eq!(info.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+
+    let merged = nano_gcp_logging::BuildInfo {
+        git_sha: Some("abc123".into()),
+        ..Default::default()
+    }
+    .or(info);
+    let labels = merged.labels();
+    assert!(labels.contains(&("git_sha", "abc123".to_string())));
+    assert!(labels.iter().any(|(key, _)| *key == "version"));
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_builder_rejects_resource_without_type() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .resource(MonitoredResource::new("").label("job", "worker"))
+        .build()
+        .await;
+    assert!(layer.is_err(), "Resource without a type should be rejected");
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_builder_rejects_invalid_log_name() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .log_name("with spaces")
+        .build()
+        .await;
+    assert!(layer.is_err(), "Invalid log name should be rejected");
+
+    for name in [
+        "organizations/123/audit",
+        "folders//logs/audit",
+        "projects/a/b/logs/audit",
+        "billingAccounts/0A1B-2C3D/logs/",
+    ] {
+        let layer = GcpLoggingLayer::builder("dummy-project-id")
+            .log_name(name)
+            .build()
+            .await;
+        assert!(layer.is_err(), "{} should be rejected", name);
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_builder_accepts_grpc_transport() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(instance("dummy-project-id"))
+        .grpc(true)
+        .build()
+        .await
+        .expect("Layer uploading through gRPC should build");
+
+    let handle = layer.handle();
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!("sent through gRPC when credentials are available");
+    });
+    assert!(handle.emit(LogEntry::new(Severity::Info, "queued")).is_ok());
+}
+
+#[test]
+fn test_resource_type_constructors_reject_empty_labels() {
+    let task = ResourceType::generic_task("dummy-project-id", "us-east1", "prod", "billing", "7")
+        .expect("Complete labels should be accepted");
+    let resource = MonitoredResource::from(task);
+    assert_eq!(resource.resource_type, "generic_task");
+    assert_eq!(resource.labels["job"], "billing");
+    assert_eq!(resource.labels["task_id"], "7");
+
+    assert_eq!(
+        ResourceType::global(""),
+        Err(ConfigError::MissingField("project_id"))
+    );
+    assert_eq!(
+        ResourceType::generic_node("dummy-project-id", "", "prod", "node-1"),
+        Err(ConfigError::MissingField("location"))
+    );
+    assert_eq!(
+        ResourceType::cloud_run_revision("dummy-project-id", "", "api-00001", "api", "us-east1"),
+        Err(ConfigError::MissingField("service_name"))
+    );
+    assert_eq!(
+        ResourceType::k8s_container("", "us-east1", "prod", "payments", "api-7d9f", "api"),
+        Err(ConfigError::MissingField("project_id"))
+    );
+    assert_eq!(
+        ResourceType::gce_instance("dummy-project-id", "1234", ""),
+        Err(ConfigError::MissingField("zone"))
+    );
+    assert!(ResourceType::gce_instance("dummy-project-id", "1234", "us-east1-b").is_ok());
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_resource_type_carries_required_labels() {
+    let resource = MonitoredResource::from(ResourceType::K8sContainer {
+        project_id: "dummy-project-id".into(),
+        location: "us-central1".into(),
+        cluster_name: "prod".into(),
+        namespace_name: "payments".into(),
+        pod_name: "api-7d9f".into(),
+        container_name: "api".into(),
+    });
+    assert_eq!(resource.resource_type, "k8s_container");
+    assert_eq!(resource.labels.len(), 6);
+    assert_eq!(resource.labels["namespace_name"], "payments");
+
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .resource(ResourceType::Global {
+            project_id: "dummy-project-id".into(),
+        })
+        .build()
+        .await;
+    assert!(layer.is_ok(), "Typed resource should be accepted");
+}
@@ -0,0 +1,49 @@
+// Tests for Cloud Run resource detection; kept in its own binary since it sets env vars.
+#![cfg(all(feature = "testing", any(feature = "reqwest", feature = "hyper")))]
+
+use std::time::Duration;
+
+use nano_gcp_logging::{
+    GcpLoggingLayer, InstanceMetadata, LogEntry, Severity, TestSink,
+    CLOUD_RUN_CONFIGURATION_ENV_VAR, CLOUD_RUN_REVISION_ENV_VAR, CLOUD_RUN_SERVICE_ENV_VAR,
+};
+
+#[tokio::test]
+async fn test_cloud_run_revision_resource_is_detected() {
+    std::env::set_var(CLOUD_RUN_SERVICE_ENV_VAR, "checkout");
+    std::env::set_var(CLOUD_RUN_REVISION_ENV_VAR, "checkout-00042-abc");
+    std::env::set_var(CLOUD_RUN_CONFIGURATION_ENV_VAR, "checkout");
+
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("run-project")
+        .instance_metadata(InstanceMetadata {
+            name: "checkout".into(),
+            id: "00bf4bf0".into(),
+            zone: "us-central1-1".into(),
+            region: "us-central1".into(),
+            project_id: "run-project".into(),
+        })
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer should build on Cloud Run");
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "ready"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["resource"],
+        serde_json::json!({
+            "type": "cloud_run_revision",
+            "labels": {
+                "configuration_name": "checkout",
+                "location": "us-central1",
+                "project_id": "run-project",
+                "revision_name": "checkout-00042-abc",
+                "service_name": "checkout"
+            }
+        })
+    );
+}
@@ -0,0 +1,83 @@
+// Tests for the diagnostics file replacing stderr.
+// The destination is process-wide, so these tests live in a binary of their own.
+
+#[cfg(feature = "reqwest")]
+use nano_gcp_logging::{
+    DiagnosticsFile, GcpLoggingHandle, GcpLoggingLayer, InstanceMetadata, LogEntry, RetryPolicy,
+    Severity, TokenFuture, TokenProvider,
+};
+#[cfg(feature = "reqwest")]
+use std::time::Duration;
+
+#[cfg(feature = "reqwest")]
+struct StaticToken;
+
+#[cfg(feature = "reqwest")]
+impl TokenProvider for StaticToken {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async { Ok("custom-token".to_string()) })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+/// Emit an entry and wait until its upload failed
+async fn fail_upload(handle: &GcpLoggingHandle, failures: u64) {
+    handle
+        .emit(LogEntry::new(Severity::Error, "unreachable"))
+        .expect("Emit should succeed while running");
+    for _ in 0..200 {
+        if handle.stats().failed == failures {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the upload should have failed");
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_diagnostics_go_to_a_rotated_file() {
+    let dir = std::env::temp_dir().join(format!("nano-gcp-logging-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("diagnostics.log");
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken)
+        .on_request(|_| reqwest::Client::new().post("http://127.0.0.1:9/"))
+        .retry_policy(RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        })
+        .flush_on(Severity::Error)
+        .diagnostics_file(DiagnosticsFile {
+            max_bytes: 1,
+            max_files: 1,
+            ..DiagnosticsFile::new(&path)
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+
+    fail_upload(&handle, 1).await;
+    let first = std::fs::read_to_string(&path).expect("Diagnostics should be written");
+    assert!(first.contains("Failed to send log entry"), "{}", first);
+    // A timestamp leads each line.
+    assert!(first.starts_with(&chrono::Utc::now().format("%Y-").to_string()));
+
+    // Every line is over the limit, so the next one rotates the file.
+    fail_upload(&handle, 2).await;
+    fail_upload(&handle, 3).await;
+    let current = std::fs::read_to_string(&path).unwrap();
+    assert!(current.contains("Failed to send log entry"), "{}", current);
+    assert!(dir.join("diagnostics.log.1").exists());
+    assert!(!dir.join("diagnostics.log.2").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
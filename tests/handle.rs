@@ -0,0 +1,890 @@
+// Tests for GcpLoggingHandle emission outside the tracing pipeline.
+#![cfg(any(feature = "reqwest", feature = "hyper"))]
+
+use nano_gcp_logging::{
+    DeliveryMetrics, EmitError, GcpLogEntry, GcpLoggingHandle, GcpLoggingLayer, InstanceMetadata,
+    LogEntry, LogStats, Severity, TokenFuture, TokenProvider, TokenStatus,
+};
+#[cfg(feature = "reqwest")]
+use nano_gcp_logging::{
+    ErrorReporting, JsonEncoder, MetricsExport, RetryPolicy, ShutdownReport, TraceExport,
+    WireEncoder, WireEntry, MAX_ENTRY_BYTES, MAX_LABELS, MAX_LABEL_KEY_BYTES,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+#[tokio::test]
+async fn test_emit_queues_entry() {
+    let layer = GcpLoggingLayer::new("dummy-project-id".to_string())
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    let handle = layer.handle();
+
+    let entry = LogEntry::new(Severity::Critical, "direct entry").field("job", "nightly");
+    assert!(
+        handle.emit(entry).is_ok(),
+        "Emit should succeed while running"
+    );
+}
+
+#[test]
+fn test_emit_reports_closed_channel() {
+    // The background task dies with the runtime it was spawned on.
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let layer = runtime
+        .block_on(GcpLoggingLayer::new("dummy-project-id".to_string()))
+        .expect("Failed to create GcpLoggingLayer");
+    let handle = layer.handle();
+    drop(runtime);
+
+    let result = handle.emit(LogEntry::new(tracing::Level::ERROR, "lost"));
+    assert_eq!(result, Err(EmitError::Closed));
+}
+
+/// Messages of the entries passed to an `on_drop` callback
+#[derive(Clone, Default)]
+struct Dropped(Arc<Mutex<Vec<String>>>);
+
+impl Dropped {
+    fn hook(&self) -> impl Fn(&GcpLogEntry) + Send + Sync + 'static {
+        let dropped = self.0.clone();
+        move |entry| dropped.lock().unwrap().push(entry.message.clone())
+    }
+
+    fn messages(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_on_drop_sees_entries_after_the_task_stopped() {
+    let dropped = Dropped::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let layer = runtime
+        .block_on(
+            GcpLoggingLayer::builder("dummy-project-id")
+                .on_drop(dropped.hook())
+                .build(),
+        )
+        .expect("Failed to create GcpLoggingLayer");
+    let handle = layer.handle();
+    drop(runtime);
+
+    let result = handle.emit(LogEntry::new(Severity::Error, "lost"));
+    assert_eq!(result, Err(EmitError::Closed));
+    assert_eq!(dropped.messages(), ["lost"]);
+}
+
+#[tokio::test]
+async fn test_on_drop_sees_entries_dropped_without_credentials() {
+    let dropped = Dropped::default();
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .on_drop(dropped.hook())
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    let handle = layer.handle();
+
+    handle
+        .emit(LogEntry::new(Severity::Info, "emitted"))
+        .expect("Emit should succeed while running");
+    // Events are still captured so the callback can see them.
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("traced");
+    });
+
+    wait_for_stats(&handle, |stats| stats.dropped == 2).await;
+    let messages = dropped.messages();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0], "emitted");
+    assert!(messages[1].ends_with("traced"));
+}
+
+#[tokio::test]
+async fn test_token_status_empty_without_credentials() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .enabled(false)
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    assert_eq!(layer.handle().token_status(), TokenStatus::default());
+}
+
+#[tokio::test]
+async fn test_metrics_start_empty() {
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .enabled(false)
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    assert_eq!(layer.handle().metrics(), DeliveryMetrics::default());
+}
+
+struct StaticToken(Option<&'static str>);
+
+impl TokenProvider for StaticToken {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            self.0
+                .map(str::to_string)
+                .ok_or_else(|| "token unavailable".into())
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_token_provider_replaces_default_credentials() {
+    let instance = InstanceMetadata {
+        name: "logical-service".into(),
+        id: "1234".into(),
+        zone: "us-central1-a".into(),
+        region: "us-central1".into(),
+        project_id: "dummy-project-id".into(),
+    };
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(instance.clone())
+        .token_provider(StaticToken(Some("custom-token")))
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    let status = layer.handle().token_status();
+    assert!(status.acquired_at.is_some());
+    assert_eq!(status.expires_at, None);
+    assert_eq!(status.refresh_count, 0);
+
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(instance)
+        .token_provider(StaticToken(None))
+        .build()
+        .await
+        .expect("A failing provider should not fail the build");
+    assert_eq!(layer.handle().token_status(), TokenStatus::default());
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_request_hook_sees_quota_project_auth_and_user_agent() {
+    let (headers, captured) = std::sync::mpsc::channel();
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .quota_project("billing-project")
+        .user_agent_suffix("checkout/2.1")
+        .max_latency(Duration::from_millis(10))
+        .on_request(move |request| {
+            let built = request.build().expect("Upload request should be valid");
+            let _ = headers.send(built.headers().clone());
+            // Nothing listens on the discard port, so the upload fails fast.
+            reqwest::Client::new().post("http://127.0.0.1:9/")
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "hooked"))
+        .expect("Emit should succeed while running");
+
+    let headers =
+        tokio::task::spawn_blocking(move || captured.recv_timeout(Duration::from_secs(5)))
+            .await
+            .unwrap()
+            .expect("The hook should see the upload");
+    assert_eq!(headers["x-goog-user-project"], "billing-project");
+    assert_eq!(headers["authorization"], "Bearer custom-token");
+    assert_eq!(
+        headers["user-agent"],
+        format!(
+            "nano-gcp-logging/{} checkout/2.1",
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_error_reporting_reports_severe_entries() {
+    let (reports, captured) = std::sync::mpsc::channel();
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .error_reporting(ErrorReporting {
+            version: Some("1.2.3".into()),
+            ..ErrorReporting::new("checkout")
+        })
+        .on_request(move |request| {
+            let built = request.build().expect("Report request should be valid");
+            if built.url().path().ends_with("events:report") {
+                let body = built.body().and_then(|body| body.as_bytes()).unwrap();
+                let event: serde_json::Value = serde_json::from_slice(body).unwrap();
+                let _ = reports.send((built.url().to_string(), event));
+            }
+            reqwest::Client::new().post("http://127.0.0.1:9/")
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    let handle = layer.handle();
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::warn!("slow checkout");
+        tracing::error!(order = 7, "payment declined");
+    });
+    handle
+        .emit(LogEntry::new(Severity::Critical, "worker crashed").field("stack_trace", "at main"))
+        .expect("Emit should succeed while running");
+    // Without a location or stack trace the error could not be grouped.
+    handle
+        .emit(LogEntry::new(Severity::Error, "unlocated"))
+        .expect("Emit should succeed while running");
+
+    let reports = tokio::task::spawn_blocking(move || {
+        let mut reports = Vec::new();
+        while let Ok(report) = captured.recv_timeout(Duration::from_secs(2)) {
+            reports.push(report);
+        }
+        reports
+    })
+    .await
+    .unwrap();
+    assert_eq!(reports.len(), 2);
+    let (url, event) = &reports[0];
+    assert_eq!(
+        url,
+        "https://clouderrorreporting.googleapis.com/v1beta1/projects/dummy-project-id/events:report"
+    );
+    assert_eq!(event["message"], "payment declined");
+    assert_eq!(event["serviceContext"]["service"], "checkout");
+    assert_eq!(event["serviceContext"]["version"], "1.2.3");
+    let location = &event["context"]["reportLocation"];
+    assert_eq!(location["filePath"], file!());
+    assert_eq!(location["functionName"], module_path!());
+    assert_eq!(reports[1].1["message"], "worker crashed\nat main");
+    assert!(reports[1].1.get("context").is_none());
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_metrics_export_pushes_delivery_metrics() {
+    let (pushes, captured) = std::sync::mpsc::channel();
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .metrics_export(MetricsExport::new("checkout"))
+        .retry_policy(RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        })
+        .on_request(move |request| {
+            let built = request.build().expect("Metrics request should be valid");
+            if built.url().path().ends_with("/timeSeries") {
+                let body = built.body().and_then(|body| body.as_bytes()).unwrap();
+                let series: serde_json::Value = serde_json::from_slice(body).unwrap();
+                let _ = pushes.send((built.url().to_string(), series));
+            }
+            reqwest::Client::new().post("http://127.0.0.1:9/")
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+    handle
+        .emit(LogEntry::new(Severity::Error, "unreachable"))
+        .expect("Emit should succeed while running");
+    wait_for_stats(&handle, |stats| stats.failed == 1).await;
+    // The final push follows the shutdown.
+    handle.shutdown(Duration::from_secs(2)).await;
+
+    let (url, body) = tokio::task::spawn_blocking(move || {
+        captured
+            .recv_timeout(Duration::from_secs(2))
+            .expect("Metrics should be pushed after the shutdown")
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        url,
+        "https://monitoring.googleapis.com/v3/projects/dummy-project-id/timeSeries"
+    );
+    let series = body["timeSeries"].as_array().unwrap();
+    let value = |name: &str| {
+        let series = series
+            .iter()
+            .find(|series| series["metric"]["type"].as_str().unwrap().ends_with(name))
+            .unwrap_or_else(|| panic!("{} should be pushed", name));
+        series["points"][0]["value"]["int64Value"].clone()
+    };
+    assert_eq!(value("/failed"), "1");
+    assert_eq!(value("/sent"), "0");
+    assert_eq!(value("/dropped"), "0");
+    assert_eq!(value("/queue_depth"), "0");
+    // Nothing was acknowledged, so there is no latency to report.
+    assert_eq!(series.len(), 6);
+    let first = &series[0];
+    assert_eq!(
+        first["metric"]["type"],
+        "custom.googleapis.com/nano_gcp_logging/queue_depth"
+    );
+    assert_eq!(first["metricKind"], "GAUGE");
+    let labels = &first["resource"]["labels"];
+    assert_eq!(first["resource"]["type"], "generic_task");
+    assert_eq!(labels["location"], "us-central1");
+    assert_eq!(labels["job"], "checkout");
+    assert_eq!(
+        labels["task_id"],
+        format!("logical-service-{}", std::process::id())
+    );
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_trace_export_writes_closed_spans() {
+    let (requests, captured) = std::sync::mpsc::channel();
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .trace_export(TraceExport {
+            flush_interval: Duration::from_millis(50),
+            ..TraceExport::default()
+        })
+        .on_request(move |request| {
+            let built = request.build().expect("Export request should be valid");
+            if built.url().path().ends_with("traces:batchWrite") {
+                let body = built.body().and_then(|body| body.as_bytes()).unwrap();
+                let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+                let _ = requests.send((built.url().to_string(), body));
+            }
+            reqwest::Client::new().post("http://127.0.0.1:9/")
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let request = tracing::info_span!(
+            "request",
+            traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            path = "/checkout",
+            attempt = 2
+        );
+        request.in_scope(|| tracing::info_span!("query").in_scope(|| {}));
+    });
+
+    let (url, body) =
+        tokio::task::spawn_blocking(move || captured.recv_timeout(Duration::from_secs(5)))
+            .await
+            .unwrap()
+            .expect("The spans should be exported");
+    assert_eq!(
+        url,
+        "https://cloudtrace.googleapis.com/v2/projects/dummy-project-id/traces:batchWrite"
+    );
+    let spans = body["spans"].as_array().unwrap();
+    assert_eq!(spans.len(), 2);
+    let (query, request) = (&spans[0], &spans[1]);
+    assert_eq!(request["displayName"]["value"], "request");
+    assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+    let name = format!(
+        "projects/dummy-project-id/traces/4bf92f3577b34da6a3ce929d0e0e4736/spans/{}",
+        request["spanId"].as_str().unwrap()
+    );
+    assert_eq!(request["name"], name);
+    assert_eq!(query["parentSpanId"], request["spanId"]);
+    let attributes = &request["attributes"]["attributeMap"];
+    assert_eq!(attributes["path"]["stringValue"]["value"], "/checkout");
+    assert_eq!(attributes["attempt"]["intValue"], "2");
+    assert!(attributes.get("traceparent").is_none());
+    assert!(request["startTime"].as_str() <= request["endTime"].as_str());
+}
+
+#[cfg(feature = "reqwest")]
+/// A layer with a token whose uploads fail fast against a closed local port
+async fn unreachable_layer() -> GcpLoggingLayer {
+    GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .on_request(|_| reqwest::Client::new().post("http://127.0.0.1:9/"))
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_shutdown_reports_undelivered_entries() {
+    let handle = unreachable_layer().await.handle();
+    for i in 0..3 {
+        handle
+            .emit(LogEntry::new(Severity::Info, format!("entry {}", i)))
+            .expect("Emit should succeed while running");
+    }
+
+    let report = handle
+        .shutdown(Duration::from_secs(2))
+        .await
+        .expect("The background task should answer");
+    assert_eq!(
+        report,
+        ShutdownReport {
+            sent: 0,
+            dropped: 0,
+            remaining: 3
+        }
+    );
+    assert_eq!(report.undelivered(), 3);
+    assert_eq!(
+        handle.emit(LogEntry::new(Severity::Info, "late")),
+        Err(EmitError::Closed)
+    );
+    assert_eq!(handle.shutdown(Duration::from_secs(2)).await, Some(report));
+
+    let disabled = GcpLoggingLayer::builder("dummy-project-id")
+        .enabled(false)
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    assert_eq!(
+        disabled.handle().shutdown(Duration::ZERO).await,
+        Some(ShutdownReport::default())
+    );
+}
+
+#[cfg(feature = "reqwest")]
+/// A layer writing errors synchronously to `url` with a short timeout
+async fn sync_write_layer(url: String) -> GcpLoggingLayer {
+    GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .on_request(move |_| reqwest::Client::new().post(&url))
+        .sync_write(Severity::Error, Duration::from_millis(200))
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_sync_write_counts_timeouts_and_failures_as_failed() {
+    // Connections queue in the backlog of a listener that never answers.
+    let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoints = [
+        format!("http://{}/", silent.local_addr().unwrap()),
+        "http://127.0.0.1:9/".to_string(),
+    ];
+    for url in endpoints {
+        let layer = sync_write_layer(url.clone()).await;
+        let handle = layer.handle();
+        let subscriber = Registry::default().with(layer);
+
+        let started = std::time::Instant::now();
+        tracing::subscriber::with_default(subscriber, || tracing::error!("undelivered"));
+        assert!(started.elapsed() < Duration::from_secs(2), "{}", url);
+        let stats = wait_for_stats(&handle, |stats| stats.failed == 1).await;
+        assert_eq!(stats.failed, 1, "{}", url);
+
+        // An abandoned upload is not counted again later.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let stats = handle.stats();
+        assert_eq!(
+            (stats.queued, stats.sent_ok, stats.failed),
+            (1, 0, 1),
+            "{}",
+            url
+        );
+    }
+}
+
+/// Poll the layer's stats until `done` holds, or give up after a few seconds
+async fn wait_for_stats(handle: &GcpLoggingHandle, done: impl Fn(&LogStats) -> bool) -> LogStats {
+    for _ in 0..300 {
+        let stats = handle.stats();
+        if done(&stats) {
+            return stats;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    handle.stats()
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_stats_count_queued_dropped_and_failed_entries() {
+    // Without credentials the background task drops what it is handed.
+    let handle = GcpLoggingLayer::new("dummy-project-id".to_string())
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+    for i in 0..2 {
+        handle
+            .emit(LogEntry::new(Severity::Info, format!("entry {}", i)))
+            .expect("Emit should succeed while running");
+    }
+    let stats = wait_for_stats(&handle, |stats| stats.dropped == 2).await;
+    assert_eq!(
+        stats,
+        LogStats {
+            queued: 2,
+            dropped: 2,
+            ..LogStats::default()
+        }
+    );
+
+    // Without retries, a failed upload is given up on at once.
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .on_request(|_| reqwest::Client::new().post("http://127.0.0.1:9/"))
+        .retry_policy(RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        })
+        .flush_on(Severity::Error)
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+    handle
+        .emit(LogEntry::new(Severity::Error, "unreachable"))
+        .expect("Emit should succeed while running");
+    let stats = wait_for_stats(&handle, |stats| stats.failed == 1).await;
+    assert_eq!(stats.queued, 1);
+    assert_eq!(stats.failed, 1);
+    assert_eq!(stats.sent_ok, 0);
+    assert_eq!(stats.bytes_sent, 0);
+
+    let disabled = GcpLoggingLayer::builder("dummy-project-id")
+        .enabled(false)
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer");
+    disabled
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "ignored"))
+        .expect("A disabled layer accepts entries");
+    assert_eq!(disabled.handle().stats(), LogStats::default());
+}
+
+#[cfg(feature = "reqwest")]
+/// Entries per upload request of three entries emitted 20ms apart under `warmup`
+///
+/// Partial batches are due at once, so only the warmup coalesces entries.
+async fn request_sizes(warmup: Option<Duration>, severity: Severity) -> Vec<usize> {
+    let (sizes, captured) = std::sync::mpsc::channel();
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .flush_on(Severity::Error)
+        .flush_interval(Duration::ZERO)
+        .batch_warmup(warmup)
+        .retry_policy(RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        })
+        .on_request(move |request| {
+            let built = request.build().expect("Upload request should be valid");
+            let body = built
+                .body()
+                .and_then(|body| body.as_bytes())
+                .unwrap_or_default();
+            let entries = body.windows(9).filter(|w| w == b"\"logName\"").count();
+            let _ = sizes.send(entries);
+            reqwest::Client::new().post("http://127.0.0.1:9/")
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+    for i in 0..3 {
+        handle
+            .emit(LogEntry::new(severity, format!("startup {}", i)))
+            .expect("Emit should succeed while running");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    wait_for_stats(&handle, |stats| stats.failed == 3).await;
+    captured.try_iter().collect()
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_warmup_coalesces_startup_entries() {
+    let warmup = Some(Duration::from_millis(200));
+    assert_eq!(request_sizes(warmup, Severity::Info).await, [3]);
+    assert_eq!(request_sizes(None, Severity::Info).await, [1, 1, 1]);
+    // `flush_on` still sends errors logged during the warmup at once.
+    assert_eq!(request_sizes(warmup, Severity::Error).await, [1, 1, 1]);
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_flush_on_sends_the_partial_batch_at_once() {
+    let (messages, captured) = std::sync::mpsc::channel();
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .batch_size(10)
+        .flush_interval(Duration::from_secs(60))
+        .batch_warmup(None)
+        .flush_on(Severity::Error)
+        .on_request(move |request| {
+            let built = request.build().expect("Upload request should be valid");
+            let body = built.body().and_then(|body| body.as_bytes()).unwrap();
+            let body: serde_json::Value = serde_json::from_slice(body).unwrap();
+            let sent: Vec<String> = body["entries"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| {
+                    entry["jsonPayload"]["message"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect();
+            let _ = messages.send(sent);
+            reqwest::Client::new().post("http://127.0.0.1:9/")
+        })
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+
+    // Entries below the severity wait for the batch to fill or the interval.
+    handle
+        .emit(LogEntry::new(Severity::Info, "request served"))
+        .expect("Emit should succeed while running");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(
+        captured.try_recv().is_err(),
+        "The batch should still be pending"
+    );
+
+    handle
+        .emit(LogEntry::new(Severity::Error, "payment failed"))
+        .expect("Emit should succeed while running");
+    let sent = tokio::task::spawn_blocking(move || captured.recv_timeout(Duration::from_secs(5)))
+        .await
+        .unwrap()
+        .expect("The error should flush the partial batch");
+    assert_eq!(sent.len(), 2);
+    assert!(sent[0].contains("request served"), "{:?}", sent);
+    assert!(sent[1].contains("payment failed"), "{:?}", sent);
+}
+
+#[cfg(feature = "reqwest")]
+#[test]
+fn test_blocking_shutdown_outside_a_runtime() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let handle = runtime.block_on(unreachable_layer()).handle();
+    handle
+        .emit(LogEntry::new(Severity::Error, "exiting"))
+        .expect("Emit should succeed while running");
+
+    let report = handle.blocking_shutdown(Duration::from_secs(2));
+    assert_eq!(report.map(|report| report.remaining), Some(1));
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_blocking_shutdown_inside_a_runtime() {
+    let handle = unreachable_layer().await.handle();
+    handle
+        .emit(LogEntry::new(Severity::Error, "exiting"))
+        .expect("Emit should succeed while running");
+    let report = handle.blocking_shutdown(Duration::from_secs(2));
+    assert_eq!(report.map(|report| report.remaining), Some(1));
+
+    // A current-thread runtime cannot wait on itself, so it gives up at once.
+    let handle = tokio::task::spawn_blocking(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let handle = unreachable_layer().await.handle();
+            handle.blocking_shutdown(Duration::from_secs(2))
+        })
+    });
+    assert_eq!(handle.await.unwrap(), None);
+}
+
+/// Encoder recording what it encodes, then encoding it as JSON
+#[cfg(feature = "reqwest")]
+#[derive(Clone, Default)]
+struct CapturingEncoder {
+    /// Log name and message of every encoded entry
+    entries: Arc<Mutex<Vec<(String, String)>>>,
+    /// Every request body built
+    bodies: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+#[cfg(feature = "reqwest")]
+impl WireEncoder for CapturingEncoder {
+    fn encode_entry(&self, entry: &WireEntry<'_>, buffer: &mut Vec<u8>) -> std::io::Result<()> {
+        let captured = (entry.log_name().to_string(), entry.entry().message.clone());
+        self.entries.lock().unwrap().push(captured);
+        JsonEncoder.encode_entry(entry, buffer)
+    }
+
+    fn encode_batch(&self, entries: &[Vec<u8>], body: &mut Vec<u8>) {
+        JsonEncoder.encode_batch(entries, body);
+        self.bodies.lock().unwrap().push(body.clone());
+    }
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_wire_encoder_sees_every_upload() {
+    let encoder = CapturingEncoder::default();
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .on_request(|_| reqwest::Client::new().post("http://127.0.0.1:9/"))
+        .wire_encoder(encoder.clone())
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+    for i in 0..2 {
+        handle
+            .emit(LogEntry::new(Severity::Info, format!("entry {}", i)))
+            .expect("Emit should succeed while running");
+    }
+    handle.shutdown(Duration::from_secs(2)).await;
+
+    let entries = encoder.entries.lock().unwrap().clone();
+    let log_name = "projects/dummy-project-id/logs/proxie".to_string();
+    assert_eq!(
+        entries,
+        [
+            (log_name.clone(), "entry 0".to_string()),
+            (log_name, "entry 1".to_string())
+        ]
+    );
+    let bodies = encoder.bodies.lock().unwrap().clone();
+    let body: serde_json::Value = serde_json::from_slice(&bodies[0]).unwrap();
+    assert_eq!(body["entries"][1]["jsonPayload"]["message"], "entry 1");
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_entries_are_cut_down_to_api_limits() {
+    let encoder = CapturingEncoder::default();
+    let handle = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken(Some("custom-token")))
+        .on_request(|_| reqwest::Client::new().post("http://127.0.0.1:9/"))
+        .wire_encoder(encoder.clone())
+        .build()
+        .await
+        .expect("Failed to create GcpLoggingLayer")
+        .handle();
+    handle.set_label("k".repeat(MAX_LABEL_KEY_BYTES + 1), "v");
+    handle.set_label("team", "payments");
+    for i in 0..MAX_LABELS {
+        handle.set_label(format!("l{:02}", i), "x");
+    }
+    let blob = "x".repeat(MAX_ENTRY_BYTES);
+    for entry in [
+        LogEntry::new(Severity::Info, "big field")
+            .field("blob", blob.as_str())
+            .field("small", 1),
+        LogEntry::new(Severity::Info, blob.as_str()),
+    ] {
+        handle
+            .emit(entry)
+            .expect("Emit should succeed while running");
+    }
+    handle.shutdown(Duration::from_secs(2)).await;
+
+    let bodies = encoder.bodies.lock().unwrap().clone();
+    let body: serde_json::Value = serde_json::from_slice(&bodies[0]).unwrap();
+    for entry in body["entries"].as_array().unwrap() {
+        assert!(serde_json::to_vec(entry).unwrap().len() <= MAX_ENTRY_BYTES);
+        // The longest key is cut to the limit; the last keys in order are dropped.
+        let labels = entry["labels"].as_object().unwrap();
+        assert_eq!(labels.len(), MAX_LABELS);
+        let key = "k".repeat(MAX_LABEL_KEY_BYTES);
+        assert_eq!(labels[&key], "v");
+        let payload = &entry["jsonPayload"];
+        assert_eq!(payload["labels_truncated"], serde_json::json!([key]));
+        assert_eq!(
+            payload["labels_dropped"],
+            serde_json::json!(["l63", "team"])
+        );
+    }
+    let payload = &body["entries"][0]["jsonPayload"];
+    assert_eq!(payload["fields_dropped"], serde_json::json!(["blob"]));
+    assert_eq!(payload["small"], 1);
+    let payload = &body["entries"][1]["jsonPayload"];
+    assert_eq!(payload["message_truncated"], MAX_ENTRY_BYTES);
+    assert!(payload["message"].as_str().unwrap().ends_with('…'));
+}
@@ -0,0 +1,192 @@
+// Tests for metadata helpers.
+
+#[cfg(feature = "reqwest")]
+use nano_gcp_logging::collect_log_metadata_from_host;
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use nano_gcp_logging::{
+    collect_log_metadata, GcpLoggingLayer, Platform, RuntimeEnvironment, METADATA_HOST_ENV_VAR,
+};
+use nano_gcp_logging::{region_from_zone, ContainerMetadata, HostIdSource};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+use tokio::net::TcpListener;
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+/// Serve fake instance data like the metadata server, answering 404 elsewhere
+///
+/// An empty `zone` is not served at all.
+///
+/// # Returns
+/// The host and port to query
+async fn mock_metadata_server(zone: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![0u8; 4096];
+            let read = stream.read(&mut request).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..read]).to_string();
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let flavored = request
+                .to_ascii_lowercase()
+                .contains("metadata-flavor: google");
+            let body = match path.strip_prefix("/computeMetadata/v1/") {
+                Some("instance/name") if flavored => Some("mock-vm"),
+                Some("instance/id") if flavored => Some("4520031799277581759"),
+                Some("instance/zone") if flavored && !zone.is_empty() => Some(zone),
+                _ => None,
+            };
+            let response = match body {
+                Some(body) => format!(
+                    "HTTP/1.1 200 OK\r\nmetadata-flavor: Google\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                ),
+                None => "HTTP/1.1 404 Not Found\r\ncontent-length: 9\r\nconnection: close\r\n\r\nnot found"
+                    .to_string(),
+            };
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    host
+}
+
+#[test]
+fn test_region_from_zone() {
+    assert_eq!(region_from_zone("us-central1-a"), "us-central1");
+    assert_eq!(region_from_zone("europe-west4-b"), "europe-west4");
+    assert_eq!(region_from_zone("asia-northeast1-c"), "asia-northeast1");
+}
+
+#[test]
+fn test_region_from_zone_edge_cases() {
+    // Already a region, or no suffix at all.
+    assert_eq!(region_from_zone("us-central1"), "us-central1");
+    assert_eq!(region_from_zone("global"), "global");
+    assert_eq!(region_from_zone(""), "");
+    // Suffixes that are not a single zone letter are kept.
+    assert_eq!(region_from_zone("us-central1-ab"), "us-central1-ab");
+    assert_eq!(region_from_zone("us-central1-1"), "us-central1-1");
+    assert_eq!(region_from_zone("-a"), "-a");
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_environment_probe_is_consistent_and_cached() {
+    let first = RuntimeEnvironment::detect().await;
+    if !first.on_gcp {
+        assert_eq!(first.platform, Platform::Other);
+    } else {
+        assert_ne!(first.platform, Platform::Other);
+    }
+    assert_eq!(RuntimeEnvironment::detect().await, first);
+}
+
+#[test]
+fn test_host_id_fallback_chain() {
+    let hostname = HostIdSource::Hostname.resolve();
+    assert_eq!(
+        HostIdSource::Hostname.resolve(),
+        hostname,
+        "Hash must be stable"
+    );
+
+    assert_eq!(
+        HostIdSource::Fixed(" host-a ".into()).resolve().as_deref(),
+        Some("host-a")
+    );
+    assert_eq!(HostIdSource::Fixed("  ".into()).resolve(), hostname);
+
+    let path = std::env::temp_dir().join(format!("nano-gcp-host-id-{}", std::process::id()));
+    std::fs::write(&path, "provisioned-42\n").unwrap();
+    assert_eq!(
+        HostIdSource::File(path.clone()).resolve().as_deref(),
+        Some("provisioned-42")
+    );
+    std::fs::write(&path, "").unwrap();
+    assert_eq!(HostIdSource::File(path.clone()).resolve(), hostname);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(HostIdSource::File(path).resolve(), hostname);
+
+    let machine_id = std::fs::read_to_string("/etc/machine-id")
+        .ok()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty());
+    if let Some(machine_id) = machine_id {
+        // Only a hash of the confidential machine ID leaves the host.
+        let id = HostIdSource::MachineId.resolve().unwrap();
+        assert_eq!(id.len(), 16);
+        assert!(id.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert!(!machine_id.contains(&id));
+        assert_ne!(Some(&id), hostname.as_ref());
+        assert_eq!(HostIdSource::MachineId.resolve(), Some(id));
+    }
+}
+
+#[test]
+fn test_container_metadata_from_stubbed_cgroup() {
+    let path = std::env::temp_dir().join(format!("nano-gcp-cgroup-{}", std::process::id()));
+    std::fs::write(
+        &path,
+        "0::/kubepods/besteffort/pod1234/3f0c9e2b7a41d5e8\n1:name=systemd:/ignored\n",
+    )
+    .unwrap();
+    let container = ContainerMetadata::from_cgroup(&path).expect("Cgroup entry should be found");
+    assert_eq!(container.id, "3f0c9e2b7a41d5e8");
+
+    std::fs::write(&path, "no paths here\n").unwrap();
+    assert!(ContainerMetadata::from_cgroup(&path).is_none());
+    std::fs::remove_file(&path).unwrap();
+    assert!(ContainerMetadata::from_cgroup(&path).is_none());
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_container_metadata_toggle_builds() {
+    for enabled in [true, false] {
+        let layer = GcpLoggingLayer::builder("dummy-project-id")
+            .collect_container_metadata(enabled)
+            .build()
+            .await;
+        assert!(layer.is_ok(), "Layer should build either way");
+    }
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_collect_metadata_from_mock_server() {
+    let host = mock_metadata_server("projects/123456789/zones/europe-west4-b").await;
+    let metadata =
+        collect_log_metadata_from_host("mock-project".into(), &reqwest::Client::new(), &host)
+            .await
+            .expect("Collection should succeed");
+    assert_eq!(metadata.instance.name, "mock-vm");
+    assert_eq!(metadata.instance.id, "4520031799277581759");
+    assert_eq!(metadata.instance.zone, "europe-west4-b");
+    assert_eq!(metadata.instance.region, "europe-west4");
+    assert_eq!(metadata.instance.project_id, "mock-project");
+
+    // Paths the server does not know are left empty rather than set to the error page.
+    let host = mock_metadata_server("").await;
+    let metadata =
+        collect_log_metadata_from_host("mock-project".into(), &reqwest::Client::new(), &host)
+            .await
+            .expect("Collection should succeed");
+    assert_eq!(metadata.instance.zone, "");
+    assert_eq!(metadata.instance.region, "");
+}
+
+#[cfg(any(feature = "reqwest", feature = "hyper"))]
+#[tokio::test]
+async fn test_metadata_host_env_var_redirects_lookups() {
+    let host = mock_metadata_server("projects/1/zones/us-east1-c").await;
+    std::env::set_var(METADATA_HOST_ENV_VAR, &host);
+    let metadata = collect_log_metadata("mock-project".into())
+        .await
+        .expect("Collection should succeed");
+    std::env::remove_var(METADATA_HOST_ENV_VAR);
+    assert_eq!(metadata.instance.name, "mock-vm");
+    assert_eq!(metadata.instance.zone, "us-east1-c");
+    assert_eq!(metadata.instance.region, "us-east1");
+}
@@ -0,0 +1,58 @@
+// Tests for the panic hook.
+// The hook is process-wide, so these tests live in a binary of their own.
+#![cfg(all(feature = "testing", any(feature = "reqwest", feature = "hyper")))]
+
+use std::time::Duration;
+
+use nano_gcp_logging::{GcpLoggingLayer, InstanceMetadata, TestSink};
+
+#[tokio::test]
+async fn test_panics_are_sent_as_critical_entries() {
+    let sink = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "test-project".into(),
+        })
+        .sink(sink.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    layer.handle().install_panic_hook();
+
+    let line = line!() + 3;
+    let panicked = std::thread::Builder::new()
+        .name("worker-7".into())
+        .spawn(|| panic!("boom: {}", 42))
+        .unwrap()
+        .join();
+    assert!(panicked.is_err());
+
+    let entries = sink.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry["severity"], "CRITICAL");
+    let message = entry["jsonPayload"]["message"].as_str().unwrap();
+    assert!(
+        message.contains(&format!(
+            "thread 'worker-7' panicked at tests/panic.rs:{}",
+            line
+        )),
+        "{}",
+        message
+    );
+    assert!(message.ends_with("boom: 42"), "{}", message);
+    assert_eq!(entry["sourceLocation"]["file"], "tests/panic.rs");
+    assert_eq!(entry["sourceLocation"]["line"], line.to_string());
+    // Error Reporting needs the message first, then the backtrace.
+    let stack_trace = entry["jsonPayload"]["stack_trace"].as_str().unwrap();
+    assert!(
+        stack_trace.starts_with("thread 'worker-7' panicked"),
+        "{}",
+        stack_trace
+    );
+    assert!(stack_trace.lines().count() > 1, "{}", stack_trace);
+}
@@ -0,0 +1,321 @@
+// Property tests for field capture and payload assembly, run on generated inputs.
+#![cfg(feature = "testing")]
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use nano_gcp_logging::{
+    build_entry, EntryConfig, GcpLogEntry, GcpLoggingLayer, GcpLoggingLayerBuilder,
+    InstanceMetadata, LogContextMetadata, LogSink, MetadataNamespace, PendingFields, Severity,
+    CLOUD_TRACE_CONTEXT_FIELD,
+};
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestRunner};
+use serde_json::{Map, Value};
+use tracing::Dispatch;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+/// Keys the crate writes into every payload with the default configuration
+const INJECTED_KEYS: [&str; 4] = ["logger", "message", "container", "instance"];
+
+fn instance() -> InstanceMetadata {
+    InstanceMetadata {
+        name: "logical-service".into(),
+        id: "1234".into(),
+        zone: "us-central1-a".into(),
+        region: "us-central1".into(),
+        project_id: "test-project".into(),
+    }
+}
+
+/// Sink handing every built entry to the test thread
+struct Forward(Mutex<Sender<Value>>);
+
+impl LogSink for Forward {
+    fn write(&self, entry: Value) {
+        let _ = self.0.lock().unwrap().send(entry);
+    }
+}
+
+/// A layer started once and reused for every generated case
+struct Harness {
+    dispatch: Dispatch,
+    entries: Receiver<Value>,
+    #[cfg(any(feature = "reqwest", feature = "hyper"))]
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl Harness {
+    fn start(configure: impl FnOnce(GcpLoggingLayerBuilder) -> GcpLoggingLayerBuilder) -> Self {
+        let (sender, entries) = channel();
+        let builder = configure(
+            GcpLoggingLayer::builder("test-project")
+                .instance_metadata(instance())
+                .sink(Forward(Mutex::new(sender))),
+        );
+        // Without an async HTTP client, the sender runs on its own thread instead.
+        #[cfg(any(feature = "reqwest", feature = "hyper"))]
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        #[cfg(any(feature = "reqwest", feature = "hyper"))]
+        let layer = runtime.block_on(builder.build());
+        #[cfg(not(any(feature = "reqwest", feature = "hyper")))]
+        let layer = builder.build_blocking();
+        Self {
+            dispatch: Dispatch::new(
+                Registry::default().with(layer.expect("Layer with a sink should build")),
+            ),
+            entries,
+            #[cfg(any(feature = "reqwest", feature = "hyper"))]
+            _runtime: runtime,
+        }
+    }
+
+    fn capture(&self, count: usize, emit: impl FnOnce()) -> Vec<Value> {
+        tracing::dispatcher::with_default(&self.dispatch, emit);
+        (0..count)
+            .map(|_| {
+                self.entries
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("Entry should reach the sink")
+            })
+            .collect()
+    }
+}
+
+fn runner() -> TestRunner {
+    TestRunner::new(Config::with_cases(128))
+}
+
+/// Strings shaped like the values the suffix conversions parse, plus noise
+fn suffixed_value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        any::<String>(),
+        "[0-9]{0,6}(\\.[0-9]{0,4})?(s|ms|µs|us|ns|m|)",
+        "SystemTime \\{ tv_sec: -?[0-9]{0,14}, tv_nsec: -?[0-9]{0,10} \\}",
+        "[0-9]{4}-[0-9]{2}-[0-9]{2}[ T][0-9]{2}:[0-9]{2}:[0-9]{2}(\\.[0-9]{1,9})?( UTC|Z| \\+[0-9]{2}:[0-9]{2})?",
+    ]
+}
+
+/// Strings that are, or almost are, JSON objects and arrays
+fn json_like() -> impl Strategy<Value = String> {
+    prop_oneof![
+        any::<String>(),
+        "[\\[{][\\[\\]{}:,\" a-z0-9]{0,20}",
+        any::<Vec<i32>>().prop_map(|values| serde_json::to_string(&values).unwrap()),
+        any::<std::collections::BTreeMap<String, bool>>()
+            .prop_map(|map| serde_json::to_string(&map).unwrap()),
+    ]
+}
+
+#[test]
+fn test_arbitrary_event_values_yield_valid_payloads() {
+    let harness =
+        Harness::start(|builder| builder.parse_json_debug_fields(true).summarize_fields(true));
+    let values = (
+        any::<String>(),
+        any::<String>(),
+        any::<i64>(),
+        any::<f64>(),
+        suffixed_value(),
+        suffixed_value(),
+        json_like(),
+        any::<String>(),
+    );
+    runner()
+        .run(
+            &values,
+            |(message, text, number, ratio, elapsed, created, payload, header)| {
+                let entries = harness.capture(1, || {
+                    tracing::info!(
+                        text = text.as_str(),
+                        number,
+                        ratio,
+                        elapsed_ms = elapsed.as_str(),
+                        created_at = %created,
+                        payload = %payload,
+                        cloud_trace_context = header.as_str(),
+                        "{}",
+                        message
+                    );
+                });
+                let entry = &entries[0];
+                let json = serde_json::to_string(entry).unwrap();
+                // serde_json parses floats approximately, so only validity is
+                // checked, not an exact round trip.
+                prop_assert!(serde_json::from_str::<Value>(&json).is_ok_and(|v| v.is_object()));
+
+                let fields = entry["jsonPayload"].as_object().unwrap();
+                prop_assert!(!fields.contains_key(CLOUD_TRACE_CONTEXT_FIELD));
+                prop_assert!(fields["message"].as_str().unwrap().contains(&message));
+                prop_assert_eq!(fields["instance"]["id"].as_str(), Some("1234"));
+                prop_assert_eq!(fields["text"].as_str(), Some(text.as_str()));
+                prop_assert_eq!(&fields["number"], &Value::from(number));
+                if ratio.is_finite() {
+                    prop_assert_eq!(fields["ratio"].as_f64(), Some(ratio));
+                } else {
+                    let rendered = ratio.to_string();
+                    prop_assert_eq!(fields["ratio"].as_str(), Some(rendered.as_str()));
+                }
+                // Unparsable suffixed values are kept as recorded.
+                let elapsed_ms = &fields["elapsed_ms"];
+                prop_assert!(
+                    elapsed_ms.is_number() || elapsed_ms.as_str() == Some(elapsed.as_str())
+                );
+                prop_assert!(fields["created_at"].is_string());
+                Ok(())
+            },
+        )
+        .unwrap();
+}
+
+#[test]
+fn test_max_fields_bounds_user_fields() {
+    let mut runner = runner();
+    for max in [0, 1, 5, 6] {
+        let harness = Harness::start(|builder| builder.max_fields(max));
+        runner
+            .run(&any::<[u8; 6]>(), |[a, b, c, d, e, f]| {
+                let entries = harness.capture(1, || {
+                    tracing::info!(a, b, c, d, e, f, "bounded");
+                });
+                let fields = entries[0]["jsonPayload"].as_object().unwrap();
+                let truncated = fields.get("fields_truncated") == Some(&Value::Bool(true));
+                let user_fields = fields
+                    .keys()
+                    .filter(|key| !INJECTED_KEYS.contains(&key.as_str()))
+                    .filter(|key| *key != "fields_truncated")
+                    .count();
+                prop_assert_eq!(user_fields, max.min(6));
+                prop_assert_eq!(truncated, max < 6);
+                Ok(())
+            })
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_split_lines_reassemble_the_message() {
+    let mut runner = runner();
+    for max in [1, 3] {
+        let harness = Harness::start(|builder| builder.split_multiline(max));
+        runner
+            .run(&"[a-z \\n]{0,40}", |message| {
+                let expected = if message.contains('\n') {
+                    max.min(message.matches('\n').count() + 1)
+                } else {
+                    1
+                };
+                let entries = harness.capture(expected, || {
+                    tracing::info!("{}", message);
+                });
+                // The message line ends with `[<message>]`, and the generated
+                // messages contain no brackets.
+                let lines: Vec<&str> = entries
+                    .iter()
+                    .map(|entry| {
+                        let line = entry["jsonPayload"]["message"].as_str().unwrap();
+                        let start = line.rfind("] [").unwrap() + 3;
+                        &line[start..line.len() - 1]
+                    })
+                    .collect();
+                prop_assert_eq!(lines.join("\n"), message);
+                Ok(())
+            })
+            .unwrap();
+        assert!(
+            harness
+                .entries
+                .recv_timeout(Duration::from_millis(50))
+                .is_err(),
+            "No entries beyond the split ones should be emitted"
+        );
+    }
+}
+
+/// Field names biased towards the keys the crate injects
+fn field_name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just("message".to_string()),
+        Just("instance".to_string()),
+        Just("container".to_string()),
+        Just("user_instance".to_string()),
+        Just("_meta".to_string()),
+        Just("_instance".to_string()),
+        "[a-z_]{1,12}",
+        any::<String>(),
+    ]
+}
+
+fn field_value() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<String>().prop_map(Value::from),
+    ]
+}
+
+fn namespace() -> impl Strategy<Value = MetadataNamespace> {
+    prop_oneof![
+        Just(MetadataNamespace::Flat),
+        Just(MetadataNamespace::Nested("_meta".into())),
+        Just(MetadataNamespace::Prefixed("_".into())),
+    ]
+}
+
+#[test]
+fn test_user_fields_never_clobber_injected_metadata() {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let cases = (
+        prop::collection::vec((field_name(), field_value()), 0..8),
+        namespace(),
+    );
+    runner()
+        .run(&cases, |(pairs, namespace)| {
+            let fields: Map<String, Value> = pairs.into_iter().collect();
+            let log_entry = GcpLogEntry {
+                message: "checkout".into(),
+                severity: Severity::Info,
+                fields: fields.clone(),
+                timestamp: Utc::now(),
+                source_location: None,
+                operation: None,
+                trace: None,
+                labels: Default::default(),
+                runtime_labels: None,
+                internal: false,
+                callsite: None,
+                pending: PendingFields::default(),
+            };
+            let mut config = EntryConfig::default();
+            config.metadata_namespace = namespace.clone();
+            let entry = build_entry(&metadata, &config, &log_entry);
+            let payload = entry["jsonPayload"].as_object().unwrap();
+
+            prop_assert_eq!(payload["message"].as_str(), Some("checkout"));
+            let (instance, injected) = match &namespace {
+                MetadataNamespace::Flat => (&payload["instance"], 3),
+                MetadataNamespace::Nested(key) => (&payload[key.as_str()]["instance"], 2),
+                MetadataNamespace::Prefixed(prefix) => {
+                    (&payload[format!("{}instance", prefix).as_str()], 3)
+                }
+            };
+            prop_assert_eq!(instance["id"].as_str(), Some("1234"));
+            // Every user field survives, possibly renamed with `user_` prefixes.
+            prop_assert_eq!(payload.len(), fields.len() + injected);
+            for (key, value) in &fields {
+                let survives = (0..=fields.len()).any(|renames| {
+                    let renamed = format!("{}{}", "user_".repeat(renames), key);
+                    payload.get(&renamed) == Some(value)
+                });
+                prop_assert!(survives, "field {:?} was lost", key);
+            }
+            Ok(())
+        })
+        .unwrap();
+}
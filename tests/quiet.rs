@@ -0,0 +1,91 @@
+// Tests for silencing warnings through `QUIET_ENV_VAR`.
+// Environment variables are process-wide, so these tests live in a binary of their own.
+
+#[cfg(feature = "reqwest")]
+use nano_gcp_logging::{
+    DiagnosticsFile, GcpLoggingHandle, GcpLoggingLayer, GcpLoggingLayerBuilder, InstanceMetadata,
+    LogEntry, RetryPolicy, Severity, TokenFuture, TokenProvider, MAX_ENTRY_BYTES, QUIET_ENV_VAR,
+};
+#[cfg(feature = "reqwest")]
+use std::time::Duration;
+
+#[cfg(feature = "reqwest")]
+struct StaticToken;
+
+#[cfg(feature = "reqwest")]
+impl TokenProvider for StaticToken {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async { Ok("custom-token".to_string()) })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+/// A layer whose uploads fail at once and whose diagnostics go to `path`
+fn builder(path: &std::path::Path) -> GcpLoggingLayerBuilder {
+    GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            name: "logical-service".into(),
+            id: "1234".into(),
+            zone: "us-central1-a".into(),
+            region: "us-central1".into(),
+            project_id: "dummy-project-id".into(),
+        })
+        .token_provider(StaticToken)
+        .on_request(|_| reqwest::Client::new().post("http://127.0.0.1:9/"))
+        .retry_policy(RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        })
+        .flush_on(Severity::Error)
+        .diagnostics_file(DiagnosticsFile::new(path))
+}
+
+#[cfg(feature = "reqwest")]
+/// Emit an entry and wait until its upload failed
+async fn fail_upload(handle: &GcpLoggingHandle, log_entry: LogEntry) {
+    handle
+        .emit(log_entry)
+        .expect("Emit should succeed while running");
+    for _ in 0..200 {
+        if handle.stats().failed == 1 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the upload should have failed");
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_quiet_env_var_silences_warnings_but_not_errors() {
+    let dir = std::env::temp_dir().join(format!("nano-gcp-logging-quiet-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("diagnostics.log");
+
+    std::env::set_var(QUIET_ENV_VAR, "1");
+    let handle = builder(&path).build().await.unwrap().handle();
+    // Oversized entries are repaired with a warning.
+    let oversized = "x".repeat(MAX_ENTRY_BYTES + 1);
+    fail_upload(&handle, LogEntry::new(Severity::Error, oversized)).await;
+    let quiet = std::fs::read_to_string(&path).expect("Diagnostics should be written");
+    assert!(quiet.contains("Failed to send log entry"), "{}", quiet);
+    assert!(!quiet.contains("Warning"), "{}", quiet);
+
+    std::env::remove_var(QUIET_ENV_VAR);
+    // Label keys over the limit are trimmed with a warning.
+    let handle = builder(&path)
+        .label("k".repeat(1000), "value")
+        .build()
+        .await
+        .unwrap()
+        .handle();
+    fail_upload(&handle, LogEntry::new(Severity::Error, "labelled")).await;
+    let loud = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        loud.contains("Warning: labels of a log entry were trimmed"),
+        "{}",
+        loud
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,2534 @@
+// Tests for the `testing` feature: building entries and capturing them with a sink.
+#![cfg(all(feature = "testing", any(feature = "reqwest", feature = "hyper")))]
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use nano_gcp_logging::{
+    build_entry, to_structured_log, write_entries, write_entry, CompactFormatter,
+    ContainerMetadata, EntryConfig, FullFormatter, GcpLogEntry, GcpLoggingLayer, HostIdSource,
+    InstanceMetadata, JsonOnlyFormatter, LogContextMetadata, LogEntry, MessageFormat,
+    MessageFormatter, MessageParts, MetadataNamespace, MonitoredResource, OutputFormat,
+    PayloadParts, PayloadSerializer, PendingFields, RequestId, ResourceType, RuntimeEnvironment,
+    Severity, SeverityRoute, SourceLocation, SpanFieldMode, TestSink, TraceContext, TraceExport,
+    INTERNAL_LABEL, REQUEST_ID_FIELD, TENANT_LABEL,
+};
+#[cfg(feature = "reqwest")]
+use nano_gcp_logging::{RetryPolicy, TokenFuture, TokenProvider};
+use serde_json::{Map, Value};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::Registry;
+
+fn instance() -> InstanceMetadata {
+    InstanceMetadata {
+        name: "logical-service".into(),
+        id: "1234".into(),
+        zone: "us-central1-a".into(),
+        region: "us-central1".into(),
+        project_id: "test-project".into(),
+    }
+}
+
+/// Wait for `count` entries, then briefly for any entry beyond them
+///
+/// Lets a test check that filtered events were not shipped without waiting
+/// out the whole timeout.
+async fn wait_for_exactly(sink: &TestSink, count: usize) -> Vec<Value> {
+    sink.wait_for(count, Duration::from_secs(5)).await;
+    sink.wait_for(count + 1, Duration::from_millis(100)).await
+}
+
+/// Stand-in for application code under test
+fn handle_payment(amount: u64) {
+    if amount > 1_000 {
+        tracing::error!(amount, order_id = "A-17", "payment declined");
+    } else {
+        tracing::info!(amount, "payment accepted");
+    }
+}
+
+#[tokio::test]
+async fn test_test_sink_asserts_on_shipped_entries() {
+    let sink = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(sink.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        handle_payment(20);
+        handle_payment(5_000);
+    });
+
+    let entries = sink.wait_for(2, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 2);
+    let declined = sink.assert_contains(|entry| {
+        entry["severity"] == "ERROR" && entry["jsonPayload"]["order_id"] == "A-17"
+    });
+    assert_eq!(declined["jsonPayload"]["amount"], 5_000);
+    assert_eq!(sink.entries(), entries);
+
+    // Fewer entries than awaited come back once the timeout elapses.
+    let entries = sink.wait_for(3, Duration::from_millis(50)).await;
+    assert_eq!(entries.len(), 2);
+}
+
+#[tokio::test]
+async fn test_builder_accepts_instance_override() {
+    let sink = TestSink::new();
+    let layer = GcpLoggingLayer::builder("dummy-project-id")
+        .instance_metadata(InstanceMetadata {
+            project_id: "override-project".into(),
+            ..instance()
+        })
+        .container_metadata(ContainerMetadata {
+            id: "abc".into(),
+            name: "my-container".into(),
+        })
+        .sink(sink.clone())
+        .build()
+        .await
+        .expect("Failed to build layer with instance override");
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "overridden"))
+        .expect("Entry should be queued");
+
+    let entries = sink.wait_for(1, Duration::from_secs(5)).await;
+    let entry = &entries[0];
+    // The overrides replace what the metadata server would have reported.
+    assert_eq!(entry["logName"], "projects/override-project/logs/proxie");
+    assert_eq!(
+        entry["resource"],
+        serde_json::json!({
+            "type": "gce_instance",
+            "labels": {
+                "instance_id": "1234",
+                "project_id": "override-project",
+                "zone": "us-central1-a"
+            }
+        })
+    );
+    assert_eq!(entry["jsonPayload"]["instance"]["name"], "logical-service");
+    assert_eq!(
+        entry["jsonPayload"]["container"],
+        serde_json::json!({"id": "abc", "name": "my-container"})
+    );
+}
+
+#[tokio::test]
+async fn test_json_debug_fields_become_structured_values() {
+    for parse in [true, false] {
+        let sink = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .parse_json_debug_fields(parse)
+            .sink(sink.clone())
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+        let order = r#"{"id":7,"items":["a","b"]}"#;
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info!(
+                order = %order,
+                broken = %"{not json",
+                text = order,
+                "order placed"
+            );
+        });
+
+        let entries = sink.wait_for(1, Duration::from_secs(5)).await;
+        let payload = &entries[0]["jsonPayload"];
+        match parse {
+            true => assert_eq!(
+                payload["order"],
+                serde_json::json!({"id": 7, "items": ["a", "b"]})
+            ),
+            false => assert_eq!(payload["order"], order),
+        }
+        // Values that fail to parse, and plain strings, are kept as they are.
+        assert_eq!(payload["broken"], "{not json");
+        assert_eq!(payload["text"], order);
+    }
+}
+
+#[tokio::test]
+async fn test_span_lifecycle_logging_handles_spans() {
+    let sink = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .log_span_lifecycle(true)
+        .sink(sink.clone())
+        .build()
+        .await
+        .expect("Layer with span lifecycle logging should build");
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let span = tracing::warn_span!("request", path = "/health", status = tracing::field::Empty);
+        let _guard = span.enter();
+        span.record("status", 200);
+        tracing::info!("inside the span");
+        std::thread::sleep(Duration::from_millis(20));
+    });
+
+    let entries = sink.wait_for(3, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 3);
+    let message = |entry: &Value| {
+        entry["jsonPayload"]["message"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let (opened, event, closed) = (&entries[0], &entries[1], &entries[2]);
+    assert!(
+        message(opened).contains("span request opened"),
+        "{}",
+        opened
+    );
+    assert!(message(event).contains("inside the span"), "{}", event);
+    assert!(
+        message(closed).contains("span request closed"),
+        "{}",
+        closed
+    );
+
+    // Both entries take the span's level and name it.
+    for entry in [opened, closed] {
+        assert_eq!(entry["severity"], "WARNING");
+        assert_eq!(entry["jsonPayload"]["span"], "request");
+        assert_eq!(entry["jsonPayload"]["path"], "/health");
+    }
+    assert_eq!(event["severity"], "INFO");
+
+    // Only the closing entry has the recorded field and the duration.
+    assert!(opened["jsonPayload"].get("status").is_none(), "{}", opened);
+    assert!(
+        opened["jsonPayload"].get("elapsed_ms").is_none(),
+        "{}",
+        opened
+    );
+    assert_eq!(closed["jsonPayload"]["status"], 200);
+    let elapsed = closed["jsonPayload"]["elapsed_ms"]
+        .as_f64()
+        .expect("duration");
+    assert!(elapsed >= 20.0, "{}", elapsed);
+
+    // The two entries are the first and last of one operation.
+    let operation = &opened["operation"];
+    assert_eq!(operation["first"], true);
+    assert!(operation.get("last").is_none(), "{}", operation);
+    assert_eq!(closed["operation"]["id"], operation["id"]);
+    assert!(closed["operation"].get("first").is_none(), "{}", closed);
+    assert_eq!(closed["operation"]["last"], true);
+}
+
+/// Entries of an error event with a trace, in the given format
+async fn formatted_entries(format: OutputFormat) -> Vec<Value> {
+    let sink = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .label("team", "payments")
+        .message_format(MessageFormat::Plain)
+        .output_format(format)
+        .sink(sink.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let span = tracing::info_span!(
+            "request",
+            traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+        let _guard = span.enter();
+        tracing::error!(
+            order_id = "A-17",
+            id = 7,
+            retried = true,
+            "payment declined\ncard expired"
+        );
+    });
+    sink.wait_for(1, Duration::from_secs(5)).await
+}
+
+#[tokio::test]
+async fn test_ecs_output_format() {
+    let entries = formatted_entries(OutputFormat::Ecs).await;
+    let entry = &entries[0];
+    assert!(entry.get("logName").is_none());
+    assert!(entry["@timestamp"].as_str().unwrap().ends_with('Z'));
+    assert_eq!(entry["log.level"], "error");
+    assert_eq!(entry["message"], "payment declined\ncard expired");
+    assert_eq!(entry["ecs.version"], "8.11.0");
+    assert_eq!(entry["trace.id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(entry["span.id"], "00f067aa0ba902b7");
+    assert_eq!(entry["labels"]["team"], "payments");
+    assert_eq!(entry["cloud.provider"], "gcp");
+    assert_eq!(entry["cloud.project.id"], "test-project");
+    assert_eq!(entry["cloud.availability_zone"], "us-central1-a");
+    assert_eq!(entry["cloud.instance.id"], "1234");
+    assert_eq!(entry["order_id"], "A-17");
+    assert_eq!(entry["id"], 7);
+    assert_eq!(entry["retried"], true);
+}
+
+#[tokio::test]
+async fn test_gelf_output_format() {
+    let entries = formatted_entries(OutputFormat::Gelf).await;
+    let entry = &entries[0];
+    assert_eq!(entry["version"], "1.1");
+    assert_eq!(entry["host"], "logical-service");
+    assert_eq!(entry["short_message"], "payment declined");
+    assert_eq!(entry["full_message"], "payment declined\ncard expired");
+    assert!(entry["timestamp"].as_f64().unwrap() > 1e9);
+    assert_eq!(entry["level"], 3);
+    assert_eq!(entry["_trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(entry["_labels.team"], "payments");
+    assert_eq!(entry["_order_id"], "A-17");
+    // `_id` is reserved and additional fields only hold strings and numbers.
+    assert!(entry.get("_id").is_none());
+    assert_eq!(entry["_user_id"], 7);
+    assert_eq!(entry["_retried"], "true");
+    let fields = entry.as_object().unwrap();
+    let additional = fields.keys().filter(|key| !key.starts_with('_'));
+    let standard = [
+        "version",
+        "host",
+        "short_message",
+        "full_message",
+        "timestamp",
+        "level",
+    ];
+    assert!(additional
+        .clone()
+        .all(|key| standard.contains(&key.as_str())));
+    assert_eq!(additional.count(), standard.len());
+}
+
+#[test]
+#[should_panic(expected = "no matching entry among the 0 received")]
+fn test_test_sink_reports_missing_entries() {
+    TestSink::new().assert_contains(|entry| entry["severity"] == "CRITICAL");
+}
+
+#[test]
+fn test_build_entry_keeps_metadata_over_user_fields() {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let mut fields = Map::new();
+    fields.insert("message".into(), "shadowed".into());
+    fields.insert("order_id".into(), 42.into());
+    let log_entry = GcpLogEntry {
+        message: "checkout".into(),
+        severity: Severity::Warning,
+        fields,
+        timestamp: Utc::now(),
+        source_location: None,
+        operation: None,
+        trace: None,
+        labels: Default::default(),
+        runtime_labels: None,
+        internal: false,
+        callsite: None,
+        pending: PendingFields::default(),
+    };
+
+    let entry = build_entry(&metadata, &EntryConfig::default(), &log_entry);
+    assert_eq!(entry["severity"], "WARNING");
+    assert_eq!(entry["jsonPayload"]["message"], "checkout");
+    assert_eq!(entry["jsonPayload"]["user_message"], "shadowed");
+    assert_eq!(entry["jsonPayload"]["order_id"], 42);
+    assert_eq!(entry["logName"], "projects/test-project/logs/proxie");
+
+    // Entries can be taken apart by value.
+    let GcpLogEntry {
+        message, fields, ..
+    } = log_entry;
+    assert_eq!(message, "checkout");
+    assert_eq!(fields["order_id"], 42);
+}
+
+#[test]
+fn test_metadata_namespace_keeps_user_fields() {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let mut fields = Map::new();
+    fields.insert("instance".into(), "blue".into());
+    fields.insert("_meta".into(), "mine".into());
+    let log_entry = GcpLogEntry {
+        message: "checkout".into(),
+        severity: Severity::Info,
+        fields,
+        timestamp: Utc::now(),
+        source_location: None,
+        operation: None,
+        trace: None,
+        labels: Default::default(),
+        runtime_labels: None,
+        internal: false,
+        callsite: None,
+        pending: PendingFields::default(),
+    };
+
+    let flat = build_entry(&metadata, &EntryConfig::default(), &log_entry);
+    assert_eq!(flat["jsonPayload"]["instance"]["id"], "1234");
+    assert_eq!(flat["jsonPayload"]["user_instance"], "blue");
+
+    let mut config = EntryConfig::default();
+    config.metadata_namespace = MetadataNamespace::Nested("_meta".into());
+    let nested = build_entry(&metadata, &config, &log_entry);
+    let payload = &nested["jsonPayload"];
+    assert_eq!(payload["_meta"]["instance"]["id"], "1234");
+    assert_eq!(payload["instance"], "blue");
+    assert_eq!(payload["user__meta"], "mine");
+    assert_eq!(payload["message"], "checkout");
+
+    let mut config = EntryConfig::default();
+    config.metadata_namespace = MetadataNamespace::Prefixed("gcp.".into());
+    let prefixed = build_entry(&metadata, &config, &log_entry);
+    let payload = &prefixed["jsonPayload"];
+    assert_eq!(payload["gcp.instance"]["id"], "1234");
+    assert_eq!(payload["instance"], "blue");
+    assert!(payload.get("container").is_none());
+}
+
+#[test]
+fn test_metadata_namespace_renames_colliding_fields() {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let mut fields = Map::new();
+    fields.insert("gcp.instance".into(), "mine".into());
+    fields.insert("user_gcp.instance".into(), "taken".into());
+    fields.insert("severity_number".into(), "custom".into());
+    fields.insert("gcp.severity_number".into(), 7.into());
+    let log_entry = GcpLogEntry {
+        message: "checkout".into(),
+        severity: Severity::Warning,
+        fields,
+        timestamp: Utc::now(),
+        source_location: None,
+        operation: None,
+        trace: None,
+        labels: Default::default(),
+        runtime_labels: None,
+        internal: false,
+        callsite: None,
+        pending: PendingFields::default(),
+    };
+    let mut config = EntryConfig::default();
+    config.severity_number = true;
+
+    // Flat: only the unprefixed metadata keys are reserved.
+    let flat = build_entry(&metadata, &config, &log_entry);
+    let payload = flat["jsonPayload"].as_object().unwrap();
+    assert_eq!(payload["severity_number"], 400);
+    assert_eq!(payload["user_severity_number"], "custom");
+    assert_eq!(payload["gcp.instance"], "mine");
+    assert_eq!(payload["gcp.severity_number"], 7);
+
+    // Prefixed: the prefixed keys are reserved, and a renamed field skips
+    // the keys of other fields, which keep theirs.
+    config.metadata_namespace = MetadataNamespace::Prefixed("gcp.".into());
+    let prefixed = build_entry(&metadata, &config, &log_entry);
+    let payload = prefixed["jsonPayload"].as_object().unwrap();
+    assert_eq!(payload["gcp.instance"]["id"], "1234");
+    assert_eq!(payload["gcp.severity_number"], 400);
+    assert_eq!(payload["user_gcp.instance"], "taken");
+    assert_eq!(payload["user_user_gcp.instance"], "mine");
+    assert_eq!(payload["user_gcp.severity_number"], 7);
+    assert_eq!(payload["severity_number"], "custom");
+
+    // Nested: only the namespace key itself is reserved.
+    config.metadata_namespace = MetadataNamespace::Nested("gcp.instance".into());
+    let nested = build_entry(&metadata, &config, &log_entry);
+    let payload = nested["jsonPayload"].as_object().unwrap();
+    assert_eq!(payload["gcp.instance"]["instance"]["id"], "1234");
+    assert_eq!(payload["gcp.instance"]["severity_number"], 400);
+    assert_eq!(payload["user_user_gcp.instance"], "mine");
+    assert_eq!(payload["severity_number"], "custom");
+
+    // No user field is lost whatever the placement.
+    for entry in [&flat, &prefixed, &nested] {
+        let payload = entry["jsonPayload"].as_object().unwrap();
+        let values: Vec<&Value> = payload.values().collect();
+        for value in log_entry.fields.values() {
+            assert!(
+                values.contains(&value),
+                "{} missing from {:?}",
+                value,
+                payload
+            );
+        }
+    }
+}
+
+#[test]
+fn test_write_entry_matches_build_entry() {
+    let metadata = LogContextMetadata {
+        container: Some(ContainerMetadata {
+            id: "c0ffee".into(),
+            name: "checkout".into(),
+        }),
+        instance: instance(),
+    };
+    let mut fields = Map::new();
+    fields.insert("message".into(), "shadowed".into());
+    fields.insert("user_message".into(), "taken".into());
+    fields.insert(
+        "cart".into(),
+        serde_json::json!({"items": [1, 2], "total": 9.5}),
+    );
+    let log_entry = GcpLogEntry {
+        message: "checkout \"quoted\"".into(),
+        severity: Severity::Error,
+        fields,
+        timestamp: Utc::now(),
+        source_location: Some(SourceLocation {
+            file: "src/main.rs".into(),
+            line: 7,
+        }),
+        operation: None,
+        trace: Some(TraceContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+            sampled: true,
+        }),
+        labels: Default::default(),
+        runtime_labels: None,
+        internal: true,
+        callsite: None,
+        pending: PendingFields::default(),
+    };
+    let mut config = EntryConfig::default();
+    config.severity_number = true;
+    config.preserve_order = true;
+    config.labels.insert("team".into(), "payments".into());
+    config.severity_routes.insert(
+        Severity::Error,
+        SeverityRoute {
+            log_name: Some("errors".into()),
+            ..Default::default()
+        },
+    );
+    let namespaces = [
+        MetadataNamespace::Flat,
+        MetadataNamespace::Nested("_meta".into()),
+        MetadataNamespace::Prefixed("gcp.".into()),
+    ];
+    for namespace in namespaces {
+        config.metadata_namespace = namespace;
+        let mut written = Vec::new();
+        write_entry(&metadata, &config, &log_entry, &mut written).unwrap();
+        let built = build_entry(&metadata, &config, &log_entry);
+        let written: Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(written, built);
+
+        let mut body = Vec::new();
+        write_entries(&metadata, &config, [&log_entry, &log_entry], &mut body).unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "entries": [built, built] }));
+    }
+    let payload = &build_entry(&metadata, &config, &log_entry)["jsonPayload"];
+    assert_eq!(payload["user_message"], "taken");
+    assert_eq!(payload["user_user_message"], "shadowed");
+}
+
+#[tokio::test]
+async fn test_sink_receives_built_entries() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .label("team", "payments")
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Error, "boom").field("code", 7))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["severity"], "ERROR");
+    assert_eq!(entries[0]["jsonPayload"]["code"], 7);
+    assert_eq!(entries[0]["labels"]["team"], "payments");
+}
+
+#[tokio::test]
+async fn test_self_generated_entries_carry_the_internal_label() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .heartbeat(Duration::from_millis(50))
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    // The background task runs on this thread, so anything it logged through
+    // `tracing` would reach the layer again.
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("application event");
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    let (internal, application): (Vec<_>, Vec<_>) = entries
+        .iter()
+        .partition(|entry| entry["labels"].get(INTERNAL_LABEL).is_some());
+    assert_eq!(application.len(), 1);
+    let message = application[0]["jsonPayload"]["message"].as_str().unwrap();
+    assert!(message.ends_with("[application event]"), "{}", message);
+    assert!(internal.len() >= 2);
+    for entry in internal {
+        assert_eq!(entry["labels"][INTERNAL_LABEL], "true");
+        assert_eq!(
+            entry["jsonPayload"]["message"],
+            "nano-gcp-logging heartbeat"
+        );
+    }
+}
+
+#[cfg(feature = "reqwest")]
+struct StaticToken;
+
+#[cfg(feature = "reqwest")]
+impl TokenProvider for StaticToken {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async { Ok("custom-token".to_string()) })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+/// Accept connections like an HTTP proxy refusing every request
+///
+/// # Returns
+/// The proxy's URL, and the first line of each request it receives
+async fn refusing_proxy() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, requests) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            let _ = stream.read_line(&mut line).await;
+            let _ = sender.send(line.trim_end().to_string());
+            let _ = stream
+                .write_all(
+                    b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                )
+                .await;
+        }
+    });
+    (url, requests)
+}
+
+#[cfg(feature = "reqwest")]
+#[tokio::test]
+async fn test_layers_upload_through_the_shared_http_client() {
+    let (proxy, mut requests) = refusing_proxy().await;
+    // Only the shared client goes through the proxy, so every upload the
+    // proxy sees was sent with it.
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(&proxy).unwrap())
+        .build()
+        .unwrap();
+    let mut handles = Vec::new();
+    for _ in 0..2 {
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .token_provider(StaticToken)
+            .http_client(client.clone())
+            .retry_policy(RetryPolicy {
+                max_attempts: 0,
+                ..RetryPolicy::default()
+            })
+            .max_latency(Duration::from_millis(10))
+            .build()
+            .await
+            .expect("Layer with a shared client should build");
+        handles.push(layer.handle());
+    }
+
+    for handle in &handles {
+        handle
+            .emit(LogEntry::new(Severity::Info, "through the proxy"))
+            .expect("Entry should be queued");
+        let request = tokio::time::timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .expect("The upload should go through the shared client")
+            .unwrap();
+        assert_eq!(request, "CONNECT logging.googleapis.com:443 HTTP/1.1");
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_shutdown_on_signal_delivers_entries_once_terminated() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let handle = layer.handle();
+    let waiting = tokio::spawn({
+        let handle = handle.clone();
+        async move { handle.shutdown_on_signal(Duration::from_secs(5)).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished(), "Shutdown should wait for a signal");
+
+    // Nothing is awaited until the signal is sent, so the background task on
+    // this single-threaded runtime has not written the entries yet.
+    for message in ["draining", "last words"] {
+        handle
+            .emit(LogEntry::new(Severity::Info, message))
+            .expect("Entry should be queued");
+    }
+    let status = std::process::Command::new("kill")
+        .args(["-TERM", &std::process::id().to_string()])
+        .status()
+        .expect("kill should run");
+    assert!(status.success());
+
+    let report = tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .expect("SIGTERM should trigger the shutdown")
+        .unwrap()
+        .expect("The background task should report");
+    assert_eq!(report.remaining, 0);
+    assert_eq!(collector.entries().len(), 2);
+    assert!(handle
+        .emit(LogEntry::new(Severity::Info, "too late"))
+        .is_err());
+}
+
+#[test]
+fn test_dedicated_runtime_works_without_tokio() {
+    assert!(tokio::runtime::Handle::try_current().is_err());
+    let collector = TestSink::new();
+    let layer = futures_executor::block_on(
+        GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .sink(collector.clone())
+            .dedicated_runtime(true)
+            .build(),
+    )
+    .expect("Layer with a dedicated runtime should build");
+    let handle = layer.handle();
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::warn!(order_id = 42, "payment retried");
+    });
+    handle
+        .emit(LogEntry::new(Severity::Error, "boom"))
+        .expect("Entry should be queued");
+
+    for _ in 0..100 {
+        if collector.entries().len() >= 2 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let entries = collector.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["jsonPayload"]["order_id"], 42);
+    assert_eq!(entries[1]["severity"], "ERROR");
+    let report = futures_executor::block_on(handle.shutdown(Duration::from_secs(5)));
+    assert!(report.is_some_and(|report| report.remaining == 0));
+    assert_eq!(handle.blocking_shutdown(Duration::from_secs(5)), report);
+}
+
+#[tokio::test]
+async fn test_suffixed_fields_are_converted() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let created = chrono::DateTime::parse_from_rfc3339("2024-05-01T12:00:00.250Z").unwrap();
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(
+            elapsed_ms = ?Duration::from_micros(1_500),
+            created_at = %created.with_timezone(&Utc),
+            label_ms = "not a duration",
+            "request done"
+        );
+    });
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    let payload = &entries[0]["jsonPayload"];
+    assert_eq!(payload["elapsed_ms"], 1.5);
+    assert_eq!(payload["created_at"], "2024-05-01T12:00:00.250Z");
+    assert_eq!(payload["label_ms"], "not a duration");
+}
+
+#[tokio::test]
+async fn test_sizes_and_counts_get_human_readable_companions() {
+    for enabled in [true, false] {
+        let collector = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .human_readable_units(enabled)
+            .sink(collector.clone())
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                body_bytes = 1_258_291u64,
+                header_bytes = ?512usize,
+                row_count = 3_400,
+                delta_count = -1_500_000i64,
+                retry_count = 2,
+                cache_bytes = "unknown",
+                upload_bytes = 2048,
+                upload_bytes_human = "two KiB",
+                "request done"
+            );
+        });
+
+        let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+        let payload = &entries[0]["jsonPayload"];
+        assert_eq!(payload["body_bytes"], 1_258_291);
+        assert_eq!(payload["row_count"], 3_400);
+        assert_eq!(payload["cache_bytes"], "unknown");
+        assert_eq!(payload["upload_bytes_human"], "two KiB");
+        if enabled {
+            assert_eq!(payload["header_bytes"], 512);
+            assert_eq!(payload["body_bytes_human"], "1.2 MiB");
+            assert_eq!(payload["header_bytes_human"], "512 B");
+            assert_eq!(payload["row_count_human"], "3.4k");
+            assert_eq!(payload["delta_count_human"], "-1.5M");
+            assert_eq!(payload["retry_count_human"], "2");
+            assert!(payload.get("cache_bytes_human").is_none());
+        } else {
+            assert_eq!(payload["header_bytes"], "512");
+            assert!(payload.get("body_bytes_human").is_none());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_resource_override_is_used_verbatim() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .resource(
+            MonitoredResource::new("generic_task")
+                .label("job", "indexer")
+                .label("task_id", "7"),
+        )
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a resource override should build");
+
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "indexed"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["resource"],
+        serde_json::json!({"type": "generic_task", "labels": {"job": "indexer", "task_id": "7"}})
+    );
+    assert_eq!(entries[0]["logName"], "projects/test-project/logs/proxie");
+}
+
+#[cfg(feature = "opentelemetry")]
+#[tokio::test]
+async fn test_entries_carry_the_otel_trace_context() {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let provider = SdkTracerProvider::builder().build();
+    let otel = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+    let subscriber = Registry::default().with(otel).with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!("outside any span");
+        let span = tracing::info_span!("request");
+        let _guard = span.enter();
+        tracing::info!("inside the span");
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    assert!(entries[0].get("trace").is_none());
+    assert!(entries[0].get("traceSampled").is_none());
+    let trace = entries[1]["trace"].as_str().expect("trace should be set");
+    assert!(trace.starts_with("projects/test-project/traces/"));
+    assert_eq!(entries[1]["spanId"].as_str().map(str::len), Some(16));
+    assert_eq!(entries[1]["traceSampled"], true);
+}
+
+#[tokio::test]
+async fn test_span_fields_flatten_and_nest() {
+    let modes = [
+        SpanFieldMode::Omit,
+        SpanFieldMode::Flatten,
+        SpanFieldMode::Nested,
+        SpanFieldMode::prefixed(),
+        SpanFieldMode::Prefixed { separator: "__" },
+    ];
+    for mode in modes {
+        let collector = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .span_fields(mode)
+            .sink(collector.clone())
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", path = "/orders", id = 1);
+            let _request = request.enter();
+            let query = tracing::info_span!("query", table = "orders", id = 2);
+            let _query = query.enter();
+            tracing::info!(rows = 3, "query.table" = "event", "query done");
+        });
+
+        let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+        let payload = &entries[0]["jsonPayload"];
+        assert_eq!(payload["rows"], 3);
+        match mode {
+            SpanFieldMode::Omit => {
+                let keys: Vec<_> = payload.as_object().unwrap().keys().collect();
+                assert!(!keys.iter().any(|key| key.contains("path")), "{:?}", keys);
+                assert!(payload.get("context").is_none());
+            }
+            SpanFieldMode::Flatten => {
+                assert_eq!(payload["path"], "/orders");
+                assert_eq!(payload["table"], "orders");
+                assert_eq!(payload["id"], 2);
+            }
+            SpanFieldMode::Nested => {
+                assert!(payload.get("path").is_none());
+                assert_eq!(
+                    payload["context"],
+                    serde_json::json!({
+                        "request": { "path": "/orders", "id": 1 },
+                        "query": { "table": "orders", "id": 2 },
+                    })
+                );
+            }
+            SpanFieldMode::Prefixed { separator } => {
+                assert!(payload.get("path").is_none());
+                assert_eq!(payload[format!("request{}path", separator)], "/orders");
+                assert_eq!(payload[format!("request{}id", separator)], 1);
+                assert_eq!(payload[format!("query{}id", separator)], 2);
+                // The event's own field wins over the span field it shadows.
+                let table = if separator == "." { "event" } else { "orders" };
+                assert_eq!(payload[format!("query{}table", separator)], table);
+            }
+        }
+    }
+}
+
+#[tracing::instrument(fields(attempt = tracing::field::Empty))]
+fn handle_order(request_id: &str, amount: u64) {
+    tracing::Span::current().record("attempt", 2);
+    tracing::info!("charging");
+    charge_card();
+}
+
+#[tracing::instrument]
+fn charge_card() {
+    tracing::warn!(code = "declined", "card declined");
+}
+
+#[tokio::test]
+async fn test_nested_span_fields_merge_same_named_spans_and_keep_event_fields() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .span_fields(SpanFieldMode::Nested)
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let outer = tracing::info_span!("retry", attempt = 1, first = true);
+        let _outer = outer.enter();
+        let inner = tracing::info_span!("retry", attempt = 2);
+        let _inner = inner.enter();
+        tracing::info!(context = "checkout", user_context = "taken", "retrying");
+    });
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    let payload = &entries[0]["jsonPayload"];
+    // The inner span overrides the outer one's fields.
+    assert_eq!(
+        payload["context"],
+        serde_json::json!({ "retry": { "attempt": 2, "first": true } })
+    );
+    // The event's own `context` is renamed rather than overwritten.
+    assert_eq!(payload["user_user_context"], "checkout");
+    assert_eq!(payload["user_context"], "taken");
+}
+
+#[tokio::test]
+async fn test_instrument_fields_reach_every_event_inside() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        handle_order("ab-12", 42);
+        tracing::info!("after the request");
+    });
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    for entry in &entries[..2] {
+        let payload = &entry["jsonPayload"];
+        // `#[instrument]` records arguments with `Debug`; strings lose their quotes.
+        assert_eq!(payload["request_id"], "ab-12");
+        assert_eq!(payload["amount"], 42);
+        assert_eq!(payload["attempt"], 2);
+    }
+    assert_eq!(entries[1]["jsonPayload"]["code"], "declined");
+    assert!(entries[2]["jsonPayload"].get("request_id").is_none());
+}
+
+#[tokio::test]
+async fn test_context_fields_follow_their_task() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .span_fields(SpanFieldMode::Flatten)
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    nano_gcp_logging::context::scope(async {
+        nano_gcp_logging::context::insert("user_id", 7);
+        nano_gcp_logging::context::insert("request_id", "ctx");
+        let span = tracing::info_span!("request", request_id = "span");
+        span.in_scope(|| tracing::info!("inside"));
+        tokio::task::yield_now().await;
+        assert_eq!(
+            nano_gcp_logging::context::remove("request_id"),
+            Some("ctx".into())
+        );
+        tracing::info!(user_id = 8, "own field");
+    })
+    .await;
+    tracing::info!("outside");
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["jsonPayload"]["user_id"], 7);
+    // Span fields and the event's own win over context fields.
+    assert_eq!(entries[0]["jsonPayload"]["request_id"], "span");
+    assert_eq!(entries[1]["jsonPayload"]["user_id"], 8);
+    assert!(entries[1]["jsonPayload"].get("request_id").is_none());
+    assert!(entries[2]["jsonPayload"].get("user_id").is_none());
+}
+
+#[tokio::test]
+async fn test_request_id_labels_every_entry_of_the_request() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .label_field(REQUEST_ID_FIELD)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    let incoming = RequestId::from_header(Some(" ab-12 "));
+    assert_eq!(incoming.as_str(), "ab-12");
+    incoming
+        .scope(async {
+            tracing::info!("received");
+            tokio::task::yield_now().await;
+            tracing::warn!("slow upstream");
+        })
+        .await;
+    let generated = RequestId::from_header(Some("has spaces"));
+    assert_eq!(generated.as_str().len(), 32);
+    assert_ne!(generated, RequestId::generate());
+    generated.in_scope(|| tracing::info!("sync handler"));
+    tracing::info!("between requests");
+
+    let entries = collector.wait_for(4, Duration::from_secs(5)).await;
+    for entry in &entries[..2] {
+        assert_eq!(entry["labels"]["request_id"], "ab-12");
+        assert_eq!(entry["jsonPayload"]["request_id"], "ab-12");
+    }
+    assert_eq!(entries[2]["labels"]["request_id"], generated.as_str());
+    assert!(entries[3].get("labels").is_none());
+}
+
+tokio::task_local! {
+    static TENANT: String;
+}
+
+#[tokio::test]
+async fn test_tenant_resolver_labels_entries_from_ambient_context() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .label("tenant_id", "static")
+        .tenant_resolver(|| TENANT.try_with(Clone::clone).ok())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    TENANT
+        .scope("acme".to_string(), async {
+            tracing::info!("tenant request");
+        })
+        .await;
+    tracing::info!("no tenant");
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["labels"][TENANT_LABEL], "acme");
+    assert_eq!(entries[1]["labels"][TENANT_LABEL], "static");
+}
+
+#[tokio::test]
+async fn test_cloud_trace_header_links_entries() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!(
+            "request",
+            cloud_trace_context = "105445aa7843bc8bf206b12000100000/1;o=1"
+        );
+        let _guard = span.enter();
+        tracing::info!("handled");
+    });
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["trace"],
+        "projects/test-project/traces/105445aa7843bc8bf206b12000100000"
+    );
+    assert_eq!(entries[0]["spanId"], "0000000000000001");
+    assert_eq!(entries[0]["traceSampled"], true);
+    assert!(entries[0]["jsonPayload"]
+        .get("cloud_trace_context")
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_traceparent_links_entries() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!(
+            "request",
+            traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            cloud_trace_context = "105445aa7843bc8bf206b12000100000/1;o=0"
+        );
+        let _guard = span.enter();
+        tracing::info!("handled");
+        // A malformed event value falls back to the span's header.
+        tracing::info!(traceparent = "00-garbage-01", "malformed");
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    for entry in &entries {
+        assert_eq!(
+            entry["trace"],
+            "projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(entry["spanId"], "00f067aa0ba902b7");
+        assert_eq!(entry["traceSampled"], true);
+        assert!(entry["jsonPayload"].get("traceparent").is_none());
+        assert!(entry["jsonPayload"].get("cloud_trace_context").is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_trace_export_links_entries_to_their_spans() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .trace_export(TraceExport::default())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info_span!("job").in_scope(|| {
+            tracing::info!("started");
+            tracing::info_span!("step").in_scope(|| tracing::info!("stepped"));
+        });
+        let request = tracing::info_span!(
+            "request",
+            traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+        );
+        request.in_scope(|| tracing::info!("handled"));
+        tracing::info!("outside");
+    });
+
+    let entries = collector.wait_for(4, Duration::from_secs(5)).await;
+    let trace = entries[0]["trace"].as_str().expect("trace should be set");
+    assert!(trace.starts_with("projects/test-project/traces/"));
+    assert_eq!(entries[1]["trace"], trace);
+    assert_eq!(entries[0]["spanId"].as_str().map(str::len), Some(16));
+    assert_ne!(entries[0]["spanId"], entries[1]["spanId"]);
+    assert_eq!(entries[0]["traceSampled"], true);
+    // The request span joins the caller's trace as a child of its span.
+    assert_eq!(
+        entries[2]["trace"],
+        "projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+    );
+    assert_ne!(entries[2]["spanId"], "00f067aa0ba902b7");
+    assert_ne!(entries[2]["traceSampled"], true);
+    assert!(entries[3].get("trace").is_none());
+}
+
+#[tokio::test]
+async fn test_max_fields_truncates_and_marks_entry() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .max_fields(2)
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(a = 1, b = 2, c = 3, "too many fields");
+        tracing::info!(a = 1, "few fields");
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    let payload = &entries[0]["jsonPayload"];
+    assert_eq!(payload["a"], 1);
+    assert_eq!(payload["b"], 2);
+    assert!(payload.get("c").is_none());
+    assert_eq!(payload["fields_truncated"], true);
+    assert!(entries[1]["jsonPayload"].get("fields_truncated").is_none());
+}
+
+#[test]
+fn test_max_entry_bytes_drops_largest_fields_first() {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let mut fields = Map::new();
+    fields.insert("body".into(), "x".repeat(4000).into());
+    fields.insert("headers".into(), "y".repeat(2000).into());
+    fields.insert("status".into(), 200.into());
+    let log_entry = GcpLogEntry {
+        message: "request done".into(),
+        severity: Severity::Info,
+        fields,
+        timestamp: Utc::now(),
+        source_location: None,
+        operation: None,
+        trace: None,
+        labels: Default::default(),
+        runtime_labels: None,
+        internal: false,
+        callsite: None,
+        pending: PendingFields::default(),
+    };
+    let size = |entry: &Value| serde_json::to_vec(entry).unwrap().len();
+
+    let mut config = EntryConfig::default();
+    config.max_entry_bytes = Some(3000);
+    let entry = build_entry(&metadata, &config, &log_entry);
+    let payload = &entry["jsonPayload"];
+    assert!(size(&entry) <= 3000);
+    assert!(payload.get("body").is_none());
+    assert_eq!(payload["headers"].as_str().map(str::len), Some(2000));
+    assert_eq!(payload["status"], 200);
+    assert_eq!(payload["fields_dropped"], serde_json::json!(["body"]));
+
+    // Metadata and the message stay even when the entry cannot fit.
+    config.max_entry_bytes = Some(10);
+    let entry = build_entry(&metadata, &config, &log_entry);
+    let payload = &entry["jsonPayload"];
+    assert_eq!(payload["message"], "request done");
+    assert_eq!(payload["instance"]["id"], "1234");
+    assert_eq!(
+        payload["fields_dropped"],
+        serde_json::json!(["body", "headers", "status"])
+    );
+
+    config.max_entry_bytes = Some(1 << 20);
+    let entry = build_entry(&metadata, &config, &log_entry);
+    assert!(entry["jsonPayload"].get("fields_dropped").is_none());
+}
+
+#[tokio::test]
+async fn test_ignored_targets_break_upload_feedback_loops() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::debug!(target: "hyper", "polling");
+        tracing::debug!(target: "hyper_util::client", "connecting");
+        tracing::debug!(target: "reqwest::connect", "starting new connection");
+        tracing::warn!(target: "nano_gcp_logging::worker", "upload failed");
+        tracing::info!(target: "myapp::db", "shipped");
+        // Crates merely named like an ignored one are shipped.
+        tracing::info!(target: "hyperdrive::engine", "warp");
+        tracing::info!(target: "reqwest_middleware", "retrying");
+    });
+    let entries = wait_for_exactly(&collector, 3).await;
+    let loggers: Vec<_> = entries
+        .iter()
+        .map(|entry| entry["jsonPayload"]["logger"].clone())
+        .collect();
+    assert_eq!(
+        loggers,
+        ["myapp::db", "hyperdrive::engine", "reqwest_middleware"]
+    );
+
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .ignored_targets(["myapp::db"])
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "myapp::db", "ignored");
+        tracing::debug!(target: "reqwest::connect", "shipped");
+    });
+    let entries = wait_for_exactly(&collector, 1).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["jsonPayload"]["logger"], "reqwest::connect");
+}
+
+/// Layer counting the events the subscriber delivers to it
+#[derive(Clone, Default)]
+struct Seen(Arc<AtomicUsize>);
+
+impl<S: tracing::Subscriber> Layer<S> for Seen {
+    fn on_event(&self, _event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[tokio::test]
+async fn test_shipped_targets_only_ship_matching_events_and_spans() {
+    let collector = TestSink::new();
+    let seen = Seen::default();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .log_span_lifecycle(true)
+        .span_fields(SpanFieldMode::Flatten)
+        .sink(collector.clone())
+        .shipped_targets("myapp=info")
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let subscriber = Registry::default().with(layer).with(seen.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!(target: "otherdep", "pool", conn = 7);
+        let _entered = span.enter();
+        tracing::info!(target: "otherdep", "not listed");
+        tracing::debug!(target: "myapp", "too verbose");
+        tracing::info!(target: "myapp", "shipped");
+    });
+
+    let entries = wait_for_exactly(&collector, 1).await;
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    let payload = &entries[0]["jsonPayload"];
+    assert!(payload["message"].as_str().unwrap().ends_with("[shipped]"));
+    // The unshipped span never reaches the layer: no lifecycle entries, no fields.
+    assert!(payload.get("conn").is_none());
+    assert_eq!(seen.0.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn test_global_filter_disables_unshipped_callsites() {
+    for global in [false, true] {
+        let collector = TestSink::new();
+        let seen = Seen::default();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .global_filter(global)
+            .sink(collector.clone())
+            .shipped_targets("myapp=info")
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+        let subscriber = Registry::default().with(layer).with(seen.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(target: "myapp::db", "too verbose");
+            tracing::info!(target: "otherdep", "not listed");
+            tracing::info!(target: "reqwest::connect", "ignored");
+            tracing::info!(target: "myapp::db", "shipped");
+        });
+        let entries = wait_for_exactly(&collector, 1).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["jsonPayload"]["logger"], "myapp::db");
+        let expected = if global { 1 } else { 4 };
+        assert_eq!(seen.0.load(Ordering::Relaxed), expected);
+    }
+}
+
+#[tokio::test]
+async fn test_with_filter_filters_only_the_gcp_layer() {
+    let collector = TestSink::new();
+    let seen = Seen::default();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .span_fields(SpanFieldMode::Flatten)
+        .sink(collector.clone())
+        .with_filter(LevelFilter::WARN)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let subscriber = Registry::default().with(seen.clone()).with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "myapp", "console only");
+        let outer = tracing::info_span!("session", user = "ada");
+        let _outer = outer.enter();
+        let span = tracing::warn_span!("request", path = "/pay");
+        let _entered = span.enter();
+        tracing::info!(target: "myapp", "still console only");
+        tracing::warn!(target: "myapp", "shipped");
+    });
+
+    let entries = wait_for_exactly(&collector, 1).await;
+    assert_eq!(entries.len(), 1);
+    let payload = &entries[0]["jsonPayload"];
+    assert!(payload["message"].as_str().unwrap().ends_with("[shipped]"));
+    assert_eq!(payload["path"], "/pay");
+    // The filtered-out span is invisible to the layer.
+    assert!(payload.get("user").is_none());
+    assert_eq!(seen.0.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn test_global_filter_hints_the_most_verbose_shipped_level() {
+    let hint = |global: bool, filter: Option<&str>| {
+        let builder = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .global_filter(global)
+            .sink(TestSink::new());
+        let filter = filter.map(str::to_string);
+        async move {
+            let Some(filter) = filter else {
+                let layer = builder
+                    .build()
+                    .await
+                    .expect("Layer with a sink should build");
+                return Layer::<Registry>::max_level_hint(&layer);
+            };
+            let layer = builder
+                .shipped_targets(filter)
+                .build::<Registry>()
+                .await
+                .expect("Layer with a sink should build");
+            Layer::<Registry>::max_level_hint(&layer)
+        }
+    };
+    assert_eq!(
+        hint(true, Some("warn,myapp=debug")).await,
+        Some(LevelFilter::DEBUG)
+    );
+    assert_eq!(hint(true, Some("myapp=off")).await, Some(LevelFilter::OFF));
+    // The per-layer filter hints too; the subscriber only applies it
+    // globally when every other layer is at most as verbose.
+    assert_eq!(
+        hint(false, Some("warn,myapp=debug")).await,
+        Some(LevelFilter::DEBUG)
+    );
+    assert_eq!(hint(true, None).await, None);
+}
+
+#[tokio::test]
+async fn test_startup_metadata_becomes_labels() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .label("team", "payments")
+        .startup_metadata(|| async {
+            HashMap::from([
+                ("config_hash".to_string(), "5f2c".to_string()),
+                ("team".to_string(), "discovered".to_string()),
+            ])
+        })
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "started"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["labels"]["config_hash"], "5f2c");
+    assert_eq!(entries[0]["labels"]["team"], "payments");
+}
+
+#[tokio::test]
+async fn test_full_log_names_write_to_other_resources() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .log_name("organizations/123/logs/audit/trail")
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let handle = layer.handle();
+
+    handle
+        .emit(LogEntry::new(Severity::Info, "org"))
+        .expect("Entry should be queued");
+    collector.wait_for(1, Duration::from_secs(5)).await;
+    handle
+        .set_log_name("folders/456/logs/app%2Fdebug")
+        .expect("Encoded log IDs should be accepted");
+    handle
+        .emit(LogEntry::new(Severity::Info, "folder"))
+        .expect("Entry should be queued");
+    collector.wait_for(2, Duration::from_secs(5)).await;
+    assert!(handle.set_log_name("folders/456/app").is_err());
+    handle
+        .set_log_name("projects/central-logs/logs/app")
+        .expect("Other projects should be accepted");
+    handle
+        .emit(LogEntry::new(Severity::Info, "project"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["logName"],
+        "organizations/123/logs/audit%2Ftrail"
+    );
+    assert_eq!(entries[1]["logName"], "folders/456/logs/app%2Fdebug");
+    assert_eq!(entries[2]["logName"], "projects/central-logs/logs/app");
+    assert_eq!(
+        entries[2]["resource"]["labels"]["project_id"],
+        "test-project"
+    );
+}
+
+#[tokio::test]
+async fn test_set_log_name_redirects_entries() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .log_name("my-app")
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let handle = layer.handle();
+
+    handle
+        .emit(LogEntry::new(Severity::Info, "before"))
+        .expect("Entry should be queued");
+    collector.wait_for(1, Duration::from_secs(5)).await;
+    assert!(handle.set_log_name("bad name!").is_err());
+    handle
+        .set_log_name("my-app/debug")
+        .expect("Valid log name should be accepted");
+    handle
+        .emit(LogEntry::new(Severity::Info, "after"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    let stats = handle.stats();
+    assert_eq!((stats.queued, stats.sent_ok, stats.bytes_sent), (2, 2, 0));
+    assert_eq!(entries[0]["logName"], "projects/test-project/logs/my-app");
+    assert_eq!(
+        entries[1]["logName"],
+        "projects/test-project/logs/my-app%2Fdebug"
+    );
+}
+
+#[tokio::test]
+async fn test_runtime_labels_tag_later_entries() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .label("team", "payments")
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let handle = layer.handle();
+
+    handle.set_label("incident", "INC-123");
+    handle.set_label("team", "oncall");
+    handle
+        .emit(LogEntry::new(Severity::Info, "during"))
+        .expect("Entry should be queued");
+    collector.wait_for(1, Duration::from_secs(5)).await;
+    handle.remove_label("incident");
+    handle.remove_label("team");
+    handle
+        .emit(LogEntry::new(Severity::Info, "after"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["labels"]["incident"], "INC-123");
+    assert_eq!(entries[0]["labels"]["team"], "oncall");
+    assert!(entries[1]["labels"].get("incident").is_none());
+    assert_eq!(entries[1]["labels"]["team"], "payments");
+}
+
+#[tokio::test]
+async fn test_runtime_labels_skip_entries_queued_before_the_change() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let handle = layer.handle();
+
+    // Nothing is awaited in between, so the background task on this
+    // single-threaded runtime only builds the entries once all are queued.
+    let emit = |message: &str| {
+        handle
+            .emit(LogEntry::new(Severity::Info, message))
+            .expect("Entry should be queued");
+    };
+    emit("before");
+    handle.set_label("incident", "INC-123");
+    emit("during");
+    handle.remove_label("incident");
+    emit("after");
+
+    let entries = wait_for_exactly(&collector, 3).await;
+    let incident = |entry: &Value| entry["labels"].get("incident").cloned();
+    assert_eq!(incident(&entries[0]), None);
+    assert_eq!(incident(&entries[1]), Some("INC-123".into()));
+    assert_eq!(incident(&entries[2]), None);
+}
+
+#[tokio::test]
+async fn test_every_level_maps_to_a_severity() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .default_severity(Severity::Notice)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::trace!("trace");
+        tracing::debug!("debug");
+        tracing::info!("info");
+        tracing::warn!("warn");
+        tracing::error!("error");
+    });
+
+    let entries = collector.wait_for(5, Duration::from_secs(5)).await;
+    let severities: Vec<&Value> = entries.iter().map(|entry| &entry["severity"]).collect();
+    assert_eq!(
+        severities,
+        ["DEBUG", "DEBUG", "INFO", "WARNING", "ERROR"],
+        "Each level should map to its severity, not the default"
+    );
+}
+
+#[tokio::test]
+async fn test_severity_number_is_opt_in() {
+    for enabled in [true, false] {
+        let collector = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .severity_number(enabled)
+            .sink(collector.clone())
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::debug!("debug");
+            tracing::info!("info");
+            tracing::warn!("warn");
+            tracing::error!("error");
+        });
+        let entries = collector.wait_for(4, Duration::from_secs(5)).await;
+        let numbers: Vec<&Value> = entries
+            .iter()
+            .map(|entry| &entry["jsonPayload"]["severity_number"])
+            .collect();
+        match enabled {
+            true => assert_eq!(numbers, [100, 200, 400, 500]),
+            false => assert!(numbers.iter().all(|number| number.is_null())),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_preserve_order_stamps_increasing_insert_ids() {
+    for enabled in [true, false] {
+        let collector = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .preserve_order(enabled)
+            .sink(collector.clone())
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for i in 0..12 {
+                tracing::info!(i, "in order");
+            }
+        });
+        let entries = collector.wait_for(12, Duration::from_secs(5)).await;
+        let ids: Vec<Option<&str>> = entries
+            .iter()
+            .map(|entry| entry["insertId"].as_str())
+            .collect();
+        if !enabled {
+            assert!(ids.iter().all(Option::is_none), "{:?}", ids);
+            continue;
+        }
+        let ids: Vec<&str> = ids.into_iter().flatten().collect();
+        assert_eq!(ids.len(), 12);
+        // Sorting the ids as strings, as Cloud Logging does, keeps the order
+        // the entries were emitted in, past 9 and 10 too.
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(sorted, ids);
+        sorted.dedup();
+        assert_eq!(sorted.len(), 12);
+    }
+}
+
+#[tokio::test]
+async fn test_event_timestamp_and_queue_latency_are_opt_in() {
+    for enabled in [true, false] {
+        let collector = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .event_timestamp(enabled)
+            .queue_latency_field(enabled)
+            .sink(collector.clone())
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+        // Sent timestamps may be truncated, e.g. to microseconds.
+        let before = Utc::now() - chrono::Duration::milliseconds(1);
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info!("timed");
+        });
+        let after = Utc::now();
+        let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+        let entry = &entries[0];
+        if !enabled {
+            assert!(entry.get("timestamp").is_none(), "{}", entry);
+            assert!(entry["jsonPayload"].get("queue_latency_ms").is_none());
+            continue;
+        }
+        // The capture time, not the time the entry reached the sink.
+        let timestamp = entry["timestamp"].as_str().expect("timestamp");
+        let timestamp = chrono::DateTime::parse_from_rfc3339(timestamp).unwrap();
+        assert!(before <= timestamp && timestamp <= after, "{}", timestamp);
+        let latency = entry["jsonPayload"]["queue_latency_ms"]
+            .as_f64()
+            .expect("queue_latency_ms");
+        assert!((0.0..5_000.0).contains(&latency), "{}", latency);
+    }
+}
+
+#[tokio::test]
+async fn test_target_is_a_payload_field() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let renamed = TestSink::new();
+    let renamed_layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(renamed.clone())
+        .target_field(Some("module"))
+        .target_in_message(false)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer).with(renamed_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "myapp::db", "query done");
+    });
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["jsonPayload"]["logger"], "myapp::db");
+    assert!(entries[0]["jsonPayload"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("myapp::db"));
+
+    let entries = renamed.wait_for(1, Duration::from_secs(5)).await;
+    let payload = &entries[0]["jsonPayload"];
+    assert_eq!(payload["module"], "myapp::db");
+    assert!(payload.get("logger").is_none());
+    assert!(!payload["message"].as_str().unwrap().contains("myapp::db"));
+}
+
+#[tokio::test]
+async fn test_message_format_replaces_the_default_line() {
+    let plain = TestSink::new();
+    let plain_layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(plain.clone())
+        .message_format(MessageFormat::Plain)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    let custom = TestSink::new();
+    let custom_layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(custom.clone())
+        .message_format(MessageFormat::Custom(Arc::new(|parts| {
+            format!(
+                "{} {}@{}: {}",
+                parts.severity.as_str(),
+                parts.target,
+                parts.line.unwrap_or_default(),
+                parts.message
+            )
+        })))
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(plain_layer).with(custom_layer);
+    let line = line!() + 2;
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::warn!(target: "myapp::db", "slow query");
+    });
+
+    let entries = plain.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["jsonPayload"]["message"], "slow query");
+    let entries = custom.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["jsonPayload"]["message"],
+        format!("WARNING myapp::db@{}: slow query", line)
+    );
+}
+
+/// Formatter shouting the message, to check custom implementations are used
+struct Shouting;
+
+impl MessageFormatter for Shouting {
+    fn format<'a>(&self, parts: &MessageParts<'a>) -> Cow<'a, str> {
+        Cow::Owned(parts.message.to_uppercase())
+    }
+}
+
+/// Start a layer formatting its message lines with `formatter`
+async fn layer_with_formatter(formatter: impl MessageFormatter) -> (GcpLoggingLayer, TestSink) {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .message_formatter(formatter)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+    (layer, collector)
+}
+
+#[tokio::test]
+async fn test_message_formatters_build_the_line() {
+    let (compact, compact_lines) = layer_with_formatter(CompactFormatter).await;
+    let (json_only, json_only_lines) = layer_with_formatter(JsonOnlyFormatter).await;
+    let (full, full_lines) = layer_with_formatter(FullFormatter { target: false }).await;
+    let (shouting, shouting_lines) = layer_with_formatter(Shouting).await;
+    let collectors = [compact_lines, json_only_lines, full_lines, shouting_lines];
+    let subscriber = vec![compact, json_only, full, shouting];
+
+    let subscriber = Registry::default().with(subscriber);
+    let line = line!() + 2;
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::warn!(target: "myapp::db", "slow query");
+    });
+
+    let mut messages = Vec::new();
+    for collector in &collectors {
+        let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+        messages.push(
+            entries[0]["jsonPayload"]["message"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        );
+    }
+    assert_eq!(messages[0], "WARNING myapp::db: slow query");
+    assert_eq!(messages[1], "slow query");
+    assert!(messages[2].ends_with(&format!(
+        "] WARNING [tests/testing.rs:{}] [slow query]",
+        line
+    )));
+    assert_eq!(messages[3], "SLOW QUERY");
+}
+
+/// Serializer nesting the fields under `attributes`, next to the host name
+struct Envelope;
+
+impl PayloadSerializer for Envelope {
+    fn payload(&self, parts: &PayloadParts<'_>) -> Map<String, Value> {
+        let mut payload = Map::new();
+        payload.insert("msg".into(), parts.message.into());
+        payload.insert("host".into(), parts.metadata.instance.name.clone().into());
+        payload.insert("module".into(), parts.target.into());
+        payload.insert("attributes".into(), Value::Object(parts.fields.clone()));
+        payload
+    }
+}
+
+#[tokio::test]
+async fn test_payload_serializer_replaces_the_payload() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .message_format(MessageFormat::Plain)
+        .payload_serializer(Envelope)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::error!(target: "myapp::db", rows = 3, "slow query");
+    });
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["severity"], "ERROR");
+    assert_eq!(
+        entries[0]["jsonPayload"],
+        serde_json::json!({
+            "msg": "slow query",
+            "host": "logical-service",
+            "module": "myapp::db",
+            "attributes": { "rows": 3 },
+        })
+    );
+}
+
+/// Serializer keeping the message and the code location handed to it
+struct Located;
+
+impl PayloadSerializer for Located {
+    fn payload(&self, parts: &PayloadParts<'_>) -> Map<String, Value> {
+        let mut payload = Map::new();
+        payload.insert("message".into(), parts.message.into());
+        if let Some(location) = parts.source_location {
+            payload.insert("file".into(), location.file.clone().into());
+            payload.insert("line".into(), location.line.into());
+        }
+        payload
+    }
+}
+
+#[tokio::test]
+async fn test_callsite_location_is_shared_by_its_events() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .payload_serializer(Located)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    let line = line!() + 3;
+    tracing::subscriber::with_default(subscriber, || {
+        for attempt in 0..2 {
+            tracing::info!(target: "myapp::retry", "attempt {}", attempt);
+        }
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    for (attempt, entry) in entries.iter().enumerate() {
+        let payload = &entry["jsonPayload"];
+        assert!(payload["message"].as_str().unwrap().ends_with(&format!(
+            "] INFO [myapp::retry tests/testing.rs:{}] [attempt {}]",
+            line, attempt
+        )));
+        assert_eq!(payload["file"], "tests/testing.rs");
+        assert_eq!(payload["line"], line);
+    }
+}
+
+#[tokio::test]
+async fn test_severity_routes_pick_destination_by_range() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .label("team", "payments")
+        .severity_route(
+            Severity::Default,
+            SeverityRoute {
+                log_name: Some("app-debug".into()),
+                resource: Some(
+                    ResourceType::Global {
+                        project_id: "test-project".into(),
+                    }
+                    .into(),
+                ),
+                labels: [("tier".to_string(), "cheap".to_string())].into(),
+            },
+        )
+        .severity_route(Severity::Warning, SeverityRoute::default())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::debug!("cache miss");
+        tracing::error!("payment failed");
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["logName"],
+        "projects/test-project/logs/app-debug"
+    );
+    assert_eq!(entries[0]["resource"]["type"], "global");
+    assert_eq!(entries[0]["labels"]["tier"], "cheap");
+    assert_eq!(entries[0]["labels"]["team"], "payments");
+
+    assert_eq!(entries[1]["logName"], "projects/test-project/logs/proxie");
+    assert_eq!(entries[1]["resource"]["type"], "gce_instance");
+    assert!(entries[1]["labels"].get("tier").is_none());
+
+    let invalid = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .severity_route(
+            Severity::Info,
+            SeverityRoute {
+                log_name: Some("bad name".into()),
+                ..Default::default()
+            },
+        )
+        .build()
+        .await;
+    assert!(invalid.is_err());
+}
+
+#[tokio::test]
+async fn test_target_log_names_route_subsystems() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .log_name("app")
+        .target_log_name("shop::payments::*", "payments")
+        .target_log_name("shop::payments::audit", "organizations/42/logs/audit")
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(target: "shop::payments", "charged");
+        tracing::info!(target: "shop::payments::stripe", "webhook");
+        tracing::info!(target: "shop::payments::audit::trail", "refund approved");
+        tracing::info!(target: "shop::paymentsx", "not a submodule");
+    });
+
+    let entries = collector.wait_for(4, Duration::from_secs(5)).await;
+    let log_names: Vec<&str> = entries
+        .iter()
+        .map(|e| e["logName"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        log_names,
+        [
+            "projects/test-project/logs/payments",
+            "projects/test-project/logs/payments",
+            "organizations/42/logs/audit",
+            "projects/test-project/logs/app",
+        ]
+    );
+
+    let invalid = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .target_log_name("shop", "bad name")
+        .build()
+        .await;
+    assert!(invalid.is_err());
+}
+
+#[tokio::test]
+async fn test_log_name_templates_resolve_per_entry() {
+    std::env::set_var("NANO_GCP_TEST_ENV", "staging");
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .span_fields(SpanFieldMode::Flatten)
+        .sink(collector.clone())
+        .log_name("projects/{project}/logs/{service}-{env:NANO_GCP_TEST_ENV}")
+        .log_name_var("service", "checkout")
+        .target_log_name("shop::jobs", "jobs-{field:queue}")
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!("request");
+        tracing::info_span!(target: "shop::jobs", "job", queue = "mail/outbound").in_scope(|| {
+            tracing::info!(target: "shop::jobs", "sent");
+        });
+        tracing::info!(target: "shop::jobs", "no queue");
+    });
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    assert_eq!(
+        entries[0]["logName"],
+        "projects/test-project/logs/checkout-staging"
+    );
+    // Values never add path segments.
+    assert_eq!(
+        entries[1]["logName"],
+        "projects/test-project/logs/jobs-mail_outbound"
+    );
+    assert_eq!(
+        entries[2]["logName"],
+        "projects/test-project/logs/jobs-unknown"
+    );
+
+    for template in ["app-{", "app-{}", "{project} logs"] {
+        let invalid = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .log_name(template)
+            .build()
+            .await;
+        assert!(invalid.is_err(), "{} should be rejected", template);
+    }
+}
+
+#[tokio::test]
+async fn test_split_multiline_groups_lines_in_an_operation() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .split_multiline(3)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(table = "users", "id name\n1 ada\n2 alan\n3 grace");
+        tracing::info!("single line");
+    });
+
+    let entries = collector.wait_for(4, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 4);
+    let operation = &entries[0]["operation"];
+    assert_eq!(operation["first"], true);
+    assert!(entries[1]["operation"].get("first").is_none());
+    assert_eq!(entries[2]["operation"]["last"], true);
+    for entry in &entries[..3] {
+        assert_eq!(entry["operation"]["id"], operation["id"]);
+        assert_eq!(entry["jsonPayload"]["table"], "users");
+    }
+    let message = |i: usize| {
+        entries[i]["jsonPayload"]["message"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    };
+    assert!(message(0).ends_with("[id name]"));
+    assert!(message(1).ends_with("[1 ada]"));
+    assert!(message(2).ends_with("[2 alan\n3 grace]"));
+    assert!(entries[3].get("operation").is_none());
+}
+
+#[tokio::test]
+async fn test_json_messages_merge_into_the_payload() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .parse_json_messages(true)
+        .split_multiline(10)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(
+            user = "ada",
+            "{}",
+            r#"{"msg": "login", "user": "bridged", "attempt": 2}"#
+        );
+        tracing::info!("{{\n  \"message\": \"pretty\",\n  \"ok\": true\n}}");
+        tracing::info!("{{not json}}");
+    });
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 3);
+    let payload = &entries[0]["jsonPayload"];
+    assert!(payload["message"].as_str().unwrap().ends_with("[login]"));
+    assert_eq!(payload["attempt"], 2);
+    assert_eq!(payload["user"], "ada");
+    assert!(payload.get("msg").is_none());
+    let payload = &entries[1]["jsonPayload"];
+    assert!(payload["message"].as_str().unwrap().ends_with("[pretty]"));
+    assert_eq!(payload["ok"], true);
+    assert!(entries[1].get("operation").is_none());
+    assert!(entries[2]["jsonPayload"]["message"]
+        .as_str()
+        .unwrap()
+        .ends_with("[{not json}]"));
+}
+
+#[tokio::test]
+async fn test_ansi_escapes_are_stripped_from_messages() {
+    async fn messages(strip: bool) -> Vec<String> {
+        let collector = TestSink::new();
+        let layer = GcpLoggingLayer::builder("test-project")
+            .instance_metadata(instance())
+            .sink(collector.clone())
+            .message_format(MessageFormat::Plain)
+            .strip_ansi(strip)
+            .build()
+            .await
+            .expect("Layer with a sink should build");
+
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("\u{1b}[1;33mwarning\u{1b}[0m: disk almost full");
+            tracing::info!("see \u{1b}]8;;https://example.com\u{7}docs\u{1b}]8;;\u{1b}\\ for help");
+        });
+        collector
+            .wait_for(2, Duration::from_secs(5))
+            .await
+            .iter()
+            .map(|entry| {
+                entry["jsonPayload"]["message"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    assert_eq!(
+        messages(true).await,
+        ["warning: disk almost full", "see docs for help"]
+    );
+    assert!(messages(false).await[0].starts_with("\u{1b}[1;33m"));
+}
+
+#[tokio::test]
+async fn test_stack_traces_stay_in_one_entry() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .message_format(MessageFormat::Plain)
+        .split_multiline(10)
+        .group_stack_traces(true)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let java = "java.lang.IllegalStateException: closed\n\tat com.example.Pool.get(Pool.java:42)\n\tat com.example.Main.main(Main.java:7)";
+    let rust = "worker failed\nstack backtrace:\n   0: app::run\n             at ./src/main.rs:3:5";
+    let python = "Traceback (most recent call last):\n  File \"app.py\", line 1, in <module>\nZeroDivisionError: division by zero";
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::error!("{}", java);
+        tracing::error!("{}", rust);
+        tracing::error!("{}", python);
+        tracing::info!("total\n  1: apples");
+        tracing::info!("a\nb");
+    });
+
+    let entries = collector.wait_for(6, Duration::from_secs(5)).await;
+    assert_eq!(entries.len(), 6);
+    let payload = |i: usize| &entries[i]["jsonPayload"];
+    assert_eq!(
+        payload(0)["message"],
+        "java.lang.IllegalStateException: closed"
+    );
+    assert!(payload(0)["stack_trace"]
+        .as_str()
+        .unwrap()
+        .starts_with("\tat com.example.Pool.get"));
+    assert_eq!(payload(1)["message"], "worker failed");
+    assert!(payload(1)["stack_trace"]
+        .as_str()
+        .unwrap()
+        .starts_with("stack backtrace:"));
+    assert_eq!(payload(2)["message"], "Traceback (most recent call last):");
+    assert_eq!(payload(2)["stack_trace"], python);
+    // A numbered line is a frame too, whatever follows it.
+    assert_eq!(payload(3)["message"], "total");
+    // Messages without a trace are still split.
+    assert_eq!(payload(4)["message"], "a");
+    assert_eq!(payload(5)["message"], "b");
+    for entry in &entries[..4] {
+        assert!(entry.get("operation").is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_summarize_fields_appends_the_fields_to_the_message() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .message_format(MessageFormat::Plain)
+        .sink(collector.clone())
+        .summarize_fields(true)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(
+            order_id = "A-17",
+            amount = 42,
+            paid = true,
+            note = "two words",
+            empty = "",
+            "payment accepted"
+        );
+        tracing::info!("no fields");
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    let payload = &entries[0]["jsonPayload"];
+    assert_eq!(
+        payload["message"],
+        r#"payment accepted amount=42 empty="" note="two words" order_id=A-17 paid=true"#
+    );
+    // The fields stay structured for filtering.
+    assert_eq!(payload["order_id"], "A-17");
+    assert_eq!(payload["amount"], 42);
+    assert_eq!(payload["paid"], true);
+    assert_eq!(payload["note"], "two words");
+    assert_eq!(entries[1]["jsonPayload"]["message"], "no fields");
+}
+
+#[tokio::test]
+async fn test_deferred_fields_are_converted_and_summarized_once() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .sink(collector.clone())
+        .span_fields(SpanFieldMode::Nested)
+        .summarize_fields(true)
+        .split_multiline(2)
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("request", path = "/orders");
+        let _span = span.enter();
+        tracing::info!(latency_ms = ?Duration::from_millis(1500), "first\nsecond");
+    });
+
+    let entries = collector.wait_for(2, Duration::from_secs(5)).await;
+    let message = |i: usize| entries[i]["jsonPayload"]["message"].as_str().unwrap();
+    assert!(message(0).ends_with("[first]"), "{}", message(0));
+    assert!(
+        message(1).ends_with("[second latency_ms=1500.0]"),
+        "{}",
+        message(1)
+    );
+    for entry in &entries {
+        let payload = &entry["jsonPayload"];
+        assert_eq!(payload["latency_ms"], 1500.0);
+        assert_eq!(payload["context"]["request"]["path"], "/orders");
+    }
+}
+
+#[tokio::test]
+async fn test_rendered_values_survive_buffer_reuse() {
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .instance_metadata(instance())
+        .message_format(MessageFormat::Plain)
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    let large = vec![7u8; 20_000];
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        tracing::info!(bytes = ?large, "large {}", 1);
+        tracing::info!(flag = ?Some(true), "small");
+        tracing::info!(message = "as str");
+    });
+
+    let entries = collector.wait_for(3, Duration::from_secs(5)).await;
+    let payload = |i: usize| &entries[i]["jsonPayload"];
+    assert_eq!(payload(0)["message"], "large 1");
+    assert_eq!(payload(0)["bytes"], format!("{:?}", large));
+    assert_eq!(payload(1)["message"], "small");
+    assert_eq!(payload(1)["flag"], "Some(true)");
+    assert_eq!(payload(2)["message"], "as str");
+}
+
+#[tokio::test]
+async fn test_host_id_fallback_replaces_unknown_instance_id() {
+    if RuntimeEnvironment::detect().await.on_gcp {
+        // The metadata server provides a real instance ID.
+        return;
+    }
+    let collector = TestSink::new();
+    let layer = GcpLoggingLayer::builder("test-project")
+        .host_id_fallback(HostIdSource::Fixed("host-a".into()))
+        .sink(collector.clone())
+        .build()
+        .await
+        .expect("Layer with a sink should build");
+
+    layer
+        .handle()
+        .emit(LogEntry::new(Severity::Info, "hello"))
+        .expect("Entry should be queued");
+
+    let entries = collector.wait_for(1, Duration::from_secs(5)).await;
+    assert_eq!(entries[0]["resource"]["labels"]["instance_id"], "host-a");
+    assert_eq!(entries[0]["jsonPayload"]["instance"]["id"], "host-a");
+}
+
+#[test]
+fn test_structured_log_uses_cloud_run_keys() {
+    let metadata = LogContextMetadata {
+        container: None,
+        instance: instance(),
+    };
+    let mut fields = Map::new();
+    fields.insert("order_id".into(), 42.into());
+    fields.insert("severity".into(), "mine".into());
+    let log_entry = GcpLogEntry {
+        message: "payment retried".into(),
+        severity: Severity::Warning,
+        fields,
+        timestamp: Utc::now(),
+        source_location: Some(SourceLocation {
+            file: "src/main.rs".into(),
+            line: 12,
+        }),
+        operation: None,
+        trace: Some(TraceContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+            sampled: true,
+        }),
+        labels: Default::default(),
+        runtime_labels: None,
+        internal: false,
+        callsite: None,
+        pending: PendingFields::default(),
+    };
+    let mut config = EntryConfig::default();
+    config.labels.insert("team".into(), "payments".into());
+    config.event_timestamp = true;
+
+    let line = to_structured_log(build_entry(&metadata, &config, &log_entry));
+    assert_eq!(line["severity"], "WARNING");
+    assert_eq!(line["message"], "payment retried");
+    assert!(line["time"].is_string());
+    assert_eq!(
+        line["logging.googleapis.com/trace"],
+        "projects/test-project/traces/4bf92f3577b34da6a3ce929d0e0e4736"
+    );
+    assert_eq!(line["logging.googleapis.com/spanId"], "00f067aa0ba902b7");
+    assert_eq!(line["logging.googleapis.com/trace_sampled"], true);
+    assert_eq!(
+        line["logging.googleapis.com/sourceLocation"],
+        serde_json::json!({ "file": "src/main.rs", "line": "12" })
+    );
+    assert_eq!(line["logging.googleapis.com/labels"]["team"], "payments");
+    assert_eq!(line["order_id"], 42);
+    assert_eq!(line["user_severity"], "mine");
+    assert_eq!(line["instance"]["id"], "1234");
+    assert!(line.get("logName").is_none());
+    assert!(line.get("resource").is_none());
+    assert!(line.get("jsonPayload").is_none());
+}
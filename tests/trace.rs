@@ -0,0 +1,76 @@
+// Tests for parsing the X-Cloud-Trace-Context and W3C traceparent headers.
+
+use nano_gcp_logging::TraceContext;
+
+#[test]
+fn test_parses_full_header() {
+    let trace = TraceContext::from_cloud_trace_header("105445AA7843BC8BF206B12000100000/1;o=1")
+        .expect("Header should parse");
+    assert_eq!(trace.trace_id, "105445aa7843bc8bf206b12000100000");
+    assert_eq!(trace.span_id, "0000000000000001");
+    assert!(trace.sampled);
+}
+
+#[test]
+fn test_span_and_options_are_optional() {
+    let trace = TraceContext::from_cloud_trace_header("105445aa7843bc8bf206b12000100000")
+        .expect("Header without span should parse");
+    assert_eq!(trace.span_id, "");
+    assert!(!trace.sampled);
+
+    let trace = TraceContext::from_cloud_trace_header("105445aa7843bc8bf206b12000100000/42;o=0")
+        .expect("Unsampled header should parse");
+    assert_eq!(trace.span_id, format!("{:016x}", 42));
+    assert!(!trace.sampled);
+}
+
+#[test]
+fn test_rejects_malformed_header() {
+    assert!(TraceContext::from_cloud_trace_header("").is_none());
+    assert!(TraceContext::from_cloud_trace_header("not-a-trace/1;o=1").is_none());
+    assert!(
+        TraceContext::from_cloud_trace_header("105445aa7843bc8bf206b12000100000/abc").is_none()
+    );
+}
+
+#[test]
+fn test_parses_traceparent() {
+    let trace =
+        TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+            .expect("Header should parse");
+    assert_eq!(trace.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(trace.span_id, "00f067aa0ba902b7");
+    assert!(trace.sampled);
+
+    let trace =
+        TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-02")
+            .expect("Unsampled header should parse");
+    assert!(!trace.sampled);
+
+    // Later versions may append fields.
+    assert!(TraceContext::from_traceparent(
+        "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"
+    )
+    .is_some());
+}
+
+#[test]
+fn test_rejects_malformed_traceparent() {
+    for header in [
+        "",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+    ] {
+        assert!(
+            TraceContext::from_traceparent(header).is_none(),
+            "{} should be rejected",
+            header
+        );
+    }
+}